@@ -29,7 +29,8 @@ pub fn run(opt: Opt) -> anyhow::Result<()> {
 		let file = path.file_name();
 		if let Some(file) = file {
 			let file = file.to_str().unwrap();
-			if file.ends_with(".json") && !file.ends_with("index.json") {
+			// alias maps for bridges are written next to the packs
+			if file.ends_with(".json") && !file.ends_with("index.json") && !file.ends_with(".aliases.json") {
 				println!("add pack {}", &file[..file.len() - 5]);
 				packs.push(file.into())
 			}
@@ -3,12 +3,15 @@ use anyhow::{bail, Context};
 use clap::Parser;
 use log::{error, info, warn};
 use mstickerlib::{
-	database::FileDatabase,
+	database::{Database as _, FileDatabase},
 	matrix,
 	matrix::sticker_formats::maunium,
-	tg::{self, pack_url_to_name, ImportConfig}
+	tg::{self, pack_url_to_name, ImportConfig, Severity}
+};
+use std::{
+	path::{Path, PathBuf},
+	process::exit
 };
-use std::{path::PathBuf, process::exit};
 use tokio::fs;
 
 #[derive(Debug, Parser)]
@@ -34,7 +37,26 @@ pub struct Opt {
 	/// The stickers can may not be shown by a matrix client.
 	/// Lottie files will be unpack from zstd archive.
 	#[clap(long)]
-	keep_lottie: bool
+	keep_lottie: bool,
+
+	/// Json file, which maps emoji to custom shortcodes, like `{"🐱": "kot"}`
+	#[clap(long)]
+	shortcodes: Option<PathBuf>,
+
+	/// Always write a new pack file,
+	/// instead of merging into an existing file of the same telegram pack at the working directory
+	#[clap(long)]
+	force_new: bool,
+
+	/// Also write a map of the shortcodes to their mxc url and emoji next to each pack, for bridges.
+	/// Can be `json` or `csv`.
+	#[clap(long)]
+	alias_map: Option<matrix::AliasFormat>,
+
+	/// Also publish a smaller variant of each pack, named "<title> (lite)", for low-bandwidth rooms
+	#[cfg(feature = "rendition")]
+	#[clap(long)]
+	lite: bool
 }
 
 #[tokio::main]
@@ -55,13 +77,44 @@ pub async fn run(mut opt: Opt) -> anyhow::Result<()> {
 		packs.push(name.to_owned());
 	}
 	let database = FileDatabase::new(&*DATABASE_FILE).await?;
+	let shortcode_dictionary = match &opt.shortcodes {
+		Some(path) => Some(
+			matrix::ShortcodeDictionary::load(path)
+				.await
+				.with_context(|| format!("failed to load shortcodes from {path:?}"))?
+		),
+		None => None
+	};
 	let mut import_config = ImportConfig::default();
 	import_config.database = Some(&database);
 	import_config.dry_run = opt.dryrun;
 	import_config.keep_webm = opt.keep_webm;
 	import_config.keep_lottie = opt.keep_lottie;
 	import_config.animation_format = config.sticker;
+	import_config.shortcode_dictionary = shortcode_dictionary.as_ref();
+	// saving and rendering the stickers does need their converted files
+	#[cfg(not(feature = "rendition"))]
+	let reuse_imports = !opt.save;
+	#[cfg(feature = "rendition")]
+	let reuse_imports = !opt.save && !opt.lite;
+	import_config.reuse_imports = reuse_imports;
 	let import_config = import_config;
+
+	// report all config problems at once; the matrix config is not used at dry run
+	let issues = import_config.validate_all(&config.telegram, &config.matrix).await;
+	let issues: Vec<_> = issues
+		.into_iter()
+		.filter(|issue| !(opt.dryrun && issue.field.starts_with("matrix.")))
+		.collect();
+	for issue in &issues {
+		match issue.severity {
+			Severity::Warning => warn!("{issue}"),
+			Severity::Error => error!("{issue}")
+		}
+	}
+	if issues.iter().any(|issue| issue.severity == Severity::Error) {
+		bail!("invalid configuration");
+	}
 	let mut empty_packs = Vec::new();
 
 	for pack in packs {
@@ -94,7 +147,7 @@ pub async fn run(mut opt: Opt) -> anyhow::Result<()> {
 			for sticker in &matrix_pack.stickers {
 				{
 					let index = sticker.tg_sticker.as_ref().unwrap().index.unwrap(); //should exist, since we have import the sticker from telegram right now
-					let extension = sticker.image.meta_data.mimetype.split('/').last().unwrap();
+					let extension = sticker.image.meta_data.mimetype.split('/').next_back().unwrap();
 					let path = format!("{dir}/{index:03}.{extension}");
 					fs::write(&path, sticker.image.url.data().as_ref().unwrap().as_ref())
 						.await
@@ -102,11 +155,47 @@ pub async fn run(mut opt: Opt) -> anyhow::Result<()> {
 				}
 			}
 		}
+		#[cfg(feature = "rendition")]
+		let lite_pack = if opt.lite && !opt.dryrun {
+			info!("create lite variant of pack {}", tg_pack.name());
+			let lite_pack = matrix_pack
+				.render(&mstickerlib::image::Rendition::lite(), &config.matrix, Some(&database))
+				.await;
+			Some(match lite_pack {
+				Ok(pack) => pack,
+				Err((lite_pack, errors)) => {
+					for (index, err) in errors {
+						let err = anyhow::Error::from(err)
+							.context(format!("failed to create lite variant of sticker {index} from pack {pack:?}"));
+						error!("{err:?}");
+					}
+					lite_pack
+				}
+			})
+		} else {
+			None
+		};
+		let alias_pack = opt.alias_map.map(|format| (format, matrix_pack.clone()));
 		let matrix_pack: maunium::StickerPack = matrix_pack.into();
-		let path: PathBuf = format!("./{}.json", tg_pack.name()).into();
-		info!("save stickerpack to {:?}", path);
-		fs::write(path, serde_json::to_string(&matrix_pack)?).await?;
+		let path = matrix::write_pack(Path::new("."), matrix_pack, opt.force_new)
+			.await
+			.with_context(|| format!("failed to save stickerpack {pack:?}"))?;
+		info!("saved stickerpack to {:?}", path);
+		if let Some((format, alias_pack)) = alias_pack {
+			let path = matrix::write_alias_map(&path, &alias_pack, format)
+				.await
+				.with_context(|| format!("failed to save alias map of stickerpack {pack:?}"))?;
+			info!("saved alias map to {:?}", path);
+		}
+		#[cfg(feature = "rendition")]
+		if let Some(lite_pack) = lite_pack {
+			let path = matrix::write_pack(Path::new("."), lite_pack.into(), opt.force_new)
+				.await
+				.with_context(|| format!("failed to save lite variant of stickerpack {pack:?}"))?;
+			info!("saved lite variant to {:?}", path);
+		}
 	}
+	database.flush().await.context("failed to save the database")?;
 	if !empty_packs.is_empty() {
 		bail!("The following packs are empty {empty_packs:?}");
 	}
@@ -1,4 +1,5 @@
 use super::{Database, Hash};
+use std::convert::Infallible;
 
 /// Dummy database to be used as default generic.
 /// This database should be never constructed or used.
@@ -7,11 +8,13 @@ use super::{Database, Hash};
 pub struct DummyDatabase {}
 
 impl Database for DummyDatabase {
-	async fn get(&self, _: &Hash) -> anyhow::Result<Option<String>> {
+	type Error = Infallible;
+
+	async fn get(&self, _: &Hash) -> Result<Option<String>, Infallible> {
 		Ok(None)
 	}
 
-	async fn add(&self, _: Hash, _url: String) -> anyhow::Result<()> {
+	async fn add(&self, _: Hash, _url: String) -> Result<(), Infallible> {
 		{
 			Ok(())
 		}
@@ -0,0 +1,82 @@
+use super::{hash, Hash};
+use crate::image::Image;
+use indexmap::IndexMap;
+
+/// Inputs which have the same content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateGroup {
+	pub hash: Hash,
+	/// index of the first input with this content; only this one is uploaded
+	pub canonical: usize,
+	/// indices of the other inputs with the same content, which reuse the upload of `canonical`
+	pub duplicates: Vec<usize>
+}
+
+/// Report, which inputs of a batch have the same content, to help finding duplicate stickers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DuplicateReport {
+	/// groups ordered by the index of their canonical input; inputs without duplicates are not listed
+	pub groups: Vec<DuplicateGroup>
+}
+
+impl DuplicateReport {
+	/// Create the report from the hashes of the inputs, like returned by [`hash`].
+	pub fn from_hashes<I>(hashes: I) -> Self
+	where
+		I: IntoIterator<Item = Hash>
+	{
+		let mut groups: IndexMap<Hash, DuplicateGroup> = IndexMap::new();
+		for (i, hash) in hashes.into_iter().enumerate() {
+			groups
+				.entry(hash)
+				.and_modify(|group| group.duplicates.push(i))
+				.or_insert_with(|| DuplicateGroup {
+					hash,
+					canonical: i,
+					duplicates: Vec::new()
+				});
+		}
+		Self {
+			groups: groups.into_values().filter(|group| !group.duplicates.is_empty()).collect()
+		}
+	}
+
+	pub fn from_images<'a, I>(images: I) -> Self
+	where
+		I: IntoIterator<Item = &'a Image>
+	{
+		Self::from_hashes(images.into_iter().map(|image| hash(image.data())))
+	}
+
+	/// Return `true` if no input has a duplicate.
+	pub fn is_empty(&self) -> bool {
+		self.groups.is_empty()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::DuplicateReport;
+	use crate::image::{Image, ImageBuilder};
+
+	#[test]
+	fn duplicate_report() {
+		let images: Vec<Image> = [&b"foo"[..], b"bar", b"foo"]
+			.into_iter()
+			.enumerate()
+			.map(|(i, data)| {
+				ImageBuilder::new()
+					.data(data.to_vec())
+					.file_name(format!("sticker{i}.webp"))
+					.width(1)
+					.height(1)
+					.build()
+					.unwrap()
+			})
+			.collect();
+		let report = DuplicateReport::from_images(&images);
+		assert_eq!(report.groups.len(), 1);
+		assert_eq!(report.groups[0].canonical, 0);
+		assert_eq!(report.groups[0].duplicates, vec![2]);
+	}
+}
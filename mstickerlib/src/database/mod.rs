@@ -1,22 +1,351 @@
-use anyhow;
+use crate::error::Error;
+use futures_util::future::BoxFuture;
 use sha2::{Digest, Sha512};
+use std::{
+	convert::Infallible,
+	fmt::{self, Display},
+	future::Future,
+	io,
+	time::Duration
+};
+
+#[cfg(feature = "log")]
+use log::warn;
 
 mod simple_file;
 pub use simple_file::FileDatabase;
 mod dummy_database;
 pub use dummy_database::DummyDatabase;
+mod duplicates;
+pub use duplicates::{DuplicateGroup, DuplicateReport};
 
 pub type Hash = [u8; 64];
 
+/// Algorithm of [`hash`], for systems which compute the hashes of the images themselves.
+pub const HASH_ALGORITHM: &str = "sha512";
+
+/// Classification of a [`DatabaseError`], which tells the crate how to handle it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DatabaseErrorKind {
+	/// The database is not available right now, for example because it is locked or not reachable.
+	/// The operation may succeed, if it is retried later.
+	Unavailable,
+	/// The stored data is damaged, so retrying does not help.
+	Corrupt,
+	/// The operation does conflict with an existing entry, for example because the hash was added concurrently.
+	Constraint,
+	Other
+}
+
+impl Display for DatabaseErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Unavailable => "unavailable",
+			Self::Corrupt => "corrupt",
+			Self::Constraint => "constraint violation",
+			Self::Other => "other error"
+		})
+	}
+}
+
+/// Error of a [`Database`].
+pub trait DatabaseError: std::error::Error + Send + Sync + 'static {
+	/// Tell the crate how to handle the error.
+	fn classify(&self) -> DatabaseErrorKind;
+
+	/// The operation may succeed, if it is retried later.
+	fn is_transient(&self) -> bool {
+		self.classify() == DatabaseErrorKind::Unavailable
+	}
+
+	/// The operation does conflict with an existing entry.
+	fn is_conflict(&self) -> bool {
+		self.classify() == DatabaseErrorKind::Constraint
+	}
+}
+
+impl DatabaseError for Infallible {
+	fn classify(&self) -> DatabaseErrorKind {
+		match *self {}
+	}
+}
+
+impl DatabaseError for io::Error {
+	fn classify(&self) -> DatabaseErrorKind {
+		match self.kind() {
+			io::ErrorKind::Interrupted
+			| io::ErrorKind::WouldBlock
+			| io::ErrorKind::TimedOut
+			| io::ErrorKind::ResourceBusy
+			| io::ErrorKind::ConnectionRefused
+			| io::ErrorKind::ConnectionReset
+			| io::ErrorKind::ConnectionAborted
+			| io::ErrorKind::NotConnected => DatabaseErrorKind::Unavailable,
+			io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => DatabaseErrorKind::Corrupt,
+			io::ErrorKind::AlreadyExists => DatabaseErrorKind::Constraint,
+			_ => DatabaseErrorKind::Other
+		}
+	}
+}
+
+/// Time to wait, before an operation on an unavailable database is retried.
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Run a database operation and retry it once, if the database is [unavailable](DatabaseErrorKind::Unavailable).
+pub(crate) async fn retry_unavailable<F, Fut, T, E>(mut operation: F) -> Result<T, Error>
+where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = Result<T, E>>,
+	E: DatabaseError
+{
+	match operation().await {
+		Err(err) if err.classify() == DatabaseErrorKind::Unavailable => {
+			#[cfg(feature = "log")]
+			warn!("database is unavailable, retry once: {err}");
+			tokio::time::sleep(RETRY_DELAY).await;
+			operation().await.map_err(Error::database)
+		},
+		result => result.map_err(Error::database)
+	}
+}
+
 /// Database which stores mappings from hashes to matrix media urls,
 /// to avoid duplicate uploads of the same file.
+///
+/// The methods can be implemented as `async fn`, whose futures must be `Send`.
+/// Every database, which is `Send + Sync`, can also be used as [`DynDatabase`] trait object.
 pub trait Database {
-	async fn get(&self, hash: &Hash) -> anyhow::Result<Option<String>>;
-	async fn add(&self, hash: Hash, url: String) -> anyhow::Result<()>;
+	type Error: DatabaseError;
+
+	fn get(&self, hash: &Hash) -> impl Future<Output = Result<Option<String>, Self::Error>> + Send;
+	fn add(&self, hash: Hash, url: String) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+	/// Persist entries, which the database does buffer; the import calls this after each pack.
+	/// Databases, which persist every entry at [`Database::add`], do not need to implement this.
+	fn flush(&self) -> impl Future<Output = Result<(), Self::Error>> + Send {
+		async { Ok(()) }
+	}
+
+	/// Sticker, which was imported from a downloaded file before, stored by [`Database::add_import`].
+	/// The `key` is the hash of the downloaded file and the import settings,
+	/// so the import can skip the conversion and upload of stickers, which it does already know.
+	/// Databases, which do not implement this, do convert every sticker again.
+	fn get_import(&self, key: &Hash) -> impl Future<Output = Result<Option<String>, Self::Error>> + Send {
+		let _ = key;
+		async { Ok(None) }
+	}
+
+	/// Remember the imported `sticker`, an opaque json document, for [`Database::get_import`].
+	fn add_import(&self, key: Hash, sticker: String) -> impl Future<Output = Result<(), Self::Error>> + Send {
+		let _ = (key, sticker);
+		async { Ok(()) }
+	}
+}
+
+/// Error of a [`DynDatabase`], which does wrap the error of the underlying [`Database`].
+#[derive(Debug)]
+pub struct DynDatabaseError(Box<dyn DatabaseError>);
+
+impl Display for DynDatabaseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		Display::fmt(&self.0, f)
+	}
+}
+
+impl std::error::Error for DynDatabaseError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		self.0.source()
+	}
+}
+
+impl DatabaseError for DynDatabaseError {
+	fn classify(&self) -> DatabaseErrorKind {
+		self.0.classify()
+	}
 }
 
+/// Object safe version of [`Database`], to store different databases behind `Box<dyn DynDatabase>`.
+///
+/// It is implemented for every [`Database`] and `dyn DynDatabase` does implement [`Database`] again,
+/// so trait objects can be passed to every function, which does expect a database.
+pub trait DynDatabase: Send + Sync {
+	fn get_boxed<'a>(&'a self, hash: &'a Hash) -> BoxFuture<'a, Result<Option<String>, DynDatabaseError>>;
+	fn add_boxed(&self, hash: Hash, url: String) -> BoxFuture<'_, Result<(), DynDatabaseError>>;
+	fn flush_boxed(&self) -> BoxFuture<'_, Result<(), DynDatabaseError>>;
+	fn get_import_boxed<'a>(&'a self, key: &'a Hash) -> BoxFuture<'a, Result<Option<String>, DynDatabaseError>>;
+	fn add_import_boxed(&self, key: Hash, sticker: String) -> BoxFuture<'_, Result<(), DynDatabaseError>>;
+}
+
+impl<D> DynDatabase for D
+where
+	D: Database + Send + Sync
+{
+	fn get_boxed<'a>(&'a self, hash: &'a Hash) -> BoxFuture<'a, Result<Option<String>, DynDatabaseError>> {
+		Box::pin(async move { self.get(hash).await.map_err(|err| DynDatabaseError(Box::new(err))) })
+	}
+
+	fn add_boxed(&self, hash: Hash, url: String) -> BoxFuture<'_, Result<(), DynDatabaseError>> {
+		Box::pin(async move { self.add(hash, url).await.map_err(|err| DynDatabaseError(Box::new(err))) })
+	}
+
+	fn flush_boxed(&self) -> BoxFuture<'_, Result<(), DynDatabaseError>> {
+		Box::pin(async move { self.flush().await.map_err(|err| DynDatabaseError(Box::new(err))) })
+	}
+
+	fn get_import_boxed<'a>(&'a self, key: &'a Hash) -> BoxFuture<'a, Result<Option<String>, DynDatabaseError>> {
+		Box::pin(async move { self.get_import(key).await.map_err(|err| DynDatabaseError(Box::new(err))) })
+	}
+
+	fn add_import_boxed(&self, key: Hash, sticker: String) -> BoxFuture<'_, Result<(), DynDatabaseError>> {
+		Box::pin(async move {
+			self.add_import(key, sticker)
+				.await
+				.map_err(|err| DynDatabaseError(Box::new(err)))
+		})
+	}
+}
+
+impl Database for dyn DynDatabase + '_ {
+	type Error = DynDatabaseError;
+
+	async fn get(&self, hash: &Hash) -> Result<Option<String>, DynDatabaseError> {
+		self.get_boxed(hash).await
+	}
+
+	async fn add(&self, hash: Hash, url: String) -> Result<(), DynDatabaseError> {
+		self.add_boxed(hash, url).await
+	}
+
+	async fn flush(&self) -> Result<(), DynDatabaseError> {
+		self.flush_boxed().await
+	}
+
+	async fn get_import(&self, key: &Hash) -> Result<Option<String>, DynDatabaseError> {
+		self.get_import_boxed(key).await
+	}
+
+	async fn add_import(&self, key: Hash, sticker: String) -> Result<(), DynDatabaseError> {
+		self.add_import_boxed(key, sticker).await
+	}
+}
+
+/// Hash of `value` by [`HASH_ALGORITHM`], which is used to find images at the database.
 pub fn hash(value: &[u8]) -> Hash {
 	let mut hasher = Sha512::new();
 	hasher.update(value);
 	hasher.finalize().into()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{Database, DatabaseError as _, DatabaseErrorKind, DummyDatabase, DynDatabase, FileDatabase, Hash};
+	use crate::{
+		image::Image,
+		mock::{matrix_config, serve_uploads},
+		tg::ImportConfig
+	};
+	use std::{collections::HashMap, convert::Infallible, io, net::TcpListener, sync::Mutex, time::Duration};
+
+	#[derive(Default)]
+	struct MemoryDatabase(Mutex<HashMap<Hash, String>>);
+
+	impl Database for MemoryDatabase {
+		type Error = Infallible;
+
+		async fn get(&self, hash: &Hash) -> Result<Option<String>, Infallible> {
+			Ok(self.0.lock().unwrap().get(hash).cloned())
+		}
+
+		async fn add(&self, hash: Hash, url: String) -> Result<(), Infallible> {
+			self.0.lock().unwrap().insert(hash, url);
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn trait_objects() {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-dyn-database-test-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let backends: Vec<Box<dyn DynDatabase>> = vec![
+			Box::new(DummyDatabase {}),
+			Box::new(FileDatabase::new(dir.join("db.txt")).await.unwrap()),
+			Box::new(MemoryDatabase::default()),
+		];
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		// the dummy database does never know an image, so it is uploaded twice
+		let server = std::thread::spawn(move || serve_uploads(listener, 4));
+		let image = Image::from_rgba(vec![255; 4], 1, 1, "sticker.webp").unwrap();
+		let mut uploads = Vec::new();
+		for backend in &backends {
+			let (first, _) = image.upload(&matrix_config, Some(backend.as_ref())).await.unwrap();
+			let (second, uploaded) = image.upload(&matrix_config, Some(backend.as_ref())).await.unwrap();
+			assert_eq!(first.url(), second.url());
+			uploads.push(uploaded);
+		}
+		server.join().unwrap();
+		assert_eq!(uploads, [true, false, false]);
+
+		// the pipeline does accept trait objects too
+		let import_config = ImportConfig::<dyn DynDatabase> {
+			database: Some(backends[2].as_ref()),
+			..Default::default()
+		};
+		let hash = super::hash(image.data());
+		assert!(import_config.database.unwrap().get(&hash).await.unwrap().is_some());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn file_flush() {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-file-flush-test-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("db.txt");
+		let entry = |i: u8| ([i; 64], format!("mxc://example.org/{i}"));
+
+		let db = FileDatabase::new(&path)
+			.await
+			.unwrap()
+			.flush_every(100, Duration::from_secs(3600));
+		for i in 0..250 {
+			let (hash, url) = entry(i);
+			db.add(hash, url).await.unwrap();
+		}
+		// the process is killed, without dropping the database
+		std::mem::forget(db);
+		let survived = std::fs::read_to_string(&path).unwrap().lines().count();
+		assert!(survived >= 200, "only {survived} entries survived");
+		let db = FileDatabase::new(&path).await.unwrap();
+		assert_eq!(db.get(&entry(199).0).await.unwrap(), Some(entry(199).1));
+
+		// flushed and dropped entries are not lost
+		let (hash, url) = entry(250);
+		db.add(hash, url).await.unwrap();
+		db.flush().await.unwrap();
+		assert!(FileDatabase::new(&path).await.unwrap().get(&hash).await.unwrap().is_some());
+		let (hash, url) = entry(251);
+		db.add(hash, url).await.unwrap();
+		drop(db);
+		assert!(FileDatabase::new(&path).await.unwrap().get(&hash).await.unwrap().is_some());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn io_error_classification() {
+		let locked = io::Error::new(io::ErrorKind::ResourceBusy, "database is locked");
+		assert_eq!(locked.classify(), DatabaseErrorKind::Unavailable);
+		assert!(locked.is_transient() && !locked.is_conflict());
+		let exists = io::Error::new(io::ErrorKind::AlreadyExists, "hash does already exist");
+		assert_eq!(exists.classify(), DatabaseErrorKind::Constraint);
+		assert!(!exists.is_transient() && exists.is_conflict());
+		let corrupt = io::Error::new(io::ErrorKind::InvalidData, "invalid line");
+		assert_eq!(corrupt.classify(), DatabaseErrorKind::Corrupt);
+		assert!(!corrupt.is_transient() && !corrupt.is_conflict());
+		let truncated = io::Error::from(io::ErrorKind::UnexpectedEof);
+		assert_eq!(truncated.classify(), DatabaseErrorKind::Corrupt);
+		let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+		assert_eq!(denied.classify(), DatabaseErrorKind::Other);
+	}
+}
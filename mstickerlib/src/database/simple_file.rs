@@ -1,11 +1,16 @@
 use super::{Database, Hash};
 
-use anyhow;
 use futures_util::stream::StreamExt as _;
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 use serde_json;
-use std::{collections::BTreeMap, io, path::Path};
+use std::{
+	collections::BTreeMap,
+	io::{self, Write as _},
+	mem,
+	path::{Path, PathBuf},
+	time::{Duration, Instant}
+};
 use tokio::{
 	fs::{self, File},
 	io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
@@ -13,18 +18,41 @@ use tokio::{
 };
 use tokio_stream::wrappers::LinesStream;
 
+#[cfg(feature = "log")]
+use log::warn;
+
 #[derive(Debug, Deserialize, Serialize)]
 struct HashUrl {
 	#[serde(with = "BigArray")]
 	hash: Hash,
-	url: String
+	url: String,
+	/// `hash` is an [import key](Database::get_import) and `url` the imported sticker
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	import: bool
+}
+
+/// Entries, which were added but not written to the file yet.
+struct Writer {
+	file: fs::File,
+	/// lines of the pending entries
+	pending: Vec<u8>,
+	pending_entries: usize,
+	last_flush: Instant
 }
 
 /// simple implemtation of the `Database` traid,
 /// with does save data to a file
+///
+/// Added entries are buffered and appended to the file every [`FileDatabase::flush_every`] entries or seconds,
+/// when [`Database::flush`] is called and when the database is dropped.
+/// So if the process is killed, at most the last few entries are lost.
 pub struct FileDatabase {
 	tree: RwLock<BTreeMap<Hash, String>>,
-	file: Mutex<fs::File>
+	imports: RwLock<BTreeMap<Hash, String>>,
+	path: PathBuf,
+	writer: Mutex<Writer>,
+	flush_entries: usize,
+	flush_interval: Duration
 }
 
 impl FileDatabase {
@@ -34,6 +62,7 @@ impl FileDatabase {
 	{
 		let path = path.as_ref();
 		let mut tree = BTreeMap::<Hash, String>::new();
+		let mut imports = BTreeMap::<Hash, String>::new();
 		match File::open(path).await {
 			Ok(file) => {
 				let bufreader = BufReader::new(file);
@@ -41,6 +70,9 @@ impl FileDatabase {
 				while let Some((i, line)) = lines.next().await {
 					let hashurl: Result<HashUrl, serde_json::Error> = serde_json::from_str(&line?);
 					match hashurl {
+						Ok(value) if value.import => {
+							imports.insert(value.hash, value.url);
+						},
 						Ok(value) => {
 							tree.insert(value.hash, value.url);
 						},
@@ -68,28 +100,114 @@ impl FileDatabase {
 			.await?;
 		Ok(FileDatabase {
 			tree: RwLock::new(tree),
-			file: Mutex::new(file)
+			imports: RwLock::new(imports),
+			path: path.to_owned(),
+			writer: Mutex::new(Writer {
+				file,
+				pending: Vec::new(),
+				pending_entries: 0,
+				last_flush: Instant::now()
+			}),
+			flush_entries: 100,
+			flush_interval: Duration::from_secs(10)
 		})
 	}
+
+	/// Write the added entries to the file, once `entries` are pending or `interval` has passed since the last write.
+	/// The default is every 100 entries or 10 seconds.
+	pub fn flush_every(mut self, entries: usize, interval: Duration) -> Self {
+		self.flush_entries = entries.max(1);
+		self.flush_interval = interval;
+		self
+	}
+
+	async fn append(&self, hash_url: &HashUrl) -> io::Result<()> {
+		let mut writer = self.writer.lock().await;
+		serde_json::to_writer(&mut writer.pending, hash_url)?;
+		writer.pending.push(b'\n');
+		writer.pending_entries += 1;
+		if writer.pending_entries >= self.flush_entries || writer.last_flush.elapsed() >= self.flush_interval {
+			writer.flush().await?;
+		}
+		Ok(())
+	}
+}
+
+impl Writer {
+	async fn flush(&mut self) -> io::Result<()> {
+		if self.pending.is_empty() {
+			return Ok(());
+		}
+		self.file.write_all(&self.pending).await?;
+		// tokio does finish the write in the background, until the file is flushed
+		self.file.flush().await?;
+		self.pending.clear();
+		self.pending_entries = 0;
+		self.last_flush = Instant::now();
+		Ok(())
+	}
+}
+
+impl Drop for FileDatabase {
+	fn drop(&mut self) {
+		let pending = mem::take(&mut self.writer.get_mut().pending);
+		if pending.is_empty() {
+			return;
+		}
+		// the async file can not be used without runtime, but every earlier write has finished at its flush
+		let written = std::fs::OpenOptions::new()
+			.append(true)
+			.open(&self.path)
+			.and_then(|mut file| file.write_all(&pending));
+		#[cfg(feature = "log")]
+		if let Err(err) = written {
+			warn!("failed to save the database {}: {err}", self.path.display());
+		}
+		#[cfg(not(feature = "log"))]
+		let _ = written;
+	}
 }
 
 impl Database for FileDatabase {
-	async fn get(&self, hash: &Hash) -> anyhow::Result<Option<String>> {
+	type Error = io::Error;
+
+	async fn get(&self, hash: &Hash) -> io::Result<Option<String>> {
 		let lock = self.tree.read().await;
 		let ret = lock.get(hash);
 		Ok(ret.cloned())
 	}
 
-	async fn add(&self, hash: Hash, url: String) -> anyhow::Result<()> {
-		let hash_url = HashUrl { hash, url };
-
-		let mut file = self.file.lock().await;
-		file.write_all(&serde_json::to_vec(&hash_url)?).await?;
-		file.write_all(b"\n").await?;
-		drop(file);
+	async fn add(&self, hash: Hash, url: String) -> io::Result<()> {
+		let hash_url = HashUrl {
+			hash,
+			url,
+			import: false
+		};
+		self.append(&hash_url).await?;
 
 		let mut tree = self.tree.write().await;
 		tree.insert(hash_url.hash, hash_url.url);
 		Ok(())
 	}
+
+	async fn get_import(&self, key: &Hash) -> io::Result<Option<String>> {
+		Ok(self.imports.read().await.get(key).cloned())
+	}
+
+	async fn add_import(&self, key: Hash, sticker: String) -> io::Result<()> {
+		let hash_url = HashUrl {
+			hash: key,
+			url: sticker,
+			import: true
+		};
+		self.append(&hash_url).await?;
+
+		let mut imports = self.imports.write().await;
+		imports.insert(hash_url.hash, hash_url.url);
+		Ok(())
+	}
+
+	async fn flush(&self) -> io::Result<()> {
+		self.writer.lock().await.flush().await
+	}
 }
@@ -1,6 +1,10 @@
 pub use crate::matrix::MatrixApiError;
+use crate::{
+	database::{DatabaseError, DatabaseErrorKind},
+	image::{ImageFormat, LottieWarning}
+};
 use reqwest::StatusCode;
-use std::{fmt::Display, io};
+use std::{fmt::Display, io, time::Duration};
 use thiserror::Error;
 use tokio::task::JoinError;
 
@@ -31,13 +35,13 @@ pub struct MatrixError {
 impl Display for MatrixError {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		if let Some(filename) = &self.filename {
-			write!(
+			writeln!(
 				f,
-				"failed to upload sticker {filename:?} with status code {}:\n",
+				"failed to upload sticker {filename:?} with status code {}:",
 				self.status_code
 			)?;
 		} else {
-			write!(f, "Matrix api request return with status code {}:\n", self.status_code)?;
+			writeln!(f, "Matrix api request return with status code {}:", self.status_code)?;
 		}
 		match &self.matrix_error {
 			Ok(value) => write!(f, "{value}"),
@@ -46,35 +50,22 @@ impl Display for MatrixError {
 	}
 }
 
-#[cfg(any(not(feature = "ffmpeg"), not(feature = "lottie")))]
-#[derive(Error, Debug)]
-pub enum UnsupportedFormat {
-	#[cfg(not(feature = "lottie"))]
-	Lottie,
-	#[cfg(not(feature = "ffmpeg"))]
-	Webm
-}
-
-#[cfg(any(not(feature = "ffmpeg"), not(feature = "lottie")))]
-impl Display for UnsupportedFormat {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-		match self {
-			#[cfg(not(feature = "lottie"))]
-			Self::Lottie => write!(f, "animated")?,
-			#[cfg(not(feature = "ffmpeg"))]
-			Self::Webm => write!(f, "video")?
-		}
-		write!(f, " sticker are unsupported, since mstickerlib was compliled without the ",)?;
-		match self {
-			#[cfg(not(feature = "lottie"))]
-			Self::Lottie => write!(f, "\"lottie\"")?,
-			#[cfg(not(feature = "ffmpeg"))]
-			Self::Webm => write!(f, "\"ffmpeg\"")?
+fn unsupported_format_message(detected: &Option<ImageFormat>) -> String {
+	match detected {
+		None => "has an unknown format".to_owned(),
+		Some(format) => match format.missing_feature() {
+			Some(feature) => {
+				format!("has the format {format}, which is unsupported, since mstickerlib was compliled without the {feature:?} feature")
+			},
+			None => format!("has the unsupported format {format}")
 		}
-		write!(f, " feature.")
 	}
 }
 
+fn lottie_warnings_message(warnings: &[LottieWarning]) -> String {
+	warnings.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
 	#[error(transparent)]
@@ -95,26 +86,215 @@ pub enum Error {
 	/// sadly we do not get more information about the error from the lottie crate
 	#[error("failed to load sticker from tmp file")]
 	AnimationLoadError,
+	#[error("failed to parse json: {0}")]
+	Json(#[from] serde_json::Error),
+	/// the converter has not return an error, but also no data
+	#[error("conversion of sticker produced empty output")]
+	ConversionProducedEmptyOutput,
 	#[cfg(feature = "lottie")]
 	#[error("failed to deencode sticker as gif: {0}")]
 	GifDecoding(#[from] gif::DecodingError),
 	#[cfg(feature = "lottie")]
 	#[error("failed to encode sticker as gif: {0}")]
 	GifEncoding(#[from] gif::EncodingError),
-	#[cfg(any(feature = "lottie", feature = "ffmpeg"))]
+	#[cfg(feature = "apng")]
+	#[error("failed to encode sticker as apng: {0}")]
+	ApngEncoding(#[from] png::EncodingError),
+	#[cfg(feature = "gif")]
+	#[error("failed to convert sticker to gif: {0}")]
+	GifConversion(#[from] image_crate::ImageError),
+	#[cfg(feature = "animation")]
 	#[error("failed to en- or decode sticker as webp: {0}")]
 	Webp(#[from] webp_animation::Error),
+	#[cfg(feature = "blurhash")]
+	#[error("failed to compute blurhash: {0}")]
+	Blurhash(#[from] blurhash::Error),
+	#[cfg(feature = "e2ee")]
+	#[error("failed to generate encryption key: {0}")]
+	Random(#[from] getrandom::Error),
+	#[error("invalid base64: {0}")]
+	Base64(#[from] base64::DecodeError),
 	#[error(transparent)]
 	NoMimeType(#[from] NoMimeType),
 	/// to avoid that this struct is generic for the database error use anyhow
 	/// This is the error crated by the user choosen databe trait impl at the import function function
-	#[error("failed to insert or check for file duplicate at the database: {0:?}")]
-	Database(anyhow::Error),
+	#[error("failed to insert or check for file duplicate at the database ({kind}): {source:?}")]
+	Database { kind: DatabaseErrorKind, source: anyhow::Error },
 	#[error(transparent)]
 	MatrixUpload(#[from] MatrixError),
-	#[cfg(any(not(feature = "ffmpeg"), not(feature = "lottie")))]
-	#[error(transparent)]
-	UnsupportedFormat(#[from] UnsupportedFormat),
+	/// The image can not be converted, since its format is not supported.
+	/// `detected` is `None`, if the format is not known at all.
+	#[error("{file_name:?} {}", unsupported_format_message(.detected))]
+	UnsupportedFormat {
+		file_name: String,
+		detected: Option<ImageFormat>
+	},
+	/// The image can only be converted with a feature of this crate, which is not enabled.
+	#[error("mstickerlib was compiled without the {0:?} feature, which is needed to convert this image")]
+	FeatureNotEnabled(&'static str),
 	#[error("Invalid matrix homeserver urls: {0}")]
-	InvalidHomeServerUrl(#[from] url::ParseError)
+	InvalidHomeServerUrl(#[from] url::ParseError),
+	#[error("failed to de- or encode image: {0}")]
+	Image(#[from] photon_rs::native::Error),
+	#[error("{operation} is not supported for {file_name:?}")]
+	UnsupportedOperation { operation: &'static str, file_name: String },
+	#[error("image is not a valid {0} file")]
+	MalformedImage(&'static str),
+	#[error("lottie animation {file_name:?} is not rendered correctly: {}", lottie_warnings_message(.warnings))]
+	LottieCompatibility {
+		file_name: String,
+		warnings: Vec<LottieWarning>
+	},
+	#[error("pixel buffer has {len} bytes, which does not match an RGBA image of {width}x{height} pixels")]
+	InvalidPixelBuffer { len: usize, width: u32, height: u32 },
+	#[error("image {file_name:?} has {width}x{height} pixels and can not be converted")]
+	DegenerateImage { file_name: String, width: u32, height: u32 },
+	#[error("conversion of {file_name:?} did not finish within {timeout:?}")]
+	Timeout { file_name: String, timeout: Duration },
+	#[error("file has {actual} bytes, more than the limit of {limit} bytes")]
+	FileTooLarge { actual: usize, limit: usize },
+	#[error("animation {file_name:?} plays {duration_ms} ms, longer than {max_duration_ms} ms")]
+	AnimationTooLong {
+		file_name: String,
+		duration_ms: u64,
+		max_duration_ms: u64
+	},
+	#[error("can not build image: {0}")]
+	IncompleteImage(&'static str),
+	#[error("image has a stored size of {stored:?} pixels, but its data has {actual:?} pixels")]
+	DimensionMismatch { stored: (u32, u32), actual: (u32, u32) },
+	#[error("supplied content hash {supplied} of image {file_name:?} does not match the hash {actual} of its data")]
+	ContentHashMismatch {
+		file_name: String,
+		supplied: String,
+		actual: String
+	},
+	#[error("pixel ({x}, {y}) is outside of the {width}x{height} image")]
+	InvalidDimensions { x: u32, y: u32, width: u32, height: u32 },
+	#[error("frame {index} has {width}x{height} pixels, but the first frame has {expected_width}x{expected_height} pixels")]
+	FrameDimensions {
+		index: usize,
+		width: u32,
+		height: u32,
+		expected_width: u32,
+		expected_height: u32
+	},
+	#[error("{0:?} is neither a path nor a http(s) url")]
+	InvalidImageSource(String),
+	#[error("downloading images from host {0:?} is not allowed")]
+	HostNotAllowed(String),
+	#[error("downloading {0} needs too many redirects")]
+	TooManyRedirects(String),
+	#[error("content type {0:?} is not an allowed image type")]
+	UnsupportedContentType(String),
+	#[error("image at {url} is larger than {max_size} bytes")]
+	RemoteImageTooLarge { url: String, max_size: u64 },
+	#[error("{0:?} is not a valid shortcode; only ascii letters, digits, `_` and `-` are allowed")]
+	InvalidShortcode(String),
+	#[error("shortcode {shortcode:?} of sticker {index} is already used by another sticker")]
+	ShortcodeCollision { shortcode: String, index: usize },
+	#[error("{0:?} is no alias map format; use `json` or `csv`")]
+	InvalidAliasFormat(String),
+	#[error("{0:?} is not a valid mxc url")]
+	InvalidMxc(String),
+	#[error("{0} was not found at the homeserver")]
+	NotFound(String),
+	#[error("the access token is not allowed to access {0}")]
+	PermissionDenied(String),
+	#[error("{0} was modified concurrently at every attempt to update it")]
+	ConcurrentModification(String),
+	#[error("no answer to {method} {url} was recorded")]
+	NotRecorded { method: String, url: String },
+	#[error("recorded answer to {0} is invalid")]
+	InvalidRecording(String),
+	#[error("answer of telegram to {operation} is larger than {max_size} bytes")]
+	TelegramResponseTooLarge { operation: String, max_size: u64 },
+	#[error("telegram pack {name:?} has {count} stickers, but at most {max_pack_size} are accepted")]
+	PackTooLarge {
+		name: String,
+		count: usize,
+		max_pack_size: usize
+	},
+	#[error("telegram pack {name:?} does contain the file {file_unique_id:?} more than once")]
+	DuplicateSticker { name: String, file_unique_id: String },
+	#[error("{0} requests per second is not a valid rate; it must be finite and larger than zero")]
+	InvalidRate(f64)
+}
+
+impl Error {
+	pub(crate) fn database<E>(err: E) -> Self
+	where
+		E: DatabaseError
+	{
+		Self::Database {
+			kind: err.classify(),
+			source: err.into()
+		}
+	}
+
+	/// `true` if the error is caused by the format of an image, like an unsupported or malformed file,
+	/// a missing mime type or a failed decoding.
+	/// Callers can skip such an image and continue with the next one,
+	/// while other errors, like network or database errors, usually affect every image.
+	pub fn is_format_error(&self) -> bool {
+		match self {
+			Self::UnsupportedFormat { .. }
+			| Self::FeatureNotEnabled(_)
+			| Self::NoMimeType(_)
+			| Self::Image(_)
+			| Self::MalformedImage(_)
+			| Self::LottieCompatibility { .. }
+			| Self::UnsupportedContentType(_) => true,
+			#[cfg(feature = "lottie")]
+			Self::AnimationLoadError | Self::GifDecoding(_) => true,
+			#[cfg(feature = "gif")]
+			Self::GifConversion(_) => true,
+			#[cfg(feature = "animation")]
+			Self::Webp(_) => true,
+			#[cfg(feature = "ffmpeg")]
+			Self::Ffmpeg(_) => true,
+			_ => false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Error, NoMimeType};
+	use crate::image::ImageFormat;
+	use std::io;
+
+	#[test]
+	fn format_errors() {
+		let unsupported = Error::UnsupportedFormat {
+			file_name: "sticker.avif".to_owned(),
+			detected: Some(ImageFormat::Avif)
+		};
+		assert_eq!(unsupported.to_string(), "\"sticker.avif\" has the unsupported format avif");
+		let unknown = Error::UnsupportedFormat {
+			file_name: "sticker.bin".to_owned(),
+			detected: None
+		};
+		let decoding = photon_rs::native::open_image_from_bytes(b"no image").unwrap_err();
+		for err in [
+			unsupported,
+			unknown,
+			Error::NoMimeType(NoMimeType),
+			Error::Image(decoding),
+			Error::MalformedImage("webp"),
+			Error::UnsupportedContentType("text/html".to_owned())
+		] {
+			assert!(err.is_format_error(), "{err}");
+		}
+
+		let network = reqwest::Client::new().get("://").build().unwrap_err();
+		for err in [
+			Error::Reqwest(network),
+			Error::database(io::Error::other("disk full")),
+			Error::IoError(io::Error::from(io::ErrorKind::NotFound)),
+			Error::NotFound("mxc://example.org/media".to_owned())
+		] {
+			assert!(!err.is_format_error(), "{err}");
+		}
+	}
 }
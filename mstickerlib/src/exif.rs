@@ -0,0 +1,128 @@
+//! Read the orientation tag from the EXIF metadata of jpeg and webp images.
+
+use crate::icc;
+
+/// Tag of the orientation at the first IFD of the EXIF data.
+const ORIENTATION_TAG: u16 = 0x0112;
+/// EXIF data of jpeg starts with this prefix, webp encoders add it sometimes too.
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+/// The EXIF data of a jpeg is stored at an `APP1` segment before the image data.
+fn jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+	let mut rest = data.strip_prefix(&[0xff, 0xd8])?;
+	// all header segments are before the start of scan marker
+	while let [0xff, marker, high, low, ..] = *rest {
+		if marker == 0xda {
+			break;
+		}
+		let len = u16::from_be_bytes([high, low]) as usize;
+		let segment = rest.get(4..2 + len)?;
+		if marker == 0xe1 {
+			if let Some(tiff) = segment.strip_prefix(EXIF_PREFIX) {
+				return Some(tiff);
+			}
+		}
+		rest = &rest[2 + len..];
+	}
+	None
+}
+
+fn webp_exif(data: &[u8]) -> Option<&[u8]> {
+	let chunks = icc::riff_chunks(&data[12..]).ok()?;
+	let (_, exif) = chunks.into_iter().find(|(kind, _)| kind == b"EXIF")?;
+	Some(exif.strip_prefix(EXIF_PREFIX).unwrap_or(exif))
+}
+
+/// Read the orientation from the first IFD of the tiff structure of EXIF data.
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+	let little_endian = match tiff.get(..4)? {
+		b"II*\0" => true,
+		b"MM\0*" => false,
+		_ => return None
+	};
+	let u16_at = |offset: usize| {
+		let bytes = tiff.get(offset..offset + 2)?.try_into().unwrap();
+		Some(if little_endian {
+			u16::from_le_bytes(bytes)
+		} else {
+			u16::from_be_bytes(bytes)
+		})
+	};
+	let bytes = tiff.get(4..8)?.try_into().unwrap();
+	let ifd = if little_endian {
+		u32::from_le_bytes(bytes)
+	} else {
+		u32::from_be_bytes(bytes)
+	} as usize;
+	// each entry has a tag, a type, a count and a value of four bytes
+	(0..u16_at(ifd)? as usize)
+		.map(|index| ifd + 2 + index * 12)
+		.find(|entry| u16_at(*entry) == Some(ORIENTATION_TAG))
+		.and_then(|entry| u16_at(entry + 8))
+}
+
+/// Return the EXIF orientation of a jpeg or webp image between 1 and 8, if it has one.
+/// See <https://www.cipa.jp/std/documents/download_e.html?DC-008-Translation-2023-E> for the meaning of the values.
+pub(crate) fn orientation(data: &[u8]) -> Option<u16> {
+	let tiff = if data.starts_with(&[0xff, 0xd8]) {
+		jpeg_exif(data)?
+	} else if data.get(..4) == Some(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+		webp_exif(data)?
+	} else {
+		return None;
+	};
+	tiff_orientation(tiff).filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Little endian tiff structure with only the orientation tag, like it is stored at the EXIF metadata.
+#[cfg(test)]
+pub(crate) fn tiff_with_orientation(orientation: u16) -> Vec<u8> {
+	let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+	// tag, type short, count one and value
+	tiff.extend_from_slice(&ORIENTATION_TAG.to_le_bytes());
+	tiff.extend_from_slice(&[3, 0, 1, 0, 0, 0]);
+	tiff.extend_from_slice(&orientation.to_le_bytes());
+	tiff.extend_from_slice(&[0; 6]);
+	tiff
+}
+
+/// Insert an `APP1` segment with the EXIF `tiff` data after the start of image marker of a jpeg.
+#[cfg(test)]
+pub(crate) fn insert_jpeg_exif(jpeg: &[u8], tiff: &[u8]) -> Vec<u8> {
+	let segment = [EXIF_PREFIX, tiff].concat();
+	let mut output = jpeg[..2].to_vec();
+	output.extend_from_slice(&[0xff, 0xe1]);
+	output.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+	output.extend_from_slice(&segment);
+	output.extend_from_slice(&jpeg[2..]);
+	output
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{insert_jpeg_exif, orientation, tiff_with_orientation};
+
+	#[test]
+	fn jpeg() {
+		let jpeg = photon_rs::PhotonImage::new(vec![255; 4 * 4 * 4], 4, 4).get_bytes_jpeg(90);
+		assert_eq!(orientation(&jpeg), None);
+		assert_eq!(orientation(&insert_jpeg_exif(&jpeg, &tiff_with_orientation(6))), Some(6));
+		// values outside of the specification are ignored
+		assert_eq!(orientation(&insert_jpeg_exif(&jpeg, &tiff_with_orientation(9))), None);
+	}
+
+	#[test]
+	fn webp() {
+		// big endian tiff with an other tag before the orientation
+		let mut tiff = b"MM\0*\0\0\0\x08\0\x02".to_vec();
+		tiff.extend_from_slice(&[0x01, 0x0f, 0, 2, 0, 0, 0, 4, b'a', b'b', b'c', 0]);
+		tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 8, 0, 0]);
+		let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+		for (kind, payload) in [(b"VP8X", &[0; 10][..]), (b"EXIF", &tiff)] {
+			data.extend_from_slice(kind);
+			data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+			data.extend_from_slice(payload);
+		}
+		assert_eq!(orientation(&data), Some(8));
+	}
+}
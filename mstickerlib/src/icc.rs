@@ -0,0 +1,271 @@
+//! Embedding and extraction of ICC color profiles in the containers of png, jpeg and webp images.
+
+use crate::error::Error;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression, Crc};
+use std::io::{Read as _, Write as _};
+
+pub(crate) const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// flag of the webp `VP8X` chunk, which indicates that an ICC profile is present
+const VP8X_ICC_FLAG: u8 = 0x20;
+const VP8X_ALPHA_FLAG: u8 = 0x10;
+
+pub(crate) fn write_png_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+	output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+	output.extend_from_slice(kind);
+	output.extend_from_slice(data);
+	let mut crc = Crc::new();
+	crc.update(kind);
+	crc.update(data);
+	output.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// Insert the profile as `iCCP` chunk after the `IHDR` chunk.
+/// Existing `iCCP` and `sRGB` chunks are removed, since only one of them is allowed.
+pub(crate) fn embed_png(data: &[u8], profile: &[u8]) -> Result<Vec<u8>, Error> {
+	let mut rest = data.strip_prefix(PNG_SIGNATURE).ok_or(Error::MalformedImage("png"))?;
+	let mut output = Vec::with_capacity(data.len() + profile.len());
+	output.extend_from_slice(PNG_SIGNATURE);
+	while !rest.is_empty() {
+		let len = rest
+			.get(..4)
+			.map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+			.ok_or(Error::MalformedImage("png"))?;
+		let chunk = rest.get(..len + 12).ok_or(Error::MalformedImage("png"))?;
+		rest = &rest[len + 12..];
+		let kind = &chunk[4..8];
+		if kind == b"iCCP" || kind == b"sRGB" {
+			continue;
+		}
+		output.extend_from_slice(chunk);
+		if kind == b"IHDR" {
+			// profile name, null separator, compression method (0 = zlib)
+			let mut iccp = b"ICC profile\0\0".to_vec();
+			let mut encoder = ZlibEncoder::new(&mut iccp, Compression::best());
+			encoder.write_all(profile)?;
+			encoder.finish()?;
+			write_png_chunk(&mut output, b"iCCP", &iccp);
+		}
+	}
+	Ok(output)
+}
+
+/// Split a png file into the four character codes and payloads of its chunks.
+pub(crate) fn png_chunks(data: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+	let mut rest = data.strip_prefix(PNG_SIGNATURE)?;
+	let mut chunks = Vec::new();
+	while !rest.is_empty() {
+		let len = u32::from_be_bytes(rest.get(..4)?.try_into().unwrap()) as usize;
+		let chunk = rest.get(..len + 12)?;
+		chunks.push((&chunk[4..8], &chunk[8..8 + len]));
+		rest = &rest[len + 12..];
+	}
+	Some(chunks)
+}
+
+fn extract_png(data: &[u8]) -> Option<Vec<u8>> {
+	let (_, iccp) = png_chunks(data)?.into_iter().find(|(kind, _)| *kind == b"iCCP")?;
+	// profile name, null separator, compression method
+	let name_end = iccp.iter().position(|byte| *byte == 0)?;
+	let compressed = iccp.get(name_end + 2..)?;
+	let mut profile = Vec::new();
+	ZlibDecoder::new(compressed).read_to_end(&mut profile).ok()?;
+	Some(profile)
+}
+
+/// The profile of a jpeg is split into `APP2` segments, which are numbered starting at one.
+fn extract_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+	let mut rest = data.strip_prefix(&[0xff, 0xd8])?;
+	let mut parts = Vec::new();
+	// all header segments are before the start of scan marker
+	while let [0xff, marker, high, low, ..] = *rest {
+		if marker == 0xda {
+			break;
+		}
+		let len = u16::from_be_bytes([high, low]) as usize;
+		let segment = rest.get(4..2 + len)?;
+		if marker == 0xe2 {
+			if let Some([index, _count, part @ ..]) = segment.strip_prefix(b"ICC_PROFILE\0") {
+				parts.push((*index, part));
+			}
+		}
+		rest = &rest[2 + len..];
+	}
+	if parts.is_empty() {
+		return None;
+	}
+	parts.sort_by_key(|(index, _)| *index);
+	Some(parts.into_iter().flat_map(|(_, part)| part).copied().collect())
+}
+
+/// Return the ICC profile of a png, jpeg or webp image, if it has one.
+pub(crate) fn extract(data: &[u8]) -> Option<Vec<u8>> {
+	if data.starts_with(PNG_SIGNATURE) {
+		extract_png(data)
+	} else if data.starts_with(&[0xff, 0xd8]) {
+		extract_jpeg(data)
+	} else if data.get(..4) == Some(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+		let chunks = riff_chunks(&data[12..]).ok()?;
+		chunks
+			.into_iter()
+			.find(|(kind, _)| kind == b"ICCP")
+			.map(|(_, profile)| profile.to_vec())
+	} else {
+		None
+	}
+}
+
+/// Embed the profile into a png or webp image, detected by the content of `data`.
+/// Return `None` for other formats, which do not support color profiles.
+pub(crate) fn embed(data: &[u8], profile: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+	if data.starts_with(PNG_SIGNATURE) {
+		embed_png(data, profile).map(Some)
+	} else if data.get(..4) == Some(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+		embed_webp(data, profile).map(Some)
+	} else {
+		Ok(None)
+	}
+}
+
+/// Four character code and payload of a RIFF chunk.
+type RiffChunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Split the content of a RIFF container into its chunks.
+pub(crate) fn riff_chunks(mut data: &[u8]) -> Result<Vec<RiffChunk<'_>>, Error> {
+	let mut chunks = Vec::new();
+	while !data.is_empty() {
+		let header = data.get(..8).ok_or(Error::MalformedImage("webp"))?;
+		let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+		let payload = data.get(8..8 + len).ok_or(Error::MalformedImage("webp"))?;
+		chunks.push((header[..4].try_into().unwrap(), payload));
+		// chunks are padded to an even size
+		data = data.get(8 + len + len % 2..).unwrap_or_default();
+	}
+	Ok(chunks)
+}
+
+fn write_riff_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+	output.extend_from_slice(kind);
+	output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+	output.extend_from_slice(data);
+	if data.len() % 2 == 1 {
+		output.push(0);
+	}
+}
+
+/// Create the payload of a `VP8X` chunk for a simple (not extended) webp image.
+fn vp8x_for_simple(kind: &[u8; 4], payload: &[u8]) -> Result<[u8; 10], Error> {
+	let (width, height, alpha) = match kind {
+		b"VP8L" if payload.len() >= 5 && payload[0] == 0x2f => {
+			let bits = u32::from_le_bytes(payload[1..5].try_into().unwrap());
+			((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1, (bits >> 28) & 1 == 1)
+		},
+		b"VP8 " if payload.len() >= 10 && payload[3..6] == [0x9d, 0x01, 0x2a] => {
+			let width = u16::from_le_bytes(payload[6..8].try_into().unwrap()) & 0x3fff;
+			let height = u16::from_le_bytes(payload[8..10].try_into().unwrap()) & 0x3fff;
+			(width as u32, height as u32, false)
+		},
+		_ => return Err(Error::MalformedImage("webp"))
+	};
+	let mut vp8x = [0; 10];
+	vp8x[0] = if alpha { VP8X_ALPHA_FLAG } else { 0 };
+	vp8x[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+	vp8x[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+	Ok(vp8x)
+}
+
+/// Insert the profile as `ICCP` chunk after the `VP8X` chunk, replacing an existing one.
+/// Simple webp images are converted to the extended format, since they can not contain a profile.
+pub(crate) fn embed_webp(data: &[u8], profile: &[u8]) -> Result<Vec<u8>, Error> {
+	if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+		return Err(Error::MalformedImage("webp"));
+	}
+	let chunks = riff_chunks(&data[12..])?;
+	let mut vp8x: [u8; 10] = match chunks.iter().find(|(kind, _)| kind == b"VP8X") {
+		Some((_, payload)) => payload
+			.get(..10)
+			.and_then(|payload| payload.try_into().ok())
+			.ok_or(Error::MalformedImage("webp"))?,
+		None => {
+			let (kind, payload) = chunks.first().ok_or(Error::MalformedImage("webp"))?;
+			vp8x_for_simple(kind, payload)?
+		}
+	};
+	vp8x[0] |= VP8X_ICC_FLAG;
+
+	let mut output = Vec::with_capacity(data.len() + profile.len() + 32);
+	output.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+	write_riff_chunk(&mut output, b"VP8X", &vp8x);
+	write_riff_chunk(&mut output, b"ICCP", profile);
+	for (kind, payload) in chunks.iter().filter(|(kind, _)| kind != b"VP8X" && kind != b"ICCP") {
+		write_riff_chunk(&mut output, kind, payload);
+	}
+	let riff_size = (output.len() - 8) as u32;
+	output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+	Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{embed_png, embed_webp, extract, riff_chunks, VP8X_ICC_FLAG};
+	use crate::image::{Image, ICC_SRGB};
+	use photon_rs::{native::open_image_from_bytes, PhotonImage};
+
+	fn pixels() -> Vec<u8> {
+		(0..4 * 3).flat_map(|i| [i as u8 * 20, 0, 255, 128]).collect()
+	}
+
+	#[test]
+	fn webp() {
+		let image = Image::from_rgba(pixels(), 4, 3, "sticker.webp").unwrap();
+		let data = embed_webp(&embed_webp(image.data_bytes(), b"old profile").unwrap(), ICC_SRGB).unwrap();
+		let chunks = riff_chunks(&data[12..]).unwrap();
+		assert_eq!(&chunks[0].0, b"VP8X");
+		assert_eq!(chunks[0].1[0] & VP8X_ICC_FLAG, VP8X_ICC_FLAG);
+		assert_eq!(chunks[0].1[4..], [3, 0, 0, 2, 0, 0]);
+		let profiles: Vec<_> = chunks.iter().filter(|(kind, _)| kind == b"ICCP").collect();
+		assert_eq!(profiles.len(), 1);
+		assert_eq!(profiles[0].1, ICC_SRGB);
+		assert_eq!(open_image_from_bytes(&data).unwrap().get_raw_pixels(), pixels());
+	}
+
+	#[test]
+	fn png() {
+		let png = PhotonImage::new(pixels(), 4, 3).get_bytes();
+		let data = embed_png(&embed_png(&png, b"old profile").unwrap(), ICC_SRGB).unwrap();
+		assert_eq!(data.windows(4).filter(|window| window == b"iCCP").count(), 1);
+		// the png decoder does check the crc of all chunks
+		assert_eq!(open_image_from_bytes(&data).unwrap().get_raw_pixels(), pixels());
+	}
+
+	#[test]
+	fn extract_profile() {
+		let png = PhotonImage::new(pixels(), 4, 3).get_bytes();
+		assert_eq!(extract(&png), None);
+		assert_eq!(extract(&embed_png(&png, ICC_SRGB).unwrap()).as_deref(), Some(ICC_SRGB));
+		let webp = Image::from_rgba(pixels(), 4, 3, "sticker.webp").unwrap();
+		assert_eq!(
+			extract(&embed_webp(webp.data_bytes(), ICC_SRGB).unwrap()).as_deref(),
+			Some(ICC_SRGB)
+		);
+
+		// jpeg with the profile split into two segments, which are stored out of order
+		let (first, second) = ICC_SRGB.split_at(ICC_SRGB.len() / 2);
+		let mut jpeg = vec![0xff, 0xd8];
+		for (index, part) in [(2, second), (1, first)] {
+			jpeg.extend_from_slice(&[0xff, 0xe2]);
+			jpeg.extend_from_slice(&(part.len() as u16 + 16).to_be_bytes());
+			jpeg.extend_from_slice(b"ICC_PROFILE\0");
+			jpeg.extend_from_slice(&[index, 2]);
+			jpeg.extend_from_slice(part);
+		}
+		jpeg.extend_from_slice(&[0xff, 0xda, 0, 2]);
+		assert_eq!(extract(&jpeg).as_deref(), Some(ICC_SRGB));
+	}
+
+	#[test]
+	fn unsupported() {
+		let mut image = Image::from_rgba(pixels(), 4, 3, "sticker.webp").unwrap();
+		image.file_name = "sticker.gif".into();
+		assert!(image.embed_icc_profile(ICC_SRGB).is_err());
+	}
+}
@@ -1,29 +1,104 @@
+mod builder;
+mod compliance;
+mod format;
+mod loop_policy;
+mod lottie_check;
+#[cfg(feature = "lottie")]
+mod lottie_render;
+#[cfg(feature = "gif")]
+mod quantize;
+#[cfg(feature = "rendition")]
+mod rendition;
+mod source;
+#[cfg(feature = "rendition")]
+mod webp;
+pub use builder::ImageBuilder;
+pub use compliance::{StickerPolicy, Violation};
+pub use format::ImageFormat;
+pub use loop_policy::{LoopFormat, LoopPolicy};
+pub use lottie_check::{LottieWarning, MAX_LOTTIE_LAYERS};
+#[cfg(feature = "lottie")]
+use lottie_render::FrameEncoder;
+#[cfg(feature = "gif")]
+pub use quantize::Quantizer;
+#[cfg(feature = "rendition")]
+pub use rendition::Rendition;
+pub use source::{ImageSource, RemoteOptions};
+#[cfg(feature = "rendition")]
+pub use webp::WebpOptions;
+
+#[cfg(feature = "ffmpeg")]
+pub use crate::video::VideoInfo;
 #[cfg(feature = "ffmpeg")]
-use crate::video::webm2webp;
+use crate::video::{webm2webp, Deadline};
 use crate::{
-	database,
+	database::{self, DynDatabase, Hash},
 	error::{Error, NoMimeType},
-	matrix::{self, Config, Mxc}
+	exif, icc,
+	matrix::{self, sticker_formats::ponies::MetaData, Config, Mxc},
+	trace::{Stage, StageSpan}
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+#[cfg(feature = "gif")]
+use image_crate::{
+	codecs::{
+		gif::{GifEncoder, Repeat},
+		png::PngDecoder,
+		webp::WebPDecoder
+	},
+	AnimationDecoder, Delay, Frame, RgbaImage
 };
 #[cfg(feature = "lottie")]
-use lottieconv::{Animation, Converter, Rgba};
+use lottieconv::{Animation, Rgba};
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 #[cfg(any(feature = "ffmpeg", feature = "lottie"))]
 use std::io::Write;
-use std::{io::Read, path::Path, sync::Arc};
-use strum_macros::Display;
+use std::{
+	fmt::{self, Display},
+	hash::Hasher,
+	io::Read,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, Weak},
+	time::{Duration, Instant}
+};
 #[cfg(feature = "lottie")]
 use tempfile::NamedTempFile;
+use tokio::sync::mpsc::UnboundedSender;
 
-use photon_rs::transform;
-use photon_rs::native::{open_image_from_bytes, image_to_bytes};
+use photon_rs::{native::open_image_from_bytes, transform, PhotonImage};
 
 #[cfg(feature = "log")]
-use log::{info, warn};
+use log::warn;
+
+/// The sRGB IEC61966-2.1 color profile, to be used with [`Image::embed_icc_profile`].
+pub const ICC_SRGB: &[u8] = include_bytes!("srgb.icc");
+
+/// Longest side of the stickers created by [`Image::to_sticker_with_poster`].
+pub const STICKER_SIZE: u32 = 512;
+
+// The Matrix spec does not limit stickers itself; the following limits are the ones of the common clients,
+// like the sticker picker of Element.
+
+/// Maximum width and height of a sticker in pixels, which all common Matrix clients show unscaled,
+/// see [`Image::sticker_compliant`].
+pub const MATRIX_STICKER_MAX_SIZE_PX: u32 = 512;
+
+/// Maximum file size of a sticker in bytes (256 KiB), see [`Image::sticker_compliant`].
+pub const MATRIX_STICKER_MAX_BYTES: usize = 256 * 1024;
+
+/// Maximum duration of an animated sticker in milliseconds, see [`Image::sticker_compliant`].
+pub const MATRIX_STICKER_MAX_DURATION_MS: u64 = 3000;
+
+/// Time, after which a webm conversion is aborted with [`Error::Timeout`], if not configured otherwise.
+/// See [`ConversionConfig::ffmpeg_timeout`].
+pub const DEFAULT_FFMPEG_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of pixels of the canvas extended by [`Image::apply_shadow`].
+pub const MAX_SHADOW_CANVAS_PIXELS: u64 = 4096 * 4096;
 
 // todo: remove copy trait. Or will gif support droppet first?
-#[derive(Clone, Copy, Debug, Default, Deserialize, Display)]
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
 #[serde(tag = "animation_format", rename_all = "lowercase")]
 pub enum AnimationFormat {
 	#[cfg(feature = "lottie")]
@@ -33,13 +108,700 @@ pub enum AnimationFormat {
 	Webp
 }
 
+impl AnimationFormat {
+	/// File extension of converted animations, without leading dot.
+	/// This is also the spelling used at the config.
+	pub fn extension(&self) -> &'static str {
+		match self {
+			#[cfg(feature = "lottie")]
+			Self::Gif { .. } => "gif",
+			Self::Webp => "webp"
+		}
+	}
+
+	/// Mime type of converted animations.
+	pub fn mime_type(&self) -> &'static str {
+		match self {
+			#[cfg(feature = "lottie")]
+			Self::Gif { .. } => "image/gif",
+			Self::Webp => "image/webp"
+		}
+	}
+}
+
+/// Show the format like its [`AnimationFormat::extension`], so logs and config use the same spelling.
+impl Display for AnimationFormat {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.extension())
+	}
+}
+
+/// Meta data of a lottie animation, read directly from its json.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct LottieInfo {
+	#[serde(rename = "w")]
+	pub width: u32,
+	#[serde(rename = "h")]
+	pub height: u32,
+	/// frames per second
+	#[serde(rename = "fr")]
+	pub framerate: f64,
+	/// first frame of the animation
+	#[serde(rename = "ip")]
+	pub in_point: f64,
+	/// frame after the last frame of the animation
+	#[serde(rename = "op")]
+	pub out_point: f64
+}
+
+impl LottieInfo {
+	/// Size of the rendered animation, if it has to fit into `max_width` × `max_height`.
+	pub fn output_size(&self, max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+		Image::resize_preserving_aspect_ratio(self.width, self.height, max_width, max_height)
+	}
+
+	pub fn frame_count(&self) -> u32 {
+		(self.out_point - self.in_point).round().max(0.0) as u32
+	}
+
+	/// Duration of the animation between in and out point in milliseconds.
+	pub fn duration(&self) -> u64 {
+		if self.framerate <= 0.0 {
+			return 0;
+		}
+		(self.frame_count() as f64 * 1000.0 / self.framerate).round() as u64
+	}
+}
+
+/// Return [`Error::DegenerateImage`] for images without pixels, which the encoders can not handle.
+fn check_size(file_name: &Path, width: u32, height: u32) -> Result<(), Error> {
+	if width == 0 || height == 0 {
+		return Err(Error::DegenerateImage {
+			file_name: file_name.display().to_string(),
+			width,
+			height
+		});
+	}
+	Ok(())
+}
+
+/// Turn the error of decoding `data` into [`Error::UnsupportedFormat`], if the data has no format, which can be converted.
+fn decode_error(file_name: &Path, data: &[u8], err: photon_rs::native::Error) -> Error {
+	match ImageFormat::detect(data) {
+		Some(format) if format.is_supported() => err.into(),
+		detected => Error::UnsupportedFormat {
+			file_name: file_name.display().to_string(),
+			detected
+		}
+	}
+}
+
+/// Options for [`Image::convert_to_gif`].
+#[cfg(feature = "gif")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GifOptions {
+	/// Background color for not fully opaque pixels, since gif does not support an alpha channel.
+	/// If the alpha value of this color is 0, fully transparent pixels stay transparent.
+	pub transparent_color: [u8; 4],
+	/// How often the animation is repeated, `0` repeats it forever.
+	pub loop_count: u16,
+	/// Dither the colors to the gif palette, to avoid color banding.
+	pub dither: bool,
+	/// Drop frames, so that the animation is shown with at most this many frames per second.
+	pub max_fps: Option<u32>,
+	/// Algorithm, which picks the 256 colors of the gif palette.
+	pub quantizer: Quantizer
+}
+
+#[cfg(feature = "gif")]
+fn decode_frames(data: &[u8]) -> Result<Vec<Frame>, image_crate::ImageError> {
+	match image_crate::guess_format(data)? {
+		image_crate::ImageFormat::WebP => {
+			let decoder = WebPDecoder::new(std::io::Cursor::new(data))?;
+			if decoder.has_animation() {
+				return decoder.into_frames().collect_frames();
+			}
+		},
+		image_crate::ImageFormat::Png => {
+			let decoder = PngDecoder::new(std::io::Cursor::new(data))?;
+			if decoder.is_apng() {
+				return decoder.apng().into_frames().collect_frames();
+			}
+		},
+		_ => {}
+	}
+	Ok(vec![Frame::new(image_crate::load_from_memory(data)?.into_rgba8())])
+}
+
+/// Round the delays of `frames` to the centiseconds of gif, keeping the total duration.
+/// Frames, which would be shown shorter than one centisecond or than allowed by `max_fps`, are dropped
+/// and their time is added to the previous frame.
+#[cfg(feature = "gif")]
+fn retime_frames(frames: Vec<Frame>, max_fps: Option<u32>) -> Vec<Frame> {
+	let min_delay = match max_fps {
+		Some(fps) if fps > 0 => 1000_u32.div_ceil(fps).div_ceil(10) * 10,
+		_ => 10
+	};
+	let centiseconds = |ms: f64| (ms / 10.0).round() as u32 * 10;
+	// frames with the time, when they are shown
+	let mut kept: Vec<(Frame, u32)> = Vec::with_capacity(frames.len());
+	let mut end = 0.0;
+	for frame in frames {
+		let start = centiseconds(end);
+		let (numer, denom) = frame.delay().numer_denom_ms();
+		end += numer as f64 / denom.max(1) as f64;
+		if kept.last().is_none_or(|(_, last)| start >= last + min_delay) {
+			kept.push((frame, start));
+		}
+	}
+	let Some((_, last)) = kept.last() else {
+		return Vec::new();
+	};
+	let end = centiseconds(end).max(last + 10);
+	let ends: Vec<u32> = kept.iter().skip(1).map(|(_, start)| *start).chain([end]).collect();
+	kept.into_iter()
+		.zip(ends)
+		.map(|((frame, start), end)| {
+			let (left, top) = (frame.left(), frame.top());
+			Frame::from_parts(frame.into_buffer(), left, top, Delay::from_numer_denom_ms(end - start, 1))
+		})
+		.collect()
+}
+
+/// Blend not opaque pixels with the background color and quantize the frame, dithering it if requested.
+#[cfg(feature = "gif")]
+fn prepare_gif_frame(buffer: &mut RgbaImage, options: &GifOptions) {
+	let [r, g, b, keep_transparent] = options.transparent_color;
+	let keep_transparent = keep_transparent == 0;
+	for pixel in buffer.pixels_mut() {
+		let alpha = pixel[3] as u16;
+		if alpha == 255 || (alpha == 0 && keep_transparent) {
+			continue;
+		}
+		for (channel, background) in pixel.0.iter_mut().zip([r, g, b]) {
+			*channel = ((*channel as u16 * alpha + background as u16 * (255 - alpha) + 127) / 255) as u8;
+		}
+		pixel[3] = 255;
+	}
+	quantize::quantize_frame(buffer, options.quantizer, options.dither);
+}
+
+/// Image returned by [`Image::resize`], which tells if the image was actually changed.
+#[derive(Clone, Debug)]
+pub enum ResizedImage {
+	/// the image does already have the requested size and was not encoded again
+	Unchanged(Image),
+	Resized(Image)
+}
+
+impl ResizedImage {
+	pub fn into_image(self) -> Image {
+		match self {
+			Self::Unchanged(image) | Self::Resized(image) => image
+		}
+	}
+
+	pub fn is_resized(&self) -> bool {
+		matches!(self, Self::Resized(_))
+	}
+}
+
+/// Filter, which is used to resize images.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplingFilter {
+	/// [`SamplingFilter::Lanczos3`] for downscaling and [`SamplingFilter::CatmullRom`] for upscaling
+	#[default]
+	Auto,
+	Nearest,
+	Triangle,
+	CatmullRom,
+	Gaussian,
+	Lanczos3
+}
+
+impl SamplingFilter {
+	/// Replace [`SamplingFilter::Auto`] with the filter for scaling an image of size `from` to size `to`.
+	pub fn resolve(self, from: (u32, u32), to: (u32, u32)) -> Self {
+		match self {
+			Self::Auto if to.0 < from.0 || to.1 < from.1 => Self::Lanczos3,
+			Self::Auto => Self::CatmullRom,
+			filter => filter
+		}
+	}
+
+	fn to_photon(self, from: (u32, u32), to: (u32, u32)) -> transform::SamplingFilter {
+		match self.resolve(from, to) {
+			Self::Nearest => transform::SamplingFilter::Nearest,
+			Self::Triangle => transform::SamplingFilter::Triangle,
+			Self::CatmullRom => transform::SamplingFilter::CatmullRom,
+			Self::Gaussian => transform::SamplingFilter::Gaussian,
+			Self::Auto | Self::Lanczos3 => transform::SamplingFilter::Lanczos3
+		}
+	}
+}
+
+/// Options for [`Image::resize_with_options`].
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ResizeOptions {
+	pub filter: SamplingFilter
+}
+
+/// Clockwise rotation by a multiple of a quarter turn, see [`Image::rotate`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+	Rotate90,
+	Rotate180,
+	Rotate270
+}
+
+/// Axis of [`Image::flip`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FlipAxis {
+	/// swap left and right
+	Horizontal,
+	/// swap top and bottom
+	Vertical
+}
+
+/// How [`Image::resize_to_fit`] fills its target size, like the css `object-fit` property.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+	/// scale the image to fit into the target and pad the remaining space with the background
+	#[default]
+	Contain,
+	/// scale the image to cover the whole target and crop the parts, which stick out
+	Cover
+}
+
+/// Pack wide defaults for [`Image::convert`], which are used if no explicit value is passed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ConversionConfig {
+	/// format to which animated stickers are converted; [`AnimationFormat::Webp`] if not set
+	pub default_animation_format: Option<AnimationFormat>,
+	pub default_max_width: Option<u32>,
+	pub default_max_height: Option<u32>,
+	/// Store animated webp, whose frames are all the same, as static webp.
+	/// See [`Image::collapse_static_animation`].
+	pub collapse_static: bool,
+	/// Copy the ICC color profile of png, jpeg and webp images to the converted png or webp image.
+	/// If `false`, the profile is dropped whenever the image is encoded again.
+	/// Converted lottie and webm stickers have no profile.
+	pub keep_color_profile: bool,
+	/// Loop count of the converted animations, by their format.
+	pub loop_policy: LoopPolicy,
+	/// Fail with [`Error::LottieCompatibility`] before rendering a lottie animation,
+	/// which uses features that are not rendered correctly, instead of only logging them.
+	/// See [`Image::lottie_warnings`].
+	pub strict_lottie: bool,
+	/// Fail with [`Error::AnimationTooLong`] before rendering a lottie animation, which plays longer than
+	/// [`MATRIX_STICKER_MAX_DURATION_MS`], instead of only logging it; some clients refuse to play such stickers.
+	pub enforce_max_duration: bool,
+	/// Keep the original image, if the converted one is larger and the original does already
+	/// have an accepted format and fits into the maximum size. See [`ConversionNote`].
+	pub prefer_smaller: bool,
+	/// If set, the format of animations is picked per image by this selector, instead of using
+	/// [`ConversionConfig::default_animation_format`]; so a single batch can produce different formats.
+	/// A format passed explicitly to [`Image::convert`] does still take precedence.
+	#[serde(skip)]
+	pub format_selector: Option<FormatSelector>,
+	/// Abort the conversion of a webm video with [`Error::Timeout`], if it takes longer;
+	/// so a malformed video can not block its conversion task forever. See [`Image::convert_webm2webp_with_timeout`].
+	pub ffmpeg_timeout: Duration
+}
+
+/// Function, which picks the format of an animation by the image before its conversion.
+pub type FormatSelectorFn = dyn Fn(&Image) -> AnimationFormat + Send + Sync;
+
+/// See [`ConversionConfig::format_selector`].
+#[derive(Clone)]
+pub struct FormatSelector(pub Arc<FormatSelectorFn>);
+
+impl FormatSelector {
+	pub fn new<F>(selector: F) -> Self
+	where
+		F: Fn(&Image) -> AnimationFormat + Send + Sync + 'static
+	{
+		Self(Arc::new(selector))
+	}
+
+	pub fn select(&self, image: &Image) -> AnimationFormat {
+		(self.0)(image)
+	}
+}
+
+impl fmt::Debug for FormatSelector {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("FormatSelector")
+	}
+}
+
+impl Default for ConversionConfig {
+	fn default() -> Self {
+		Self {
+			default_animation_format: None,
+			default_max_width: None,
+			default_max_height: None,
+			collapse_static: true,
+			keep_color_profile: false,
+			loop_policy: LoopPolicy::default(),
+			strict_lottie: false,
+			enforce_max_duration: false,
+			prefer_smaller: true,
+			format_selector: None,
+			ffmpeg_timeout: DEFAULT_FFMPEG_TIMEOUT
+		}
+	}
+}
+
+/// Sizes, formats and duration of a single conversion, see [`Image::convert_with_stats`].
+/// The formats are the file extensions without leading dot, e.g. `tgs` for animated telegram stickers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConversionStats {
+	pub input_size_bytes: usize,
+	pub output_size_bytes: usize,
+	pub conversion_time_ms: u64,
+	pub input_format: String,
+	pub output_format: String,
+	pub input_dimensions: (u32, u32),
+	pub output_dimensions: (u32, u32)
+}
+
+impl ConversionStats {
+	/// Size of the input divided by the size of the output; values below 1 mean that the conversion made the file larger.
+	pub fn compression_ratio(&self) -> f64 {
+		self.input_size_bytes as f64 / self.output_size_bytes as f64
+	}
+}
+
+/// Remark about a conversion, which did produce a larger file than the original, see [`Image::convert_reporting`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConversionNote {
+	/// The original did already satisfy the config, so it was kept instead of the converted image.
+	KeptOriginal { original_size: usize, converted_size: usize },
+	/// The converted image was kept, because the original does not satisfy the config
+	/// or [`ConversionConfig::prefer_smaller`] is not set.
+	SizeRegression { original_size: usize, converted_size: usize }
+}
+
+/// Progress of a running conversion, see [`Image::convert_lottie_with_progress`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConversionProgress {
+	/// `frame` of `total` frames was rendered and encoded.
+	Rendering { frame: usize, total: usize }
+}
+
+/// Check the animation flag of the `VP8X` chunk, which is always the first chunk of extended webp files.
+#[cfg(any(feature = "animation", feature = "gif"))]
+fn is_animated_webp(data: &[u8]) -> bool {
+	data.get(12..16) == Some(b"VP8X") && data.get(20).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// Sum of the frame delays of a gif in milliseconds, or `None` if it has only one frame.
+fn gif_duration(data: &[u8]) -> Option<u64> {
+	if !data.starts_with(b"GIF8") {
+		return None;
+	}
+	let mut pos = loop_policy::gif_blocks_offset(data).ok()?;
+	let (mut frames, mut duration, mut delay) = (0, 0, 0);
+	// skip the data sub-blocks, which follow extensions and images
+	let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+		loop {
+			let len = *data.get(pos)? as usize;
+			pos += 1 + len;
+			if len == 0 {
+				return Some(pos);
+			}
+		}
+	};
+	loop {
+		match *data.get(pos)? {
+			0x21 => {
+				// the graphic control extension stores the delay of the next image in centiseconds
+				if data.get(pos + 1) == Some(&0xf9) {
+					delay = u16::from_le_bytes(data.get(pos + 4..pos + 6)?.try_into().unwrap()) as u64 * 10;
+				}
+				pos = skip_sub_blocks(pos + 2)?;
+			},
+			0x2c => {
+				let flags = *data.get(pos + 9)?;
+				let color_table = if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+				// descriptor, local color table and minimum code size of the image data
+				pos = skip_sub_blocks(pos + 10 + color_table + 1)?;
+				frames += 1;
+				duration += delay;
+				delay = 0;
+			},
+			_ => break
+		}
+	}
+	(frames > 1).then_some(duration)
+}
+
+/// Sum of the frame delays of an apng in milliseconds, or `None` if this is no apng.
+fn apng_duration(data: &[u8]) -> Option<u64> {
+	let chunks = icc::png_chunks(data)?;
+	if !chunks.iter().any(|(kind, _)| *kind == b"acTL") {
+		return None;
+	}
+	let duration = chunks
+		.iter()
+		.filter(|(kind, payload)| *kind == b"fcTL" && payload.len() >= 24)
+		.map(|(_, payload)| {
+			let numerator = u16::from_be_bytes([payload[20], payload[21]]) as u64;
+			// a denominator of zero means centiseconds
+			let denominator = match u16::from_be_bytes([payload[22], payload[23]]) {
+				0 => 100,
+				denominator => denominator as u64
+			};
+			numerator * 1000 / denominator
+		})
+		.sum();
+	Some(duration)
+}
+
+/// Blur a `width` × `height` mask with a gaussian kernel of the given `radius`, one direction after the other.
+fn gaussian_blur(mask: &[f32], width: usize, height: usize, radius: f32) -> Vec<f32> {
+	let size = radius.ceil() as isize;
+	if size <= 0 {
+		return mask.to_vec();
+	}
+	// the kernel covers about two standard deviations on each side
+	let sigma = radius / 2.0;
+	let kernel: Vec<f32> = (-size..=size)
+		.map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+		.collect();
+	let sum: f32 = kernel.iter().sum();
+	let pass = |src: &[f32], step: (isize, isize)| -> Vec<f32> {
+		let mut dst = vec![0.0; src.len()];
+		for y in 0..height as isize {
+			for x in 0..width as isize {
+				let mut value = 0.0;
+				for (weight, i) in kernel.iter().zip(-size..=size) {
+					let (sx, sy) = (x + i * step.0, y + i * step.1);
+					if (0..width as isize).contains(&sx) && (0..height as isize).contains(&sy) {
+						value += weight * src[sy as usize * width + sx as usize];
+					}
+				}
+				dst[y as usize * width + x as usize] = value / sum;
+			}
+		}
+		dst
+	};
+	pass(&pass(mask, (1, 0)), (0, 1))
+}
+
+/// Composite the RGBA8 pixel `front` over `back`.
+fn blend_over(front: [u8; 4], back: [u8; 4]) -> [u8; 4] {
+	let front_alpha = front[3] as u32;
+	let back_alpha = back[3] as u32 * (255 - front_alpha) / 255;
+	let alpha = front_alpha + back_alpha;
+	if alpha == 0 {
+		return [0; 4];
+	}
+	let channel = |i: usize| ((front[i] as u32 * front_alpha + back[i] as u32 * back_alpha) / alpha) as u8;
+	[channel(0), channel(1), channel(2), alpha as u8]
+}
+
+/// Rotate raw RGBA8 pixels and return them together with their new width and height.
+fn rotate_rgba(pixels: &[u8], width: u32, height: u32, rotation: Rotation) -> (Vec<u8>, u32, u32) {
+	let (new_width, new_height) = match rotation {
+		Rotation::Rotate180 => (width, height),
+		Rotation::Rotate90 | Rotation::Rotate270 => (height, width)
+	};
+	let mut rotated = vec![0; pixels.len()];
+	for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+		let (x, y) = (i as u32 % width, i as u32 / width);
+		let (new_x, new_y) = match rotation {
+			Rotation::Rotate90 => (height - 1 - y, x),
+			Rotation::Rotate180 => (width - 1 - x, height - 1 - y),
+			Rotation::Rotate270 => (y, width - 1 - x)
+		};
+		let start = (new_y as usize * new_width as usize + new_x as usize) * 4;
+		rotated[start..start + 4].copy_from_slice(pixel);
+	}
+	(rotated, new_width, new_height)
+}
+
+/// Keep only the top left `new_width` × `new_height` pixels of a RGBA8 buffer, which is `width` pixels wide.
+fn crop_rgba(pixels: &[u8], width: u32, new_width: u32, new_height: u32) -> Vec<u8> {
+	pixels
+		.chunks_exact(width as usize * 4)
+		.take(new_height as usize)
+		.flat_map(|row| &row[..new_width as usize * 4])
+		.copied()
+		.collect()
+}
+
 #[derive(Clone)]
 /// Generic image struct, containing the image data and its meta data.
 pub struct Image {
-	pub file_name: String,
-	pub data: Arc<Vec<u8>>,
+	/// Name of the file; its extension determines the format of the image.
+	pub file_name: PathBuf,
+	/// Encoded image data; it is only replaced as a whole, see [`Image::set_data`], so the caches stay valid.
+	data: Arc<Vec<u8>>,
 	pub width: u32,
-	pub height: u32
+	pub height: u32,
+	/// Duration of the animation in milliseconds, if it is already known.
+	/// Set by [`Image::convert_lottie`] to the duration of the original lottie animation.
+	pub duration_ms: Option<u64>,
+	/// Decoded pixels, which are reused by [`Image::pixel_at`] and [`Image::resize`].
+	pixel_cache: PixelCache,
+	/// Hash supplied by [`Image::with_content_hash`] or computed by [`Image::content_hash()`].
+	content_hash: ContentHash
+}
+
+/// Decoded RGBA8 pixels of an [`Image`] together with their width.
+type DecodedPixels = (Vec<u8>, u32);
+/// Decoded pixels together with the buffer, from which they were decoded.
+type CacheEntry = (Weak<Vec<u8>>, Arc<DecodedPixels>);
+
+/// Cache for the decoded pixels of an [`Image`].
+///
+/// The pixels belong to the buffer, which was stored at [`Image::data()`] while decoding,
+/// so they are decoded again automatically if the data of the image is replaced.
+/// Clones of an image share the cache, so resizing several clones to different sizes decodes the image only once.
+#[derive(Clone, Default)]
+struct PixelCache(Arc<Mutex<Option<CacheEntry>>>);
+
+impl PixelCache {
+	fn get_or_decode(&self, data: &Arc<Vec<u8>>) -> Result<Arc<DecodedPixels>, Error> {
+		if let Some((cached_data, pixels)) = self.0.lock().unwrap().as_ref() {
+			if Weak::ptr_eq(cached_data, &Arc::downgrade(data)) {
+				return Ok(pixels.clone());
+			}
+		}
+		// the lock is not held while decoding, so clones with other data do not wait for each other
+		let img = open_image_from_bytes(data)?;
+		let pixels = Arc::new((img.get_raw_pixels(), img.get_width()));
+		*self.0.lock().unwrap() = Some((Arc::downgrade(data), pixels.clone()));
+		Ok(pixels)
+	}
+
+	/// Like [`PixelCache::get_or_decode`], but return the pixels as [`PhotonImage`] to process them further.
+	fn decode_photon(&self, data: &Arc<Vec<u8>>) -> Result<PhotonImage, Error> {
+		let pixels = self.get_or_decode(data)?;
+		let (pixels, width) = pixels.as_ref();
+		let height = if *width == 0 { 0 } else { (pixels.len() / 4) as u32 / width };
+		Ok(PhotonImage::new(pixels.clone(), *width, height))
+	}
+}
+
+/// Hash together with the buffer, from which it was computed.
+type HashEntry = (Weak<Vec<u8>>, Hash);
+
+/// [Hash](database::hash) of [`Image::data()`], which was computed outside of this crate, see [`Image::with_content_hash`],
+/// or by [`Image::content_hash`], so it is computed at most once per buffer.
+///
+/// Like the [`PixelCache`], the hash belongs to the buffer, for which it was supplied or computed,
+/// so it is ignored once the data of the image is replaced, for example by a conversion.
+#[derive(Default)]
+struct ContentHash {
+	supplied: Option<HashEntry>,
+	computed: Mutex<Option<HashEntry>>
+}
+
+impl Clone for ContentHash {
+	fn clone(&self) -> Self {
+		Self {
+			supplied: self.supplied.clone(),
+			computed: Mutex::new(self.computed.lock().unwrap().clone())
+		}
+	}
+}
+
+fn entry_for(entry: Option<&HashEntry>, data: &Arc<Vec<u8>>) -> Option<Hash> {
+	let (hashed_data, hash) = entry?;
+	Weak::ptr_eq(hashed_data, &Arc::downgrade(data)).then_some(*hash)
+}
+
+impl ContentHash {
+	fn supplied(data: &Arc<Vec<u8>>, hash: Hash) -> Self {
+		Self {
+			supplied: Some((Arc::downgrade(data), hash)),
+			computed: Default::default()
+		}
+	}
+
+	/// Hash supplied for `data`, which was not verified yet.
+	fn get(&self, data: &Arc<Vec<u8>>) -> Option<Hash> {
+		entry_for(self.supplied.as_ref(), data)
+	}
+
+	fn get_or_compute(&self, data: &Arc<Vec<u8>>) -> Hash {
+		if let Some(hash) = self.get(data) {
+			return hash;
+		}
+		let mut computed = self.computed.lock().unwrap();
+		if let Some(hash) = entry_for(computed.as_ref(), data) {
+			return hash;
+		}
+		let hash = database::hash(data);
+		*computed = Some((Arc::downgrade(data), hash));
+		hash
+	}
+}
+
+#[cfg(test)]
+thread_local! {
+	/// Number of encoded image buffers copied by [`copy_buffer`] on this thread.
+	/// It is per thread, so tests running in parallel do not count the copies of each other.
+	pub(crate) static BUFFER_COPIES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Copy an encoded buffer, which we can not take ownership of (like the output of libwebp), into a new `Vec`.
+/// All copies of encoded image data should use this function, so tests can check that no unnecessary copies are made;
+/// decoded pixels are copied directly.
+pub(crate) fn copy_buffer(data: &[u8]) -> Vec<u8> {
+	#[cfg(test)]
+	BUFFER_COPIES.with(|copies| copies.set(copies.get() + 1));
+	data.to_vec()
+}
+
+/// The data is only shown by its size and the start of its [`Image::content_hash`],
+/// so large images do not flood the output.
+impl std::fmt::Debug for Image {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let hash = self.content_hash();
+		let data = format_args!("[{} bytes, hash={:02x}{:02x}…]", self.data_len(), hash[0], hash[1]);
+		f.debug_struct("Image")
+			.field("file_name", &self.file_name)
+			.field("data", &data)
+			.field("width", &self.width)
+			.field("height", &self.height)
+			.field("duration_ms", &self.duration_ms)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Images are equal, if they have the same meta data and the same [`Image::content_hash`],
+/// even if their data is stored in different buffers. The caches are not compared.
+impl PartialEq for Image {
+	fn eq(&self, other: &Self) -> bool {
+		self.file_name == other.file_name
+			&& self.width == other.width
+			&& self.height == other.height
+			&& self.duration_ms == other.duration_ms
+			&& (Arc::ptr_eq(&self.data, &other.data)
+				|| self.data.len() == other.data.len() && self.content_hash() == other.content_hash())
+	}
+}
+
+impl Eq for Image {}
+
+impl std::hash::Hash for Image {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.file_name.hash(state);
+		self.width.hash(state);
+		self.height.hash(state);
+		self.duration_ms.hash(state);
+		self.content_hash().hash(state);
+	}
 }
 
 fn rayon_run<F, T>(callback: F) -> T
@@ -56,12 +818,67 @@ where
 }
 
 impl Image {
+	/// [`Image::file_name`] as `&str`; empty if the name is not valid unicode.
+	pub fn file_name_str(&self) -> &str {
+		self.file_name.to_str().unwrap_or_default()
+	}
+
+	/// `true` for lottie and webm stickers and for animated webp, gif and png images, which are detected by their content.
+	/// This does not decode the image, so it is cheap enough to pick a format per image, see [`FormatSelector`].
+	pub fn is_animated(&self) -> bool {
+		self.is_tgs()
+			|| self.is_lottie()
+			|| self.is_webm()
+			|| gif_duration(&self.data).is_some()
+			|| LoopFormat::detect(&self.data).is_some_and(|format| format != LoopFormat::Gif)
+	}
+
+	/// Check if the extension of [`Image::file_name`] is `extension`, which is given without leading dot.
+	pub(crate) fn has_extension(&self, extension: &str) -> bool {
+		self.file_name.extension().is_some_and(|current| current == extension)
+	}
+
+	/// `true` if [`Image::file_name`] has the extension `gif`.
+	/// Like the other format predicates, this does only check the extension, which determines the format of the image;
+	/// see [`Image::fix_extension_from_magic_bytes`] to correct it by the content.
+	pub fn is_gif(&self) -> bool {
+		self.has_extension("gif")
+	}
+
+	/// `true` if [`Image::file_name`] has the extension `webp`; these can be static or animated.
+	pub fn is_webp(&self) -> bool {
+		self.has_extension("webp")
+	}
+
+	/// `true` if [`Image::file_name`] has the extension `png`; these can be static or animated.
+	pub fn is_png(&self) -> bool {
+		self.has_extension("png")
+	}
+
+	/// `true` if [`Image::file_name`] has the extension `webm`, like telegram video stickers.
+	pub fn is_webm(&self) -> bool {
+		self.has_extension("webm")
+	}
+
+	/// `true` if [`Image::file_name`] has the extension `lottie`, like unpacked telegram animated stickers.
+	pub fn is_lottie(&self) -> bool {
+		self.has_extension("lottie")
+	}
+
+	/// `true` if [`Image::file_name`] has the extension `tgs`, like gzip compressed telegram animated stickers.
+	pub fn is_tgs(&self) -> bool {
+		self.has_extension("tgs")
+	}
+
+	fn unsupported_format(&self, detected: Option<ImageFormat>) -> Error {
+		Error::UnsupportedFormat {
+			file_name: self.file_name.display().to_string(),
+			detected
+		}
+	}
+
 	pub fn mime_type(&self) -> Result<String, NoMimeType> {
-		let extension = Path::new(&self.file_name)
-			.extension()
-			.ok_or_else(|| NoMimeType)?
-			.to_str()
-			.unwrap(); //this must be valid utf8 since we use a string as input
+		let extension = self.file_name.extension().ok_or(NoMimeType)?.to_str().ok_or(NoMimeType)?;
 		Ok(if extension == "webm" {
 			format!("video/{extension}",)
 		} else {
@@ -69,88 +886,828 @@ impl Image {
 		})
 	}
 
-	/// unpack gzip compression `tgs`, converting it to `lottie`, ignore other formats
-	pub async fn unpack_tgs(mut self) -> Result<Self, Error> {
-		if !self.file_name.ends_with(".tgs") {
-			return Ok(self);
+	/// Read an image from `path`. The extension of [`Image::file_name`] is corrected by the content,
+	/// see [`Image::fix_extension_from_magic_bytes`].
+	/// Images with an EXIF orientation are rotated, see [`Image::apply_exif_orientation`].
+	/// The size of webm videos is only read with the `ffmpeg` feature and is zero otherwise.
+	pub async fn from_path<P>(path: P) -> Result<Self, Error>
+	where
+		P: AsRef<Path>
+	{
+		let path = path.as_ref();
+		let data = tokio::fs::read(path).await?;
+		Self::from_data(path.file_name().unwrap_or_default().into(), data)
+	}
+
+	/// Create an image from the encoded `data`, like [`Image::from_path`] does after reading the file.
+	fn from_data(file_name: PathBuf, data: Vec<u8>) -> Result<Self, Error> {
+		let mut image = Image {
+			file_name,
+			data: Arc::new(data),
+			width: 0,
+			height: 0,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		image.fix_extension_from_magic_bytes();
+		if image.is_tgs() || image.is_lottie() {
+			let info = image.lottie_info()?;
+			(image.width, image.height) = (info.width, info.height);
+		} else if image.is_webm() {
+			#[cfg(feature = "ffmpeg")]
+			{
+				let info = image.video_info()?;
+				(image.width, image.height) = (info.width, info.height);
+			}
+		} else {
+			let img = open_image_from_bytes(&image.data).map_err(|err| decode_error(&image.file_name, &image.data, err))?;
+			(image.width, image.height) = (img.get_width(), img.get_height());
+			image = image.apply_exif_orientation()?;
 		}
-		let image: Result<Image, Error> = tokio::task::spawn_blocking(move || {
-			rayon_run(move || {
-				let mut output = Vec::new();
-				let input_reader = &**self.data;
-				flate2::read::GzDecoder::new(input_reader).read_to_end(&mut output)?;
-				self.data = Arc::new(output);
-				self.file_name.truncate(self.file_name.len() - 3);
-				self.file_name += "lottie";
-				Ok(self)
-			})
-		})
-		.await?;
-		Ok(image?)
+		Ok(image)
 	}
 
-	/// convert `tgs` image to webp or gif, ignore other formats
-	#[cfg(feature = "lottie")]
-	pub async fn convert_lottie(self, animation_format: AnimationFormat, max_width: Option<u32>, max_height: Option<u32>) -> Result<Self, Error> {
-		use lottieconv::Size;
+	/// Decode an image from standard base64, like it is embedded at data uris.
+	/// The image is read like by [`Image::from_path`], so the extension of `file_name` is corrected by the content.
+	pub fn from_base64(b64: &str, file_name: String) -> Result<Self, Error> {
+		let data = STANDARD.decode(b64.trim())?;
+		Self::from_data(file_name.into(), data)
+	}
 
-		if !self.file_name.ends_with(".lottie") {
-			return Ok(self);
+	/// Encode the data of the image as standard base64, with padding.
+	pub fn to_base64(&self) -> String {
+		STANDARD.encode(&**self.data)
+	}
+
+	/// Return the image as data uri, like `data:image/webp;base64,UklGR…`.
+	pub fn to_data_uri(&self) -> Result<String, Error> {
+		Ok(format!("data:{};base64,{}", self.mime_type()?, self.to_base64()))
+	}
+
+	/// Set the extension of [`Image::file_name`] to `tgs`, `webm` or `lottie`, if the data is a gzip file,
+	/// a webm video or a json document, since telegram does sometimes serve them as `.bin` or without extension.
+	/// Other file names are not changed.
+	pub fn fix_extension_from_magic_bytes(&mut self) {
+		let extension = match ImageFormat::detect(&self.data) {
+			Some(format @ (ImageFormat::Tgs | ImageFormat::Webm | ImageFormat::Lottie)) => format.extension(),
+			_ => return
+		};
+		if !self.has_extension(extension) {
+			self.file_name.set_extension(extension);
 		}
-		let mut image = self.unpack_tgs().await?;
-		tokio::task::spawn_blocking(move || {
-			rayon_run(move || {
-				//save to image to file
-				let mut tmp = NamedTempFile::new()?;
-				tmp.write_all(&image.data)?;
-				tmp.flush()?;
-				let animation = Animation::from_file(tmp.path()).ok_or_else(|| Error::AnimationLoadError)?;
-				let size = animation.size();
-				let aspect_ratio = size.width.clone() as f32 / size.height.clone() as f32;
-				let (new_width, new_height) = Self::resize_preserving_aspect_ratio(size.width as u32, size.height as u32, max_width, max_height);
-				let new_size = Size {
-					width: new_width as usize,
-					height: new_height as usize
-				};
-				image.file_name.truncate(image.file_name.len() - 6);
-				let converter = Converter::new(animation);
-				match animation_format {
-					AnimationFormat::Gif { transparent_color } => {
-						let mut data = Vec::new();
-						converter.with_size(new_size).gif(transparent_color, &mut data)?.convert()?;
-						image.data = Arc::new(data);
-						image.file_name += "gif";
-					},
-					AnimationFormat::Webp => {
-						image.data = Arc::new(converter.with_size(new_size).webp()?.convert()?.to_vec());
-						image.file_name += "webp";
-					}
-				}
-				image.width = new_size.width as u32;
-				image.height = new_size.height as u32;
-				Ok(image)
-			})
-		})
-		.await?
 	}
 
-	#[cfg(feature = "ffmpeg")]
-	/// convert `webm` video stickers to webp, ignore other formats
-	pub async fn convert_webm2webp(mut self, new_width: Option<u32>, new_height: Option<u32>) -> Result<Self, Error> {
-		if !self.file_name.ends_with(".webm") {
-			return Ok(self);
+	/// Check that [`Image::width`] and [`Image::height`] are the size of the encoded data.
+	/// Lottie animations are checked by their json; webm videos can only be checked with the `ffmpeg` feature.
+	pub fn verify_data_matches_dimensions(&self) -> Result<(), Error> {
+		let Some(actual) = self.encoded_dimensions()? else {
+			return Ok(());
+		};
+		let stored = (self.width, self.height);
+		if stored != actual {
+			return Err(Error::DimensionMismatch { stored, actual });
 		}
+		Ok(())
+	}
 
+	/// Read the size of the encoded data; `None` for webm videos without the `ffmpeg` feature.
+	fn encoded_dimensions(&self) -> Result<Option<(u32, u32)>, Error> {
+		if self.is_tgs() || self.is_lottie() {
+			let info = self.lottie_info()?;
+			Ok(Some((info.width, info.height)))
+		} else if self.is_webm() {
+			#[cfg(not(feature = "ffmpeg"))]
+			return Ok(None);
+			#[cfg(feature = "ffmpeg")]
+			{
+				let info = self.video_info()?;
+				Ok(Some((info.width, info.height)))
+			}
+		} else {
+			let img = open_image_from_bytes(&self.data)?;
+			Ok(Some((img.get_width(), img.get_height())))
+		}
+	}
+
+	/// Use `hash` as [hash](database::hash) of the current data, instead of computing it again,
+	/// for example because another system does store the images by their hash.
+	///
+	/// The hash is trusted and used to find the image at the database; use [`database::HASH_ALGORITHM`] to compute it.
+	/// Debug builds check it by [`Image::verify_content_hash`] before uploading.
+	pub fn with_content_hash(mut self, hash: Hash) -> Self {
+		self.content_hash = ContentHash::supplied(&self.data, hash);
+		self
+	}
+
+	/// [Hash](database::hash) of the data, which is used to deduplicate uploads.
+	/// A hash supplied by [`Image::with_content_hash`] is returned without computing it;
+	/// otherwise the hash is computed once and kept until the data is replaced.
+	pub fn content_hash(&self) -> Hash {
+		self.content_hash.get_or_compute(&self.data)
+	}
+
+	/// Check that the hash supplied by [`Image::with_content_hash`] is the hash of the data.
+	pub fn verify_content_hash(&self) -> Result<(), Error> {
+		let Some(supplied) = self.content_hash.get(&self.data) else {
+			return Ok(());
+		};
+		let actual = database::hash(&self.data);
+		if supplied != actual {
+			let hex = |hash: Hash| hash.iter().map(|byte| format!("{byte:02x}")).collect();
+			return Err(Error::ContentHashMismatch {
+				file_name: self.file_name.display().to_string(),
+				supplied: hex(supplied),
+				actual: hex(actual)
+			});
+		}
+		Ok(())
+	}
+
+	/// Return the encoded image data.
+	pub fn data_bytes(&self) -> &[u8] {
+		&self.data
+	}
+
+	/// Return the encoded image data as shared buffer, which can be cloned without copying the data.
+	pub fn data(&self) -> &Arc<Vec<u8>> {
+		&self.data
+	}
+
+	/// Replace the encoded image data and drop the decoded pixels and the hash of the old data.
+	/// The meta data like [`Image::width`] is not changed and must be updated by the caller, if it does not fit anymore.
+	pub fn set_data<D>(&mut self, data: D)
+	where
+		D: Into<Arc<Vec<u8>>>
+	{
+		self.data = data.into();
+		self.pixel_cache = Default::default();
+		self.content_hash = Default::default();
+	}
+
+	/// Size of the encoded image data in bytes.
+	pub fn data_len(&self) -> usize {
+		self.data.len()
+	}
+
+	/// Check if the encoded image data has at most `max_bytes` bytes.
+	pub fn is_within_size_limit(&self, max_bytes: usize) -> bool {
+		self.data.len() <= max_bytes
+	}
+
+	/// Like [`Image::is_within_size_limit`], but return [`Error::FileTooLarge`] if the image is too large.
+	pub fn assert_within_size_limit(&self, max_bytes: usize) -> Result<(), Error> {
+		if !self.is_within_size_limit(max_bytes) {
+			return Err(Error::FileTooLarge {
+				actual: self.data.len(),
+				limit: max_bytes
+			});
+		}
+		Ok(())
+	}
+
+	/// Length of the longest side in pixels.
+	pub fn max_dimension(&self) -> u32 {
+		self.width.max(self.height)
+	}
+
+	/// Length of the shortest side in pixels.
+	pub fn min_dimension(&self) -> u32 {
+		self.width.min(self.height)
+	}
+
+	/// Number of pixels; unlike `width * height` this can not overflow.
+	pub fn total_pixels(&self) -> u64 {
+		self.width as u64 * self.height as u64
+	}
+
+	/// Size of a single decoded RGBA8 frame in bytes, like returned by [`Image::decode_rgba`].
+	pub fn approximate_uncompressed_size_bytes(&self) -> u64 {
+		self.total_pixels() * 4
+	}
+
+	/// `true` if the image satisfies the common Matrix sticker requirements: it is a webp or webm sticker,
+	/// which fits into [`MATRIX_STICKER_MAX_SIZE_PX`] and [`MATRIX_STICKER_MAX_BYTES`],
+	/// and an animated webp does not play longer than [`MATRIX_STICKER_MAX_DURATION_MS`].
+	/// The duration of webp is only known with the `animation` feature, see [`Image::duration`].
+	pub fn sticker_compliant(&self) -> bool {
+		if self.max_dimension() > MATRIX_STICKER_MAX_SIZE_PX || self.data.len() > MATRIX_STICKER_MAX_BYTES {
+			return false;
+		}
+		if self.is_webm() {
+			return true;
+		}
+		self.is_webp()
+			&& self
+				.duration()
+				.is_ok_and(|duration| duration.is_none_or(|duration| duration <= MATRIX_STICKER_MAX_DURATION_MS))
+	}
+
+	/// Consume the image and return its encoded data, without copying it.
+	pub fn into_data(self) -> Arc<Vec<u8>> {
+		self.data
+	}
+
+	/// Write the encoded data to `path`.
+	/// The data is written as is: [`Image::file_name`] is not used and the extension of `path` is ignored.
+	pub fn save_to_path_sync(&self, path: &Path) -> Result<(), Error> {
+		Ok(std::fs::write(path, &*self.data)?)
+	}
+
+	/// Async version of [`Image::save_to_path_sync`].
+	pub async fn save_to_path(&self, path: &Path) -> Result<(), Error> {
+		// `tokio::fs::write` would copy the data, to move it to the blocking thread
+		let data = self.data.clone();
+		let path = path.to_owned();
+		tokio::task::spawn_blocking(move || std::fs::write(path, &*data)).await??;
+		Ok(())
+	}
+
+	/// Format the matrix url of the uploaded image together with the meta data of the image,
+	/// like `mxc://example.org/abc (512×512, image/webp, 24KB)`.
+	pub fn to_matrix_content_uri_string(&self, mxc: &Mxc) -> String {
+		let mime_type = self.mime_type().unwrap_or_else(|_| "application/octet-stream".to_owned());
+		let size = (self.data_len() + 512) / 1024;
+		format!("{mxc} ({}×{}, {mime_type}, {size}KB)", self.width, self.height)
+	}
+
+	/// Content of a `m.sticker` event, which posts the image uploaded to `mxc` with `body` as description.
+	/// The `info` has the mime type, size and file size of the image, so clients can show it before loading it.
+	///
+	/// Return [`Error::NoMimeType`] if the file name has no extension, and [`Error::InvalidMxc`]
+	/// if the upload was deferred, since the url is not known yet; see [`Mxc::is_deferred`].
+	pub fn as_matrix_sticker_event(&self, mxc: &Mxc, body: &str) -> Result<serde_json::Value, Error> {
+		if mxc.is_deferred() {
+			return Err(Error::InvalidMxc(mxc.url().to_owned()));
+		}
+		let info = MetaData::try_from(self.clone())?;
+		Ok(serde_json::json!({
+			"body": body,
+			"url": mxc.url(),
+			"info": info
+		}))
+	}
+
+	/// unpack gzip compression `tgs`, converting it to `lottie`, ignore other formats
+	///
+	/// Some `tgs` files are not compressed at all; those are only renamed to `lottie`.
+	pub async fn unpack_tgs(mut self) -> Result<Self, Error> {
+		if !self.is_tgs() {
+			return Ok(self);
+		}
+		if !self.data.starts_with(&[0x1f, 0x8b]) {
+			self.file_name.set_extension("lottie");
+			return Ok(self);
+		}
+		tokio::task::spawn_blocking(move || {
+			rayon_run(move || -> Result<Self, Error> {
+				// lottie json is typically compressed by a factor of about 6
+				let mut output = Vec::with_capacity(self.data.len() * 6);
+				// a slice is already buffered, so the decoder does not need to copy it into its own buffer
+				let input_reader = &**self.data;
+				flate2::bufread::GzDecoder::new(input_reader).read_to_end(&mut output)?;
+				self.data = Arc::new(output);
+				self.file_name.set_extension("lottie");
+				Ok(self)
+			})
+		})
+		.await?
+	}
+
+	/// Read size, framerate and frame count of a `lottie` or `tgs` animation, without rendering it.
+	pub fn lottie_info(&self) -> Result<LottieInfo, Error> {
+		let info = if self.is_tgs() {
+			serde_json::from_reader(flate2::bufread::GzDecoder::new(&**self.data))?
+		} else {
+			serde_json::from_slice(&self.data)?
+		};
+		Ok(info)
+	}
+
+	/// Inspect a `lottie` or `tgs` animation for features, which rlottie does not render correctly,
+	/// like expressions, effects and unsupported layer types, without rendering it.
+	pub fn lottie_warnings(&self) -> Result<Vec<LottieWarning>, Error> {
+		let json = if self.is_tgs() {
+			serde_json::from_reader(flate2::bufread::GzDecoder::new(&**self.data))?
+		} else {
+			serde_json::from_slice(&self.data)?
+		};
+		Ok(lottie_check::inspect(&json))
+	}
+
+	/// `true` if every frame of the image is fully transparent or all its pixels have the same color,
+	/// like animations which use features that are not rendered.
+	pub fn is_blank(&self) -> Result<bool, Error> {
+		#[cfg(feature = "animation")]
+		if self.is_webp() && is_animated_webp(&self.data) {
+			let frames: Vec<_> = webp_animation::Decoder::new(&self.data)?.into_iter().collect();
+			return Ok(lottie_check::is_blank(frames.iter().map(|frame| frame.data())));
+		}
+		let (pixels, ..) = self.decode_rgba()?;
+		Ok(lottie_check::is_blank([&pixels[..]]))
+	}
+
+	/// Without the `lottie` feature, lottie files can not be converted and [`Error::FeatureNotEnabled`] is returned.
+	/// Other formats are still ignored.
+	#[cfg(not(feature = "lottie"))]
+	pub async fn convert_lottie(
+		self,
+		_animation_format: AnimationFormat,
+		_max_width: Option<u32>,
+		_max_height: Option<u32>
+	) -> Result<Self, Error> {
+		if !self.is_lottie() {
+			return Ok(self);
+		}
+		Err(Error::FeatureNotEnabled("lottie"))
+	}
+
+	/// Without the `lottie` feature, this is the same as [`Image::convert_lottie`] and no progress is sent.
+	#[cfg(not(feature = "lottie"))]
+	pub async fn convert_lottie_with_progress(
+		self,
+		animation_format: AnimationFormat,
+		max_width: Option<u32>,
+		max_height: Option<u32>,
+		_progress: Option<UnboundedSender<ConversionProgress>>
+	) -> Result<Self, Error> {
+		self.convert_lottie(animation_format, max_width, max_height).await
+	}
+
+	/// convert `tgs` image to webp or gif, ignore other formats
+	#[cfg(feature = "lottie")]
+	pub async fn convert_lottie(
+		self,
+		animation_format: AnimationFormat,
+		max_width: Option<u32>,
+		max_height: Option<u32>
+	) -> Result<Self, Error> {
+		self.convert_lottie_with_progress(animation_format, max_width, max_height, None)
+			.await
+	}
+
+	/// Like [`Image::convert_lottie`], but send [`ConversionProgress::Rendering`] to `progress`.
+	///
+	/// The progress is sent after each frame was rendered and encoded.
+	#[cfg(feature = "lottie")]
+	pub async fn convert_lottie_with_progress(
+		self,
+		animation_format: AnimationFormat,
+		max_width: Option<u32>,
+		max_height: Option<u32>,
+		progress: Option<UnboundedSender<ConversionProgress>>
+	) -> Result<Self, Error> {
+		use lottieconv::Size;
+
+		if !self.is_lottie() {
+			return Ok(self);
+		}
+		let mut image = self;
+		tokio::task::spawn_blocking(move || {
+			rayon_run(move || {
+				// the size is read from the json, the renderer is only needed for the conversion itself
+				let info = image.lottie_info()?;
+				check_size(&image.file_name, info.width, info.height)?;
+				#[cfg(feature = "log")]
+				if info.duration() > MATRIX_STICKER_MAX_DURATION_MS {
+					warn!(
+						"lottie animation {:?} plays {:.1} s, longer than the {} s, which all matrix clients play",
+						image.file_name,
+						info.duration() as f64 / 1000.0,
+						MATRIX_STICKER_MAX_DURATION_MS / 1000
+					);
+				}
+				let (new_width, new_height) = info.output_size(max_width, max_height);
+				//save to image to file
+				let mut tmp = NamedTempFile::new()?;
+				tmp.write_all(&image.data)?;
+				tmp.flush()?;
+				let mut animation = Animation::from_file(tmp.path()).ok_or_else(|| Error::AnimationLoadError)?;
+				let frame_count = animation.totalframe();
+				let framerate = animation.framerate();
+				if framerate > 0.0 {
+					image.duration_ms = Some((frame_count as f64 / framerate * 1000.0).round() as u64);
+				}
+				let new_size = Size {
+					width: new_width as usize,
+					height: new_height as usize
+				};
+				let mut encoder = match animation_format {
+					AnimationFormat::Gif { transparent_color } => FrameEncoder::gif(new_size, framerate, transparent_color)?,
+					AnimationFormat::Webp => FrameEncoder::webp(new_size, framerate)?
+				};
+				let mut surface = rlottie::Surface::new(new_size);
+				for frame in 0..frame_count {
+					animation.render(frame, &mut surface);
+					encoder.add_frame(&surface)?;
+					if let Some(progress) = &progress {
+						// the receiver may not be interested in the progress anymore, which does not affect the conversion
+						let _ = progress.send(ConversionProgress::Rendering {
+							frame: frame + 1,
+							total: frame_count
+						});
+					}
+				}
+				image.data = Arc::new(encoder.finish()?);
+				image.file_name.set_extension(animation_format.extension());
+				if image.data.is_empty() {
+					return Err(Error::ConversionProducedEmptyOutput);
+				}
+				image.width = new_size.width as u32;
+				image.height = new_size.height as u32;
+				Ok(image)
+			})
+		})
+		.await?
+	}
+
+	/// convert `lottie` image to an animated png, ignore other formats
+	///
+	/// Unlike webp and gif, apng is no [`AnimationFormat`], since it is not supported by all matrix clients.
+	/// The animation is sampled every `frame_delay_ms` milliseconds, so its speed does not change.
+	#[cfg(feature = "apng")]
+	pub async fn convert_lottie_to_apng(self, frame_delay_ms: u32) -> Result<Self, Error> {
+		if !self.is_lottie() {
+			return Ok(self);
+		}
+		let mut image = self;
 		tokio::task::spawn_blocking(move || {
 			rayon_run(move || {
-				let mut tmp = tempfile::Builder::new().suffix(".webm").tempfile()?;
-				tmp.write_all(&self.data)?;
+				let mut tmp = NamedTempFile::new()?;
+				tmp.write_all(&image.data)?;
 				tmp.flush()?;
+				let mut animation = Animation::from_file(tmp.path()).ok_or_else(|| Error::AnimationLoadError)?;
+				let size = animation.size();
+				check_size(&image.file_name, size.width as u32, size.height as u32)?;
+				let frame_delay_ms = frame_delay_ms.clamp(1, u16::MAX as u32);
+				let duration_ms = (animation.duration() * 1000.0).round().max(1.0) as u64;
+				let frame_count = duration_ms.div_ceil(frame_delay_ms as u64) as u32;
+
+				let mut data = Vec::new();
+				let mut encoder = png::Encoder::new(&mut data, size.width as u32, size.height as u32);
+				encoder.set_color(png::ColorType::Rgba);
+				encoder.set_depth(png::BitDepth::Eight);
+				// loop forever
+				encoder.set_animated(frame_count, 0)?;
+				encoder.set_frame_delay(frame_delay_ms as u16, 1000)?;
+				let mut writer = encoder.write_header()?;
+				let mut surface = rlottie::Surface::new(size);
+				for i in 0..frame_count {
+					let pos = (i as u64 * frame_delay_ms as u64) as f32 / duration_ms as f32;
+					animation.render(animation.frame_at_pos(pos), &mut surface);
+					// rlottie renders bgra
+					let pixels: Vec<u8> = surface
+						.data()
+						.iter()
+						.flat_map(|pixel| [pixel.r, pixel.g, pixel.b, pixel.a])
+						.collect();
+					writer.write_image_data(&pixels)?;
+				}
+				writer.finish()?;
+
+				image.file_name.set_extension("png");
+				image.data = Arc::new(data);
+				image.width = size.width as u32;
+				image.height = size.height as u32;
+				image.duration_ms = Some(frame_count as u64 * frame_delay_ms as u64);
+				Ok(image)
+			})
+		})
+		.await?
+	}
+
+	/// convert webp, png and lottie images to gif, ignore gifs
+	///
+	/// Converting `tgs` and `lottie` stickers requires the `lottie` feature.
+	/// For those only the [`GifOptions::transparent_color`] is used.
+	#[cfg(feature = "gif")]
+	pub async fn convert_to_gif(mut self, options: GifOptions) -> Result<Self, Error> {
+		if self.is_gif() {
+			return Ok(self);
+		}
+		if self.is_tgs() || self.is_lottie() {
+			#[cfg(feature = "lottie")]
+			{
+				let [r, g, b, a] = options.transparent_color;
+				let transparent_color = Rgba { r, g, b, a: a == 0 };
+				return self
+					.unpack_tgs()
+					.await?
+					.convert_lottie(AnimationFormat::Gif { transparent_color }, None, None)
+					.await;
+			}
+			#[cfg(not(feature = "lottie"))]
+			return Err(Error::FeatureNotEnabled("lottie"));
+		}
+
+		tokio::task::spawn_blocking(move || {
+			rayon_run(move || {
+				let mut frames = retime_frames(decode_frames(&self.data)?, options.max_fps);
+				let (width, height) = match frames.first() {
+					Some(frame) => frame.buffer().dimensions(),
+					None => return Err(Error::ConversionProducedEmptyOutput)
+				};
+				check_size(&self.file_name, width, height)?;
+				for frame in &mut frames {
+					prepare_gif_frame(frame.buffer_mut(), &options);
+				}
+
+				let mut data = Vec::new();
+				{
+					let mut encoder = GifEncoder::new_with_speed(&mut data, 10);
+					encoder.set_repeat(match options.loop_count {
+						0 => Repeat::Infinite,
+						count => Repeat::Finite(count)
+					})?;
+					encoder.encode_frames(frames)?;
+				}
+				self.data = Arc::new(data);
+				self.file_name.set_extension("gif");
+				self.width = width;
+				self.height = height;
+				Ok(self)
+			})
+		})
+		.await?
+	}
+
+	/// Convert animated webp images to gif, like [`Image::convert_to_gif`]; other images are returned unchanged.
+	///
+	/// This allows to serve animations from sources, which provide webp, to clients, which do only animate gifs.
+	#[cfg(feature = "gif")]
+	pub async fn convert_webp2gif(self, options: GifOptions) -> Result<Self, Error> {
+		if !self.is_webp() || !is_animated_webp(&self.data) {
+			return Ok(self);
+		}
+		self.convert_to_gif(options).await
+	}
+
+	/// Convert the image to a static gif, containing only the first frame of animations.
+	/// See [`GifOptions::transparent_color`] for `transparent_color`.
+	/// If it is `None`, fully transparent pixels stay transparent and the other ones are blended with black.
+	#[cfg(feature = "gif")]
+	pub fn to_gif(mut self, transparent_color: Option<[u8; 4]>) -> Result<Self, Error> {
+		let options = GifOptions {
+			transparent_color: transparent_color.unwrap_or_default(),
+			..Default::default()
+		};
+		let mut buffer = image_crate::load_from_memory(&self.data)?.into_rgba8();
+		prepare_gif_frame(&mut buffer, &options);
+		let (width, height) = buffer.dimensions();
+
+		let mut data = Vec::new();
+		GifEncoder::new_with_speed(&mut data, 10).encode_frame(Frame::new(buffer))?;
+		self.data = Arc::new(data);
+		self.file_name.set_extension("gif");
+		self.width = width;
+		self.height = height;
+		self.duration_ms = None;
+		Ok(self)
+	}
+
+	/// Convert lottie and webm stickers to the animation format and resize the image to the maximum size.
+	/// Parameters, which are `None`, fall back to the defaults of `config`.
+	pub async fn convert(
+		self,
+		config: &ConversionConfig,
+		animation_format: Option<AnimationFormat>,
+		max_width: Option<u32>,
+		max_height: Option<u32>
+	) -> Result<Self, Error> {
+		let (image, _) = self
+			.convert_reporting(config, animation_format, max_width, max_height)
+			.await?;
+		Ok(image)
+	}
+
+	/// Like [`Image::convert`], but also return a note, if the converted image was larger than the original.
+	pub async fn convert_reporting(
+		self,
+		config: &ConversionConfig,
+		animation_format: Option<AnimationFormat>,
+		max_width: Option<u32>,
+		max_height: Option<u32>
+	) -> Result<(Self, Option<ConversionNote>), Error> {
+		let span = StageSpan::new(Stage::Convert, &self.file_name, self.data.len());
+		let (image, note) = span
+			.run(self.convert_stages(config, animation_format, max_width, max_height))
+			.await?;
+		span.bytes_out(image.data.len());
+		Ok((image, note))
+	}
+
+	async fn convert_stages(
+		self,
+		config: &ConversionConfig,
+		animation_format: Option<AnimationFormat>,
+		max_width: Option<u32>,
+		max_height: Option<u32>
+	) -> Result<(Self, Option<ConversionNote>), Error> {
+		if let Some(format) = ImageFormat::detect(&self.data).filter(|format| !format.is_supported()) {
+			return Err(self.unsupported_format(Some(format)));
+		}
+		let max_width = max_width.or(config.default_max_width);
+		let max_height = max_height.or(config.default_max_height);
+		let profile = if config.keep_color_profile { self.icc_profile() } else { None };
+		let animation_format = animation_format
+			.or_else(|| config.format_selector.as_ref().map(|selector| selector.select(&self)))
+			.or(config.default_animation_format)
+			.unwrap_or_default();
+		// cloning is cheap, since the data is shared
+		let original = self.clone();
+		let image = self.unpack_tgs().await?;
+		let mut image = if image.is_lottie() {
+			let warnings = image.lottie_warnings()?;
+			if config.strict_lottie && !warnings.is_empty() {
+				return Err(Error::LottieCompatibility {
+					file_name: image.file_name.display().to_string(),
+					warnings
+				});
+			}
+			#[cfg(feature = "log")]
+			for warning in &warnings {
+				warn!("lottie animation {:?} {warning}", image.file_name);
+			}
+			if config.enforce_max_duration {
+				let duration_ms = image.lottie_info()?.duration();
+				if duration_ms > MATRIX_STICKER_MAX_DURATION_MS {
+					return Err(Error::AnimationTooLong {
+						file_name: image.file_name.display().to_string(),
+						duration_ms,
+						max_duration_ms: MATRIX_STICKER_MAX_DURATION_MS
+					});
+				}
+			}
+			let image = image.convert_lottie(animation_format, max_width, max_height).await?;
+			// a blank conversion is still a valid sticker, but most likely not what was intended
+			#[cfg(feature = "log")]
+			if image.is_blank()? {
+				warn!("lottie animation {:?} {}", image.file_name, LottieWarning::BlankOutput);
+			}
+			image
+		} else if image.is_webm() {
+			#[cfg(feature = "ffmpeg")]
+			{
+				image
+					.convert_webm2webp_with_timeout(max_width, max_height, config.ffmpeg_timeout)
+					.await?
+			}
+			#[cfg(not(feature = "ffmpeg"))]
+			return Err(Error::FeatureNotEnabled("ffmpeg"));
+		} else if max_width.is_none() && max_height.is_none() {
+			image
+		} else {
+			image
+				.resize(max_width.unwrap_or(u32::MAX), max_height.unwrap_or(u32::MAX))?
+				.into_image()
+		};
+		// animated webp, including converted webm stickers, are served as gif too
+		#[cfg(all(feature = "gif", feature = "lottie"))]
+		if let AnimationFormat::Gif { transparent_color } = animation_format {
+			let options = GifOptions {
+				transparent_color: [
+					transparent_color.r,
+					transparent_color.g,
+					transparent_color.b,
+					if transparent_color.a { 0 } else { 255 }
+				],
+				..Default::default()
+			};
+			image = image.convert_webp2gif(options).await?;
+		}
+		#[cfg(feature = "animation")]
+		if config.collapse_static {
+			image = image.collapse_static_animation()?;
+		}
+		if let Some(data) = config.loop_policy.apply(&image.data)? {
+			image.data = Arc::new(data);
+		}
+		if let Some(profile) = profile {
+			if let Some(data) = icc::embed(&image.data, &profile)? {
+				image.data = Arc::new(data);
+			}
+		}
+		let (original_size, converted_size) = (original.data.len(), image.data.len());
+		if converted_size <= original_size || Arc::ptr_eq(&original.data, &image.data) {
+			return Ok((image, None));
+		}
+		if config.prefer_smaller && original.satisfies(config, animation_format, max_width, max_height)? {
+			return Ok((
+				original,
+				Some(ConversionNote::KeptOriginal {
+					original_size,
+					converted_size
+				})
+			));
+		}
+		#[cfg(feature = "log")]
+		warn!(
+			"converting {:?} increased its size from {original_size} to {converted_size} bytes",
+			original.file_name
+		);
+		Ok((
+			image,
+			Some(ConversionNote::SizeRegression {
+				original_size,
+				converted_size
+			})
+		))
+	}
+
+	/// Check if the image can be used without conversion: it has no format, which [`Image::convert`] does always convert,
+	/// it fits into the maximum size and the loop count does already match the [`LoopPolicy`].
+	fn satisfies(
+		&self,
+		config: &ConversionConfig,
+		animation_format: AnimationFormat,
+		max_width: Option<u32>,
+		max_height: Option<u32>
+	) -> Result<bool, Error> {
+		if self.is_tgs() || self.is_lottie() || self.is_webm() {
+			return Ok(false);
+		}
+		#[cfg(all(feature = "gif", feature = "lottie"))]
+		if matches!(animation_format, AnimationFormat::Gif { .. }) && is_animated_webp(&self.data) {
+			return Ok(false);
+		}
+		#[cfg(not(all(feature = "gif", feature = "lottie")))]
+		let _ = animation_format;
+		let fits = self.width <= max_width.unwrap_or(u32::MAX) && self.height <= max_height.unwrap_or(u32::MAX);
+		Ok(fits && config.loop_policy.apply(&self.data)?.is_none())
+	}
 
-				self.file_name.truncate(self.file_name.len() - 1);
-				self.file_name += "p";
-				let (webp, width, height) = webm2webp(&tmp.path(), new_width, new_height)?;
-				self.data = Arc::new(webp.to_vec());
+	/// Like [`Image::convert`], but also return statistics about the conversion, e.g. for logging batches.
+	pub async fn convert_with_stats(
+		self,
+		config: &ConversionConfig,
+		animation_format: Option<AnimationFormat>,
+		max_width: Option<u32>,
+		max_height: Option<u32>
+	) -> Result<(Self, ConversionStats), Error> {
+		let input_size_bytes = self.data.len();
+		let input_format = self.file_extension();
+		let input_dimensions = (self.width, self.height);
+		let start = Instant::now();
+		let image = self.convert(config, animation_format, max_width, max_height).await?;
+		let stats = ConversionStats {
+			input_size_bytes,
+			output_size_bytes: image.data.len(),
+			conversion_time_ms: start.elapsed().as_millis() as u64,
+			input_format,
+			output_format: image.file_extension(),
+			input_dimensions,
+			output_dimensions: (image.width, image.height)
+		};
+		Ok((image, stats))
+	}
+
+	/// File extension of the image, without leading dot.
+	fn file_extension(&self) -> String {
+		let extension = Path::new(&self.file_name).extension().unwrap_or_default();
+		extension.to_string_lossy().into_owned()
+	}
+
+	#[cfg(feature = "ffmpeg")]
+	/// convert `webm` video stickers to webp, ignore other formats
+	///
+	/// On unix the video is passed to ffmpeg through a pipe and is not written to disk;
+	/// ffmpeg builds, which can not read from the pipe, get the video as temporary file.
+	/// The conversion is aborted with [`Error::Timeout`] after [`DEFAULT_FFMPEG_TIMEOUT`].
+	pub async fn convert_webm2webp(self, new_width: Option<u32>, new_height: Option<u32>) -> Result<Self, Error> {
+		self.convert_webm2webp_with_timeout(new_width, new_height, DEFAULT_FFMPEG_TIMEOUT)
+			.await
+	}
+
+	#[cfg(feature = "ffmpeg")]
+	/// Like [`Image::convert_webm2webp`], but abort the conversion with [`Error::Timeout`] after `timeout`.
+	///
+	/// ffmpeg runs in process and not as child process, which could be killed. So the deadline is checked
+	/// before decoding each packet and, while reading a temporary file, by the interrupt callback of ffmpeg;
+	/// the blocking task does stop there, instead of running forever at a malformed video.
+	pub async fn convert_webm2webp_with_timeout(
+		mut self,
+		new_width: Option<u32>,
+		new_height: Option<u32>,
+		timeout: Duration
+	) -> Result<Self, Error> {
+		if !self.is_webm() {
+			return Ok(self);
+		}
+
+		tokio::task::spawn_blocking(move || {
+			rayon_run(move || {
+				let deadline = Deadline::after(timeout);
+				let (webp, width, height) = match webm2webp(&self.data, new_width, new_height, &deadline) {
+					Err(_) if deadline.expired() => {
+						return Err(Error::Timeout {
+							file_name: self.file_name.display().to_string(),
+							timeout
+						})
+					},
+					converted => converted?
+				};
+				self.file_name.set_extension("webp");
+				self.data = Arc::new(copy_buffer(&webp));
 				self.width = width;
 				self.height = height;
 
@@ -160,73 +1717,2620 @@ impl Image {
 		.await?
 	}
 
+	/// Check if the hash of the image data is equal to the hex encoded digest `expected_hex`, ignoring case.
+	/// The same hash algorithm as for the [database](database::hash) is used.
+	pub fn checksum_matches(&self, expected_hex: &str) -> bool {
+		let hash = database::hash(&self.data);
+		let actual_hex: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+		actual_hex.eq_ignore_ascii_case(expected_hex)
+	}
+
 	///upload image to matrix
 	/// return mxc_url and true if image was uploaded now; false if it was already uploaded before and exist at the database
-	pub async fn upload<D>(&self, matrix_config: &Config, database: Option<&D>) -> Result<(Mxc, bool), Error>
+	/// or if the upload was deferred to the [`Config::outbox`](matrix::Config::outbox), see [`Mxc::is_deferred`]
+	///
+	/// Fail with [`Error::FileTooLarge`], if the image is larger than [`Config::max_upload_size`](matrix::Config::max_upload_size).
+	/// If the [`Config::pre_upload_hook`](matrix::Config::pre_upload_hook) changes the data, the changed data is uploaded
+	/// and its hash is the key at the database.
+	pub async fn upload(&self, matrix_config: &Config, database: Option<&dyn DynDatabase>) -> Result<(Mxc, bool), Error> {
+		self.upload_with(matrix_config, database).await
+	}
+
+	/// Like [`Image::upload`], but with static dispatch of the database.
+	pub async fn upload_with<D>(&self, matrix_config: &Config, database: Option<&D>) -> Result<(Mxc, bool), Error>
+	where
+		D: database::Database + ?Sized
+	{
+		let hooked;
+		let image = match matrix_config.pre_upload_hook.as_ref().and_then(|hook| (hook.0)(&self.data)) {
+			Some(data) => {
+				// `set_data` drops the content hash, so the url is stored by the hash of the uploaded data
+				let mut image = self.clone();
+				image.set_data(data);
+				hooked = image;
+				&hooked
+			},
+			None => self
+		};
+		if let Some(max_bytes) = matrix_config.max_upload_size {
+			image.assert_within_size_limit(max_bytes)?;
+		}
+		// images with a wrong size would be shown distorted by the clients
+		#[cfg(debug_assertions)]
+		{
+			self.verify_data_matches_dimensions()?;
+			// a wrong hash would store the url for other images at the database
+			self.verify_content_hash()?;
+		}
+		let span = StageSpan::new(Stage::Upload, &image.file_name, image.data.len());
+		let (mxc, uploaded) = span.run(image.upload_stages(matrix_config, database)).await?;
+		span.cache_hit(!uploaded && !mxc.is_deferred());
+		span.bytes_out(if uploaded { image.data.len() } else { 0 });
+		Ok((mxc, uploaded))
+	}
+
+	async fn upload_stages<D>(&self, matrix_config: &Config, database: Option<&D>) -> Result<(Mxc, bool), Error>
 	where
-		D: database::Database
+		D: database::Database + ?Sized
 	{
-		let hash = Lazy::new(|| database::hash(&self.data));
+		let hash = Lazy::new(|| self.content_hash());
 
 		// if database is some and datbase.unwrap().get() is also some
 		if let Some(db) = database {
-			if let Some(url) = db.get(&hash).await.map_err(Error::Database)? {
-				return Ok((url.into(), false));
+			let lookup = StageSpan::new(Stage::DbLookup, &self.file_name, self.data.len());
+			// a corrupt database does fail the upload, since its entries can not be trusted anymore
+			let known = lookup.run(database::retry_unavailable(|| db.get(&hash))).await?;
+			lookup.cache_hit(known.is_some());
+			if let Some(url) = known {
+				return Ok((Mxc::new(url, Some(self.data.clone())), false));
+			}
+		}
+
+		if let Some(exists_check) = &matrix_config.exists_check {
+			if let Some(mut mxc) = (exists_check.0)(&self.data).await {
+				mxc.data.get_or_insert_with(|| self.data.clone());
+				if let Some(db) = database {
+					database::retry_unavailable(|| db.add(*hash, mxc.url().to_owned())).await?;
+				}
+				return Ok((mxc, false));
 			}
 		}
 
-		let mxc = matrix::upload(matrix_config, &self.file_name, self.data.clone(), &self.mime_type()?).await?;
+		let file_name = self.file_name.to_string_lossy().into_owned();
+		let mxc = match matrix::upload(matrix_config, &file_name, self.data.clone(), &self.mime_type()?).await {
+			Ok(mxc) => mxc,
+			Err(err) => {
+				return match &matrix_config.outbox {
+					Some(outbox) if matrix::is_unreachable(&err) => {
+						let mxc = outbox.push(&file_name, self.data.clone(), &self.mime_type()?).await?;
+						Ok((mxc, false))
+					},
+					_ => Err(err)
+				};
+			}
+		};
 		if let Some(db) = database {
-			db.add(*hash, mxc.url().to_owned()).await.map_err(Error::Database)?;
+			database::retry_unavailable(|| db.add(*hash, mxc.url().to_owned())).await?;
 		}
 		Ok((mxc, true))
 	}
 
-	fn resize_preserving_aspect_ratio(
+	/// Size of an image of `width` × `height` pixels, which is scaled to the given maximum width and height.
+	/// The result has at least one pixel in each direction; images without pixels keep their size,
+	/// they are rejected with [`Error::DegenerateImage`] when they are resized.
+	pub(crate) fn resize_preserving_aspect_ratio(
+		width: u32,
+		height: u32,
+		max_width: Option<u32>,
+		max_height: Option<u32>
+	) -> (u32, u32) {
+		if width == 0 || height == 0 {
+			return (width, height);
+		}
+		let (new_width, new_height) = Self::scale_preserving_aspect_ratio(width, height, max_width, max_height);
+		(new_width.max(1), new_height.max(1))
+	}
+
+	fn scale_preserving_aspect_ratio(
 		width: u32,
 		height: u32,
 		max_width: Option<u32>,
 		max_height: Option<u32>
 	) -> (u32, u32) {
 		let aspect_ratio = width as f64 / height as f64;
-	
+
 		match (max_width, max_height) {
 			(None, None) => (width, height),
 			(Some(w), None) => {
 				let new_width = w as f64;
 				let new_height = new_width / aspect_ratio;
-				return (new_width.round() as u32, new_height.round() as u32);
+				(new_width.round() as u32, new_height.round() as u32)
 			},
 			(None, Some(h)) => {
 				let new_height = h as f64;
 				let new_width = new_height * aspect_ratio;
-				return (new_width.round() as u32, new_height.round() as u32);
+				(new_width.round() as u32, new_height.round() as u32)
 			},
 			(Some(w), Some(h)) => {
 				let max_w = w as f64;
 				let max_h = h as f64;
-		
+
 				let scale_w = max_w / width as f64;
 				let scale_h = max_h / height as f64;
 				let scale = scale_w.min(scale_h);
-		
+
 				let new_width = (width as f64 * scale).round();
 				let new_height = (height as f64 * scale).round();
-		
-				return (new_width as u32, new_height as u32);
+
+				(new_width as u32, new_height as u32)
 			}
 		}
 	}
 
-	pub fn resize(mut self, max_width: u32, max_height: u32) -> Result<Self, Error> {
-		let mut img = open_image_from_bytes(&self.data).unwrap();
-		let img_width = img.clone().get_width();
-		let img_height = img.clone().get_height();
-		let (width, height) = Self::resize_preserving_aspect_ratio(img_width, img_height, Some(max_width), Some(max_height));
-		img = transform::resize(&mut img, width, height, transform::SamplingFilter::Lanczos3);
-		self.data = Arc::new(img.get_bytes_webp().to_vec());
-		self.width = width;
-		self.height = height;
-		return Ok(self);
+	/// Resize the image to fit into `max_width` × `max_height`, preserving its aspect ratio.
+	/// If the image does already have this size, it is returned unchanged, without encoding it again.
+	/// The decoded pixels are kept at the [`PixelCache`], so resizing clones of the image does not decode it again.
+	pub fn resize(self, max_width: u32, max_height: u32) -> Result<ResizedImage, Error> {
+		self.resize_with_options(max_width, max_height, ResizeOptions::default())
+	}
+
+	/// Like [`Image::resize`], but allow to choose the [`SamplingFilter`].
+	pub fn resize_with_options(
+		self,
+		max_width: u32,
+		max_height: u32,
+		options: ResizeOptions
+	) -> Result<ResizedImage, Error> {
+		let img = self.pixel_cache.decode_photon(&self.data)?;
+		self.resize_decoded(img, max_width, max_height, options)
+	}
+
+	/// Scale the image up or down, so that its longest side has `size` pixels, preserving its aspect ratio.
+	/// This looks better in grids of packs with mixed aspect ratios than fitting the stickers into different boxes.
+	pub fn normalize_longest_side(self, size: u32) -> Result<ResizedImage, Error> {
+		self.resize(size, size)
+	}
+
+	/// Like [`Image::resize`], but images, which do already fit into `max_width` × `max_height`, are not scaled up.
+	pub fn downscale_if_needed(self, max_width: u32, max_height: u32) -> Result<ResizedImage, Error> {
+		let img = self.pixel_cache.decode_photon(&self.data)?;
+		let max_width = max_width.min(img.get_width());
+		let max_height = max_height.min(img.get_height());
+		self.resize_decoded(img, max_width, max_height, ResizeOptions::default())
+	}
+
+	/// Scale the image down, so that its longest side has at most `max` pixels, preserving its aspect ratio.
+	/// Larger images are scaled, so that their longest side has exactly `max` pixels, no matter if it is the width
+	/// or the height; smaller images are never scaled up.
+	/// This is the same as `downscale_if_needed(max, max)`, but smaller images are returned without decoding them.
+	pub fn downscale_longest_side(self, max: u32) -> Result<Self, Error> {
+		if self.max_dimension() <= max {
+			return Ok(self);
+		}
+		Ok(self.downscale_if_needed(max, max)?.into_image())
+	}
+
+	/// Scale the image down, so that its longest edge has `px` pixels, like [`Image::downscale_longest_side`].
+	/// Images, whose longest edge has already at most `px` pixels, are returned unchanged.
+	pub fn downscale_to_longest_edge(self, px: u32) -> Result<Image, Error> {
+		self.downscale_longest_side(px)
+	}
+
+	/// Scale the image, preserving its aspect ratio, and center it at a canvas of exactly `target_width` × `target_height`.
+	/// Depending on `mode`, the remaining space is filled with the `background` color or the image is cropped.
+	/// Unlike [`Image::resize`] the result has always the target size, which is useful for clients expecting square stickers.
+	/// Return a new static webp image; for animated images only the first frame is used.
+	/// If the image does already have the target size, it is returned unchanged.
+	pub fn resize_to_fit(
+		self,
+		target_width: u32,
+		target_height: u32,
+		mode: FitMode,
+		background: [u8; 4]
+	) -> Result<Self, Error> {
+		check_size(&self.file_name, target_width, target_height)?;
+		if (self.width, self.height) == (target_width, target_height) {
+			return Ok(self);
+		}
+		let img = self.pixel_cache.decode_photon(&self.data)?;
+		let (width, height) = (img.get_width(), img.get_height());
+		check_size(&self.file_name, width, height)?;
+		let scale_x = target_width as f64 / width as f64;
+		let scale_y = target_height as f64 / height as f64;
+		let scale = match mode {
+			FitMode::Contain => scale_x.min(scale_y),
+			FitMode::Cover => scale_x.max(scale_y)
+		};
+		let new_width = ((width as f64 * scale).round() as u32).max(1);
+		let new_height = ((height as f64 * scale).round() as u32).max(1);
+		let filter = SamplingFilter::default().to_photon((width, height), (new_width, new_height));
+		let pixels = transform::resize(&img, new_width, new_height, filter).get_raw_pixels();
+
+		// the offset of the scaled image at the canvas is negative for the cropped sides
+		let left = (target_width as i64 - new_width as i64) / 2;
+		let top = (target_height as i64 - new_height as i64) / 2;
+		let mut canvas = background.repeat(target_width as usize * target_height as usize);
+		for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+			let x = (i as u32 % new_width) as i64 + left;
+			let y = (i as u32 / new_width) as i64 + top;
+			if x < 0 || y < 0 || x >= target_width as i64 || y >= target_height as i64 {
+				continue;
+			}
+			let start = (y as usize * target_width as usize + x as usize) * 4;
+			let back = &mut canvas[start..start + 4];
+			let blended = blend_over(pixel.try_into().unwrap(), [back[0], back[1], back[2], back[3]]);
+			back.copy_from_slice(&blended);
+		}
+		Image::from_rgba(canvas, target_width, target_height, &self.file_name)
+	}
+
+	fn resize_decoded(
+		mut self,
+		img: PhotonImage,
+		max_width: u32,
+		max_height: u32,
+		options: ResizeOptions
+	) -> Result<ResizedImage, Error> {
+		// the decoded dimensions are authoritative, the stored ones can be stale
+		let img_width = img.get_width();
+		let img_height = img.get_height();
+		check_size(&self.file_name, img_width, img_height)?;
+		debug_assert!(
+			(self.width, self.height) == (img_width, img_height),
+			"stored size {}x{} of {:?} does not match the decoded size {img_width}x{img_height}",
+			self.width,
+			self.height,
+			self.file_name
+		);
+		let span = StageSpan::new(Stage::Resize, &self.file_name, self.data.len());
+		let _entered = span.enter();
+		let (width, height) = Self::resize_preserving_aspect_ratio(img_width, img_height, Some(max_width), Some(max_height));
+		if (width, height) == (img_width, img_height) {
+			self.width = img_width;
+			self.height = img_height;
+			span.bytes_out(self.data.len());
+			return Ok(ResizedImage::Unchanged(self));
+		}
+		let filter = options.filter.to_photon((img_width, img_height), (width, height));
+		let img = transform::resize(&img, width, height, filter);
+		self.width = img.get_width();
+		self.height = img.get_height();
+		self.data = Arc::new(img.get_bytes_webp());
+		// the cache does still hold the pixels of the original data for the clones of the image
+		self.pixel_cache = Default::default();
+		span.bytes_out(self.data.len());
+		Ok(ResizedImage::Resized(self))
+	}
+
+	/// Like [`Image::resize`], but return the image together with `true` if the image was changed.
+	pub fn resize_reporting(self, max_width: u32, max_height: u32) -> Result<(Self, bool), Error> {
+		let resized = self.resize(max_width, max_height)?;
+		let changed = resized.is_resized();
+		Ok((resized.into_image(), changed))
+	}
+
+	/// Return the number of frames of a webp or lottie animation; static webp images have a single frame.
+	#[cfg(feature = "animation")]
+	pub fn frame_count(&self) -> Result<u32, Error> {
+		if self.is_webp() {
+			Ok(webp_animation::Decoder::new(&self.data)?.into_iter().count() as u32)
+		} else if self.is_lottie() || self.is_tgs() {
+			Ok(self.lottie_info()?.frame_count())
+		} else {
+			Err(Error::UnsupportedOperation {
+				operation: "counting frames",
+				file_name: self.file_name.display().to_string()
+			})
+		}
+	}
+
+	/// Return the duration of an animation in milliseconds, or `None` for static images.
+	/// If [`Image::duration_ms`] is set, it is returned without looking at the data.
+	/// The duration of webp is only known with the `animation` feature and the one of webm only with the `ffmpeg` feature.
+	pub fn duration(&self) -> Result<Option<u64>, Error> {
+		if let Some(duration) = self.duration_ms {
+			return Ok(Some(duration));
+		}
+		if self.is_lottie() || self.is_tgs() {
+			return Ok(Some(self.lottie_info()?.duration()));
+		}
+		if self.is_gif() {
+			return Ok(gif_duration(&self.data));
+		}
+		if self.is_png() || self.has_extension("apng") {
+			return Ok(apng_duration(&self.data));
+		}
+		#[cfg(feature = "animation")]
+		if self.is_webp() {
+			if !is_animated_webp(&self.data) {
+				return Ok(None);
+			}
+			// the timestamp of a decoded frame is the time, when it ends
+			let last = webp_animation::Decoder::new(&self.data)?.into_iter().last();
+			return Ok(last.map(|frame| frame.timestamp().max(0) as u64));
+		}
+		#[cfg(feature = "ffmpeg")]
+		if self.is_webm() {
+			let mut tmp = tempfile::Builder::new().suffix(".webm").tempfile()?;
+			tmp.write_all(&self.data)?;
+			tmp.flush()?;
+			return crate::video::duration(&tmp.path());
+		}
+		Ok(None)
+	}
+
+	/// Total playback time of one loop of the animation, see [`Image::duration`]; zero for static images.
+	pub fn total_duration(&self) -> Result<Duration, Error> {
+		Ok(Duration::from_millis(self.duration()?.unwrap_or(0)))
+	}
+
+	/// Read size, duration and if there is an audio track from a webm video, without decoding it.
+	#[cfg(feature = "ffmpeg")]
+	pub fn video_info(&self) -> Result<VideoInfo, Error> {
+		if !self.is_webm() {
+			return Err(Error::UnsupportedOperation {
+				operation: "probing a video",
+				file_name: self.file_name.display().to_string()
+			});
+		}
+		let mut tmp = tempfile::Builder::new().suffix(".webm").tempfile()?;
+		tmp.write_all(&self.data)?;
+		tmp.flush()?;
+		crate::video::probe(&tmp.path())
+	}
+
+	/// Convert an animated webp, whose frames are all the same, to a static webp.
+	/// Such animations are for example created from static lottie files,
+	/// and waste space and confuse some clients.
+	/// Ignore other formats and real animations.
+	#[cfg(feature = "animation")]
+	pub fn collapse_static_animation(mut self) -> Result<Self, Error> {
+		if !self.is_webp() {
+			return Ok(self);
+		}
+		let decoder = webp_animation::Decoder::new(&self.data)?;
+		let (width, height) = decoder.dimensions();
+		let mut frames = decoder.into_iter();
+		let first = match frames.next() {
+			Some(frame) => frame,
+			None => return Ok(self)
+		};
+		// libwebp does already merge identical consecutive frames, but other encoders do not
+		if !is_animated_webp(&self.data) || frames.any(|frame| frame.data() != first.data()) {
+			return Ok(self);
+		}
+		let img = PhotonImage::new(first.data().to_vec(), width, height);
+		self.data = Arc::new(img.get_bytes_webp());
+		self.duration_ms = None;
+		Ok(self)
+	}
+
+	/// Reduce an animated webp to at most `max_frames` frames, by dropping frames evenly across the animation.
+	/// The duration of dropped frames is added to the kept frame before them, so the total duration stays the same.
+	/// Ignore other formats and animations, which does not have more than `max_frames` frames.
+	#[cfg(feature = "animation")]
+	pub fn decimate_frames(mut self, max_frames: u32) -> Result<Self, Error> {
+		if !self.is_webp() {
+			return Ok(self);
+		}
+		let decoder = webp_animation::Decoder::new(&self.data)?;
+		let dimensions = decoder.dimensions();
+		let frames: Vec<_> = decoder.into_iter().collect();
+		let max_frames = max_frames.max(1) as usize;
+		if frames.len() <= max_frames {
+			return Ok(self);
+		}
+
+		// the timestamp of a decoded frame is the time, when it ends;
+		// the encoder expects the time, when a frame starts
+		let mut encoder = webp_animation::Encoder::new(dimensions)?;
+		for i in 0..max_frames {
+			let first = i * frames.len() / max_frames;
+			let start = first.checked_sub(1).map(|prev| frames[prev].timestamp()).unwrap_or(0);
+			encoder.add_frame(frames[first].data(), start)?;
+		}
+		let end = frames.last().map(|frame| frame.timestamp()).unwrap_or_default();
+		self.data = Arc::new(copy_buffer(&encoder.finalize(end)?));
+		Ok(self)
+	}
+
+	/// Scale the duration of every frame of an animated webp, so one loop of the animation plays for `target`.
+	/// Each frame is shown for at least one millisecond, so the frame count stays the same.
+	///
+	/// Static webp images and animations without duration are returned unchanged.
+	/// Other formats return [`Error::UnsupportedOperation`].
+	#[cfg(feature = "animation")]
+	pub fn set_duration(mut self, target: Duration) -> Result<Self, Error> {
+		if !self.is_webp() {
+			return Err(Error::UnsupportedOperation {
+				operation: "changing the duration",
+				file_name: self.file_name.display().to_string()
+			});
+		}
+		if !is_animated_webp(&self.data) {
+			return Ok(self);
+		}
+		let decoder = webp_animation::Decoder::new(&self.data)?;
+		let dimensions = decoder.dimensions();
+		let frames: Vec<_> = decoder.into_iter().collect();
+		let total = frames.last().map(|frame| frame.timestamp()).unwrap_or_default();
+		if total <= 0 {
+			return Ok(self);
+		}
+		let scale = target.as_millis().min(i32::MAX as u128) as f64 / total as f64;
+
+		// the timestamp of a decoded frame is the time, when it ends;
+		// the encoder expects the time, when a frame starts
+		let mut encoder = webp_animation::Encoder::new(dimensions)?;
+		let mut start = 0;
+		for (i, frame) in frames.iter().enumerate() {
+			if i > 0 {
+				start = ((frames[i - 1].timestamp() as f64 * scale).round() as i32).max(start + 1);
+			}
+			encoder.add_frame(frame.data(), start)?;
+		}
+		let end = ((total as f64 * scale).round() as i32).max(start + 1);
+		self.data = Arc::new(copy_buffer(&encoder.finalize(end)?));
+		self.duration_ms = Some(end as u64);
+		Ok(self)
+	}
+
+	/// Fit a webp sticker into [`STICKER_SIZE`] × [`STICKER_SIZE`], preserving its aspect ratio,
+	/// and return it together with a static poster of its first frame, like it is used as thumbnail of the sticker.
+	/// Animations are decoded only once for both images; the poster of a static image is a copy of the sticker.
+	/// Other formats return [`Error::UnsupportedOperation`].
+	#[cfg(feature = "animation")]
+	pub fn to_sticker_with_poster(mut self) -> Result<(Self, Self), Error> {
+		if !self.is_webp() {
+			return Err(Error::UnsupportedOperation {
+				operation: "creating a sticker with poster",
+				file_name: self.file_name.display().to_string()
+			});
+		}
+		let poster_name = self.file_name.with_extension("poster.webp");
+		if !is_animated_webp(&self.data) {
+			let sticker = self.resize(STICKER_SIZE, STICKER_SIZE)?.into_image();
+			let poster = Self {
+				file_name: poster_name,
+				..sticker.clone()
+			};
+			return Ok((sticker, poster));
+		}
+		let decoder = webp_animation::Decoder::new(&self.data)?;
+		let (canvas_width, canvas_height) = decoder.dimensions();
+		check_size(&self.file_name, canvas_width, canvas_height)?;
+		let (width, height) =
+			Self::resize_preserving_aspect_ratio(canvas_width, canvas_height, Some(STICKER_SIZE), Some(STICKER_SIZE));
+
+		// the timestamp of a decoded frame is the time, when it ends
+		let mut encoder = webp_animation::Encoder::new((width, height))?;
+		let mut poster = None;
+		let mut start = 0;
+		for frame in decoder {
+			let mut img = PhotonImage::new(frame.data().to_vec(), canvas_width, canvas_height);
+			if (width, height) != (canvas_width, canvas_height) {
+				let filter = SamplingFilter::Auto.to_photon((canvas_width, canvas_height), (width, height));
+				img = transform::resize(&img, width, height, filter);
+			}
+			let pixels = img.get_raw_pixels();
+			encoder.add_frame(&pixels, start)?;
+			poster.get_or_insert(pixels);
+			start = frame.timestamp();
+		}
+		let poster = poster.ok_or(Error::MalformedImage("webp"))?;
+		let poster = Self::from_rgba(poster, width, height, poster_name)?;
+		self.data = Arc::new(copy_buffer(&encoder.finalize(start)?));
+		self.width = width;
+		self.height = height;
+		self.duration_ms = Some(start as u64);
+		Ok((self, poster))
+	}
+
+	/// Return the ICC color profile of a png, jpeg or webp image, if it has one.
+	pub fn icc_profile(&self) -> Option<Vec<u8>> {
+		icc::extract(&self.data)
+	}
+
+	/// Embed an ICC color profile, like [ICC_SRGB], into a png or webp image, replacing any existing profile.
+	/// Other formats like gif and webm do not support color profiles.
+	pub fn embed_icc_profile(mut self, profile: &[u8]) -> Result<Self, Error> {
+		let data = match self.file_name.extension().and_then(|extension| extension.to_str()) {
+			Some("png") => icc::embed_png(&self.data, profile)?,
+			Some("webp") => icc::embed_webp(&self.data, profile)?,
+			_ => {
+				return Err(Error::UnsupportedOperation {
+					operation: "embedding an icc profile",
+					file_name: self.file_name.display().to_string()
+				})
+			},
+		};
+		self.data = Arc::new(data);
+		Ok(self)
+	}
+
+	/// Decode the image to raw RGBA8 pixels.
+	/// Return the pixel buffer together with the width and height of the image.
+	/// For animated images only the first frame is decoded.
+	pub fn decode_rgba(&self) -> Result<(Vec<u8>, u32, u32), Error> {
+		let img = open_image_from_bytes(&self.data)?;
+		Ok((img.get_raw_pixels(), img.get_width(), img.get_height()))
+	}
+
+	/// Decode the image to a flat RGBA8 pixel buffer in row-major order,
+	/// so channel `c` of pixel `(x, y)` is at `data[y * width * 4 + x * 4 + c]`.
+	/// For animated images only the first frame is decoded.
+	pub fn to_raw_rgba(&self) -> Result<Vec<u8>, Error> {
+		Ok(self.decode_rgba()?.0)
+	}
+
+	/// Like [`Image::to_raw_rgba`], but without alpha channel,
+	/// so channel `c` of pixel `(x, y)` is at `data[y * width * 3 + x * 3 + c]`.
+	pub fn to_raw_rgb(&self) -> Result<Vec<u8>, Error> {
+		let rgba = self.to_raw_rgba()?;
+		Ok(rgba.chunks_exact(4).flat_map(|pixel| &pixel[..3]).copied().collect())
+	}
+
+	/// Compute the [blurhash](https://blurha.sh/) of the image.
+	/// For animated images the first frame is used.
+	#[cfg(feature = "blurhash")]
+	pub fn blurhash(&self) -> Result<String, Error> {
+		let (pixels, width, height) = self.decode_rgba()?;
+		Ok(blurhash::encode(4, 3, width, height, &pixels)?)
+	}
+
+	/// Return the RGBA8 value of the pixel at `(x, y)`; for animated images of the first frame.
+	/// The decoded image is cached, so reading multiple pixels does decode the image only once.
+	pub fn pixel_at(&self, x: u32, y: u32) -> Result<[u8; 4], Error> {
+		let out_of_bounds = || Error::InvalidDimensions {
+			x,
+			y,
+			width: self.width,
+			height: self.height
+		};
+		if x >= self.width || y >= self.height {
+			return Err(out_of_bounds());
+		}
+		let pixels = self.pixel_cache.get_or_decode(&self.data)?;
+		let (data, width) = pixels.as_ref();
+		// the decoded image can be smaller, if the size of the image is wrong
+		let start = (y as usize * *width as usize + x as usize) * 4;
+		data.get(start..start + 4)
+			.and_then(|pixel| pixel.try_into().ok())
+			.ok_or_else(out_of_bounds)
+	}
+
+	/// Show the image over a checkerboard of `cell` × `cell` pixel squares, to judge its transparent parts.
+	/// The checkerboard starts with a `light` square at the top left corner.
+	/// Return a new static webp image; for animated images only the first frame is used.
+	pub fn preview_on_checkerboard(&self, cell: u32, light: [u8; 4], dark: [u8; 4]) -> Result<Image, Error> {
+		let (mut pixels, width, height) = self.decode_rgba()?;
+		let cell = cell.max(1);
+		for (i, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+			let (x, y) = (i as u32 % width, i as u32 / width);
+			let back = if (x / cell + y / cell).is_multiple_of(2) {
+				light
+			} else {
+				dark
+			};
+			let blended = blend_over([pixel[0], pixel[1], pixel[2], pixel[3]], back);
+			pixel.copy_from_slice(&blended);
+		}
+		Image::from_rgba(pixels, width, height, &self.file_name)
+	}
+
+	/// Rotate the image clockwise; width and height are swapped by a quarter turn.
+	/// Return a new static webp image; for animated images only the first frame is used.
+	pub fn rotate(self, rotation: Rotation) -> Result<Self, Error> {
+		let (pixels, width, height) = self.decode_rgba()?;
+		// `transform::rotate` does also shear the image at right angles, which moves the pixels by one
+		let (pixels, width, height) = rotate_rgba(&pixels, width, height, rotation);
+		Image::from_rgba(pixels, width, height, &self.file_name)
+	}
+
+	/// Rotate and mirror a jpeg or webp image, like requested by the orientation tag of its EXIF metadata,
+	/// since some matrix clients show such images sideways otherwise.
+	/// Return a new static webp image without EXIF metadata, if the image has to be changed.
+	/// Images without orientation and animations are returned unchanged.
+	pub fn apply_exif_orientation(self) -> Result<Self, Error> {
+		let (rotation, mirror) = match exif::orientation(&self.data) {
+			Some(2) => (None, true),
+			Some(3) => (Some(Rotation::Rotate180), false),
+			Some(4) => (Some(Rotation::Rotate180), true),
+			Some(5) => (Some(Rotation::Rotate90), true),
+			Some(6) => (Some(Rotation::Rotate90), false),
+			Some(7) => (Some(Rotation::Rotate270), true),
+			Some(8) => (Some(Rotation::Rotate270), false),
+			_ => return Ok(self)
+		};
+		if self.is_animated() {
+			return Ok(self);
+		}
+		let (mut pixels, mut width, mut height) = self.decode_rgba()?;
+		if let Some(rotation) = rotation {
+			(pixels, width, height) = rotate_rgba(&pixels, width, height, rotation);
+		}
+		if mirror {
+			let mut img = PhotonImage::new(pixels, width, height);
+			transform::fliph(&mut img);
+			pixels = img.get_raw_pixels();
+		}
+		Image::from_rgba(pixels, width, height, &self.file_name)
+	}
+
+	/// Mirror the image along `axis`.
+	/// Return a new static webp image; for animated images only the first frame is used.
+	pub fn flip(self, axis: FlipAxis) -> Result<Self, Error> {
+		let (pixels, width, height) = self.decode_rgba()?;
+		let mut img = PhotonImage::new(pixels, width, height);
+		match axis {
+			FlipAxis::Horizontal => transform::fliph(&mut img),
+			FlipAxis::Vertical => transform::flipv(&mut img)
+		}
+		Image::from_rgba(img.get_raw_pixels(), width, height, &self.file_name)
+	}
+
+	/// Add a drop shadow in `color`, which is moved by `offset_x` and `offset_y` and blurred with a radius of `blur` pixels.
+	/// The canvas is extended on each side by twice the largest offset or blur radius, so the shadow is not cut off.
+	/// Return a new static webp image; for animated images only the first frame is used.
+	/// Images without alpha channel have no shape to cast a shadow and are rejected.
+	/// Since encoders often store an alpha channel anyway, this is the case if all pixels are opaque.
+	/// Offsets and blur radii, which would extend the canvas beyond [`MAX_SHADOW_CANVAS_PIXELS`], are rejected, too.
+	pub fn apply_shadow(self, offset_x: i32, offset_y: i32, blur: f32, color: [u8; 4]) -> Result<Self, Error> {
+		let (pixels, width, height) = self.decode_rgba()?;
+		if pixels.chunks_exact(4).all(|pixel| pixel[3] == 255) {
+			return Err(Error::UnsupportedOperation {
+				operation: "adding a shadow to an image without alpha channel",
+				file_name: self.file_name.display().to_string()
+			});
+		}
+		let too_large = || Error::UnsupportedOperation {
+			operation: "adding a shadow, which extends the canvas beyond the maximum size,",
+			file_name: self.file_name.display().to_string()
+		};
+		// `as` saturates, so an infinite blur is caught by the checked arithmetic below
+		let blur = blur.max(0.0);
+		let pad = (offset_x.unsigned_abs().max(offset_y.unsigned_abs()) as u64)
+			.max(blur.ceil() as u64)
+			.checked_mul(2)
+			.ok_or_else(too_large)?;
+		let extend = |side: u32| pad.checked_mul(2).and_then(|border| border.checked_add(side as u64));
+		let (new_width, new_height) = extend(width).zip(extend(height)).ok_or_else(too_large)?;
+		if new_width
+			.checked_mul(new_height)
+			.is_none_or(|pixels| pixels > MAX_SHADOW_CANVAS_PIXELS)
+		{
+			return Err(too_large());
+		}
+		// all values fit into u32, since the canvas is within the limit
+		let (pad, new_width, new_height) = (pad as u32, new_width as u32, new_height as u32);
+		let (canvas_width, canvas_height) = (new_width as usize, new_height as usize);
+
+		let mut mask = vec![0.0; canvas_width * canvas_height];
+		for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+			let x = (i as u32 % width + pad) as i64 + offset_x as i64;
+			let y = (i as u32 / width + pad) as i64 + offset_y as i64;
+			mask[y as usize * canvas_width + x as usize] = pixel[3] as f32 / 255.0;
+		}
+		let mask = gaussian_blur(&mask, canvas_width, canvas_height, blur);
+		let mut canvas: Vec<u8> = mask
+			.iter()
+			.flat_map(|coverage| {
+				let alpha = (color[3] as f32 * coverage.clamp(0.0, 1.0)).round() as u8;
+				[color[0], color[1], color[2], alpha]
+			})
+			.collect();
+		for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+			let (x, y) = ((i as u32 % width + pad) as usize, (i as u32 / width + pad) as usize);
+			let start = (y * canvas_width + x) * 4;
+			let back = &mut canvas[start..start + 4];
+			let blended = blend_over(pixel.try_into().unwrap(), [back[0], back[1], back[2], back[3]]);
+			back.copy_from_slice(&blended);
+		}
+		Image::from_rgba(canvas, new_width, new_height, &self.file_name)
+	}
+
+	/// Split `images` into packs of at most `max_per_pack` images, keeping their order,
+	/// since matrix clients often limit the size of a pack (often to 50 stickers).
+	/// Only the last pack can be smaller; a `max_per_pack` of zero is treated as one.
+	pub fn split_into_packs(images: Vec<Image>, max_per_pack: usize) -> Vec<Vec<Image>> {
+		let max_per_pack = max_per_pack.max(1);
+		let mut packs = Vec::with_capacity(images.len().div_ceil(max_per_pack));
+		let mut images = images.into_iter().peekable();
+		while images.peek().is_some() {
+			packs.push(images.by_ref().take(max_per_pack).collect());
+		}
+		packs
+	}
+
+	/// Sort `images` by the number at the start of their file name, like `001.png`, `2.png`, `10.png`,
+	/// so packs keep the order of their source files, also after conversion.
+	/// Images without number follow in lexical order of their file name, as do images with the same number.
+	pub fn sort_by_sequence(images: &mut [Image]) {
+		images.sort_by_cached_key(|image| {
+			let file_name = image
+				.file_name
+				.file_name()
+				.map(|file_name| file_name.to_string_lossy().into_owned())
+				.unwrap_or_default();
+			let digits: String = file_name.chars().take_while(char::is_ascii_digit).collect();
+			// `false` is sorted first, so images with number come before the others
+			let sequence = digits.parse::<u128>().map_or((true, 0), |number| (false, number));
+			(sequence, file_name)
+		});
+	}
+
+	/// Arrange `images` in a grid with `cols` columns of `cell` × `cell` pixels, to preview a whole pack at once.
+	/// Every image is scaled to fit its cell and centered; the padding and empty trailing cells stay transparent.
+	/// Return a new static webp image; for animated images only the first frame is used.
+	pub fn contact_sheet(images: &[Image], cols: u32, cell: u32) -> Result<Self, Error> {
+		let file_name = "contact_sheet.webp";
+		if images.is_empty() || cols == 0 || cell == 0 {
+			return Err(Error::UnsupportedOperation {
+				operation: "creating a contact sheet without images, columns or cell size",
+				file_name: file_name.to_owned()
+			});
+		}
+		let rows = (images.len() as u32).div_ceil(cols);
+		let (width, height) = (cols * cell, rows * cell);
+		let mut sheet = vec![0; width as usize * height as usize * 4];
+		for (i, image) in images.iter().enumerate() {
+			let (pixels, image_width, image_height) = image.clone().resize(cell, cell)?.into_image().decode_rgba()?;
+			let left = i as u32 % cols * cell + (cell - image_width.min(cell)) / 2;
+			let top = i as u32 / cols * cell + (cell - image_height.min(cell)) / 2;
+			for (y, row) in pixels.chunks_exact(image_width as usize * 4).take(cell as usize).enumerate() {
+				let start = ((top as usize + y) * width as usize + left as usize) * 4;
+				let row = &row[..row.len().min(cell as usize * 4)];
+				sheet[start..start + row.len()].copy_from_slice(row);
+			}
+		}
+		Image::from_rgba(sheet, width, height, file_name)
+	}
+
+	/// Create a new image from raw RGBA8 pixels, like returned by [`Image::decode_rgba`].
+	/// The pixels will be encoded as webp, so the extension of `file_name` is replaced by `webp`.
+	pub fn from_rgba<P>(pixels: Vec<u8>, width: u32, height: u32, file_name: P) -> Result<Self, Error>
+	where
+		P: AsRef<Path>
+	{
+		if pixels.len() as u64 != width as u64 * height as u64 * 4 {
+			return Err(Error::InvalidPixelBuffer {
+				len: pixels.len(),
+				width,
+				height
+			});
+		}
+		let img = PhotonImage::new(pixels, width, height);
+		Ok(Self {
+			file_name: file_name.as_ref().with_extension("webp"),
+			data: Arc::new(img.get_bytes_webp()),
+			width,
+			height,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		})
+	}
+
+	/// Create an animated webp, which shows each of `frames` for `frame_delay_ms` milliseconds.
+	/// All frames must have the same size; for animated frames only their first frame is used.
+	#[cfg(feature = "animation")]
+	pub fn from_frames(frames: Vec<Image>, frame_delay_ms: u32) -> Result<Self, Error> {
+		let file_name = "animation.webp";
+		let Some(first) = frames.first() else {
+			return Err(Error::UnsupportedOperation {
+				operation: "creating an animation without frames",
+				file_name: file_name.to_owned()
+			});
+		};
+		let (_, width, height) = first.decode_rgba()?;
+		let mut encoder = webp_animation::Encoder::new((width, height))?;
+		let mut timestamp = 0;
+		for (index, frame) in frames.iter().enumerate() {
+			let (pixels, frame_width, frame_height) = frame.decode_rgba()?;
+			if (frame_width, frame_height) != (width, height) {
+				return Err(Error::FrameDimensions {
+					index,
+					width: frame_width,
+					height: frame_height,
+					expected_width: width,
+					expected_height: height
+				});
+			}
+			encoder.add_frame(&pixels, timestamp)?;
+			timestamp += frame_delay_ms as i32;
+		}
+		Ok(Self {
+			file_name: file_name.into(),
+			data: Arc::new(copy_buffer(&encoder.finalize(timestamp)?)),
+			width,
+			height,
+			duration_ms: Some(timestamp as u64),
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		})
+	}
+
+	/// Remove the last column and/or row of the image, if its width or height is odd,
+	/// because some codecs, like the vp9 encoder used for webm, does only support even dimensions.
+	/// Animated webp images are cropped frame by frame; other images are encoded as webp afterwards.
+	/// Images with even dimensions are returned unchanged.
+	pub fn ensure_even_dimensions(mut self) -> Result<Self, Error> {
+		let (width, height) = (self.width & !1, self.height & !1);
+		if (width, height) == (self.width, self.height) {
+			return Ok(self);
+		}
+		if width == 0 || height == 0 {
+			return Err(Error::UnsupportedOperation {
+				operation: "cropping an image to even dimensions, which is only one pixel wide or high",
+				file_name: self.file_name.display().to_string()
+			});
+		}
+		#[cfg(feature = "animation")]
+		if self.is_webp() && is_animated_webp(&self.data) {
+			let decoder = webp_animation::Decoder::new(&self.data)?;
+			let (canvas_width, _) = decoder.dimensions();
+			// the timestamp of a decoded frame is the time, when it ends
+			let mut encoder = webp_animation::Encoder::new((width, height))?;
+			let mut start = 0;
+			for frame in decoder {
+				encoder.add_frame(&crop_rgba(frame.data(), canvas_width, width, height), start)?;
+				start = frame.timestamp();
+			}
+			self.data = Arc::new(copy_buffer(&encoder.finalize(start)?));
+			self.width = width;
+			self.height = height;
+			return Ok(self);
+		}
+		let (pixels, img_width, _) = self.decode_rgba()?;
+		let img = PhotonImage::new(crop_rgba(&pixels, img_width, width, height), width, height);
+		self.file_name.set_extension("webp");
+		self.data = Arc::new(img.get_bytes_webp());
+		self.width = width;
+		self.height = height;
+		Ok(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{AnimationFormat, FlipAxis, Image, ImageFormat, ResizeOptions, Rotation, SamplingFilter, ICC_SRGB};
+	use crate::{
+		matrix::{Config, ExistsCheck, Mxc},
+		mock::matrix_config
+	};
+	use futures_util::FutureExt as _;
+	use std::{
+		cell::Cell,
+		path::Path,
+		sync::{atomic::Ordering, Arc, Weak}
+	};
+
+	fn rgba_image(width: u32, height: u32) -> Image {
+		let pixels = (0..width * height).flat_map(|i| [i as u8, 0, 255, 255]).collect();
+		Image::from_rgba(pixels, width, height, "sticker.webp").unwrap()
+	}
+
+	#[test]
+	fn rotate() {
+		let image = rgba_image(3, 2);
+		let rotated = image.clone().rotate(Rotation::Rotate90).unwrap();
+		assert_eq!((rotated.width, rotated.height), (2, 3));
+		// the top left pixel is moved to the top right corner
+		assert_eq!(rotated.pixel_at(1, 0).unwrap(), image.pixel_at(0, 0).unwrap());
+		assert_eq!(rotated.pixel_at(0, 2).unwrap(), image.pixel_at(2, 1).unwrap());
+		let rotated = image.clone().rotate(Rotation::Rotate180).unwrap();
+		assert_eq!((rotated.width, rotated.height), (3, 2));
+		assert_eq!(rotated.pixel_at(2, 1).unwrap(), image.pixel_at(0, 0).unwrap());
+		let back = rotated
+			.rotate(Rotation::Rotate90)
+			.unwrap()
+			.rotate(Rotation::Rotate90)
+			.unwrap();
+		assert_eq!(back.to_raw_rgba().unwrap(), image.to_raw_rgba().unwrap());
+	}
+
+	#[test]
+	fn flip() {
+		let image = rgba_image(3, 2);
+		for axis in [FlipAxis::Horizontal, FlipAxis::Vertical] {
+			let flipped = image.clone().flip(axis).unwrap();
+			assert_eq!((flipped.width, flipped.height), (3, 2));
+			assert_ne!(flipped.to_raw_rgba().unwrap(), image.to_raw_rgba().unwrap());
+			let back = flipped.flip(axis).unwrap();
+			assert_eq!(back.to_raw_rgba().unwrap(), image.to_raw_rgba().unwrap());
+		}
+		let flipped = image.clone().flip(FlipAxis::Horizontal).unwrap();
+		assert_eq!(flipped.pixel_at(2, 0).unwrap(), image.pixel_at(0, 0).unwrap());
+	}
+
+	#[test]
+	fn apply_shadow() {
+		use crate::error::Error;
+
+		// red 2×2 square at the center of a transparent 4×4 image
+		let pixels = (0..16)
+			.flat_map(|i| {
+				if (1..=2).contains(&(i % 4)) && (1..=2).contains(&(i / 4)) {
+					[255, 0, 0, 255]
+				} else {
+					[0; 4]
+				}
+			})
+			.collect();
+		let image = Image::from_rgba(pixels, 4, 4, "sticker.png").unwrap();
+		let shadow = image.apply_shadow(2, 1, 1.0, [0, 0, 0, 200]).unwrap();
+		// padded by twice the largest offset on each side
+		assert_eq!((shadow.width, shadow.height), (12, 12));
+		assert_eq!(shadow.file_name_str(), "sticker.webp");
+		assert_eq!(shadow.pixel_at(5, 5).unwrap(), [255, 0, 0, 255]);
+		// below the square only the shadow is visible
+		let [r, g, b, alpha] = shadow.pixel_at(8, 6).unwrap();
+		assert_eq!((r, g, b), (0, 0, 0));
+		assert!(alpha > 50 && alpha <= 200, "{alpha}");
+		assert_eq!(shadow.pixel_at(0, 0).unwrap()[3], 0);
+
+		let err = rgba_image(4, 4).apply_shadow(2, 2, 1.0, [0, 0, 0, 200]).unwrap_err();
+		assert!(matches!(err, Error::UnsupportedOperation { .. }));
+	}
+
+	#[test]
+	fn apply_shadow_extreme_values() {
+		use crate::error::Error;
+
+		let pixels = (0..16)
+			.flat_map(|i| if i % 5 == 0 { [255, 0, 0, 255] } else { [0; 4] })
+			.collect();
+		let image = Image::from_rgba(pixels, 4, 4, "sticker.png").unwrap();
+		for (offset_x, offset_y, blur) in [
+			(i32::MIN, 0, 0.0),
+			(0, i32::MAX, 0.0),
+			(1, 1, f32::INFINITY),
+			(1, 1, f32::MAX),
+			(2048, 0, 0.0)
+		] {
+			let err = image
+				.clone()
+				.apply_shadow(offset_x, offset_y, blur, [0, 0, 0, 200])
+				.unwrap_err();
+			assert!(matches!(err, Error::UnsupportedOperation { .. }), "{err}");
+		}
+		// a NaN blur is treated like no blur
+		let shadow = image.apply_shadow(1, 1, f32::NAN, [0, 0, 0, 200]).unwrap();
+		assert_eq!((shadow.width, shadow.height), (8, 8));
+	}
+
+	#[test]
+	fn animation_format_strings() {
+		#[cfg(feature = "lottie")]
+		use lottieconv::Rgba;
+
+		let webp = AnimationFormat::Webp;
+		assert_eq!(
+			(webp.extension(), webp.mime_type(), webp.to_string().as_str()),
+			("webp", "image/webp", "webp")
+		);
+		#[cfg(feature = "lottie")]
+		{
+			let gif = AnimationFormat::Gif {
+				transparent_color: Rgba {
+					r: 0,
+					g: 0,
+					b: 0,
+					a: true
+				}
+			};
+			assert_eq!(
+				(gif.extension(), gif.mime_type(), gif.to_string().as_str()),
+				("gif", "image/gif", "gif")
+			);
+		}
+	}
+
+	#[test]
+	fn animation_format_copy() {
+		// the transparent color of the gif variant is only compiled with the `lottie` feature
+		fn assert_copy<T: Copy>() {}
+		assert_copy::<AnimationFormat>();
+		#[cfg(feature = "lottie")]
+		assert_copy::<lottieconv::Rgba>();
+	}
+
+	/// Animation of three different frames.
+	#[cfg(feature = "animation")]
+	fn animated_webp() -> Image {
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		for i in 0..3 {
+			encoder.add_frame(&[i as u8 * 100; 4 * 4 * 4], i * 100).unwrap();
+		}
+		Image {
+			file_name: "animated.webp".into(),
+			data: Arc::new(encoder.finalize(300).unwrap().to_vec()),
+			width: 4,
+			height: 4,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		}
+	}
+
+	#[cfg(feature = "animation")]
+	#[tokio::test]
+	async fn format_selector_per_item() {
+		use super::{ConversionConfig, FormatSelector};
+		use std::sync::Mutex;
+
+		let selected = Arc::new(Mutex::new(Vec::new()));
+		let config = ConversionConfig {
+			format_selector: Some(FormatSelector::new({
+				let selected = selected.clone();
+				move |image: &Image| {
+					selected
+						.lock()
+						.unwrap()
+						.push((image.file_name_str().to_owned(), image.is_animated()));
+					AnimationFormat::Webp
+				}
+			})),
+			..Default::default()
+		};
+		for image in [animated_webp(), rgba_image(4, 4)] {
+			image.convert(&config, None, None, None).await.unwrap();
+		}
+		assert_eq!(*selected.lock().unwrap(), [
+			("animated.webp".to_owned(), true),
+			("sticker.webp".to_owned(), false)
+		]);
+		// an explicit format takes precedence
+		rgba_image(4, 4)
+			.convert(&config, Some(AnimationFormat::Webp), None, None)
+			.await
+			.unwrap();
+		assert_eq!(selected.lock().unwrap().len(), 2);
+
+		let lottie = Image {
+			file_name: "sticker.tgs".into(),
+			..rgba_image(4, 4)
+		};
+		assert!(lottie.is_animated());
+	}
+
+	#[cfg(all(feature = "gif", feature = "lottie"))]
+	#[tokio::test]
+	async fn format_selector_mixed_outputs() {
+		use super::{ConversionConfig, FormatSelector};
+		use lottieconv::Rgba;
+
+		let config = ConversionConfig {
+			format_selector: Some(FormatSelector::new(|image: &Image| match image.is_animated() {
+				true => AnimationFormat::Gif {
+					transparent_color: Rgba {
+						r: 0,
+						g: 0,
+						b: 0,
+						a: true
+					}
+				},
+				false => AnimationFormat::Webp
+			})),
+			..Default::default()
+		};
+		// a single batch produces gif for the animations and webp for the still images
+		for image in [animated_webp(), rgba_image(4, 4), animated_webp(), rgba_image(8, 8)] {
+			let animated = image.is_animated();
+			let converted = image.convert(&config, None, None, None).await.unwrap();
+			if animated {
+				assert!(converted.is_gif(), "{}", converted.file_name_str());
+				assert!(converted.data.starts_with(b"GIF8"));
+			} else {
+				assert!(converted.is_webp(), "{}", converted.file_name_str());
+				assert!(converted.data.starts_with(b"RIFF"));
+			}
+		}
+	}
+
+	#[cfg(feature = "apng")]
+	#[tokio::test]
+	async fn convert_lottie_to_apng() {
+		let image = Image {
+			file_name: "sticker.lottie".into(),
+			data: Arc::new(br#"{"v":"5.5.2","fr":30,"ip":0,"op":30,"w":32,"h":16,"layers":[]}"#.to_vec()),
+			width: 32,
+			height: 16,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let apng = image.convert_lottie_to_apng(100).await.unwrap();
+		assert_eq!(apng.file_name_str(), "sticker.png");
+		assert_eq!((apng.width, apng.height), (32, 16));
+		assert_eq!(apng.duration_ms, Some(1000));
+		let decoder = png::Decoder::new(apng.data.as_slice()).read_info().unwrap();
+		assert_eq!(decoder.info().animation_control().unwrap().num_frames, 10);
+	}
+
+	#[cfg(feature = "lottie")]
+	#[tokio::test]
+	async fn convert_lottie_progress() {
+		use super::ConversionProgress;
+
+		let image = Image {
+			file_name: "sticker.lottie".into(),
+			data: Arc::new(br#"{"v":"5.5.2","fr":30,"ip":0,"op":30,"w":32,"h":16,"layers":[]}"#.to_vec()),
+			width: 32,
+			height: 16,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+		image
+			.convert_lottie_with_progress(AnimationFormat::Webp, None, None, Some(sender))
+			.await
+			.unwrap();
+		let mut frames = Vec::new();
+		while let Some(ConversionProgress::Rendering { frame, total }) = receiver.recv().await {
+			assert_eq!(total, 30);
+			frames.push(frame);
+		}
+		assert_eq!(frames, (1..=30).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn contact_sheet() {
+		let images = [rgba_image(8, 8), rgba_image(16, 8), rgba_image(4, 4)];
+		let sheet = Image::contact_sheet(&images, 2, 8).unwrap();
+		assert_eq!(sheet.file_name_str(), "contact_sheet.webp");
+		assert_eq!((sheet.width, sheet.height), (16, 16));
+		assert_eq!(sheet.pixel_at(0, 0).unwrap()[3], 255);
+		// the wide image is scaled to 8×4 and centered vertically
+		assert_eq!(sheet.pixel_at(12, 1).unwrap()[3], 0);
+		assert_eq!(sheet.pixel_at(12, 4).unwrap()[3], 255);
+		// the small image is scaled up to its cell; the last cell is empty
+		assert_eq!(sheet.pixel_at(0, 8).unwrap()[3], 255);
+		assert_eq!(sheet.pixel_at(12, 12).unwrap()[3], 0);
+	}
+
+	#[test]
+	fn split_into_packs() {
+		let images = || (1..=4).map(|size| rgba_image(size, 1)).collect::<Vec<_>>();
+		let sizes = |packs: Vec<Vec<Image>>| -> Vec<Vec<u32>> {
+			packs
+				.into_iter()
+				.map(|pack| pack.into_iter().map(|image| image.width).collect())
+				.collect()
+		};
+		assert_eq!(sizes(Image::split_into_packs(images(), 3)), [vec![1, 2, 3], vec![4]]);
+		assert_eq!(sizes(Image::split_into_packs(images(), 4)), [vec![1, 2, 3, 4]]);
+		assert_eq!(sizes(Image::split_into_packs(images(), 10)), [vec![1, 2, 3, 4]]);
+		assert_eq!(sizes(Image::split_into_packs(images(), 1)), [
+			vec![1],
+			vec![2],
+			vec![3],
+			vec![4]
+		]);
+		assert_eq!(sizes(Image::split_into_packs(images(), 0)).len(), 4);
+		assert!(Image::split_into_packs(Vec::new(), 3).is_empty());
+	}
+
+	#[test]
+	fn sort_by_sequence() {
+		let mut images: Vec<_> = ["10.png", "b.png", "stickers/002.png", "1_cat.png", "a.png", "1.png", "3.webm"]
+			.into_iter()
+			.map(|file_name| Image {
+				file_name: file_name.into(),
+				..rgba_image(1, 1)
+			})
+			.collect();
+		Image::sort_by_sequence(&mut images);
+		let packs = Image::split_into_packs(images, 4);
+		let file_names: Vec<Vec<_>> = packs
+			.iter()
+			.map(|pack| pack.iter().map(|image| image.file_name_str()).collect())
+			.collect();
+		assert_eq!(file_names, [vec!["1.png", "1_cat.png", "stickers/002.png", "3.webm"], vec![
+			"10.png", "a.png", "b.png"
+		]]);
+	}
+
+	#[test]
+	fn rgba_round_trip() {
+		let (width, height) = (3, 2);
+		let pixels: Vec<u8> = (0..width * height)
+			.flat_map(|i| [i as u8 * 40, 255 - i as u8, 7, 255])
+			.collect();
+		let image = Image::from_rgba(pixels.clone(), width, height, "sticker.png").unwrap();
+		assert_eq!(image.file_name_str(), "sticker.webp");
+		assert_eq!((image.width, image.height), (width, height));
+		let (decoded, decoded_width, decoded_height) = image.decode_rgba().unwrap();
+		assert_eq!((decoded_width, decoded_height), (width, height));
+		assert_eq!(decoded, pixels);
+	}
+
+	#[test]
+	fn raw_rgb() {
+		let pixels = vec![1, 2, 3, 255, 4, 5, 6, 255];
+		let image = Image::from_rgba(pixels.clone(), 2, 1, "sticker.webp").unwrap();
+		assert_eq!(image.to_raw_rgba().unwrap(), pixels);
+		assert_eq!(image.to_raw_rgb().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+	}
+
+	#[test]
+	fn from_rgba_invalid_buffer() {
+		assert!(Image::from_rgba(vec![0; 7], 1, 2, "sticker.webp").is_err());
+	}
+
+	#[test]
+	fn matrix_content_uri_string() {
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: Arc::new(vec![0; 24 * 1024]),
+			width: 512,
+			height: 512,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let mxc = Mxc::from("mxc://example.org/abc".to_owned());
+		assert_eq!(
+			image.to_matrix_content_uri_string(&mxc),
+			"mxc://example.org/abc (512×512, image/webp, 24KB)"
+		);
+	}
+
+	#[test]
+	fn matrix_sticker_event() {
+		use crate::error::Error;
+
+		let image = rgba_image(8, 4);
+		let mxc = Mxc::from("mxc://example.org/abc".to_owned());
+		let event = image.as_matrix_sticker_event(&mxc, "a cat").unwrap();
+		assert_eq!(
+			event,
+			serde_json::json!({
+				"body": "a cat",
+				"url": "mxc://example.org/abc",
+				"info": {
+					"mimetype": "image/webp",
+					"w": 8,
+					"h": 4,
+					"size": image.data_len()
+				}
+			})
+		);
+		// the required fields of the `m.sticker` schema and their types
+		let object = event.as_object().unwrap();
+		assert!(object["body"].is_string());
+		assert!(object["url"].as_str().unwrap().starts_with("mxc://"));
+		let info = object["info"].as_object().unwrap();
+		assert!(info["mimetype"].is_string());
+		for field in ["w", "h", "size"] {
+			assert!(info[field].is_u64(), "{field} is no integer");
+		}
+
+		let deferred = Mxc::from("outbox://1234".to_owned());
+		let err = image.as_matrix_sticker_event(&deferred, "a cat").unwrap_err();
+		assert!(matches!(err, Error::InvalidMxc(_)));
+		let mut no_extension = image;
+		no_extension.file_name = "sticker".into();
+		let err = no_extension.as_matrix_sticker_event(&mxc, "a cat").unwrap_err();
+		assert!(matches!(err, Error::NoMimeType(_)));
+	}
+
+	#[cfg(feature = "blurhash")]
+	#[test]
+	fn blurhash() {
+		let pixels: Vec<u8> = (0..16 * 16)
+			.flat_map(|i| [(i % 16 * 16) as u8, (i / 16 * 16) as u8, 128, 255])
+			.collect();
+		let image = Image::from_rgba(pixels, 16, 16, "gradient.webp").unwrap();
+		assert_eq!(image.blurhash().unwrap(), "LsGu,V2@wxozqSWEjte=gJfjfQfj");
+	}
+
+	#[tokio::test]
+	async fn upload_exists_check() {
+		let mxc = Mxc::from("mxc://example.org/abc".to_owned());
+		let existing = mxc.clone();
+		let matrix_config = Config {
+			exists_check: Some(ExistsCheck(Arc::new(move |_| {
+				let existing = existing.clone();
+				async move { Some(existing) }.boxed()
+			}))),
+			..matrix_config("none")
+		};
+		let image = rgba_image(1, 1);
+		// the homeserver url is invalid, so this would fail if an upload was performed
+		let (uploaded, has_uploaded) = image.upload(&matrix_config, None).await.unwrap();
+		assert_eq!(uploaded, mxc);
+		assert!(!has_uploaded);
+	}
+
+	#[tokio::test]
+	async fn upload_size_limit() {
+		use crate::error::Error;
+
+		let image = rgba_image(4, 4);
+		let len = image.data_len();
+		assert!(image.is_within_size_limit(len));
+		assert!(!image.is_within_size_limit(len - 1));
+		image.assert_within_size_limit(len).unwrap();
+		let err = image.assert_within_size_limit(len - 1).unwrap_err();
+		assert!(matches!(err, Error::FileTooLarge { actual, limit } if actual == len && limit == len - 1));
+
+		let matrix_config = Config {
+			max_upload_size: Some(len - 1),
+			..matrix_config("none")
+		};
+		// the homeserver url is invalid, so any other error would mean that the size was not checked first
+		let err = image.upload(&matrix_config, None).await.unwrap_err();
+		assert!(matches!(err, Error::FileTooLarge { .. }), "{err}");
+	}
+
+	#[tokio::test]
+	async fn upload_pre_upload_hook() {
+		use crate::{
+			database::{self, Database, Hash},
+			matrix::PreUploadHook,
+			mock::serve_sequence
+		};
+		use std::{collections::HashMap, convert::Infallible, net::TcpListener, sync::Mutex};
+
+		#[derive(Default)]
+		struct MemoryDatabase(Mutex<HashMap<Hash, String>>);
+
+		impl Database for MemoryDatabase {
+			type Error = Infallible;
+
+			async fn get(&self, hash: &Hash) -> Result<Option<String>, Infallible> {
+				Ok(self.0.lock().unwrap().get(hash).cloned())
+			}
+
+			async fn add(&self, hash: Hash, url: String) -> Result<(), Infallible> {
+				self.0.lock().unwrap().insert(hash, url);
+				Ok(())
+			}
+		}
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = Config {
+			pre_upload_hook: Some(PreUploadHook(Arc::new(|data| Some([data, b"hooked"].concat())))),
+			..matrix_config(format!("http://{}", listener.local_addr().unwrap()))
+		};
+		let server = std::thread::spawn(move || {
+			serve_sequence(listener, &[("200 OK", r#"{"content_uri":"mxc://example.org/abc"}"#)])
+		});
+		let image = rgba_image(4, 4);
+		let hooked = [image.data_bytes(), b"hooked"].concat();
+		let database = MemoryDatabase::default();
+		let (mxc, uploaded) = image.upload_with(&matrix_config, Some(&database)).await.unwrap();
+		assert!(uploaded);
+		assert_eq!(mxc.data.as_deref(), Some(&hooked));
+		let requests = server.join().unwrap();
+		assert!(requests[0].ends_with("hooked"));
+		// the url is stored by the hash of the uploaded data, not of the original data
+		let known: Vec<Hash> = database.0.lock().unwrap().keys().copied().collect();
+		assert_eq!(known, [database::hash(&hooked)]);
+		assert_ne!(known[0], image.content_hash());
+		// so the next upload of the same image finds it, without contacting the homeserver
+		let (cached, uploaded) = image.upload_with(&matrix_config, Some(&database)).await.unwrap();
+		assert!(!uploaded);
+		assert_eq!(cached, mxc);
+	}
+
+	/// Database, whose `get` fails with `error` for the first `failures` calls and then knows every hash.
+	struct FailingDatabase {
+		error: std::io::ErrorKind,
+		failures: usize,
+		calls: std::sync::atomic::AtomicUsize
+	}
+
+	impl crate::database::Database for FailingDatabase {
+		type Error = std::io::Error;
+
+		async fn get(&self, _: &crate::database::Hash) -> std::io::Result<Option<String>> {
+			if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+				return Err(self.error.into());
+			}
+			Ok(Some("mxc://example.org/known".to_owned()))
+		}
+
+		async fn add(&self, _: crate::database::Hash, _: String) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn upload_database_errors() {
+		use crate::{database::DatabaseErrorKind, error::Error};
+
+		let matrix_config = matrix_config("none");
+		let image = rgba_image(4, 4);
+		let database = |error, failures| FailingDatabase {
+			error,
+			failures,
+			calls: Default::default()
+		};
+
+		// an unavailable database is retried once
+		let locked = database(std::io::ErrorKind::ResourceBusy, 1);
+		let (mxc, uploaded) = image.upload_with(&matrix_config, Some(&locked)).await.unwrap();
+		assert_eq!(mxc.url(), "mxc://example.org/known");
+		assert!(!uploaded);
+		assert_eq!(locked.calls.load(Ordering::SeqCst), 2);
+		let down = database(std::io::ErrorKind::ConnectionRefused, 2);
+		let err = image.upload_with(&matrix_config, Some(&down)).await.unwrap_err();
+		assert!(matches!(err, Error::Database {
+			kind: DatabaseErrorKind::Unavailable,
+			..
+		}));
+		assert_eq!(down.calls.load(Ordering::SeqCst), 2);
+
+		// a corrupt database fails immediately
+		let corrupt = database(std::io::ErrorKind::InvalidData, 1);
+		let err = image.upload_with(&matrix_config, Some(&corrupt)).await.unwrap_err();
+		assert!(matches!(err, Error::Database {
+			kind: DatabaseErrorKind::Corrupt,
+			..
+		}));
+		assert!(err.to_string().contains("(corrupt)"));
+		assert_eq!(corrupt.calls.load(Ordering::SeqCst), 1);
+	}
+
+	#[cfg(feature = "gif")]
+	#[tokio::test]
+	async fn convert_to_gif() {
+		let options = super::GifOptions {
+			transparent_color: [255, 255, 255, 255],
+			..Default::default()
+		};
+		let image = rgba_image(8, 4).convert_to_gif(options).await.unwrap();
+		assert_eq!(image.file_name_str(), "sticker.gif");
+		assert_eq!((image.width, image.height), (8, 4));
+		assert!(image.data.starts_with(b"GIF89a"));
+		let (_, width, height) = image.decode_rgba().unwrap();
+		assert_eq!((width, height), (8, 4));
+	}
+
+	#[cfg(all(feature = "gif", feature = "animation"))]
+	#[tokio::test]
+	async fn convert_webp2gif() {
+		use image_crate::{codecs::gif::GifDecoder, AnimationDecoder as _};
+
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		for (i, timestamp) in [0, 100, 250].into_iter().enumerate() {
+			encoder.add_frame(&[i as u8 * 100; 4 * 4 * 4], timestamp).unwrap();
+		}
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: Arc::new(encoder.finalize(400).unwrap().to_vec()),
+			width: 4,
+			height: 4,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let delays = |image: &Image| -> Vec<u32> {
+			GifDecoder::new(std::io::Cursor::new(image.data.as_slice()))
+				.unwrap()
+				.into_frames()
+				.map(|frame| {
+					let (numer, denom) = frame.unwrap().delay().numer_denom_ms();
+					numer / denom
+				})
+				.collect()
+		};
+
+		let gif = image.clone().convert_webp2gif(Default::default()).await.unwrap();
+		assert_eq!(gif.file_name_str(), "sticker.gif");
+		assert_eq!(delays(&gif), [100, 150, 150]);
+		// at 5 fps the second frame is dropped and its time is added to the first one
+		let options = super::GifOptions {
+			max_fps: Some(5),
+			..Default::default()
+		};
+		let gif = image.convert_webp2gif(options).await.unwrap();
+		assert_eq!(delays(&gif), [250, 150]);
+
+		let image = rgba_image(4, 4);
+		let unchanged = image.clone().convert_webp2gif(Default::default()).await.unwrap();
+		assert_eq!(unchanged.file_name_str(), "sticker.webp");
+		assert!(Arc::ptr_eq(&unchanged.data, &image.data));
+	}
+
+	#[test]
+	fn preview_on_checkerboard() {
+		const LIGHT: [u8; 4] = [220, 220, 220, 255];
+		const DARK: [u8; 4] = [100, 100, 100, 255];
+		// transparent image with an opaque red pixel in the center
+		let mut pixels = vec![0; 8 * 8 * 4];
+		pixels[(4 * 8 + 4) * 4..(4 * 8 + 5) * 4].copy_from_slice(&[255, 0, 0, 255]);
+		let image = Image::from_rgba(pixels, 8, 8, "sticker.webp").unwrap();
+		let original = image.data.clone();
+		let preview = image.preview_on_checkerboard(2, LIGHT, DARK).unwrap();
+		assert_eq!(preview.pixel_at(0, 0).unwrap(), LIGHT);
+		assert_eq!(preview.pixel_at(7, 0).unwrap(), DARK);
+		assert_eq!(preview.pixel_at(0, 7).unwrap(), DARK);
+		assert_eq!(preview.pixel_at(7, 7).unwrap(), LIGHT);
+		assert_eq!(preview.pixel_at(4, 4).unwrap(), [255, 0, 0, 255]);
+		assert_eq!(image.data, original);
+		assert_eq!(image.pixel_at(0, 0).unwrap()[3], 0);
+	}
+
+	#[test]
+	fn pixel_at() {
+		use crate::error::Error;
+
+		let image = rgba_image(3, 2);
+		assert_eq!(image.pixel_at(0, 0).unwrap(), [0, 0, 255, 255]);
+		assert_eq!(image.pixel_at(2, 1).unwrap(), [5, 0, 255, 255]);
+		assert!(matches!(
+			image.pixel_at(3, 0),
+			Err(Error::InvalidDimensions { x: 3, y: 0, .. })
+		));
+		assert!(matches!(image.pixel_at(0, 2), Err(Error::InvalidDimensions { .. })));
+
+		// replacing the data must not return the cached pixels of the old data
+		let image = Image {
+			data: rgba_image(3, 2).ensure_even_dimensions().unwrap().data,
+			width: 2,
+			height: 2,
+			..image
+		};
+		assert_eq!(image.pixel_at(1, 1).unwrap(), [4, 0, 255, 255]);
+	}
+
+	#[test]
+	fn ensure_even_dimensions() {
+		for ((width, height), expected) in [((5, 3), (4, 2)), ((5, 4), (4, 4)), ((4, 2), (4, 2))] {
+			let image = rgba_image(width, height);
+			let original = image.data.clone();
+			let image = image.ensure_even_dimensions().unwrap();
+			assert_eq!((image.width, image.height), expected);
+			let (pixels, decoded_width, decoded_height) = image.decode_rgba().unwrap();
+			assert_eq!((decoded_width % 2, decoded_height % 2), (0, 0));
+			assert_eq!((decoded_width, decoded_height), expected);
+			// the top left pixels are kept
+			assert_eq!(pixels[4..8], [1, 0, 255, 255]);
+			if (width, height) == expected {
+				assert!(Arc::ptr_eq(&image.data, &original));
+			}
+		}
+	}
+
+	#[cfg(feature = "gif")]
+	#[test]
+	fn to_gif() {
+		use image_crate::{codecs::gif::GifDecoder, AnimationDecoder as _};
+
+		let image = rgba_image(8, 4).to_gif(None).unwrap();
+		assert_eq!(image.file_name_str(), "sticker.gif");
+		assert_eq!((image.width, image.height), (8, 4));
+		let frames = GifDecoder::new(std::io::Cursor::new(image.data.as_slice()))
+			.unwrap()
+			.into_frames()
+			.collect_frames()
+			.unwrap();
+		assert_eq!(frames.len(), 1);
+		assert_eq!(frames[0].buffer().dimensions(), (8, 4));
+	}
+
+	#[cfg(not(feature = "lottie"))]
+	#[tokio::test]
+	async fn convert_lottie_without_feature() {
+		use crate::error::Error;
+
+		let image = rgba_image(4, 4)
+			.convert_lottie(AnimationFormat::Webp, None, None)
+			.await
+			.unwrap();
+		assert_eq!(image.file_name_str(), "sticker.webp");
+		let image = Image {
+			file_name: "sticker.lottie".into(),
+			..image
+		};
+		let err = image.convert_lottie(AnimationFormat::Webp, None, None).await.unwrap_err();
+		assert!(matches!(err, Error::FeatureNotEnabled("lottie")));
+		assert!(err.is_format_error());
+	}
+
+	#[test]
+	fn lottie_info() {
+		// lottie_info is available without the `lottie` feature, so this can not involve the renderer
+		let image = Image {
+			file_name: "sticker.lottie".into(),
+			data: Arc::new(br#"{"v":"5.5.2","fr":60,"ip":0,"op":180,"w":512,"h":512,"nm":"sticker","layers":[]}"#.to_vec()),
+			width: 0,
+			height: 0,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let info = image.lottie_info().unwrap();
+		assert_eq!((info.width, info.height), (512, 512));
+		assert_eq!(info.framerate, 60.0);
+		assert_eq!(info.frame_count(), 180);
+	}
+
+	#[tokio::test]
+	async fn unpack_uncompressed_tgs() {
+		let json = br#"{"v":"5.5.2","fr":60,"ip":0,"op":180,"w":512,"h":512,"layers":[]}"#;
+		let image = Image {
+			file_name: "sticker.tgs".into(),
+			data: Arc::new(json.to_vec()),
+			width: 512,
+			height: 512,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let image = image.unpack_tgs().await.unwrap();
+		assert_eq!(image.file_name_str(), "sticker.lottie");
+		assert_eq!(image.data_bytes(), json);
+	}
+
+	#[tokio::test]
+	async fn no_buffer_copies() {
+		let copies = super::BUFFER_COPIES.with(Cell::get);
+		let matrix_config = Config {
+			exists_check: Some(ExistsCheck(Arc::new(|_| {
+				async { Some(Mxc::from("mxc://example.org/abc".to_owned())) }.boxed()
+			}))),
+			..matrix_config("none")
+		};
+		let image = rgba_image(64, 64);
+		let data = image.data.clone();
+		let (image, _) = image.resize_reporting(64, 64).unwrap();
+		let image = image.unpack_tgs().await.unwrap();
+		image.upload(&matrix_config, None).await.unwrap();
+		assert_eq!(image.data_bytes(), data.as_slice());
+		assert!(Arc::ptr_eq(&image.into_data(), &data));
+		assert_eq!(super::BUFFER_COPIES.with(Cell::get), copies);
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn collapse_keeps_animation() {
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		for i in 0..3 {
+			encoder.add_frame(&[i as u8 * 100; 4 * 4 * 4], i * 100).unwrap();
+		}
+		let data = Arc::new(encoder.finalize(300).unwrap().to_vec());
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: data.clone(),
+			width: 4,
+			height: 4,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let image = image.collapse_static_animation().unwrap();
+		assert!(Arc::ptr_eq(&image.data, &data));
+		assert_eq!(image.frame_count().unwrap(), 3);
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn decimate_frames() {
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		for i in 0..10 {
+			encoder.add_frame(&[i * 20; 4 * 4 * 4], i as i32 * 40).unwrap();
+		}
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: Arc::new(encoder.finalize(400).unwrap().to_vec()),
+			width: 4,
+			height: 4,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let image = image.decimate_frames(5).unwrap();
+		let frames: Vec<_> = webp_animation::Decoder::new(&image.data).unwrap().into_iter().collect();
+		assert_eq!(frames.len(), 5);
+		assert_eq!(frames.last().unwrap().timestamp(), 400);
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn set_duration() {
+		use std::time::Duration;
+
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		for i in 0..4 {
+			encoder.add_frame(&[i * 60; 4 * 4 * 4], i as i32 * 250).unwrap();
+		}
+		let image = Image {
+			data: Arc::new(encoder.finalize(1000).unwrap().to_vec()),
+			..rgba_image(4, 4)
+		};
+		assert_eq!(image.total_duration().unwrap(), Duration::from_secs(1));
+		let image = image.set_duration(Duration::from_secs(2)).unwrap();
+		assert_eq!(image.total_duration().unwrap(), Duration::from_secs(2));
+		let frames: Vec<_> = webp_animation::Decoder::new(&image.data).unwrap().into_iter().collect();
+		assert_eq!(frames.len(), 4);
+		let timestamps: Vec<_> = frames.iter().map(|frame| frame.timestamp()).collect();
+		assert_eq!(timestamps, [500, 1000, 1500, 2000]);
+		// the duration is read from the data, if it is not stored
+		let image = Image {
+			duration_ms: None,
+			..image
+		};
+		assert_eq!(image.total_duration().unwrap(), Duration::from_secs(2));
+
+		// static images are not changed
+		let image = rgba_image(4, 4);
+		let retimed = image.clone().set_duration(Duration::from_secs(2)).unwrap();
+		assert!(Arc::ptr_eq(&image.data, &retimed.data));
+		let gif = Image {
+			file_name: "sticker.gif".into(),
+			..image
+		};
+		assert!(gif.set_duration(Duration::from_secs(2)).is_err());
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn to_sticker_with_poster() {
+		let mut encoder = webp_animation::Encoder::new((1024, 512)).unwrap();
+		for i in 0..3 {
+			encoder.add_frame(&vec![i * 80; 1024 * 512 * 4], i as i32 * 100).unwrap();
+		}
+		let image = Image {
+			data: Arc::new(encoder.finalize(300).unwrap().to_vec()),
+			..rgba_image(1024, 512)
+		};
+		let (sticker, poster) = image.to_sticker_with_poster().unwrap();
+		assert_eq!((sticker.width, sticker.height), (512, 256));
+		assert_eq!(sticker.frame_count().unwrap(), 3);
+		assert_eq!(sticker.duration().unwrap(), Some(300));
+		sticker.verify_data_matches_dimensions().unwrap();
+		assert_eq!((poster.width, poster.height), (512, 256));
+		assert_eq!(poster.frame_count().unwrap(), 1);
+		assert!(!poster.is_animated());
+		assert_eq!(poster.pixel_at(0, 0).unwrap(), [0, 0, 0, 0]);
+		poster.verify_data_matches_dimensions().unwrap();
+
+		// static images are only resized
+		let (sticker, poster) = rgba_image(64, 32).to_sticker_with_poster().unwrap();
+		assert_eq!((sticker.width, sticker.height), (512, 256));
+		assert!(Arc::ptr_eq(&sticker.data, &poster.data));
+	}
+
+	#[tokio::test]
+	async fn convert_config_defaults() {
+		let config = super::ConversionConfig {
+			default_max_width: Some(32),
+			default_max_height: Some(32),
+			..Default::default()
+		};
+		let image = rgba_image(64, 64).convert(&config, None, None, None).await.unwrap();
+		assert_eq!((image.width, image.height), (32, 32));
+		// explicit values override the config
+		let image = rgba_image(64, 64).convert(&config, None, Some(16), None).await.unwrap();
+		assert_eq!((image.width, image.height), (16, 16));
+	}
+
+	#[tokio::test]
+	async fn convert_with_stats() {
+		let image = Image::from_rgba(vec![255; 64 * 32 * 4], 64, 32, "sticker.webp").unwrap();
+		let config = super::ConversionConfig::default();
+		let (converted, stats) = image.clone().convert_with_stats(&config, None, Some(16), None).await.unwrap();
+		assert_eq!(stats.input_size_bytes, image.data.len());
+		assert_eq!(stats.output_size_bytes, converted.data.len());
+		assert_eq!((stats.input_format.as_str(), stats.output_format.as_str()), ("webp", "webp"));
+		assert_eq!((stats.input_dimensions, stats.output_dimensions), ((64, 32), (16, 8)));
+		assert_eq!(
+			stats.compression_ratio(),
+			image.data.len() as f64 / converted.data.len() as f64
+		);
+	}
+
+	#[tokio::test]
+	async fn from_path() {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-from-path-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let lottie = br#"{"v":"5.5.2","fr":60,"ip":0,"op":60,"w":512,"h":256,"layers":[]}"#;
+		use std::io::Write as _;
+
+		let mut tgs = Vec::new();
+		flate2::write::GzEncoder::new(&mut tgs, flate2::Compression::default())
+			.write_all(lottie)
+			.unwrap();
+		let webp = rgba_image(8, 4);
+		for (file_name, data, expected) in [
+			("AgADBAAD.bin", tgs.as_slice(), "AgADBAAD.tgs"),
+			("sticker", lottie.as_slice(), "sticker.lottie"),
+			("sticker.tgs", tgs.as_slice(), "sticker.tgs"),
+			("sticker.webp", webp.data.as_slice(), "sticker.webp")
+		] {
+			std::fs::write(dir.join(file_name), data).unwrap();
+			let image = Image::from_path(dir.join(file_name)).await.unwrap();
+			assert_eq!(image.file_name_str(), expected);
+			assert_eq!(image.data.as_slice(), data);
+		}
+		let image = Image::from_path(dir.join("AgADBAAD.bin")).await.unwrap();
+		assert_eq!((image.width, image.height), (512, 256));
+		let image = Image::from_path(dir.join("sticker.webp")).await.unwrap();
+		assert_eq!((image.width, image.height), (8, 4));
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		let mut webm = Image {
+			file_name: "video".into(),
+			data: Arc::new(vec![0x1a, 0x45, 0xdf, 0xa3, 0x01]),
+			..rgba_image(1, 1)
+		};
+		webm.fix_extension_from_magic_bytes();
+		assert_eq!(webm.file_name_str(), "video.webm");
+	}
+
+	#[test]
+	fn base64() {
+		let image = rgba_image(8, 4);
+		let b64 = image.to_base64();
+		let uri = image.to_data_uri().unwrap();
+		assert_eq!(uri, format!("data:image/webp;base64,{b64}"));
+		let decoded = Image::from_base64(&b64, "sticker.webp".to_owned()).unwrap();
+		assert_eq!(decoded, image);
+		assert!(matches!(
+			Image::from_base64("not base64!", "sticker.webp".to_owned()),
+			Err(crate::error::Error::Base64(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn exif_orientation() {
+		use crate::exif::{insert_jpeg_exif, tiff_with_orientation};
+		use photon_rs::PhotonImage;
+
+		// left half red, right half blue
+		let pixels = (0..16 * 8).flat_map(|i| if i % 16 < 8 { [255, 0, 0, 255] } else { [0, 0, 255, 255] });
+		let jpeg = PhotonImage::new(pixels.collect(), 16, 8).get_bytes_jpeg(95);
+		let is_red = |pixel: [u8; 4]| pixel[0] > 200 && pixel[2] < 50;
+		let image = |orientation| Image {
+			file_name: "photo.jpeg".into(),
+			data: Arc::new(insert_jpeg_exif(&jpeg, &tiff_with_orientation(orientation))),
+			width: 16,
+			height: 8,
+			..rgba_image(1, 1)
+		};
+
+		// rotated clockwise, so the left half is at the top
+		let rotated = image(6).apply_exif_orientation().unwrap();
+		assert_eq!((rotated.width, rotated.height), (8, 16));
+		assert_eq!(rotated.file_name_str(), "photo.webp");
+		assert!(is_red(rotated.pixel_at(4, 2).unwrap()));
+		assert!(!is_red(rotated.pixel_at(4, 13).unwrap()));
+		let mirrored = image(2).apply_exif_orientation().unwrap();
+		assert_eq!((mirrored.width, mirrored.height), (16, 8));
+		assert!(!is_red(mirrored.pixel_at(2, 4).unwrap()));
+		assert!(is_red(mirrored.pixel_at(13, 4).unwrap()));
+		// the default orientation does not change the image
+		let unchanged = image(1);
+		assert!(Arc::ptr_eq(
+			&unchanged.data,
+			&unchanged.clone().apply_exif_orientation().unwrap().data
+		));
+
+		let dir = std::env::temp_dir().join(format!("mstickerlib-exif-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("photo.jpeg"), image(8).data.as_slice()).unwrap();
+		let loaded = Image::from_path(dir.join("photo.jpeg")).await.unwrap();
+		assert_eq!((loaded.width, loaded.height), (8, 16));
+		// rotated counterclockwise, so the left half is at the bottom
+		assert!(is_red(loaded.pixel_at(4, 13).unwrap()));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn equality() {
+		use std::hash::{BuildHasher, RandomState};
+
+		let image = rgba_image(4, 4);
+		// same bytes in a different buffer
+		let copy = Image {
+			data: Arc::new(image.data.to_vec()),
+			..image.clone()
+		};
+		assert!(!Arc::ptr_eq(&image.data, &copy.data));
+		assert_eq!(image, copy);
+		let renamed = Image {
+			file_name: "other.webp".into(),
+			..image.clone()
+		};
+		assert_ne!(image, renamed);
+		let mut data = image.data.to_vec();
+		*data.last_mut().unwrap() ^= 1;
+		let changed = Image {
+			data: Arc::new(data),
+			..image.clone()
+		};
+		assert_ne!(image, changed);
+		let hasher = RandomState::new();
+		assert_eq!(hasher.hash_one(&image), hasher.hash_one(&copy));
+		assert_ne!(hasher.hash_one(&image), hasher.hash_one(&renamed));
+		assert_ne!(hasher.hash_one(&image), hasher.hash_one(&changed));
+	}
+
+	#[test]
+	fn debug_is_bounded() {
+		let image = Image {
+			data: Arc::new(vec![0; 16 << 20]),
+			..rgba_image(1, 1)
+		};
+		let debug = format!("{image:?}");
+		assert!(debug.len() < 200, "{debug}");
+		assert!(debug.contains(&format!("data: [{} bytes, hash=", 16 << 20)), "{debug}");
+	}
+
+	#[tokio::test]
+	async fn unsupported_format() {
+		use crate::error::Error;
+
+		let avif = Image {
+			file_name: "sticker.avif".into(),
+			data: Arc::new(b"\0\0\0\x1cftypavif\0\0\0\0mif1miaf".to_vec()),
+			..rgba_image(1, 1)
+		};
+		let err = avif.convert(&Default::default(), None, None, None).await.unwrap_err();
+		assert!(
+			matches!(&err, Error::UnsupportedFormat { file_name, detected: Some(ImageFormat::Avif) } if file_name == "sticker.avif"),
+			"{err}"
+		);
+		assert!(err.is_format_error());
+
+		let dir = std::env::temp_dir().join(format!("mstickerlib-unsupported-format-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("notes.txt"), "no image").unwrap();
+		let err = Image::from_path(dir.join("notes.txt")).await.unwrap_err();
+		assert!(matches!(err, Error::UnsupportedFormat { detected: None, .. }), "{err}");
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn prefer_smaller() {
+		use super::ConversionNote;
+
+		// noise does not compress, so scaling it up does bloat the file
+		let pixels = (0..8 * 8u32).flat_map(|i| [(i * 97 % 251) as u8, (i * 31 % 241) as u8, (i * 57 % 239) as u8, 255]);
+		let image = Image::from_rgba(pixels.collect(), 8, 8, "sticker.webp").unwrap();
+		let mut config = super::ConversionConfig {
+			default_max_width: Some(64),
+			default_max_height: Some(64),
+			..Default::default()
+		};
+		let (kept, note) = image.clone().convert_reporting(&config, None, None, None).await.unwrap();
+		assert!(Arc::ptr_eq(&kept.data, &image.data));
+		assert!(
+			matches!(note, Some(ConversionNote::KeptOriginal { original_size, converted_size })
+			if original_size == image.data_len() && converted_size > original_size)
+		);
+
+		config.prefer_smaller = false;
+		let (converted, note) = image.clone().convert_reporting(&config, None, None, None).await.unwrap();
+		assert_eq!((converted.width, converted.height), (64, 64));
+		assert!(
+			matches!(note, Some(ConversionNote::SizeRegression { converted_size, .. }) if converted_size == converted.data_len())
+		);
+
+		// images, which do not fit, are always converted
+		config.prefer_smaller = true;
+		let (converted, note) = image.convert_reporting(&config, None, Some(4), None).await.unwrap();
+		assert_eq!((converted.width, converted.height), (4, 4));
+		assert_eq!(note, None);
+	}
+
+	#[tokio::test]
+	async fn keep_color_profile() {
+		let (pixels, width, height) = rgba_image(8, 8).decode_rgba().unwrap();
+		let png = photon_rs::PhotonImage::new(pixels, width, height).get_bytes();
+		let image = Image {
+			file_name: "sticker.png".into(),
+			data: Arc::new(crate::icc::embed_png(&png, ICC_SRGB).unwrap()),
+			width,
+			height,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		assert_eq!(image.icc_profile().as_deref(), Some(ICC_SRGB));
+		let mut config = super::ConversionConfig {
+			default_max_width: Some(4),
+			default_max_height: Some(4),
+			..Default::default()
+		};
+		let stripped = image.clone().convert(&config, None, None, None).await.unwrap();
+		assert_eq!(stripped.icc_profile(), None);
+		config.keep_color_profile = true;
+		let kept = image.convert(&config, None, None, None).await.unwrap();
+		assert_eq!((kept.width, kept.height), (4, 4));
+		// the resized image is encoded as webp
+		assert_eq!(&kept.data[8..12], b"WEBP");
+		assert_eq!(kept.icc_profile().as_deref(), Some(ICC_SRGB));
+	}
+
+	/// Build an animated webp, which shows the same frame `count` times.
+	/// libwebp does merge identical frames, so this can not be created with its encoder.
+	#[cfg(feature = "animation")]
+	fn repeated_frame_webp(frame: &Image, count: u32) -> Vec<u8> {
+		fn chunk(output: &mut Vec<u8>, kind: &[u8], payload: &[u8]) {
+			output.extend_from_slice(kind);
+			output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+			output.extend_from_slice(payload);
+			if payload.len() % 2 == 1 {
+				output.push(0);
+			}
+		}
+		let size = |value: u32| (value - 1).to_le_bytes()[..3].to_vec();
+		// a simple webp does only contain the image chunk
+		let image_chunk = &frame.data[12..];
+		let mut output = b"RIFF\0\0\0\0WEBP".to_vec();
+		chunk(
+			&mut output,
+			b"VP8X",
+			&[[0x12, 0, 0, 0].to_vec(), size(frame.width), size(frame.height)].concat()
+		);
+		chunk(&mut output, b"ANIM", &[0; 6]);
+		for _ in 0..count {
+			// 100ms duration, do not blend with the previous frame
+			let header = [vec![0; 6], size(frame.width), size(frame.height), vec![100, 0, 0, 0x02]].concat();
+			chunk(&mut output, b"ANMF", &[&header, image_chunk].concat());
+		}
+		let riff_size = (output.len() - 8) as u32;
+		output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+		output
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn collapse_static_animation() {
+		let frame = Image::from_rgba(vec![200; 4 * 4 * 4], 4, 4, "sticker.webp").unwrap();
+		let image = Image {
+			data: Arc::new(repeated_frame_webp(&frame, 3)),
+			..frame
+		};
+		assert_eq!(image.frame_count().unwrap(), 3);
+		let image = image.collapse_static_animation().unwrap();
+		assert!(!super::is_animated_webp(&image.data));
+		assert_eq!(image.frame_count().unwrap(), 1);
+		assert_eq!(image.to_raw_rgba().unwrap(), vec![200; 4 * 4 * 4]);
+	}
+
+	#[test]
+	fn sampling_filter_auto() {
+		assert_eq!(SamplingFilter::Auto.resolve((64, 64), (32, 32)), SamplingFilter::Lanczos3);
+		assert_eq!(SamplingFilter::Auto.resolve((64, 64), (128, 32)), SamplingFilter::Lanczos3);
+		assert_eq!(SamplingFilter::Auto.resolve((32, 32), (64, 64)), SamplingFilter::CatmullRom);
+		assert_eq!(SamplingFilter::Nearest.resolve((64, 64), (32, 32)), SamplingFilter::Nearest);
+		let options = ResizeOptions {
+			filter: SamplingFilter::Nearest
+		};
+		let image = rgba_image(16, 16).resize_with_options(32, 32, options).unwrap().into_image();
+		assert_eq!((image.width, image.height), (32, 32));
+	}
+
+	#[test]
+	fn checksum_matches() {
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: Arc::new(b"abc".to_vec()),
+			width: 0,
+			height: 0,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let digest = "DDAF35A193617ABACC417349AE20413112E6FA4E89A97EA20A9EEEE64B55D39A\
+		              2192992A274FC1A836BA3C23A3FEEBBD454D4423643CE80E2A9AC94FA54CA49F";
+		assert!(image.checksum_matches(digest));
+		assert!(image.checksum_matches(&digest.to_lowercase()));
+		assert!(!image.checksum_matches(&digest.replace('D', "E")));
+		// odd length
+		assert!(!image.checksum_matches(&digest[1..]));
+		assert!(!image.checksum_matches(""));
+	}
+
+	#[tokio::test]
+	async fn save_to_path() {
+		let image = rgba_image(4, 4);
+		let dir = std::env::temp_dir().join(format!("mstickerlib-save-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		// the extension does not matter
+		let path = dir.join("sticker.png");
+		image.save_to_path_sync(&path).unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), *image.data);
+		let path = dir.join("sticker_async");
+		image.save_to_path(&path).await.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), *image.data);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn lottie_duration() {
+		let image = Image {
+			file_name: "sticker.lottie".into(),
+			data: Arc::new(br#"{"v":"5.5.2","fr":60,"ip":30,"op":180,"w":512,"h":512,"layers":[]}"#.to_vec()),
+			width: 512,
+			height: 512,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		assert_eq!(image.duration().unwrap(), Some(2500));
+		// like after converting the lottie file
+		let image = Image {
+			duration_ms: Some(3000),
+			..rgba_image(4, 4)
+		};
+		assert_eq!(image.duration().unwrap(), Some(3000));
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn webp_duration() {
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		for i in 0..10 {
+			encoder.add_frame(&[i * 20; 4 * 4 * 4], i as i32 * 40).unwrap();
+		}
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: Arc::new(encoder.finalize(400).unwrap().to_vec()),
+			width: 4,
+			height: 4,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		assert_eq!(image.duration().unwrap(), Some(400));
+		// dropping frames must not change the duration by more than one frame
+		let duration = image.decimate_frames(3).unwrap().duration().unwrap().unwrap();
+		assert!(duration.abs_diff(400) <= 40);
+		assert_eq!(rgba_image(4, 4).duration().unwrap(), None);
+	}
+
+	#[test]
+	fn gif_duration() {
+		use std::time::Duration;
+
+		// header without global color table, with loop extension and one frame of 1 × 1 pixels per delay
+		let gif = |delays: &[u16]| {
+			let mut data = b"GIF89a\x01\x00\x01\x00\x00\x00\x00".to_vec();
+			data.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+			for delay in delays {
+				data.extend_from_slice(&[0x21, 0xf9, 0x04, 0x00]);
+				data.extend_from_slice(&delay.to_le_bytes());
+				data.extend_from_slice(&[0x00, 0x00]);
+				data.extend_from_slice(&[0x2c, 0, 0, 0, 0, 1, 0, 1, 0, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00]);
+			}
+			data.push(0x3b);
+			Image {
+				file_name: "sticker.gif".into(),
+				data: Arc::new(data),
+				width: 1,
+				height: 1,
+				duration_ms: None,
+				pixel_cache: Default::default(),
+				content_hash: Default::default()
+			}
+		};
+		let animation = gif(&[10, 20, 5]);
+		assert_eq!(animation.duration().unwrap(), Some(350));
+		assert_eq!(animation.total_duration().unwrap(), Duration::from_millis(350));
+		assert_eq!(gif(&[10]).duration().unwrap(), None);
+		assert_eq!(gif(&[10]).total_duration().unwrap(), Duration::ZERO);
+		assert_eq!(rgba_image(4, 4).total_duration().unwrap(), Duration::ZERO);
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn from_frames() {
+		use crate::error::Error;
+
+		let frames: Vec<_> = (0..3)
+			.map(|i| Image::from_rgba(vec![i * 80; 8 * 4 * 4], 8, 4, "frame.png").unwrap())
+			.collect();
+		let animation = Image::from_frames(frames.clone(), 50).unwrap();
+		assert_eq!(animation.file_name_str(), "animation.webp");
+		assert_eq!((animation.width, animation.height), (8, 4));
+		assert_eq!(animation.frame_count().unwrap(), 3);
+		assert_eq!(animation.duration().unwrap(), Some(150));
+		let decoder = webp_animation::Decoder::new(&animation.data).unwrap();
+		let timestamps: Vec<_> = decoder.into_iter().map(|frame| frame.timestamp()).collect();
+		assert_eq!(timestamps, [50, 100, 150]);
+
+		let mut frames = frames;
+		frames.push(rgba_image(4, 4));
+		let err = Image::from_frames(frames, 50).unwrap_err();
+		assert!(matches!(err, Error::FrameDimensions {
+			index: 3,
+			width: 4,
+			height: 4,
+			..
+		}));
+		assert!(Image::from_frames(Vec::new(), 50).is_err());
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn ensure_even_dimensions_animated() {
+		let mut encoder = webp_animation::Encoder::new((5, 3)).unwrap();
+		for i in 0..3 {
+			encoder.add_frame(&[i * 80; 5 * 3 * 4], i as i32 * 40).unwrap();
+		}
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: Arc::new(encoder.finalize(120).unwrap().to_vec()),
+			width: 5,
+			height: 3,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let image = image.ensure_even_dimensions().unwrap();
+		assert_eq!((image.width, image.height), (4, 2));
+		let decoder = webp_animation::Decoder::new(&image.data).unwrap();
+		assert_eq!(decoder.dimensions(), (4, 2));
+		assert_eq!(decoder.into_iter().count(), 3);
+		assert_eq!(image.duration().unwrap(), Some(120));
+	}
+
+	#[test]
+	fn mixed_aspect_ratios() {
+		use crate::matrix::sticker_formats::ponies::MetaData;
+
+		for ((width, height), fitted, normalized) in [((100, 400), (64, 256), (32, 128)), ((400, 100), (256, 64), (128, 32))]
+		{
+			let image = rgba_image(width, height);
+			let resized = image.clone().resize(256, 256).unwrap().into_image();
+			let meta_data = MetaData::try_from(resized.clone()).unwrap();
+			assert_eq!((meta_data.w, meta_data.h), fitted);
+			assert_eq!(resized.decode_rgba().unwrap().1, fitted.0);
+
+			let normalized_image = image.normalize_longest_side(128).unwrap().into_image();
+			let meta_data = MetaData::try_from(normalized_image.clone()).unwrap();
+			assert_eq!((meta_data.w, meta_data.h), normalized);
+			let (_, decoded_width, decoded_height) = normalized_image.decode_rgba().unwrap();
+			assert_eq!((decoded_width, decoded_height), normalized);
+		}
+	}
+
+	#[test]
+	fn tiny_sizes() {
+		let sizes = [None, Some(0), Some(1), Some(2), Some(3), Some(512)];
+		for width in 0..=3 {
+			for height in 0..=3 {
+				for max_width in sizes {
+					for max_height in sizes {
+						let (new_width, new_height) =
+							Image::resize_preserving_aspect_ratio(width, height, max_width, max_height);
+						if width == 0 || height == 0 {
+							assert_eq!((new_width, new_height), (width, height));
+							continue;
+						}
+						assert!(new_width >= 1 && new_height >= 1);
+						if let (Some(max_width), Some(max_height)) = (max_width, max_height) {
+							assert!(new_width <= max_width.max(1) && new_height <= max_height.max(1));
+						}
+						let info = super::LottieInfo {
+							width,
+							height,
+							framerate: 60.0,
+							in_point: 0.0,
+							out_point: 60.0
+						};
+						assert_eq!(info.output_size(max_width, max_height), (new_width, new_height));
+					}
+				}
+			}
+		}
+
+		for width in 1..=3 {
+			for height in 1..=3 {
+				for max in 0..=3 {
+					let image = rgba_image(width, height).resize(max, max).unwrap().into_image();
+					assert!(image.width >= 1 && image.height >= 1);
+					assert!(image.width <= max.max(1) && image.height <= max.max(1));
+					let (_, decoded_width, decoded_height) = image.decode_rgba().unwrap();
+					assert_eq!((decoded_width, decoded_height), (image.width, image.height));
+				}
+			}
+		}
+
+		assert!(matches!(
+			super::check_size(Path::new("placeholder.webp"), 0, 512),
+			Err(crate::error::Error::DegenerateImage {
+				width: 0,
+				height: 512,
+				..
+			})
+		));
+		assert!(super::check_size(Path::new("placeholder.webp"), 1, 1).is_ok());
+	}
+
+	#[tokio::test]
+	async fn verify_data_matches_dimensions() {
+		let image = rgba_image(8, 4);
+		image.verify_data_matches_dimensions().unwrap();
+		let stale = Image { width: 4, ..image };
+		assert!(matches!(
+			stale.verify_data_matches_dimensions(),
+			Err(crate::error::Error::DimensionMismatch {
+				stored: (4, 4),
+				actual: (8, 4)
+			})
+		));
+		// debug builds refuse to upload it
+		#[cfg(debug_assertions)]
+		{
+			let matrix_config = matrix_config("http://[::1]:1");
+			assert!(matches!(
+				stale.upload(&matrix_config, None).await,
+				Err(crate::error::Error::DimensionMismatch { .. })
+			));
+		}
+
+		let lottie = Image {
+			file_name: "sticker.lottie".into(),
+			data: Arc::new(br#"{"v":"5.5.2","fr":60,"ip":0,"op":60,"w":512,"h":512,"layers":[]}"#.to_vec()),
+			width: 512,
+			height: 512,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		lottie.verify_data_matches_dimensions().unwrap();
+	}
+
+	#[test]
+	fn resize_reporting_unchanged() {
+		let image = rgba_image(64, 64);
+		let data = image.data.clone();
+		let (image, changed) = image.resize_reporting(64, 64).unwrap();
+		assert!(!changed);
+		assert!(Arc::ptr_eq(&image.data, &data));
+		assert_eq!((image.width, image.height), (64, 64));
+	}
+
+	#[test]
+	fn downscale_if_needed() {
+		let resized = rgba_image(16, 8).downscale_if_needed(32, 32).unwrap();
+		assert!(!resized.is_resized());
+		let resized = rgba_image(64, 32).downscale_if_needed(32, 32).unwrap();
+		assert!(matches!(resized, super::ResizedImage::Resized(_)));
+		let image = resized.into_image();
+		assert_eq!((image.width, image.height), (32, 16));
+	}
+
+	#[test]
+	fn downscale_longest_side() {
+		let image = rgba_image(16, 64);
+		assert_eq!((image.max_dimension(), image.min_dimension()), (64, 16));
+		let unchanged = image.clone().downscale_longest_side(64).unwrap();
+		assert!(Arc::ptr_eq(&unchanged.data, &image.data));
+
+		// the longest side is exact, if the aspect ratio does not divide evenly
+		let cases = [
+			((64, 16), (32, 8)),
+			((16, 64), (8, 32)),
+			((48, 48), (32, 32)),
+			((100, 37), (32, 12)),
+			((37, 100), (12, 32))
+		];
+		for ((width, height), expected) in cases {
+			let resized = rgba_image(width, height).downscale_longest_side(32).unwrap();
+			assert_eq!((resized.width, resized.height), expected);
+			assert_eq!(resized.decode_rgba().unwrap().1, expected.0);
+		}
+	}
+
+	#[test]
+	fn downscale_to_longest_edge() {
+		let landscape = rgba_image(64, 32).downscale_to_longest_edge(16).unwrap();
+		assert_eq!((landscape.width, landscape.height), (16, 8));
+		let portrait = rgba_image(32, 64).downscale_to_longest_edge(16).unwrap();
+		assert_eq!((portrait.width, portrait.height), (8, 16));
+		assert_eq!(portrait.decode_rgba().unwrap().1, 8);
+
+		let image = rgba_image(12, 6);
+		let smaller = image.clone().downscale_to_longest_edge(16).unwrap();
+		assert_eq!((smaller.width, smaller.height), (12, 6));
+		assert!(Arc::ptr_eq(&smaller.data, &image.data));
+	}
+
+	#[test]
+	fn format_predicates() {
+		let mut image = rgba_image(4, 4);
+		assert!(image.is_webp());
+		assert!(!image.is_png() && !image.is_gif());
+		image.file_name = "sticker.tgs".into();
+		assert!(image.is_tgs() && !image.is_lottie());
+		// only the extension is checked
+		image.file_name = "sticker.webm".into();
+		assert!(image.is_webm() && !image.is_webp());
+		assert!(image.is_animated());
+		image.file_name = "webp".into();
+		assert!(!image.is_webp());
+	}
+
+	#[test]
+	fn sticker_compliant() {
+		assert!(rgba_image(512, 256).sticker_compliant());
+		assert!(!rgba_image(513, 256).sticker_compliant());
+		let mut png = rgba_image(4, 4);
+		png.file_name = "sticker.png".into();
+		assert!(!png.sticker_compliant());
+		let mut large = rgba_image(4, 4);
+		large.data = Arc::new(vec![0; super::MATRIX_STICKER_MAX_BYTES + 1]);
+		assert!(!large.sticker_compliant());
+		let mut long = rgba_image(4, 4);
+		long.duration_ms = Some(3001);
+		assert!(!long.sticker_compliant());
+		long.duration_ms = Some(3000);
+		assert!(long.sticker_compliant());
+	}
+
+	#[test]
+	fn resize_reporting_downscale() {
+		let (image, changed) = rgba_image(64, 32).resize_reporting(32, 32).unwrap();
+		assert!(changed);
+		assert_eq!((image.width, image.height), (32, 16));
+		let (_, width, height) = image.decode_rgba().unwrap();
+		assert_eq!((width, height), (32, 16));
+	}
+
+	#[cfg(debug_assertions)]
+	#[test]
+	#[should_panic(expected = "does not match the decoded size 64x32")]
+	fn resize_stale_dimensions() {
+		let image = Image {
+			width: 512,
+			height: 512,
+			..rgba_image(64, 32)
+		};
+		let _ = image.resize(32, 32);
+	}
+
+	#[tokio::test]
+	async fn content_hash() {
+		use crate::{
+			database::{self, Database as _, FileDatabase},
+			error::Error
+		};
+
+		let image = rgba_image(4, 4);
+		let actual = database::hash(&image.data);
+		assert_eq!(image.content_hash(), actual);
+		// a supplied hash is trusted and not computed again
+		let wrong = image.clone().with_content_hash([7; 64]);
+		assert_eq!(wrong.content_hash(), [7; 64]);
+		let supplied = image.clone().with_content_hash(actual);
+		supplied.verify_content_hash().unwrap();
+		let err = wrong.verify_content_hash().unwrap_err();
+		assert!(matches!(err, Error::ContentHashMismatch { .. }));
+		assert!(err.to_string().contains(&"07".repeat(64)));
+		// the hash belongs to the data, for which it was supplied
+		let resized = wrong.clone().resize(2, 2).unwrap().into_image();
+		assert_eq!(resized.content_hash(), database::hash(&resized.data));
+		resized.verify_content_hash().unwrap();
+
+		let dir = std::env::temp_dir().join(format!("mstickerlib-content-hash-test-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let db = FileDatabase::new(dir.join("db.txt")).await.unwrap();
+		db.add(actual, "mxc://example.org/actual".to_owned()).await.unwrap();
+		db.add([7; 64], "mxc://example.org/supplied".to_owned()).await.unwrap();
+		let matrix_config = matrix_config("http://[::1]:1");
+		let (mxc, uploaded) = supplied.upload_with(&matrix_config, Some(&db)).await.unwrap();
+		assert_eq!(mxc.url(), "mxc://example.org/actual");
+		assert!(!uploaded);
+		// debug builds refuse to upload an image with a wrong hash, release builds trust it
+		#[cfg(debug_assertions)]
+		assert!(matches!(
+			wrong.upload_with(&matrix_config, Some(&db)).await,
+			Err(Error::ContentHashMismatch { .. })
+		));
+		#[cfg(not(debug_assertions))]
+		assert_eq!(
+			wrong.upload_with(&matrix_config, Some(&db)).await.unwrap().0.url(),
+			"mxc://example.org/supplied"
+		);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn total_pixels() {
+		let image = rgba_image(8, 4);
+		assert_eq!(image.total_pixels(), 32);
+		assert_eq!(
+			image.approximate_uncompressed_size_bytes(),
+			image.decode_rgba().unwrap().0.len() as u64
+		);
+
+		// the stored size is used, so this does not need to allocate the pixels
+		let mut large = image;
+		(large.width, large.height) = (100_000, 100_000);
+		assert_eq!(large.total_pixels(), 10_000_000_000);
+		assert_eq!(large.approximate_uncompressed_size_bytes(), 40_000_000_000);
+	}
+
+	#[test]
+	fn content_hash_cached() {
+		use crate::database;
+
+		let mut image = rgba_image(4, 4);
+		let hash = image.content_hash();
+		let cached = |image: &Image| image.content_hash.computed.lock().unwrap().as_ref().map(|(_, hash)| *hash);
+		assert_eq!(cached(&image), Some(hash));
+		// clones share the buffer and so its hash
+		assert_eq!(cached(&image.clone()), Some(hash));
+
+		// a replaced buffer is hashed again
+		image.data = rgba_image(2, 2).data;
+		assert_eq!(image.content_hash(), database::hash(&image.data));
+		assert_ne!(cached(&image), Some(hash));
+	}
+
+	#[test]
+	fn set_data() {
+		use crate::database;
+
+		let mut image = rgba_image(4, 4).with_content_hash([7; 64]);
+		assert_eq!(image.content_hash(), [7; 64]);
+		assert_ne!(image.pixel_at(1, 1).unwrap(), rgba_image(2, 2).pixel_at(1, 1).unwrap());
+		let data = rgba_image(2, 2).into_data();
+		image.set_data(data.clone());
+		assert!(Arc::ptr_eq(image.data(), &data));
+		// neither the supplied hash nor the pixels of the old data are used anymore
+		assert_eq!(image.content_hash(), database::hash(&data));
+		assert!(image.pixel_cache.0.lock().unwrap().is_none());
+		assert_eq!(image.pixel_at(1, 1).unwrap(), rgba_image(2, 2).pixel_at(1, 1).unwrap());
+	}
+
+	#[test]
+	fn resize_to_fit() {
+		use super::FitMode;
+
+		let red = [255, 0, 0, 255];
+		let is_red = |pixel: [u8; 4]| pixel[0] > 200 && pixel[2] < 50;
+		let image = rgba_image(8, 4);
+
+		// the wide image is padded at the top and bottom
+		let contained = image.clone().resize_to_fit(6, 6, FitMode::Contain, red).unwrap();
+		assert_eq!((contained.width, contained.height), (6, 6));
+		assert!(is_red(contained.pixel_at(0, 0).unwrap()));
+		assert!(is_red(contained.pixel_at(5, 5).unwrap()));
+		assert!(!is_red(contained.pixel_at(0, 2).unwrap()));
+
+		// and cropped at the left and right
+		let covered = image.clone().resize_to_fit(6, 6, FitMode::Cover, red).unwrap();
+		assert_eq!((covered.width, covered.height), (6, 6));
+		for (x, y) in [(0, 0), (5, 0), (0, 5), (5, 5)] {
+			assert!(!is_red(covered.pixel_at(x, y).unwrap()));
+		}
+
+		let unchanged = image.clone().resize_to_fit(8, 4, FitMode::Cover, red).unwrap();
+		assert!(Arc::ptr_eq(&unchanged.data, &image.data));
+		assert!(image.resize_to_fit(0, 4, FitMode::Contain, red).is_err());
+	}
+
+	#[test]
+	fn resize_decode_cache() {
+		let image = rgba_image(8, 4);
+		let cached = |image: &Image| {
+			let cache = image.pixel_cache.0.lock().unwrap();
+			cache
+				.as_ref()
+				.is_some_and(|(data, _)| Weak::ptr_eq(data, &Arc::downgrade(&image.data)))
+		};
+		assert!(!cached(&image));
+		let small = image.clone().resize(4, 2).unwrap().into_image();
+		assert!(cached(&image));
+		// the resized image does not keep the pixels of the original
+		assert!(small.pixel_cache.0.lock().unwrap().is_none());
+
+		// the cached pixels are resized like the decoded ones
+		let tiny = image.clone().resize(2, 1).unwrap().into_image();
+		let direct = rgba_image(8, 4).resize(2, 1).unwrap().into_image();
+		assert_eq!((tiny.width, tiny.height), (2, 1));
+		assert_eq!(tiny.data, direct.data);
 	}
 }
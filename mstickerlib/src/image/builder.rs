@@ -0,0 +1,155 @@
+use super::Image;
+use crate::error::Error;
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc
+};
+
+/// Builder for an [`Image`], which does not require to know all of its fields.
+///
+/// ```
+/// # fn main() -> Result<(), mstickerlib::error::Error> {
+/// # let bytes = mstickerlib::image::Image::from_rgba(vec![0; 4 * 4 * 4], 4, 4, "sticker.webp")?.into_data();
+/// use mstickerlib::image::ImageBuilder;
+///
+/// let image = ImageBuilder::new().data(bytes).file_name("sticker.webp").build()?;
+/// assert_eq!((image.width, image.height), (4, 4));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ImageBuilder {
+	data: Option<Arc<Vec<u8>>>,
+	file_name: Option<PathBuf>,
+	width: Option<u32>,
+	height: Option<u32>,
+	duration_ms: Option<u64>
+}
+
+impl ImageBuilder {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Encoded image data; required.
+	pub fn data<D>(mut self, data: D) -> Self
+	where
+		D: Into<Arc<Vec<u8>>>
+	{
+		self.data = Some(data.into());
+		self
+	}
+
+	/// Name of the file, whose extension determines the format of the image; required.
+	pub fn file_name<P>(mut self, file_name: P) -> Self
+	where
+		P: AsRef<Path>
+	{
+		self.file_name = Some(file_name.as_ref().to_owned());
+		self
+	}
+
+	/// Width in pixels; must be set together with [`ImageBuilder::height`] or not at all.
+	pub fn width(mut self, width: u32) -> Self {
+		self.width = Some(width);
+		self
+	}
+
+	/// Height in pixels; must be set together with [`ImageBuilder::width`] or not at all.
+	pub fn height(mut self, height: u32) -> Self {
+		self.height = Some(height);
+		self
+	}
+
+	/// Duration of the animation in milliseconds, see [`Image::duration_ms`].
+	pub fn duration_ms(mut self, duration_ms: u64) -> Self {
+		self.duration_ms = Some(duration_ms);
+		self
+	}
+
+	/// Create the image. If neither width nor height was set, they are read from the data;
+	/// the size of webm videos is only read with the `ffmpeg` feature and is zero otherwise.
+	/// Unlike [`Image::from_path`], the data and the file name are used as they are.
+	///
+	/// Return [`Error::IncompleteImage`], if the data or the file name is missing, or only one of width and height is set.
+	pub fn build(self) -> Result<Image, Error> {
+		let data = self.data.ok_or(Error::IncompleteImage("data is missing"))?;
+		let file_name = self.file_name.ok_or(Error::IncompleteImage("file name is missing"))?;
+		let mut image = Image {
+			file_name,
+			data,
+			width: 0,
+			height: 0,
+			duration_ms: self.duration_ms,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		match (self.width, self.height) {
+			(Some(width), Some(height)) => (image.width, image.height) = (width, height),
+			(None, None) => {
+				if let Some((width, height)) = image.encoded_dimensions()? {
+					(image.width, image.height) = (width, height);
+				}
+			},
+			_ => return Err(Error::IncompleteImage("width and height must be set together"))
+		}
+		Ok(image)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::ImageBuilder;
+	use crate::{error::Error, image::Image};
+	use std::sync::Arc;
+
+	#[test]
+	fn build() {
+		let data = Image::from_rgba(vec![255; 8 * 4 * 4], 8, 4, "sticker.webp")
+			.unwrap()
+			.into_data();
+		let image = ImageBuilder::new()
+			.data(data.clone())
+			.file_name("sticker.webp")
+			.duration_ms(100)
+			.build()
+			.unwrap();
+		assert_eq!((image.width, image.height), (8, 4));
+		assert_eq!(image.duration_ms, Some(100));
+		assert!(Arc::ptr_eq(&image.data, &data));
+
+		// the given size is trusted
+		let image = ImageBuilder::new()
+			.data(data.clone())
+			.file_name("sticker.webp")
+			.width(512)
+			.height(256)
+			.build()
+			.unwrap();
+		assert_eq!((image.width, image.height), (512, 256));
+	}
+
+	#[test]
+	fn incomplete() {
+		let data = Image::from_rgba(vec![255; 4 * 4 * 4], 4, 4, "sticker.webp")
+			.unwrap()
+			.into_data();
+		let err = ImageBuilder::new().file_name("sticker.webp").build().unwrap_err();
+		assert!(matches!(err, Error::IncompleteImage("data is missing")));
+		let err = ImageBuilder::new().data(data.clone()).build().unwrap_err();
+		assert!(matches!(err, Error::IncompleteImage("file name is missing")));
+		let err = ImageBuilder::new()
+			.data(data)
+			.file_name("sticker.webp")
+			.width(4)
+			.build()
+			.unwrap_err();
+		assert!(matches!(err, Error::IncompleteImage(_)));
+		let err = ImageBuilder::new()
+			.data(b"no image".to_vec())
+			.file_name("sticker.webp")
+			.build()
+			.unwrap_err();
+		assert!(err.is_format_error());
+	}
+}
@@ -0,0 +1,184 @@
+use super::{Image, MATRIX_STICKER_MAX_BYTES, MATRIX_STICKER_MAX_DURATION_MS, MATRIX_STICKER_MAX_SIZE_PX};
+use std::fmt::{self, Display};
+
+/// Limits, which are checked by [`Image::check_compliance`].
+/// The default are the common Matrix sticker requirements, like used by [`Image::sticker_compliant`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StickerPolicy {
+	pub max_width: u32,
+	pub max_height: u32,
+	/// maximum file size in bytes
+	pub max_bytes: usize,
+	/// file extensions without leading dot, which the clients can show
+	pub formats: Vec<String>,
+	/// maximum duration of animations in milliseconds
+	pub max_duration_ms: Option<u64>,
+	/// maximum number of frames of animations
+	pub max_frames: Option<u32>
+}
+
+impl Default for StickerPolicy {
+	fn default() -> Self {
+		Self {
+			max_width: MATRIX_STICKER_MAX_SIZE_PX,
+			max_height: MATRIX_STICKER_MAX_SIZE_PX,
+			max_bytes: MATRIX_STICKER_MAX_BYTES,
+			formats: vec!["webp".to_owned(), "webm".to_owned()],
+			max_duration_ms: Some(MATRIX_STICKER_MAX_DURATION_MS),
+			max_frames: None
+		}
+	}
+}
+
+/// Requirement of a [`StickerPolicy`], which an image does not satisfy, together with the offending value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Violation {
+	/// The image does not fit into `max_width` × `max_height`.
+	Dimensions {
+		width: u32,
+		height: u32,
+		max_width: u32,
+		max_height: u32
+	},
+	/// The file has more than `max` bytes.
+	FileSize { bytes: usize, max: usize },
+	/// The extension of the file is not one of the allowed formats.
+	Format { extension: String },
+	/// The animation plays longer than `max` milliseconds.
+	Duration { duration_ms: u64, max: u64 },
+	/// The animation has more than `max` frames.
+	FrameCount { frames: u32, max: u32 }
+}
+
+impl Display for Violation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Dimensions {
+				width,
+				height,
+				max_width,
+				max_height
+			} => write!(f, "has {width}×{height} pixels, more than {max_width}×{max_height}"),
+			Self::FileSize { bytes, max } => write!(f, "has {bytes} bytes, more than {max}"),
+			Self::Format { extension } => write!(f, "has the format {extension:?}, which is not allowed"),
+			Self::Duration { duration_ms, max } => write!(f, "plays {duration_ms} ms, longer than {max} ms"),
+			Self::FrameCount { frames, max } => write!(f, "has {frames} frames, more than {max}")
+		}
+	}
+}
+
+impl Image {
+	/// Check the image against all limits of `policy` and return every violated one; an empty list means compliant.
+	/// Unlike the conversions, this does not fix anything.
+	///
+	/// Durations and frame counts, which can not be read for the format or without the needed features,
+	/// are not checked, see [`Image::duration`] and [`Image::frame_count`].
+	pub fn check_compliance(&self, policy: &StickerPolicy) -> Vec<Violation> {
+		let mut violations = Vec::new();
+		if self.width > policy.max_width || self.height > policy.max_height {
+			violations.push(Violation::Dimensions {
+				width: self.width,
+				height: self.height,
+				max_width: policy.max_width,
+				max_height: policy.max_height
+			});
+		}
+		if self.data.len() > policy.max_bytes {
+			violations.push(Violation::FileSize {
+				bytes: self.data.len(),
+				max: policy.max_bytes
+			});
+		}
+		if !policy.formats.iter().any(|format| self.has_extension(format)) {
+			let extension = self.file_name.extension().unwrap_or_default();
+			violations.push(Violation::Format {
+				extension: extension.to_string_lossy().into_owned()
+			});
+		}
+		if let Some(max) = policy.max_duration_ms {
+			if let Ok(Some(duration_ms)) = self.duration() {
+				if duration_ms > max {
+					violations.push(Violation::Duration { duration_ms, max });
+				}
+			}
+		}
+		#[cfg(feature = "animation")]
+		if let Some(max) = policy.max_frames {
+			if let Ok(frames) = self.frame_count() {
+				if frames > max {
+					violations.push(Violation::FrameCount { frames, max });
+				}
+			}
+		}
+		violations
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::StickerPolicy;
+	use crate::image::Image;
+
+	#[test]
+	fn compliant() {
+		let image = Image::from_rgba(vec![255; 64 * 64 * 4], 64, 64, "sticker.webp").unwrap();
+		assert_eq!(image.check_compliance(&StickerPolicy::default()), []);
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn oversized_long_animation() {
+		use super::Violation;
+		use std::sync::Arc;
+
+		let mut encoder = webp_animation::Encoder::new((600, 300)).unwrap();
+		for (i, timestamp) in [0, 2000, 4000].into_iter().enumerate() {
+			encoder.add_frame(&vec![i as u8 * 100; 600 * 300 * 4], timestamp).unwrap();
+		}
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: Arc::new(encoder.finalize(5000).unwrap().to_vec()),
+			width: 600,
+			height: 300,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let policy = StickerPolicy {
+			max_bytes: 16,
+			max_frames: Some(2),
+			..Default::default()
+		};
+		assert_eq!(image.check_compliance(&policy), [
+			Violation::Dimensions {
+				width: 600,
+				height: 300,
+				max_width: 512,
+				max_height: 512
+			},
+			Violation::FileSize {
+				bytes: image.data_len(),
+				max: 16
+			},
+			Violation::Duration {
+				duration_ms: 5000,
+				max: 3000
+			},
+			Violation::FrameCount { frames: 3, max: 2 }
+		]);
+
+		let mut gif = image.clone();
+		gif.file_name = "sticker.gif".into();
+		gif.duration_ms = Some(1000);
+		let violations = gif.check_compliance(&StickerPolicy {
+			max_frames: None,
+			..policy
+		});
+		assert!(violations.contains(&Violation::Format {
+			extension: "gif".to_owned()
+		}));
+		assert!(!violations
+			.iter()
+			.any(|violation| matches!(violation, Violation::Duration { .. })));
+	}
+}
@@ -0,0 +1,121 @@
+use std::fmt::{self, Display};
+
+/// Format of an image, which is detected by its content, see [`ImageFormat::detect`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ImageFormat {
+	Png,
+	Jpeg,
+	Gif,
+	Webp,
+	Bmp,
+	Tiff,
+	/// gzip compressed lottie animation, like it is used by telegram
+	Tgs,
+	Lottie,
+	Webm,
+	Avif,
+	Heif
+}
+
+impl ImageFormat {
+	/// Detect the format by the magic bytes of `data`. Lottie animations are detected as json document.
+	pub fn detect(data: &[u8]) -> Option<Self> {
+		// brand of the `ftyp` box of isobmff files
+		let brand = (data.get(4..8) == Some(b"ftyp")).then(|| data.get(8..12)).flatten();
+		let format = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+			Self::Png
+		} else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+			Self::Jpeg
+		} else if data.starts_with(b"GIF8") {
+			Self::Gif
+		} else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+			Self::Webp
+		} else if data.starts_with(b"BM") {
+			Self::Bmp
+		} else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+			Self::Tiff
+		} else if data.starts_with(&[0x1f, 0x8b]) {
+			Self::Tgs
+		} else if data.trim_ascii_start().starts_with(b"{") {
+			Self::Lottie
+		} else if data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+			Self::Webm
+		} else if matches!(brand, Some(b"avif" | b"avis")) {
+			Self::Avif
+		} else if matches!(brand, Some(b"heic" | b"heix" | b"hevc" | b"mif1" | b"msf1")) {
+			Self::Heif
+		} else {
+			return None;
+		};
+		Some(format)
+	}
+
+	/// File extension of the format, without leading dot.
+	pub fn extension(self) -> &'static str {
+		match self {
+			Self::Png => "png",
+			Self::Jpeg => "jpeg",
+			Self::Gif => "gif",
+			Self::Webp => "webp",
+			Self::Bmp => "bmp",
+			Self::Tiff => "tiff",
+			Self::Tgs => "tgs",
+			Self::Lottie => "lottie",
+			Self::Webm => "webm",
+			Self::Avif => "avif",
+			Self::Heif => "heif"
+		}
+	}
+
+	/// Feature of this crate, which is needed to convert the format, if it is not enabled.
+	pub(crate) fn missing_feature(self) -> Option<&'static str> {
+		match self {
+			Self::Tgs | Self::Lottie if !cfg!(feature = "lottie") => Some("lottie"),
+			Self::Webm if !cfg!(feature = "ffmpeg") => Some("ffmpeg"),
+			_ => None
+		}
+	}
+
+	/// `true` if images of this format can be converted with the enabled features.
+	pub fn is_supported(self) -> bool {
+		!matches!(self, Self::Avif | Self::Heif) && self.missing_feature().is_none()
+	}
+}
+
+/// Show the format like its [`ImageFormat::extension`].
+impl Display for ImageFormat {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.extension())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::ImageFormat;
+	use crate::{icc, image::Image};
+
+	#[test]
+	fn detect() {
+		let webp = Image::from_rgba(vec![255; 4 * 4 * 4], 4, 4, "sticker.webp").unwrap();
+		let png = photon_rs::PhotonImage::new(vec![255; 4 * 4 * 4], 4, 4).get_bytes();
+		assert!(png.starts_with(icc::PNG_SIGNATURE));
+		let cases: [(&[u8], _); 9] = [
+			(&webp.data, Some(ImageFormat::Webp)),
+			(&png, Some(ImageFormat::Png)),
+			(b"GIF89a\x01\x00\x01\x00", Some(ImageFormat::Gif)),
+			(b"\x1f\x8b\x08\x00", Some(ImageFormat::Tgs)),
+			(b" {\"v\":\"5.5.2\"}", Some(ImageFormat::Lottie)),
+			(b"\x1a\x45\xdf\xa3\x01", Some(ImageFormat::Webm)),
+			(b"\0\0\0\x1cftypavif\0\0\0\0", Some(ImageFormat::Avif)),
+			(b"\0\0\0\x18ftypheic\0\0\0\0", Some(ImageFormat::Heif)),
+			(b"plain text", None)
+		];
+		for (data, format) in cases {
+			assert_eq!(ImageFormat::detect(data), format, "{:?}", String::from_utf8_lossy(data));
+		}
+		assert!(ImageFormat::Webp.is_supported());
+		assert!(!ImageFormat::Avif.is_supported());
+		assert_eq!(ImageFormat::Lottie.is_supported(), cfg!(feature = "lottie"));
+		assert_eq!(ImageFormat::Webm.is_supported(), cfg!(feature = "ffmpeg"));
+	}
+}
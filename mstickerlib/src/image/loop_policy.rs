@@ -0,0 +1,257 @@
+use crate::{error::Error, icc};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Application extension of gif files, which stores the loop count, without the count and the terminator.
+const NETSCAPE_EXTENSION: &[u8] = b"\x21\xff\x0bNETSCAPE2.0\x03\x01";
+
+/// Animation formats, whose loop count can be set by a [`LoopPolicy`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LoopFormat {
+	Webp,
+	Gif,
+	Apng
+}
+
+impl LoopFormat {
+	/// Detect the format of an animation by its content. Static webp and png images have no loop count.
+	pub(super) fn detect(data: &[u8]) -> Option<Self> {
+		if data.starts_with(b"GIF8") {
+			Some(Self::Gif)
+		} else if data.starts_with(icc::PNG_SIGNATURE) {
+			icc::png_chunks(data)?
+				.iter()
+				.any(|(kind, _)| *kind == b"acTL")
+				.then_some(Self::Apng)
+		} else {
+			webp_anim_offset(data).map(|_| Self::Webp)
+		}
+	}
+}
+
+/// Offset of the payload of the `ANIM` chunk of an animated webp.
+fn webp_anim_offset(data: &[u8]) -> Option<usize> {
+	if data.get(..4) != Some(b"RIFF") || data.get(8..12) != Some(b"WEBP") {
+		return None;
+	}
+	let mut pos = 12;
+	while let Some(header) = data.get(pos..pos + 8) {
+		let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+		// background color and loop count
+		if &header[..4] == b"ANIM" && len >= 6 && data.len() >= pos + 14 {
+			return Some(pos + 8);
+		}
+		// chunks are padded to an even size
+		pos += 8 + len + len % 2;
+	}
+	None
+}
+
+/// Offset of the first block after the header and global color table of a gif.
+pub(super) fn gif_blocks_offset(data: &[u8]) -> Result<usize, Error> {
+	let flags = *data.get(10).ok_or(Error::MalformedImage("gif"))?;
+	let color_table = if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+	let offset = 13 + color_table;
+	if data.len() < offset {
+		return Err(Error::MalformedImage("gif"));
+	}
+	Ok(offset)
+}
+
+fn set_loop_count(data: &[u8], format: LoopFormat, loop_count: u16) -> Result<Vec<u8>, Error> {
+	match format {
+		LoopFormat::Webp => {
+			let offset = webp_anim_offset(data).ok_or(Error::MalformedImage("webp"))?;
+			let mut output = data.to_vec();
+			output[offset + 4..offset + 6].copy_from_slice(&loop_count.to_le_bytes());
+			Ok(output)
+		},
+		LoopFormat::Gif => {
+			// encoders write the extension directly after the global color table
+			let offset = gif_blocks_offset(data)?;
+			let mut output = data.to_vec();
+			let count_offset = offset + NETSCAPE_EXTENSION.len();
+			if data[offset..].starts_with(NETSCAPE_EXTENSION) && data.len() >= count_offset + 3 {
+				output[count_offset..count_offset + 2].copy_from_slice(&loop_count.to_le_bytes());
+			} else {
+				let mut extension = NETSCAPE_EXTENSION.to_vec();
+				extension.extend_from_slice(&loop_count.to_le_bytes());
+				extension.push(0);
+				output.splice(offset..offset, extension);
+			}
+			Ok(output)
+		},
+		LoopFormat::Apng => {
+			let chunks = icc::png_chunks(data).ok_or(Error::MalformedImage("png"))?;
+			let mut output = Vec::with_capacity(data.len());
+			output.extend_from_slice(icc::PNG_SIGNATURE);
+			for (kind, payload) in chunks {
+				let kind: &[u8; 4] = kind.try_into().unwrap();
+				if kind == b"acTL" && payload.len() == 8 {
+					// number of frames and number of plays
+					let mut actl = payload.to_vec();
+					actl[4..].copy_from_slice(&(loop_count as u32).to_be_bytes());
+					icc::write_png_chunk(&mut output, kind, &actl);
+				} else {
+					icc::write_png_chunk(&mut output, kind, payload);
+				}
+			}
+			Ok(output)
+		}
+	}
+}
+
+/// Default loop count of animations per output format, which is applied by [`Image::convert`](super::Image::convert).
+///
+/// A loop count of `0` repeats the animation forever, otherwise it is written unchanged to the file.
+/// Animations in formats without an entry keep the loop count of their encoder.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(transparent)]
+pub struct LoopPolicy(HashMap<LoopFormat, u16>);
+
+impl LoopPolicy {
+	/// Set the loop count of animations in `format`.
+	pub fn with(mut self, format: LoopFormat, loop_count: u16) -> Self {
+		self.0.insert(format, loop_count);
+		self
+	}
+
+	/// Loop count of animations in `format`, if the policy has an entry for it.
+	pub fn get(&self, format: LoopFormat) -> Option<u16> {
+		self.0.get(&format).copied()
+	}
+
+	/// Set the loop count of the animation in `data` by the policy.
+	/// Return `None` if this is no animation or the policy has no entry for its format.
+	pub(crate) fn apply(&self, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		let Some(format) = LoopFormat::detect(data) else {
+			return Ok(None);
+		};
+		let Some(loop_count) = self.get(format) else {
+			return Ok(None);
+		};
+		set_loop_count(data, format, loop_count).map(Some)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{gif_blocks_offset, webp_anim_offset, LoopFormat, LoopPolicy, NETSCAPE_EXTENSION};
+	use crate::{
+		icc,
+		image::{ConversionConfig, Image}
+	};
+	use std::sync::Arc;
+
+	fn webp_chunk(output: &mut Vec<u8>, kind: &[u8], payload: &[u8]) {
+		output.extend_from_slice(kind);
+		output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+		output.extend_from_slice(payload);
+	}
+
+	/// Container of an animated webp, which does play once; the frames are not needed to set the loop count.
+	fn animated_webp() -> Vec<u8> {
+		let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+		webp_chunk(&mut data, b"VP8X", &[0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		webp_chunk(&mut data, b"ANIM", &[0, 0, 0, 0, 1, 0]);
+		let size = (data.len() - 8) as u32;
+		data[4..8].copy_from_slice(&size.to_le_bytes());
+		data
+	}
+
+	/// Container of an apng, which does loop forever.
+	fn apng() -> Vec<u8> {
+		let mut data = icc::PNG_SIGNATURE.to_vec();
+		icc::write_png_chunk(&mut data, b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+		icc::write_png_chunk(&mut data, b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]);
+		icc::write_png_chunk(&mut data, b"IEND", &[]);
+		data
+	}
+
+	/// Header of a gif with a global color table of two colors and without loop extension.
+	fn gif() -> Vec<u8> {
+		let mut data = b"GIF89a\x01\x00\x01\x00\x80\x00\x00".to_vec();
+		data.extend_from_slice(&[0; 6]);
+		data.push(0x3b);
+		data
+	}
+
+	fn loop_count(data: &[u8]) -> u16 {
+		match LoopFormat::detect(data).unwrap() {
+			LoopFormat::Webp => {
+				let offset = webp_anim_offset(data).unwrap();
+				u16::from_le_bytes(data[offset + 4..offset + 6].try_into().unwrap())
+			},
+			LoopFormat::Gif => {
+				let offset = gif_blocks_offset(data).unwrap() + NETSCAPE_EXTENSION.len();
+				u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+			},
+			LoopFormat::Apng => {
+				let chunks = icc::png_chunks(data).unwrap();
+				let (_, actl) = chunks.iter().find(|(kind, _)| *kind == b"acTL").unwrap();
+				u32::from_be_bytes(actl[4..].try_into().unwrap()) as u16
+			}
+		}
+	}
+
+	fn image(file_name: &str, data: Vec<u8>) -> Image {
+		Image {
+			file_name: file_name.into(),
+			data: Arc::new(data),
+			width: 1,
+			height: 1,
+			duration_ms: Some(100),
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		}
+	}
+
+	#[tokio::test]
+	async fn convert_applies_policy() {
+		let config = ConversionConfig {
+			collapse_static: false,
+			loop_policy: LoopPolicy::default().with(LoopFormat::Webp, 0).with(LoopFormat::Apng, 1),
+			..Default::default()
+		};
+		let webp = image("sticker.webp", animated_webp());
+		assert_eq!(loop_count(&webp.data), 1);
+		let webp = webp.convert(&config, None, None, None).await.unwrap();
+		assert_eq!(loop_count(&webp.data), 0);
+
+		let png = image("sticker.png", apng());
+		assert_eq!(loop_count(&png.data), 0);
+		let png = png.convert(&config, None, None, None).await.unwrap();
+		assert_eq!(loop_count(&png.data), 1);
+		// the checksum of the changed chunk is updated, so setting the old value does restore the file
+		let restored = LoopPolicy::default().with(LoopFormat::Apng, 0).apply(&png.data).unwrap();
+		assert_eq!(restored, Some(apng()));
+
+		// formats without entry are not changed
+		let gif = image("sticker.gif", gif());
+		let converted = gif.clone().convert(&config, None, None, None).await.unwrap();
+		assert_eq!(converted.data, gif.data);
+	}
+
+	#[test]
+	fn gif_extension() {
+		let data = gif();
+		let policy = LoopPolicy::default().with(LoopFormat::Gif, 3);
+		let looped = policy.apply(&data).unwrap().unwrap();
+		assert_eq!(looped.len(), data.len() + NETSCAPE_EXTENSION.len() + 3);
+		assert_eq!(loop_count(&looped), 3);
+		// an existing extension is updated
+		let looped = policy.with(LoopFormat::Gif, 0).apply(&looped).unwrap().unwrap();
+		assert_eq!(looped.len(), data.len() + NETSCAPE_EXTENSION.len() + 3);
+		assert_eq!(loop_count(&looped), 0);
+	}
+
+	#[test]
+	fn static_images() {
+		let policy = LoopPolicy::default().with(LoopFormat::Webp, 0).with(LoopFormat::Apng, 0);
+		let image = Image::from_rgba(vec![255; 4 * 4 * 4], 4, 4, "static.webp").unwrap();
+		assert_eq!(policy.apply(&image.data).unwrap(), None);
+		let png = photon_rs::PhotonImage::new(vec![255; 4 * 4 * 4], 4, 4).get_bytes();
+		assert_eq!(policy.apply(&png).unwrap(), None);
+	}
+}
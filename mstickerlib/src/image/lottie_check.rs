@@ -0,0 +1,239 @@
+use serde_json::Value;
+use std::fmt::{self, Display};
+
+/// Animations with more layers are flagged, since their rendering time grows with each layer.
+pub const MAX_LOTTIE_LAYERS: usize = 100;
+
+/// Feature of a lottie animation, which rlottie does not render correctly,
+/// or a rendered animation, which looks broken. See [`Image::lottie_warnings`](super::Image::lottie_warnings).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LottieWarning {
+	/// The animation uses `count` javascript expressions, which are ignored by rlottie.
+	Expressions { count: usize },
+	/// The layer `index` uses effects, like blur or drop shadow, which are ignored by rlottie.
+	Effects { index: u64 },
+	/// The layer `index` has a type, like text or camera, which rlottie does not render.
+	UnsupportedLayer { index: u64, layer_type: u64 },
+	/// The animation has `count` layers, more than [`MAX_LOTTIE_LAYERS`].
+	TooManyLayers { count: usize },
+	/// Every frame of the rendered animation is fully transparent or has a single color.
+	BlankOutput
+}
+
+impl Display for LottieWarning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Expressions { count } => write!(f, "uses {count} expressions, which are ignored"),
+			Self::Effects { index } => write!(f, "layer {index} uses effects, which are ignored"),
+			Self::UnsupportedLayer { index, layer_type } => {
+				write!(f, "layer {index} has the unsupported type {layer_type}")
+			},
+			Self::TooManyLayers { count } => write!(f, "has {count} layers, more than {MAX_LOTTIE_LAYERS}"),
+			Self::BlankOutput => f.write_str("was rendered blank")
+		}
+	}
+}
+
+/// Count the properties, which are driven by an expression; those store the script as string at the key `x`.
+/// Split positions use `x` for an animated value instead, which is an object.
+fn count_expressions(value: &Value) -> usize {
+	match value {
+		Value::Object(object) => object
+			.iter()
+			.map(|(key, value)| match value {
+				Value::String(_) if key == "x" => 1,
+				value => count_expressions(value)
+			})
+			.sum(),
+		Value::Array(array) => array.iter().map(count_expressions).sum(),
+		_ => 0
+	}
+}
+
+/// Inspect the json of a lottie animation for features, which rlottie does not render correctly.
+pub(crate) fn inspect(json: &Value) -> Vec<LottieWarning> {
+	let mut warnings = Vec::new();
+	let expressions = count_expressions(json);
+	if expressions > 0 {
+		warnings.push(LottieWarning::Expressions { count: expressions });
+	}
+	// layers of precompositions are stored at the assets
+	let assets = json["assets"].as_array().into_iter().flatten();
+	let layers: Vec<_> = json["layers"]
+		.as_array()
+		.into_iter()
+		.chain(assets.filter_map(|asset| asset["layers"].as_array()))
+		.flatten()
+		.collect();
+	for layer in &layers {
+		let index = layer["ind"].as_u64().unwrap_or_default();
+		// precomposition, solid, image, null and shape layers
+		match layer["ty"].as_u64() {
+			Some(0..=4) | None => {},
+			Some(layer_type) => warnings.push(LottieWarning::UnsupportedLayer { index, layer_type })
+		}
+		if layer["ef"].as_array().is_some_and(|effects| !effects.is_empty()) {
+			warnings.push(LottieWarning::Effects { index });
+		}
+	}
+	if layers.len() > MAX_LOTTIE_LAYERS {
+		warnings.push(LottieWarning::TooManyLayers { count: layers.len() });
+	}
+	warnings
+}
+
+/// `true` if all `frames` of RGBA pixels are fully transparent, or all of their pixels have the same color.
+pub(crate) fn is_blank<'a, I>(frames: I) -> bool
+where
+	I: IntoIterator<Item = &'a [u8]>
+{
+	let mut first: Option<&[u8]> = None;
+	for frame in frames {
+		for pixel in frame.chunks_exact(4) {
+			let first = *first.get_or_insert(pixel);
+			if pixel != first && (pixel[3] != 0 || first[3] != 0) {
+				return false;
+			}
+		}
+	}
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{inspect, is_blank, LottieWarning, MAX_LOTTIE_LAYERS};
+	use crate::image::Image;
+	use serde_json::json;
+	use std::sync::Arc;
+
+	fn lottie(json: &[u8]) -> Image {
+		Image {
+			file_name: "sticker.lottie".into(),
+			data: Arc::new(json.to_vec()),
+			width: 512,
+			height: 512,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		}
+	}
+
+	#[test]
+	fn expressions() {
+		let json = json!({
+			"v": "5.5.2", "fr": 60, "ip": 0, "op": 60, "w": 512, "h": 512,
+			"assets": [{"id": "comp", "layers": [
+				{"ty": 5, "ind": 3, "ks": {}},
+				{"ty": 4, "ind": 4, "ks": {"r": {"a": 0, "k": 0, "x": "time * 90"}}}
+			]}],
+			"layers": [
+				{"ty": 0, "ind": 1, "refId": "comp", "ks": {"p": {"s": true, "x": {"a": 0, "k": 0}, "y": {"a": 0, "k": 0}}}},
+				{"ty": 4, "ind": 2, "ef": [{"ty": 29}], "ks": {"o": {"a": 0, "k": 100, "x": "wiggle(2, 30)"}}}
+			]
+		});
+		let warnings = inspect(&json);
+		assert_eq!(warnings, [
+			LottieWarning::Expressions { count: 2 },
+			LottieWarning::Effects { index: 2 },
+			LottieWarning::UnsupportedLayer { index: 3, layer_type: 5 }
+		]);
+		assert_eq!(warnings[0].to_string(), "uses 2 expressions, which are ignored");
+
+		let layers: Vec<_> = (0..=MAX_LOTTIE_LAYERS).map(|ind| json!({"ty": 4, "ind": ind})).collect();
+		let json = json!({"layers": layers});
+		assert_eq!(inspect(&json), [LottieWarning::TooManyLayers {
+			count: MAX_LOTTIE_LAYERS + 1
+		}]);
+
+		let image = lottie(br#"{"v":"5.5.2","fr":60,"ip":0,"op":60,"w":512,"h":512,"layers":[{"ty":4,"ind":1}]}"#);
+		assert!(image.lottie_warnings().unwrap().is_empty());
+	}
+
+	#[cfg(feature = "lottie")]
+	#[tokio::test]
+	async fn enforce_max_duration() {
+		use crate::{
+			error::Error,
+			image::{ConversionConfig, MATRIX_STICKER_MAX_DURATION_MS}
+		};
+
+		// 180 frames at 30 fps
+		let image = lottie(br#"{"v":"5.5.2","fr":30,"ip":0,"op":180,"w":32,"h":32,"layers":[]}"#);
+		let config = ConversionConfig {
+			enforce_max_duration: true,
+			..Default::default()
+		};
+		// the animation is rejected before it is rendered
+		let err = image.clone().convert(&config, None, None, None).await.unwrap_err();
+		assert!(
+			matches!(err, Error::AnimationTooLong {
+				duration_ms: 6000,
+				max_duration_ms: MATRIX_STICKER_MAX_DURATION_MS,
+				..
+			}),
+			"{err}"
+		);
+		// by default it is only logged
+		let converted = image.convert(&ConversionConfig::default(), None, None, None).await;
+		assert!(!matches!(converted, Err(Error::AnimationTooLong { .. })));
+	}
+
+	#[cfg(feature = "lottie")]
+	#[tokio::test]
+	async fn strict() {
+		use crate::{error::Error, image::ConversionConfig};
+
+		let image = lottie(br#"{"v":"5.5.2","fr":60,"ip":0,"op":60,"w":512,"h":512,"layers":[{"ty":13,"ind":1}]}"#);
+		let config = ConversionConfig {
+			strict_lottie: true,
+			..Default::default()
+		};
+		// the animation is rejected before it is rendered
+		let err = image.convert(&config, None, None, None).await.unwrap_err();
+		assert!(matches!(&err, Error::LottieCompatibility { warnings, .. }
+			if warnings == &[LottieWarning::UnsupportedLayer { index: 1, layer_type: 13 }]));
+		assert!(err.is_format_error());
+	}
+
+	#[test]
+	fn blank() {
+		let transparent = [[10, 20, 30, 0], [0, 0, 0, 0]].concat();
+		let red = [255, 0, 0, 255].repeat(2);
+		let circle = [[255, 0, 0, 255], [0, 0, 0, 0]].concat();
+		assert!(is_blank([&transparent[..], &transparent]));
+		assert!(is_blank([&red[..], &red]));
+		assert!(!is_blank([&circle[..], &circle]));
+		assert!(!is_blank([&transparent[..], &red]));
+
+		let image = Image::from_rgba(transparent, 2, 1, "sticker.webp").unwrap();
+		assert!(image.is_blank().unwrap());
+		let image = Image::from_rgba(circle, 2, 1, "sticker.webp").unwrap();
+		assert!(!image.is_blank().unwrap());
+	}
+
+	#[cfg(feature = "animation")]
+	#[test]
+	fn blank_animation() {
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		encoder.add_frame(&[0; 4 * 4 * 4], 0).unwrap();
+		encoder.add_frame(&[255; 4 * 4 * 4], 100).unwrap();
+		let image = Image {
+			data: Arc::new(encoder.finalize(200).unwrap().to_vec()),
+			..Image::from_rgba(vec![0; 4 * 4 * 4], 4, 4, "sticker.webp").unwrap()
+		};
+		assert!(!image.is_blank().unwrap());
+
+		// like a lottie animation, whose shapes are not rendered at all
+		let mut encoder = webp_animation::Encoder::new((4, 4)).unwrap();
+		encoder.add_frame(&[0; 4 * 4 * 4], 0).unwrap();
+		let mut transparent = [0; 4 * 4 * 4];
+		transparent[0] = 255;
+		encoder.add_frame(&transparent, 100).unwrap();
+		let image = Image {
+			data: Arc::new(encoder.finalize(200).unwrap().to_vec()),
+			..image
+		};
+		assert_eq!(image.frame_count().unwrap(), 2);
+		assert!(image.is_blank().unwrap());
+	}
+}
@@ -0,0 +1,105 @@
+use super::copy_buffer;
+use crate::error::Error;
+use gif::{DisposalMethod, Repeat};
+use lottieconv::{Rgba, Size};
+use rlottie::Surface;
+
+/// Encoder for the frames rendered by rlottie, which produces the same output as the converter of lottieconv.
+///
+/// lottieconv renders and encodes all frames in a single call, without any callback,
+/// so the frames are rendered by [`Image::convert_lottie_with_progress`](super::Image::convert_lottie_with_progress)
+/// itself, to report the progress after each frame.
+pub(super) enum FrameEncoder {
+	Gif {
+		encoder: gif::Encoder<Vec<u8>>,
+		size: Size,
+		transparent_color: Rgba,
+		delay: u16
+	},
+	Webp {
+		encoder: Box<webp_animation::Encoder>,
+		delay: f64,
+		timestamp: f64
+	}
+}
+
+impl FrameEncoder {
+	pub(super) fn gif(size: Size, framerate: f64, transparent_color: Rgba) -> Result<Self, Error> {
+		let mut encoder = gif::Encoder::new(Vec::new(), size.width as u16, size.height as u16, &[])?;
+		encoder.set_repeat(Repeat::Infinite)?;
+		Ok(Self::Gif {
+			encoder,
+			size,
+			transparent_color,
+			delay: (100.0 / framerate).round() as u16
+		})
+	}
+
+	pub(super) fn webp(size: Size, framerate: f64) -> Result<Self, Error> {
+		Ok(Self::Webp {
+			encoder: Box::new(webp_animation::Encoder::new((size.width as u32, size.height as u32))?),
+			delay: 1000.0 / framerate,
+			timestamp: 0.0
+		})
+	}
+
+	/// Append the frame currently rendered to `surface`.
+	pub(super) fn add_frame(&mut self, surface: &Surface) -> Result<(), Error> {
+		match self {
+			Self::Gif {
+				encoder,
+				size,
+				transparent_color,
+				delay
+			} => {
+				// gif has no alpha channel, so the frame is blended onto the transparent color
+				let mut pixels: Vec<u8> = surface
+					.data()
+					.iter()
+					.flat_map(|pixel| {
+						let alpha = pixel.a as u32;
+						let blend = |channel: u8, background: u8| {
+							let channel = if alpha == 0 { 0 } else { channel as u32 };
+							(channel + background as u32 * (255 - alpha) / 255) as u8
+						};
+						let a = if transparent_color.a && pixel.a == 0 { 0 } else { 255 };
+						[
+							blend(pixel.r, transparent_color.r),
+							blend(pixel.g, transparent_color.g),
+							blend(pixel.b, transparent_color.b),
+							a
+						]
+					})
+					.collect();
+				let mut frame = gif::Frame::from_rgba_speed(size.width as u16, size.height as u16, &mut pixels, 10);
+				frame.delay = *delay;
+				if transparent_color.a {
+					frame.dispose = DisposalMethod::Background;
+				}
+				encoder.write_frame(&frame)?;
+			},
+			Self::Webp {
+				encoder,
+				delay,
+				timestamp
+			} => {
+				// rlottie renders bgra
+				let pixels: Vec<u8> = surface
+					.data()
+					.iter()
+					.flat_map(|pixel| [pixel.r, pixel.g, pixel.b, pixel.a])
+					.collect();
+				encoder.add_frame(&pixels, timestamp.round() as i32)?;
+				*timestamp += *delay;
+			}
+		}
+		Ok(())
+	}
+
+	pub(super) fn finish(self) -> Result<Vec<u8>, Error> {
+		match self {
+			Self::Gif { encoder, .. } => Ok(encoder.into_inner()?),
+			Self::Webp { encoder, timestamp, .. } => Ok(copy_buffer(&encoder.finalize(timestamp.round() as i32)?))
+		}
+	}
+}
@@ -0,0 +1,187 @@
+use image_crate::{
+	imageops::{self, colorops::ColorMap},
+	Rgba, RgbaImage
+};
+
+/// Algorithm, which reduces the colors of a gif frame to its palette of 256 colors.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Quantizer {
+	/// Neural network quantizer, which is also used by the gif encoder itself.
+	#[default]
+	NeuQuant,
+	/// Split the colors at their median along the widest channel, until the palette is full.
+	/// Unlike [`Quantizer::NeuQuant`], every color of the palette is the average of actual pixels,
+	/// which does often reproduce smooth gradients better.
+	MedianCut
+}
+
+/// Palette created by the median cut algorithm from the opaque pixels of a frame.
+/// Transparent pixels are kept, so they do not spread their color when dithering.
+struct MedianCut {
+	palette: Vec<[u8; 3]>
+}
+
+impl MedianCut {
+	fn new(pixels: &RgbaImage, colors: usize) -> Self {
+		let opaque: Vec<[u8; 3]> = pixels
+			.pixels()
+			.filter(|pixel| pixel[3] != 0)
+			.map(|pixel| [pixel[0], pixel[1], pixel[2]])
+			.collect();
+		let mut boxes = vec![opaque];
+		while boxes.len() < colors {
+			// split the box with the widest range of a single channel
+			let Some((index, channel, _)) = boxes
+				.iter()
+				.enumerate()
+				.flat_map(|(index, colors)| (0..3).map(move |channel| (index, channel, range(colors, channel))))
+				.filter(|(_, _, range)| *range > 0)
+				.max_by_key(|(_, _, range)| *range)
+			else {
+				break;
+			};
+			let mut colors = boxes.swap_remove(index);
+			colors.sort_unstable_by_key(|color| color[channel]);
+			let upper = colors.split_off(colors.len() / 2);
+			boxes.push(colors);
+			boxes.push(upper);
+		}
+		let palette = boxes
+			.iter()
+			.filter(|colors| !colors.is_empty())
+			.map(|colors| {
+				let mut sum = [0_u64; 3];
+				for color in colors {
+					for (sum, channel) in sum.iter_mut().zip(color) {
+						*sum += *channel as u64;
+					}
+				}
+				sum.map(|sum| ((sum + colors.len() as u64 / 2) / colors.len() as u64) as u8)
+			})
+			.collect();
+		Self { palette }
+	}
+}
+
+fn range(colors: &[[u8; 3]], channel: usize) -> u8 {
+	let (min, max) = colors.iter().fold((u8::MAX, u8::MIN), |(min, max), color| {
+		(min.min(color[channel]), max.max(color[channel]))
+	});
+	max.saturating_sub(min)
+}
+
+impl ColorMap for MedianCut {
+	type Color = Rgba<u8>;
+
+	fn index_of(&self, color: &Rgba<u8>) -> usize {
+		let distance = |entry: &[u8; 3]| -> u32 {
+			entry
+				.iter()
+				.zip(color.0)
+				.map(|(entry, channel)| (*entry as i32 - channel as i32).pow(2) as u32)
+				.sum()
+		};
+		self.palette
+			.iter()
+			.enumerate()
+			.min_by_key(|(_, entry)| distance(entry))
+			.map(|(index, _)| index)
+			.unwrap_or_default()
+	}
+
+	fn map_color(&self, color: &mut Rgba<u8>) {
+		if color[3] == 0 {
+			return;
+		}
+		if let Some([r, g, b]) = self.palette.get(self.index_of(color)) {
+			*color = Rgba([*r, *g, *b, 255]);
+		}
+	}
+}
+
+/// Reduce the colors of a frame with only opaque and fully transparent pixels.
+/// Frames quantized by [`Quantizer::NeuQuant`] without dithering are left to the gif encoder.
+pub(super) fn quantize_frame(buffer: &mut RgbaImage, quantizer: Quantizer, dither: bool) {
+	match (quantizer, dither) {
+		(Quantizer::NeuQuant, false) => {},
+		(Quantizer::NeuQuant, true) => {
+			let palette = color_quant::NeuQuant::new(10, 256, buffer.as_raw());
+			imageops::dither(buffer, &palette);
+		},
+		(Quantizer::MedianCut, dither) => {
+			let transparent = buffer.pixels().any(|pixel| pixel[3] == 0);
+			// one entry of the gif palette is used by the transparent color
+			let palette = MedianCut::new(buffer, if transparent { 255 } else { 256 });
+			if dither {
+				imageops::dither(buffer, &palette);
+			} else {
+				buffer.pixels_mut().for_each(|pixel| palette.map_color(pixel));
+			}
+			// all transparent pixels share a single palette entry
+			for pixel in buffer.pixels_mut().filter(|pixel| pixel[3] == 0) {
+				*pixel = Rgba([0; 4]);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Quantizer;
+	use crate::image::{GifOptions, Image};
+	use std::collections::HashSet;
+
+	/// Smooth two dimensional gradient with more colors than fit into a gif palette.
+	fn gradient(size: u32) -> (Vec<u8>, Image) {
+		let pixels: Vec<u8> = (0..size * size)
+			.flat_map(|i| {
+				let (x, y) = (i % size, i / size);
+				[
+					(x * 255 / size) as u8,
+					(y * 255 / size) as u8,
+					((x + y) * 255 / (2 * size)) as u8,
+					255
+				]
+			})
+			.collect();
+		let image = Image::from_rgba(pixels.clone(), size, size, "sticker.png").unwrap();
+		(pixels, image)
+	}
+
+	#[tokio::test]
+	async fn quantizers() {
+		let (pixels, image) = gradient(128);
+		for quantizer in [Quantizer::NeuQuant, Quantizer::MedianCut] {
+			let options = GifOptions {
+				quantizer,
+				..Default::default()
+			};
+			let gif = image.clone().convert_to_gif(options).await.unwrap();
+			assert!(gif.data.starts_with(b"GIF89a"));
+			let (decoded, width, height) = gif.decode_rgba().unwrap();
+			assert_eq!((width, height), (128, 128));
+			let colors: HashSet<&[u8]> = decoded.chunks_exact(4).collect();
+			assert!(colors.len() <= 256, "{quantizer:?} produced {} colors", colors.len());
+			// mean error per channel
+			let error = pixels.iter().zip(&decoded).map(|(a, b)| a.abs_diff(*b) as u64).sum::<u64>() / pixels.len() as u64;
+			assert!(error < 8, "{quantizer:?} has a mean error of {error}");
+		}
+	}
+
+	#[tokio::test]
+	async fn median_cut_transparent() {
+		let mut pixels = vec![0; 16 * 16 * 4];
+		for (i, pixel) in pixels.chunks_exact_mut(4).enumerate().skip(128) {
+			pixel.copy_from_slice(&[i as u8, 255 - i as u8, 0, 255]);
+		}
+		let image = Image::from_rgba(pixels, 16, 16, "sticker.png").unwrap();
+		let options = GifOptions {
+			quantizer: Quantizer::MedianCut,
+			dither: true,
+			..Default::default()
+		};
+		let gif = image.convert_to_gif(options).await.unwrap();
+		assert_eq!(gif.pixel_at(0, 0).unwrap()[3], 0);
+		assert_eq!(gif.pixel_at(15, 15).unwrap()[3], 255);
+	}
+}
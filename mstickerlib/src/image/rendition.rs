@@ -0,0 +1,175 @@
+use super::{copy_buffer, Image, SamplingFilter, WebpOptions};
+use crate::error::Error;
+use photon_rs::{native::open_image_from_bytes, transform, PhotonImage};
+use std::sync::Arc;
+use webp_animation::{Decoder, Encoder, EncoderOptions};
+
+/// Settings for an additional variant of a pack, whose stickers are converted again after the import.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rendition {
+	/// appended to the title of the pack, like ` (lite)`
+	pub title_suffix: String,
+	/// appended to the id of the pack, like `_lite`
+	pub id_suffix: String,
+	/// maximum width and height of the stickers; smaller stickers are not scaled up
+	pub max_size: u32,
+	/// Reduce the colors of each frame to this number, between 1 and 256.
+	pub colors: Option<usize>,
+	pub webp: WebpOptions
+}
+
+impl Rendition {
+	/// Small variant for low-bandwidth deployments: 256 px, 64 colors and lossy encoded.
+	pub fn lite() -> Self {
+		Self {
+			title_suffix: " (lite)".to_owned(),
+			id_suffix: "_lite".to_owned(),
+			max_size: 256,
+			colors: Some(64),
+			webp: WebpOptions {
+				quality: Some(60.0),
+				near_lossless: None
+			}
+		}
+	}
+}
+
+/// Reduce the colors of a RGBA8 buffer; the alpha channel is quantized together with the colors.
+fn quantize(pixels: &mut [u8], colors: usize) {
+	let palette = color_quant::NeuQuant::new(10, colors.clamp(1, 256), pixels);
+	for pixel in pixels.chunks_exact_mut(4) {
+		palette.map_pixel(pixel);
+	}
+}
+
+impl Image {
+	/// Convert the image for `rendition`, see [`Rendition`].
+	/// Animated webp images are converted frame by frame. The result is always a webp image.
+	pub fn render(&self, rendition: &Rendition) -> Result<Self, Error> {
+		if self.is_lottie() || self.is_tgs() || self.is_webm() {
+			return Err(Error::UnsupportedOperation {
+				operation: "rendering",
+				file_name: self.file_name.display().to_string()
+			});
+		}
+		// frames together with the time, when they end
+		let (frames, (width, height)) = if self.is_webp() {
+			let decoder = Decoder::new(&self.data)?;
+			let dimensions = decoder.dimensions();
+			let frames: Vec<_> = decoder
+				.into_iter()
+				.map(|frame| (frame.data().to_vec(), frame.timestamp()))
+				.collect();
+			(frames, dimensions)
+		} else {
+			let img = open_image_from_bytes(&self.data)?;
+			let dimensions = (img.get_width(), img.get_height());
+			(vec![(img.get_raw_pixels(), 100)], dimensions)
+		};
+		let max_size = rendition.max_size.max(1);
+		let (new_width, new_height) =
+			Self::resize_preserving_aspect_ratio(width, height, Some(max_size.min(width)), Some(max_size.min(height)));
+
+		let prepare = |pixels: Vec<u8>| {
+			let mut pixels = if (new_width, new_height) == (width, height) {
+				pixels
+			} else {
+				let img = PhotonImage::new(pixels, width, height);
+				let filter = SamplingFilter::Auto.to_photon((width, height), (new_width, new_height));
+				transform::resize(&img, new_width, new_height, filter).get_raw_pixels()
+			};
+			if let Some(colors) = rendition.colors {
+				quantize(&mut pixels, colors);
+			}
+			pixels
+		};
+
+		// near lossless mode is only available for static images
+		let data = if rendition.webp.near_lossless.is_some() && frames.len() == 1 {
+			let (pixels, _) = frames.into_iter().next().unwrap();
+			rendition.webp.encode(&prepare(pixels), new_width, new_height)?
+		} else {
+			let options = EncoderOptions {
+				encoding_config: Some(rendition.webp.encoding_config()),
+				..Default::default()
+			};
+			let mut encoder = Encoder::new_with_options((new_width, new_height), options)?;
+			let mut start = 0;
+			for (pixels, end) in frames {
+				encoder.add_frame(&prepare(pixels), start)?;
+				start = end;
+			}
+			copy_buffer(&encoder.finalize(start)?)
+		};
+		Ok(Self {
+			file_name: self.file_name.with_extension("webp"),
+			data: Arc::new(data),
+			width: new_width,
+			height: new_height,
+			duration_ms: self.duration_ms,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Rendition;
+	use crate::image::Image;
+
+	/// Image with many colors, which does not compress well.
+	fn noisy_image(size: u32) -> Image {
+		let mut state: u32 = 42;
+		let pixels = (0..size * size)
+			.flat_map(|_| {
+				state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+				let [r, g, b, _] = state.to_le_bytes();
+				[r, g, b, 255]
+			})
+			.collect();
+		Image::from_rgba(pixels, size, size, "sticker.png").unwrap()
+	}
+
+	#[test]
+	fn lite() {
+		let image = noisy_image(512);
+		let lite = image.render(&Rendition::lite()).unwrap();
+		assert_eq!((lite.width, lite.height), (256, 256));
+		assert_eq!(lite.file_name_str(), "sticker.webp");
+		assert!(
+			lite.data_len() * 4 < image.data_len(),
+			"lite rendition has {} bytes, original {} bytes",
+			lite.data_len(),
+			image.data_len()
+		);
+		let (pixels, width, height) = lite.decode_rgba().unwrap();
+		assert_eq!((width, height), (256, 256));
+		assert!(!pixels.is_empty());
+	}
+
+	#[test]
+	fn animated() {
+		let mut encoder = webp_animation::Encoder::new((64, 32)).unwrap();
+		for i in 0..3 {
+			encoder.add_frame(&[i * 80; 64 * 32 * 4], i as i32 * 40).unwrap();
+		}
+		let image = Image {
+			file_name: "sticker.webp".into(),
+			data: std::sync::Arc::new(encoder.finalize(120).unwrap().to_vec()),
+			width: 64,
+			height: 32,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let rendition = Rendition {
+			max_size: 16,
+			..Rendition::lite()
+		};
+		let rendered = image.render(&rendition).unwrap();
+		assert_eq!((rendered.width, rendered.height), (16, 8));
+		assert_eq!(rendered.frame_count().unwrap(), 3);
+		assert_eq!(rendered.duration().unwrap(), Some(120));
+	}
+}
@@ -0,0 +1,276 @@
+use super::Image;
+use crate::{
+	error::Error,
+	recorder::{self, HttpRecorder}
+};
+use photon_rs::native::open_image_from_bytes;
+use reqwest::{header::LOCATION, redirect::Policy, Client, Response};
+use serde::Deserialize;
+use std::{
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{Arc, OnceLock}
+};
+use url::Url;
+
+/// Maximum number of redirects, which are followed while downloading an image.
+const MAX_REDIRECTS: usize = 10;
+
+/// Client for downloading images, which does not follow redirects by itself,
+/// so that the host of every redirect can be checked against [`RemoteOptions::allowed_hosts`].
+static DOWNLOAD_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Location of an image, like it is referenced by the metadata of an import.
+///
+/// Can be parsed from a local path, a `file://` url or a `http(s)://` url.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub enum ImageSource {
+	Path(PathBuf),
+	Url(Url)
+}
+
+impl FromStr for ImageSource {
+	type Err = Error;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		let url = match Url::parse(value) {
+			Ok(url) => url,
+			// relative paths are no valid urls
+			Err(_) => return Ok(Self::Path(value.into()))
+		};
+		match url.scheme() {
+			"http" | "https" => Ok(Self::Url(url)),
+			"file" => url
+				.to_file_path()
+				.map(Self::Path)
+				.map_err(|_| Error::InvalidImageSource(value.to_owned())),
+			// windows path with drive letter, like `C:\sticker.webp`
+			scheme if scheme.len() == 1 => Ok(Self::Path(value.into())),
+			_ => Err(Error::InvalidImageSource(value.to_owned()))
+		}
+	}
+}
+
+impl TryFrom<String> for ImageSource {
+	type Error = Error;
+
+	fn try_from(value: String) -> Result<Self, Self::Error> {
+		value.parse()
+	}
+}
+
+/// Restrictions for downloading images from [`ImageSource::Url`].
+#[derive(Clone, Debug)]
+pub struct RemoteOptions {
+	/// maximum size of a downloaded image in bytes
+	pub max_size: u64,
+	/// Hosts, from which images can be downloaded. All hosts are allowed, if `None`.
+	/// Set this, if the metadata is not trusted, to avoid that requests to internal services are made.
+	pub allowed_hosts: Option<Vec<String>>,
+	/// mime types, which are accepted as `Content-Type` of downloaded images
+	pub allowed_types: Vec<String>,
+	/// If set, downloads are recorded or replayed, see [`HttpRecorder`].
+	pub http_recorder: Option<Arc<HttpRecorder>>
+}
+
+impl Default for RemoteOptions {
+	fn default() -> Self {
+		Self {
+			max_size: 10 * 1024 * 1024,
+			allowed_hosts: None,
+			allowed_types: ["image/webp", "image/png", "image/gif", "image/jpeg"]
+				.into_iter()
+				.map(ToOwned::to_owned)
+				.collect(),
+			http_recorder: None
+		}
+	}
+}
+
+impl ImageSource {
+	/// Read or download the image.
+	/// Images with an EXIF orientation are rotated, see [`Image::apply_exif_orientation`].
+	pub async fn load(&self, options: &RemoteOptions) -> Result<Image, Error> {
+		let (file_name, data) = match self {
+			Self::Path(path) => return Image::from_path(path).await,
+			Self::Url(url) => download(url, options).await?
+		};
+		let img = open_image_from_bytes(&data).map_err(|err| super::decode_error(Path::new(&file_name), &data, err))?;
+		Image {
+			file_name: file_name.into(),
+			width: img.get_width(),
+			height: img.get_height(),
+			data: Arc::new(data),
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		}
+		.apply_exif_orientation()
+	}
+}
+
+/// Download the image and return it together with a file name, whose extension does match the content type.
+async fn download(url: &Url, options: &RemoteOptions) -> Result<(String, Vec<u8>), Error> {
+	let mut response = get_following_redirects(url, options).await?.error_for_status()?;
+	let content_type = response
+		.headers()
+		.get(reqwest::header::CONTENT_TYPE)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.split(';').next())
+		.unwrap_or_default()
+		.trim()
+		.to_ascii_lowercase();
+	if !options.allowed_types.contains(&content_type) {
+		return Err(Error::UnsupportedContentType(content_type));
+	}
+	let too_large = || Error::RemoteImageTooLarge {
+		url: url.to_string(),
+		max_size: options.max_size
+	};
+	if response.content_length().is_some_and(|len| len > options.max_size) {
+		return Err(too_large());
+	}
+	// the content length can be missing or wrong, so check the size while reading
+	let mut data = Vec::new();
+	while let Some(chunk) = response.chunk().await? {
+		if (data.len() + chunk.len()) as u64 > options.max_size {
+			return Err(too_large());
+		}
+		data.extend_from_slice(&chunk);
+	}
+
+	let stem = url
+		.path_segments()
+		.and_then(|mut segments| segments.next_back())
+		.map(|name| Path::new(name).file_stem().unwrap_or_default().to_string_lossy().into_owned())
+		.filter(|stem| !stem.is_empty())
+		.unwrap_or_else(|| "image".to_owned());
+	let extension = content_type.strip_prefix("image/").unwrap_or(&content_type);
+	Ok((format!("{stem}.{extension}"), data))
+}
+
+/// Request `url` and follow redirects, while checking the host of each of them.
+async fn get_following_redirects(url: &Url, options: &RemoteOptions) -> Result<Response, Error> {
+	let client = DOWNLOAD_CLIENT.get_or_init(|| Client::builder().redirect(Policy::none()).build().unwrap());
+	let mut current = url.clone();
+	for _ in 0..=MAX_REDIRECTS {
+		check_host(&current, options)?;
+		let response = recorder::send(options.http_recorder.as_deref(), client.get(current.clone())).await?;
+		if !response.status().is_redirection() {
+			return Ok(response);
+		}
+		let Some(location) = response.headers().get(LOCATION).and_then(|value| value.to_str().ok()) else {
+			return Ok(response);
+		};
+		current = current
+			.join(location)
+			.ok()
+			.filter(|next| matches!(next.scheme(), "http" | "https"))
+			.ok_or_else(|| Error::InvalidImageSource(location.to_owned()))?;
+	}
+	Err(Error::TooManyRedirects(url.to_string()))
+}
+
+/// Check, that images may be downloaded from the host of `url`.
+fn check_host(url: &Url, options: &RemoteOptions) -> Result<(), Error> {
+	let host = url.host_str().unwrap_or_default();
+	if let Some(allowed_hosts) = &options.allowed_hosts {
+		if !allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+			return Err(Error::HostNotAllowed(host.to_owned()));
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ImageSource, RemoteOptions};
+	use crate::{
+		error::Error,
+		image::Image,
+		mock::{serve_file, serve_redirect}
+	};
+	use std::{net::TcpListener, path::PathBuf};
+
+	#[test]
+	fn parse() {
+		assert_eq!(
+			"stickers/cat.webp".parse::<ImageSource>().unwrap(),
+			ImageSource::Path("stickers/cat.webp".into())
+		);
+		assert_eq!(
+			"file:///tmp/cat.webp".parse::<ImageSource>().unwrap(),
+			ImageSource::Path(PathBuf::from("/tmp/cat.webp"))
+		);
+		assert!(matches!(
+			"https://cdn.example.org/cat.webp".parse::<ImageSource>().unwrap(),
+			ImageSource::Url(_)
+		));
+		assert!("ftp://example.org/cat.webp".parse::<ImageSource>().is_err());
+	}
+
+	#[tokio::test]
+	async fn download() {
+		let image = Image::from_rgba(vec![255; 8 * 4 * 4], 8, 4, "cat.webp").unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/assets/cat", listener.local_addr().unwrap());
+		let data = image.data.clone();
+		let server = std::thread::spawn(move || serve_file(listener, "image/webp", &data));
+		let options = RemoteOptions {
+			allowed_hosts: Some(vec!["127.0.0.1".to_owned()]),
+			..Default::default()
+		};
+		let downloaded = url.parse::<ImageSource>().unwrap().load(&options).await.unwrap();
+		server.join().unwrap();
+		assert_eq!(downloaded.file_name_str(), "cat.webp");
+		assert_eq!((downloaded.width, downloaded.height), (8, 4));
+		assert_eq!(downloaded.data, image.data);
+	}
+
+	#[tokio::test]
+	async fn host_not_allowed() {
+		let options = RemoteOptions {
+			allowed_hosts: Some(vec!["cdn.example.org".to_owned()]),
+			..Default::default()
+		};
+		// the host is checked before any request is made
+		let source: ImageSource = "http://169.254.169.254/latest/meta-data".parse().unwrap();
+		let err = source.load(&options).await.unwrap_err();
+		assert!(matches!(err, Error::HostNotAllowed(host) if host == "169.254.169.254"));
+	}
+
+	#[tokio::test]
+	async fn redirect() {
+		let image = Image::from_rgba(vec![255; 8 * 4 * 4], 8, 4, "cat.webp").unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/assets/cat", listener.local_addr().unwrap());
+		let data = image.data.clone();
+		let server = std::thread::spawn(move || {
+			let request = serve_redirect(&listener, "/cdn/cat");
+			serve_file(listener, "image/webp", &data);
+			request
+		});
+		let options = RemoteOptions {
+			allowed_hosts: Some(vec!["127.0.0.1".to_owned()]),
+			..Default::default()
+		};
+		let downloaded = url.parse::<ImageSource>().unwrap().load(&options).await.unwrap();
+		assert_eq!(server.join().unwrap(), "GET /assets/cat HTTP/1.1");
+		assert_eq!(downloaded.data, image.data);
+	}
+
+	#[tokio::test]
+	async fn redirect_to_host_not_allowed() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/assets/cat", listener.local_addr().unwrap());
+		let server = std::thread::spawn(move || serve_redirect(&listener, "http://169.254.169.254/latest/meta-data"));
+		let options = RemoteOptions {
+			allowed_hosts: Some(vec!["127.0.0.1".to_owned()]),
+			..Default::default()
+		};
+		let err = url.parse::<ImageSource>().unwrap().load(&options).await.unwrap_err();
+		server.join().unwrap();
+		assert!(matches!(err, Error::HostNotAllowed(host) if host == "169.254.169.254"));
+	}
+}
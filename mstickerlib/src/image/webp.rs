@@ -0,0 +1,167 @@
+use super::copy_buffer;
+use crate::error::Error;
+use libwebp_sys as webp;
+use serde::Deserialize;
+use std::{
+	ffi::{c_int, c_void},
+	mem::MaybeUninit,
+	slice
+};
+use webp_animation::EncodingConfig;
+
+/// Writer of libwebp, which does append the output to the [`WebPMemoryWriter`](webp::WebPMemoryWriter)
+/// stored at `custom_ptr` of the picture.
+extern "C" fn write_to_memory(data: *const u8, data_size: usize, picture: *const webp::WebPPicture) -> c_int {
+	// SAFETY: libwebp does call the writer only with the picture, whose `custom_ptr` points to the memory writer
+	unsafe { webp::WebPMemoryWrite(data, data_size, picture) }
+}
+
+/// How images are encoded as webp.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct WebpOptions {
+	/// Encode lossy with this quality between 0 and 100; lossless if `None`.
+	pub quality: Option<f32>,
+	/// Use the near lossless mode of libwebp with this preprocessing level between 0 and 100,
+	/// where 100 is lossless and lower levels produce smaller files.
+	/// This preserves the sharp edges of line art far better than lossy encoding with a similar size.
+	/// If set, [`WebpOptions::quality`] is ignored.
+	/// For animations near lossless mode is not available, so they are encoded lossless instead.
+	pub near_lossless: Option<u8>
+}
+
+impl WebpOptions {
+	/// Config for each frame of an animation.
+	pub(crate) fn encoding_config(&self) -> EncodingConfig {
+		match (self.near_lossless, self.quality) {
+			(None, Some(quality)) => EncodingConfig::new_lossy(quality),
+			_ => EncodingConfig::default()
+		}
+	}
+
+	/// Encode RGBA8 `pixels` as static webp.
+	pub(crate) fn encode(&self, pixels: &[u8], width: u32, height: u32) -> Result<Vec<u8>, Error> {
+		let expected = width as usize * height as usize * 4;
+		if pixels.len() != expected {
+			return Err(webp_animation::Error::BufferSizeFailed(expected, pixels.len()).into());
+		}
+		// SAFETY: config and picture are initialized by libwebp before they are used,
+		// the pixels have the size of the picture, which is checked above,
+		// and the output of the writer is copied before it is freed.
+		unsafe {
+			let mut config = MaybeUninit::<webp::WebPConfig>::uninit();
+			if webp::WebPConfigInit(config.as_mut_ptr()) == 0 {
+				return Err(webp_animation::Error::OptionsInitFailed.into());
+			}
+			let mut config = config.assume_init();
+			match (self.near_lossless, self.quality) {
+				(Some(level), _) => {
+					config.lossless = 1;
+					config.near_lossless = level.min(100) as i32;
+				},
+				(None, Some(quality)) => {
+					config.lossless = 0;
+					config.quality = quality;
+				},
+				(None, None) => config.lossless = 1
+			}
+			if webp::WebPValidateConfig(&config) == 0 {
+				return Err(webp_animation::Error::OptionsInitFailed.into());
+			}
+
+			let mut picture = MaybeUninit::<webp::WebPPicture>::uninit();
+			if webp::WebPPictureInit(picture.as_mut_ptr()) == 0 {
+				return Err(webp_animation::Error::OptionsInitFailed.into());
+			}
+			let mut picture = picture.assume_init();
+			picture.use_argb = 1;
+			picture.width = width as i32;
+			picture.height = height as i32;
+			if webp::WebPPictureImportRGBA(&mut picture, pixels.as_ptr(), width as i32 * 4) == 0 {
+				webp::WebPPictureFree(&mut picture);
+				return Err(webp_animation::Error::PictureImportFailed.into());
+			}
+
+			let mut writer = MaybeUninit::<webp::WebPMemoryWriter>::uninit();
+			webp::WebPMemoryWriterInit(writer.as_mut_ptr());
+			let mut writer = writer.assume_init();
+			picture.writer = Some(write_to_memory);
+			picture.custom_ptr = &mut writer as *mut webp::WebPMemoryWriter as *mut c_void;
+			let encoded = webp::WebPEncode(&config, &mut picture) != 0;
+			webp::WebPPictureFree(&mut picture);
+			let result = match encoded {
+				true => Ok(copy_buffer(slice::from_raw_parts(writer.mem, writer.size))),
+				false => Err(webp_animation::Error::EncoderAddFailed.into())
+			};
+			webp::WebPMemoryWriterClear(&mut writer);
+			result
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::WebpOptions;
+	use crate::image::Image;
+	use std::sync::Arc;
+
+	const SIZE: u32 = 64;
+
+	/// Black and red lines of one pixel width on white background.
+	fn line_art() -> Vec<u8> {
+		let mut pixels = Vec::with_capacity((SIZE * SIZE * 4) as usize);
+		for y in 0..SIZE {
+			for x in 0..SIZE {
+				let pixel = if x == y || x + y == SIZE - 1 {
+					[220, 0, 0, 255]
+				} else if x % 8 == 0 || y % 8 == 0 {
+					[0, 0, 0, 255]
+				} else {
+					[255, 255, 255, 255]
+				};
+				pixels.extend_from_slice(&pixel);
+			}
+		}
+		pixels
+	}
+
+	/// Number of pixels, which differ noticeable from the source.
+	fn differing_pixels(options: WebpOptions) -> usize {
+		let source = line_art();
+		let data = options.encode(&source, SIZE, SIZE).unwrap();
+		let image = Image {
+			file_name: "line_art.webp".into(),
+			data: Arc::new(data),
+			width: SIZE,
+			height: SIZE,
+			duration_ms: None,
+			pixel_cache: Default::default(),
+			content_hash: Default::default()
+		};
+		let (pixels, width, height) = image.decode_rgba().unwrap();
+		assert_eq!((width, height), (SIZE, SIZE));
+		pixels
+			.chunks_exact(4)
+			.zip(source.chunks_exact(4))
+			.filter(|(decoded, source)| decoded.iter().zip(*source).any(|(a, b)| a.abs_diff(*b) > 32))
+			.count()
+	}
+
+	#[test]
+	fn near_lossless_edges() {
+		let near_lossless = differing_pixels(WebpOptions {
+			near_lossless: Some(60),
+			quality: Some(10.0)
+		});
+		let lossy = differing_pixels(WebpOptions {
+			quality: Some(75.0),
+			near_lossless: None
+		});
+		let lossless = differing_pixels(WebpOptions::default());
+		assert_eq!(lossless, 0);
+		assert!(
+			near_lossless < lossy,
+			"near lossless: {near_lossless} pixels differ, lossy: {lossy} pixels"
+		);
+	}
+}
@@ -6,12 +6,34 @@
 
 pub mod database;
 pub mod error;
+mod exif;
+mod icc;
 pub mod image;
 pub mod matrix;
+#[cfg(test)]
+mod mock;
+pub mod recorder;
+pub mod signal;
 pub mod tg;
+mod trace;
 #[cfg(feature = "ffmpeg")]
 mod video;
 
+/// Re-exports of the types needed by the most users of this crate.
+///
+/// ```
+/// use mstickerlib::prelude::*;
+/// ```
+pub mod prelude {
+	pub use crate::{
+		database::{Database, DummyDatabase, DynDatabase, FileDatabase},
+		error::Error,
+		image::{AnimationFormat, ConversionConfig, Image},
+		matrix::{self, sticker::Sticker, stickerpack::StickerPack, Config, Mxc},
+		tg::{self, ImportConfig}
+	};
+}
+
 use std::sync::OnceLock;
 
 struct Client(OnceLock<reqwest::Client>);
@@ -31,6 +53,7 @@ impl Client {
 /// This function should be called before performing any other interaction with this create.
 /// Otherwise the client can not be set anymore and an error will be return.
 /// If this function is not called, the client will be automaticly initialize with [reqwest::Client::default]
+#[allow(clippy::result_unit_err)]
 pub fn set_client(client: reqwest::Client) -> Result<(), ()> {
 	init();
 	CLIENT.0.set(client).map_err(|_| ())
@@ -0,0 +1,87 @@
+//! Requests to the admin api of [Synapse](https://element-hq.github.io/synapse/latest/usage/administration/admin_api/),
+//! which need the access token of a server admin.
+
+use super::{Config, MatrixApiError, Mxc};
+use crate::{
+	error::{Error, MatrixError},
+	CLIENT
+};
+use reqwest::{StatusCode, Url};
+
+/// Delete an uploaded file from the homeserver, for example after its sticker was removed from a pack.
+///
+/// This uses the Synapse admin api, so [`Config::access_token`] must belong to a server admin.
+pub async fn delete_media(matrix: &Config, mxc: &Mxc) -> Result<(), Error> {
+	Url::parse(&matrix.homeserver_url)?; //check if homeserver_url is a valid url
+	let (server_name, media_id) = super::split_mxc(mxc.url())?;
+	let request = CLIENT
+		.get()
+		.delete(format!(
+			"{}/_synapse/admin/v1/media/{server_name}/{media_id}",
+			matrix.homeserver_url
+		))
+		.bearer_auth(&matrix.access_token);
+	let answer = matrix.send(request).await?;
+	match answer.status() {
+		StatusCode::OK => Ok(()),
+		StatusCode::NOT_FOUND => Err(Error::NotFound(mxc.url().to_owned())),
+		StatusCode::FORBIDDEN => Err(Error::PermissionDenied(mxc.url().to_owned())),
+		status => {
+			let error: Result<MatrixApiError, _> = answer.json().await;
+			Err(Error::MatrixUpload(MatrixError {
+				status_code: status,
+				filename: None,
+				matrix_error: error
+			}))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::delete_media;
+	use crate::{
+		error::Error,
+		matrix::Mxc,
+		mock::{matrix_config, serve_status}
+	};
+	use std::net::TcpListener;
+
+	async fn delete(status: &'static str, body: &'static str) -> Result<(), Error> {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_status(listener, status, body));
+		let mxc = Mxc::from("mxc://example.org/abc".to_owned());
+		let result = delete_media(&matrix_config, &mxc).await;
+		server.join().unwrap();
+		result
+	}
+
+	#[tokio::test]
+	async fn deleted() {
+		delete("200 OK", r#"{"deleted_media":["abc"],"total":1}"#).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn errors() {
+		let err = delete("404 Not Found", r#"{"errcode":"M_NOT_FOUND","error":"Unknown media"}"#)
+			.await
+			.unwrap_err();
+		assert!(matches!(err, Error::NotFound(mxc) if mxc == "mxc://example.org/abc"));
+		let err = delete(
+			"403 Forbidden",
+			r#"{"errcode":"M_FORBIDDEN","error":"You are not a server admin"}"#
+		)
+		.await
+		.unwrap_err();
+		assert!(matches!(err, Error::PermissionDenied(_)));
+	}
+
+	#[tokio::test]
+	async fn invalid_mxc() {
+		let matrix_config = matrix_config("https://example.org");
+		let mxc = Mxc::from("https://example.org/abc".to_owned());
+		let err = delete_media(&matrix_config, &mxc).await.unwrap_err();
+		assert!(matches!(err, Error::InvalidMxc(_)));
+	}
+}
@@ -0,0 +1,203 @@
+use super::stickerpack::{shortcodes, StickerPack};
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::{
+	path::{Path, PathBuf},
+	str::FromStr
+};
+use tokio::fs;
+
+/// Format of the alias map, see [`export_alias_map`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AliasFormat {
+	Json,
+	Csv
+}
+
+impl AliasFormat {
+	pub fn extension(self) -> &'static str {
+		match self {
+			Self::Json => "json",
+			Self::Csv => "csv"
+		}
+	}
+}
+
+impl FromStr for AliasFormat {
+	type Err = Error;
+
+	fn from_str(value: &str) -> Result<Self, Self::Err> {
+		match value {
+			"json" => Ok(Self::Json),
+			"csv" => Ok(Self::Csv),
+			_ => Err(Error::InvalidAliasFormat(value.to_owned()))
+		}
+	}
+}
+
+/// Line of the alias map.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AliasEntry {
+	pub shortcode: String,
+	pub mxc: String,
+	/// text, which bridges can show instead of the sticker: its emoji, or its body if it has none
+	pub fallback: String
+}
+
+/// Quote a csv field, if it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+	if field.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_owned()
+	}
+}
+
+/// Flat mapping of the shortcodes of `pack` to their url and a fallback text, for bridges to networks without custom emotes.
+///
+/// The shortcodes are the same as for ponies packs. Entries are sorted by shortcode, so the output is stable.
+/// Csv output starts with the header `shortcode,mxc,fallback`; json output is an array of [`AliasEntry`].
+pub fn export_alias_map(pack: &StickerPack, format: AliasFormat) -> String {
+	let mut entries: Vec<AliasEntry> = shortcodes(pack)
+		.into_iter()
+		.map(|(shortcode, sticker)| AliasEntry {
+			shortcode,
+			mxc: sticker.image.url.url().to_owned(),
+			fallback: match sticker.emoji.is_empty() {
+				true => sticker.body.clone(),
+				false => sticker.emoji.concat()
+			}
+		})
+		.collect();
+	entries.sort_by(|a, b| a.shortcode.cmp(&b.shortcode));
+	match format {
+		AliasFormat::Json => serde_json::to_string_pretty(&entries).unwrap(),
+		AliasFormat::Csv => {
+			let mut csv = "shortcode,mxc,fallback\n".to_owned();
+			for entry in entries {
+				let fields = [&entry.shortcode, &entry.mxc, &entry.fallback].map(|field| csv_field(field));
+				csv += &fields.join(",");
+				csv.push('\n');
+			}
+			csv
+		}
+	}
+}
+
+/// Write the alias map of `pack` next to its stickerpicker file `pack_path`, as `<name>.aliases.<extension>`.
+/// Return the path of the written file.
+pub async fn write_alias_map(pack_path: &Path, pack: &StickerPack, format: AliasFormat) -> Result<PathBuf, Error> {
+	let name = pack_path.file_stem().unwrap_or_default().to_string_lossy();
+	let path = pack_path.with_file_name(format!("{name}.aliases.{}", format.extension()));
+	fs::write(&path, export_alias_map(pack, format)).await?;
+	Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{export_alias_map, AliasEntry, AliasFormat};
+	use crate::matrix::{
+		sticker::{Image, Sticker},
+		sticker_formats::ponies::MetaData,
+		stickerpack::StickerPack
+	};
+
+	fn sticker(emoticon: Option<&str>, emoji: &[&str], url: &str) -> Sticker {
+		Sticker {
+			body: "a sticker, \"quoted\"".to_owned(),
+			display_name: None,
+			image: Image {
+				url: url.to_owned().into(),
+				meta_data: MetaData {
+					w: 256,
+					h: 256,
+					size: 1024,
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: emoticon.map(str::to_owned),
+			emoji: emoji.iter().map(|emoji| (*emoji).to_owned()).collect(),
+			tg_sticker: None,
+			content_hash: None
+		}
+	}
+
+	fn pack() -> StickerPack {
+		StickerPack {
+			title: "Cats".to_owned(),
+			id: "cats".to_owned(),
+			tg_pack: None,
+			stickers: vec![
+				sticker(Some("kot"), &["🐱"], "mxc://example.org/kot"),
+				sticker(None, &[], "mxc://example.org/second"),
+				sticker(Some("happy_kot"), &["😸", "😺"], "mxc://example.org/happy"),
+			]
+		}
+	}
+
+	fn expected() -> Vec<AliasEntry> {
+		let entry = |shortcode: &str, mxc: &str, fallback: &str| AliasEntry {
+			shortcode: shortcode.to_owned(),
+			mxc: mxc.to_owned(),
+			fallback: fallback.to_owned()
+		};
+		vec![
+			entry("0001", "mxc://example.org/second", "a sticker, \"quoted\""),
+			entry("happy_kot", "mxc://example.org/happy", "😸😺"),
+			entry("kot", "mxc://example.org/kot", "🐱"),
+		]
+	}
+
+	/// Minimal csv reader, which does understand quoted fields.
+	fn parse_csv(csv: &str) -> Vec<Vec<String>> {
+		let mut rows = Vec::new();
+		let (mut row, mut field) = (Vec::new(), String::new());
+		let (mut quoted, mut chars) = (false, csv.chars().peekable());
+		while let Some(c) = chars.next() {
+			match (c, quoted) {
+				('"', true) if chars.peek() == Some(&'"') => {
+					chars.next();
+					field.push('"');
+				},
+				('"', _) => quoted = !quoted,
+				(',', false) => row.push(std::mem::take(&mut field)),
+				('\n', false) => {
+					row.push(std::mem::take(&mut field));
+					rows.push(std::mem::take(&mut row));
+				},
+				(c, _) => field.push(c)
+			}
+		}
+		rows
+	}
+
+	#[test]
+	fn json_round_trip() {
+		let json = export_alias_map(&pack(), AliasFormat::Json);
+		let entries: Vec<AliasEntry> = serde_json::from_str(&json).unwrap();
+		assert_eq!(entries, expected());
+	}
+
+	#[test]
+	fn csv_round_trip() {
+		let csv = export_alias_map(&pack(), AliasFormat::Csv);
+		let rows = parse_csv(&csv);
+		assert_eq!(rows[0], ["shortcode", "mxc", "fallback"]);
+		let entries: Vec<_> = rows[1..]
+			.iter()
+			.map(|row| AliasEntry {
+				shortcode: row[0].clone(),
+				mxc: row[1].clone(),
+				fallback: row[2].clone()
+			})
+			.collect();
+		assert_eq!(entries, expected());
+		assert!(csv.contains(r#""a sticker, ""quoted""""#));
+		assert_eq!("csv".parse::<AliasFormat>().unwrap(), AliasFormat::Csv);
+		assert!("xml".parse::<AliasFormat>().is_err());
+	}
+}
@@ -0,0 +1,186 @@
+use super::{upload_ref, Config, Mxc};
+use crate::error::Error;
+use aes::{
+	cipher::{KeyIvInit, StreamCipher},
+	Aes256
+};
+use base64::{
+	engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD},
+	Engine as _
+};
+use ctr::Ctr128BE;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+/// En- or decrypt `data` in place with AES-256 in counter mode, starting at the counter block `iv`.
+fn apply_keystream(key: &[u8; 32], iv: &[u8; 16], data: &mut [u8]) {
+	let mut cipher = Ctr128BE::<Aes256>::new(key.into(), iv.into());
+	cipher.apply_keystream(data);
+}
+
+/// Key of an encrypted file as JSON Web Key, like the `key` field of the
+/// [`EncryptedFile`](https://spec.matrix.org/v1.10/client-server-api/#extensions-to-mroommessage-msgtypes) of the Matrix spec.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Jwk {
+	/// always `oct`
+	pub kty: String,
+	/// always `encrypt` and `decrypt`
+	pub key_ops: Vec<String>,
+	/// always `A256CTR`
+	pub alg: String,
+	/// the key as unpadded url safe base64
+	pub k: String,
+	/// always `true`
+	pub ext: bool
+}
+
+impl Jwk {
+	fn new(key: &[u8; 32]) -> Self {
+		Self {
+			kty: "oct".to_owned(),
+			key_ops: vec!["encrypt".to_owned(), "decrypt".to_owned()],
+			alg: "A256CTR".to_owned(),
+			k: URL_SAFE_NO_PAD.encode(key),
+			ext: true
+		}
+	}
+}
+
+/// Everything, which is needed to decrypt a file uploaded by [`upload_encrypted`].
+/// Together with the url this is serialized as the `file` field of a sticker event in an encrypted room.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct EncryptedFileInfo {
+	pub key: Jwk,
+	/// serialized as unpadded base64
+	#[serde(serialize_with = "serialize_iv", deserialize_with = "deserialize_iv")]
+	pub iv: [u8; 16],
+	/// unpadded base64 of the hashes of the ciphertext by algorithm, which is always `sha256`
+	pub hashes: HashMap<String, String>,
+	/// version of the encryption scheme, always `v2`
+	pub v: String
+}
+
+fn serialize_iv<S>(iv: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error>
+where
+	S: Serializer
+{
+	serializer.serialize_str(&STANDARD_NO_PAD.encode(iv))
+}
+
+fn deserialize_iv<'de, D>(deserializer: D) -> Result<[u8; 16], D::Error>
+where
+	D: Deserializer<'de>
+{
+	let iv = String::deserialize(deserializer)?;
+	let iv = STANDARD_NO_PAD.decode(iv.trim_end_matches('=')).map_err(de::Error::custom)?;
+	iv.try_into()
+		.map_err(|iv: Vec<u8>| de::Error::invalid_length(iv.len(), &"16 bytes"))
+}
+
+/// Encrypt `data` with a new random key and return the ciphertext.
+fn encrypt(data: &[u8]) -> Result<(Vec<u8>, EncryptedFileInfo), Error> {
+	// the key is only kept as base64 inside the returned `Jwk`
+	let mut key = Zeroizing::new([0; 32]);
+	getrandom::getrandom(key.as_mut())?;
+	// the lower half is the counter, which starts at zero, so it can not overflow
+	let mut iv = [0; 16];
+	getrandom::getrandom(&mut iv[..8])?;
+	let mut ciphertext = data.to_vec();
+	apply_keystream(&key, &iv, &mut ciphertext);
+	let hash = STANDARD_NO_PAD.encode(Sha256::digest(&ciphertext));
+	let info = EncryptedFileInfo {
+		key: Jwk::new(&key),
+		iv,
+		hashes: HashMap::from([("sha256".to_owned(), hash)]),
+		v: "v2".to_owned()
+	};
+	Ok((ciphertext, info))
+}
+
+/// Encrypt `data` with AES-256 in counter mode and a random key, and upload the ciphertext,
+/// so it can be used as sticker in an encrypted room.
+///
+/// The file is uploaded as `application/octet-stream` without a meaningful name, so the server does learn neither
+/// its type nor its content. The mimetype belongs to the `info` of the event instead.
+/// The database is not used, since every upload has its own key.
+pub async fn upload_encrypted(config: &Config, data: &[u8]) -> Result<(Mxc, EncryptedFileInfo), Error> {
+	let (ciphertext, info) = encrypt(data)?;
+	let mxc = upload_ref(config, &"encrypted".to_owned(), &ciphertext, "application/octet-stream").await?;
+	Ok((mxc, info))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{apply_keystream, encrypt, upload_encrypted, EncryptedFileInfo};
+	use crate::mock::{matrix_config, serve_uploads};
+	use base64::{
+		engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD},
+		Engine as _
+	};
+	use sha2::{Digest, Sha256};
+	use std::net::TcpListener;
+
+	fn hex<const N: usize>(hex: &str) -> [u8; N] {
+		std::array::from_fn(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+	}
+
+	/// Example F.5.5 of NIST SP 800-38A, whose counter does carry into the upper half.
+	#[test]
+	fn ctr_vector() {
+		let key = hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+		let iv = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+		let mut data: [u8; 64] = hex(concat!(
+			"6bc1bee22e409f96e93d7e117393172a",
+			"ae2d8a571e03ac9c9eb76fac45af8e51",
+			"30c81c46a35ce411e5fbc1191a0a52ef",
+			"f69f2445df4f9b17ad2b417be66c3710"
+		));
+		apply_keystream(&key, &iv, &mut data);
+		assert_eq!(
+			data,
+			hex(concat!(
+				"601ec313775789a5b7a7f504bbf3d228",
+				"f443e3ca4d62b59aca84e990cacaf5c5",
+				"2b0930daa23de94ce87017ba2d84988d",
+				"dfc9c58db67aada613c2dd08457941a6"
+			))
+		);
+	}
+
+	#[test]
+	fn round_trip() {
+		let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+		let (mut ciphertext, info) = encrypt(&data).unwrap();
+		assert_ne!(ciphertext, data);
+		assert_eq!(info.iv[8..], [0; 8]);
+		assert_eq!(info.hashes["sha256"], STANDARD_NO_PAD.encode(Sha256::digest(&ciphertext)));
+
+		let json = serde_json::to_value(&info).unwrap();
+		assert_eq!(json["v"], "v2");
+		assert_eq!(json["key"]["kty"], "oct");
+		assert_eq!(json["key"]["alg"], "A256CTR");
+		assert_eq!(json["key"]["key_ops"], serde_json::json!(["encrypt", "decrypt"]));
+		assert_eq!(json["key"]["ext"], true);
+		assert_eq!(json["iv"].as_str().unwrap().len(), 22);
+		let info: EncryptedFileInfo = serde_json::from_value(json).unwrap();
+
+		let key: [u8; 32] = URL_SAFE_NO_PAD.decode(&info.key.k).unwrap().try_into().unwrap();
+		apply_keystream(&key, &info.iv, &mut ciphertext);
+		assert_eq!(ciphertext, data);
+		// every file gets its own key
+		assert_ne!(encrypt(&data).unwrap().1.key, info.key);
+	}
+
+	#[tokio::test]
+	async fn upload() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_uploads(listener, 1));
+		let (mxc, info) = upload_encrypted(&config, b"sticker").await.unwrap();
+		server.join().unwrap();
+		assert_eq!(mxc.url(), "mxc://example.org/abc");
+		assert_eq!(info.hashes.len(), 1);
+	}
+}
@@ -0,0 +1,92 @@
+use crate::{
+	database::{self, Database},
+	error::Error,
+	image::{ConversionConfig, Image}
+};
+use std::collections::HashSet;
+
+/// Result of [`estimate`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct EstimateReport {
+	/// bytes, which would be sent to the homeserver
+	pub to_upload_bytes: u64,
+	/// bytes of images, which are already known to the database and would not be uploaded again
+	pub cached_bytes: u64,
+	pub to_upload_count: usize,
+	pub cached_count: usize
+}
+
+/// Convert `images` like [`Image::convert`] and look them up at the database, to preview how many bytes an upload
+/// of the batch would send, without uploading anything.
+///
+/// Images, which occur multiple times in the batch, are only counted once as upload, if a database is given,
+/// since [`Image::upload`] does find the later ones at the database.
+pub async fn estimate<D>(images: &[Image], config: &ConversionConfig, database: Option<&D>) -> Result<EstimateReport, Error>
+where
+	D: Database + ?Sized
+{
+	let mut report = EstimateReport::default();
+	let mut seen = HashSet::new();
+	for image in images {
+		let image = image.clone().convert(config, None, None, None).await?;
+		let bytes = image.data_len() as u64;
+		let cached = match database {
+			Some(db) => {
+				let hash = image.content_hash();
+				!seen.insert(hash) || database::retry_unavailable(|| db.get(&hash)).await?.is_some()
+			},
+			None => false
+		};
+		if cached {
+			report.cached_bytes += bytes;
+			report.cached_count += 1;
+		} else {
+			report.to_upload_bytes += bytes;
+			report.to_upload_count += 1;
+		}
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{estimate, EstimateReport};
+	use crate::{
+		database::{self, Database, DummyDatabase, FileDatabase},
+		image::{ConversionConfig, Image}
+	};
+
+	#[tokio::test]
+	async fn cached_and_new() {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-estimate-test-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let db = FileDatabase::new(dir.join("db.txt")).await.unwrap();
+		let images: Vec<_> = [0, 100, 100, 200]
+			.into_iter()
+			.map(|value| Image::from_rgba(vec![value; 4 * 4 * 4], 4, 4, format!("{value}.webp")).unwrap())
+			.collect();
+		db.add(database::hash(images[0].data()), "mxc://example.org/known".to_owned())
+			.await
+			.unwrap();
+		let size = |index: usize| images[index].data_len() as u64;
+
+		// the first image is known and the duplicate is uploaded only once
+		let report = estimate(&images, &ConversionConfig::default(), Some(&db)).await.unwrap();
+		assert_eq!(report, EstimateReport {
+			to_upload_bytes: size(1) + size(3),
+			cached_bytes: size(0) + size(2),
+			to_upload_count: 2,
+			cached_count: 2
+		});
+		// nothing was uploaded or added to the database
+		assert_eq!(db.get(&database::hash(images[1].data())).await.unwrap(), None);
+
+		let report = estimate(&images, &ConversionConfig::default(), None::<&DummyDatabase>)
+			.await
+			.unwrap();
+		assert_eq!(report.to_upload_count, 4);
+		assert_eq!(report.to_upload_bytes, (0..4).map(size).sum::<u64>());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
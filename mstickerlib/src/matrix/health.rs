@@ -0,0 +1,88 @@
+use super::{media_config, whoami, Config};
+use crate::error::Error;
+
+/// Result of [`health_check`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthReport {
+	/// user, to which the access token belongs
+	pub user_id: String,
+	/// maximum size of an upload in bytes, if the homeserver has a limit
+	pub media_limit: Option<u64>,
+	/// If the homeserver does support the authenticated media endpoints, introduced by matrix 1.11.
+	pub authenticated_media_supported: bool
+}
+
+/// Check that the homeserver is reachable and the access token is valid, before starting a long import.
+/// Fails with the error of the first check, which was not successful.
+pub async fn health_check(matrix: &Config) -> Result<HealthReport, Error> {
+	let user_id = whoami(matrix).await?;
+	let media_config = media_config(matrix).await?;
+	Ok(HealthReport {
+		user_id,
+		media_limit: media_config.upload_size,
+		authenticated_media_supported: media_config.authenticated
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{health_check, HealthReport};
+	use crate::{
+		error::Error,
+		matrix::whoami,
+		mock::{matrix_config, serve_routes}
+	};
+	use std::net::TcpListener;
+
+	const WHOAMI: (&str, &str) = (
+		"/_matrix/client/v3/account/whoami",
+		r#"{"user_id":"@alice:example.org","device_id":"ABCDEF"}"#
+	);
+
+	async fn check(routes: &'static [(&'static str, &'static str)], count: usize) -> Result<HealthReport, Error> {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_routes(listener, routes, count));
+		let report = health_check(&matrix_config).await;
+		server.join().unwrap();
+		report
+	}
+
+	#[tokio::test]
+	async fn user_id() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_routes(listener, &[WHOAMI], 1));
+		assert_eq!(whoami(&matrix_config).await.unwrap(), "@alice:example.org");
+		server.join().unwrap();
+	}
+
+	#[tokio::test]
+	async fn authenticated_media() {
+		let report = check(
+			&[WHOAMI, ("/_matrix/client/v1/media/config", r#"{"m.upload.size":52428800}"#)],
+			2
+		)
+		.await
+		.unwrap();
+		assert_eq!(report, HealthReport {
+			user_id: "@alice:example.org".to_owned(),
+			media_limit: Some(52428800),
+			authenticated_media_supported: true
+		});
+	}
+
+	#[tokio::test]
+	async fn legacy_media() {
+		let report = check(&[WHOAMI, ("/_matrix/media/v3/config", "{}")], 3).await.unwrap();
+		assert_eq!(report.media_limit, None);
+		assert!(!report.authenticated_media_supported);
+	}
+
+	#[tokio::test]
+	async fn invalid_token() {
+		// the whoami endpoint is missing, so the check fails before the media config is requested
+		let err = check(&[], 1).await.unwrap_err();
+		assert!(matches!(err, Error::MatrixUpload(err) if err.status_code == 404));
+	}
+}
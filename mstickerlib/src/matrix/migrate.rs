@@ -0,0 +1,439 @@
+//! Move the packs of a stickerpicker directory to another homeserver, for example after a server migration.
+
+use super::{download, sticker_formats::maunium::StickerPack, upload, Config};
+use crate::{database, error::Error};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{BTreeMap, BTreeSet, HashSet},
+	path::{Path, PathBuf},
+	sync::Arc
+};
+use tokio::fs;
+
+#[cfg(feature = "log")]
+use log::{info, warn};
+
+/// Default name of the state file at the input directory.
+const STATE_FILE: &str = "migration-state.json";
+
+/// Options of [`rehost_packs`].
+#[derive(Clone, Debug, Default)]
+pub struct MigrationOptions {
+	/// File, which stores the progress, so an interrupted migration continues where it has stopped, when it is run again.
+	/// Defaults to `migration-state.json` at the input directory.
+	pub state_file: Option<PathBuf>
+}
+
+/// Result of [`rehost_packs`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MigrationReport {
+	/// pack files, which were rewritten with new urls
+	pub files: Vec<PathBuf>,
+	/// pack files, which were completed by a previous run and were left unchanged
+	pub skipped_files: Vec<PathBuf>,
+	/// urls, which were replaced at the packs by this run, with their new url
+	pub rewritten: BTreeMap<String, String>,
+	/// number of files uploaded to the new homeserver
+	pub uploaded: usize,
+	/// urls, which the old homeserver does not have anymore; the packs keep them
+	pub missing: Vec<String>,
+	/// urls, which could not be moved, with the reason; the packs keep them and the next run does try them again
+	pub failed: Vec<(String, String)>
+}
+
+/// Progress of a migration, which is stored at the state file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct MigrationState {
+	/// new url by old url
+	urls: BTreeMap<String, String>,
+	/// new url by hex encoded hash of the file, so a file with multiple urls is uploaded only once
+	hashes: BTreeMap<String, String>,
+	/// names of the pack files, which are migrated completely
+	done: BTreeSet<String>
+}
+
+/// Replace the file at `path` by `data`, so it is never left half written.
+async fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+	let mut temp = path.as_os_str().to_owned();
+	temp.push(".tmp");
+	fs::write(&temp, data).await?;
+	fs::rename(&temp, path).await?;
+	Ok(())
+}
+
+/// Json files at `dir`, sorted by name, without the state file.
+async fn pack_files(dir: &Path, state_path: &Path) -> Result<Vec<PathBuf>, Error> {
+	let mut files = Vec::new();
+	let mut entries = fs::read_dir(dir).await?;
+	while let Some(entry) = entries.next_entry().await? {
+		let path = entry.path();
+		if path.extension().is_some_and(|extension| extension == "json") && path != state_path {
+			files.push(path);
+		}
+	}
+	files.sort();
+	Ok(files)
+}
+
+struct Migration<'a> {
+	old_config: &'a Config,
+	new_config: &'a Config,
+	state_path: PathBuf,
+	state: MigrationState,
+	/// urls at the new homeserver, which are already migrated, if a pack was rewritten by a previous run
+	new_urls: HashSet<String>,
+	report: MigrationReport
+}
+
+impl Migration<'_> {
+	async fn save_state(&self) -> Result<(), Error> {
+		write_atomic(&self.state_path, &serde_json::to_vec(&self.state)?).await
+	}
+
+	fn fail(&mut self, url: &str, err: Error) {
+		#[cfg(feature = "log")]
+		warn!("failed to move {url}: {err}");
+		self.report.failed.push((url.to_owned(), err.to_string()));
+	}
+
+	/// Move the file of `url` to the new homeserver, if this was not done yet, and return its new url.
+	/// Return `None`, if it is missing or could not be moved.
+	async fn rehost(&mut self, url: &str) -> Result<Option<String>, Error> {
+		if let Some(new_url) = self.state.urls.get(url) {
+			return Ok(Some(new_url.clone()));
+		}
+		if self.new_urls.contains(url) {
+			return Ok(Some(url.to_owned()));
+		}
+		// do not try again, if the url is used multiple times
+		if self.report.missing.iter().any(|missing| missing == url)
+			|| self.report.failed.iter().any(|(failed, _)| failed == url)
+		{
+			return Ok(None);
+		}
+		let (data, content_type) = match download(self.old_config, url).await {
+			Ok(file) => file,
+			Err(Error::NotFound(_)) => {
+				#[cfg(feature = "log")]
+				warn!("{url} is missing at the old homeserver, keep url");
+				self.report.missing.push(url.to_owned());
+				return Ok(None);
+			},
+			Err(err) => {
+				self.fail(url, err);
+				return Ok(None);
+			}
+		};
+		let hash: String = database::hash(&data).iter().map(|byte| format!("{byte:02x}")).collect();
+		let new_url = match self.state.hashes.get(&hash) {
+			Some(new_url) => new_url.clone(),
+			None => {
+				let (_, media_id) = super::split_mxc(url)?;
+				match upload(self.new_config, &media_id.to_owned(), Arc::new(data), &content_type).await {
+					Ok(mxc) => {
+						self.report.uploaded += 1;
+						self.state.hashes.insert(hash, mxc.url().to_owned());
+						mxc.url().to_owned()
+					},
+					Err(err) => {
+						self.fail(url, err);
+						return Ok(None);
+					}
+				}
+			}
+		};
+		self.state.urls.insert(url.to_owned(), new_url.clone());
+		self.new_urls.insert(new_url.clone());
+		self.save_state().await?;
+		Ok(Some(new_url))
+	}
+
+	/// Move all files of the pack at `path` and rewrite it. Other json files are ignored.
+	async fn migrate_pack(&mut self, path: PathBuf) -> Result<(), Error> {
+		let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+		if self.state.done.contains(&name) {
+			self.report.skipped_files.push(path);
+			return Ok(());
+		}
+		// ignore other json files at the directory, like the `index.json`
+		let Ok(mut pack) = serde_json::from_slice::<StickerPack>(&fs::read(&path).await?) else {
+			return Ok(());
+		};
+		let (mut complete, mut changed) = (true, false);
+		for sticker in &mut pack.stickers {
+			for url in [&mut sticker.url, &mut sticker.info.thumbnail_url] {
+				match self.rehost(url.url()).await? {
+					Some(new_url) if new_url != *url.url() => {
+						self.report.rewritten.insert(url.url().to_owned(), new_url.clone());
+						*url = new_url.into();
+						changed = true;
+					},
+					Some(_) => (),
+					None => complete = false
+				}
+			}
+		}
+		if changed {
+			write_atomic(&path, serde_json::to_string(&pack)?.as_bytes()).await?;
+			#[cfg(feature = "log")]
+			info!("rewrote {}", path.display());
+			self.report.files.push(path);
+		}
+		if complete {
+			self.state.done.insert(name);
+			self.save_state().await?;
+		}
+		Ok(())
+	}
+}
+
+/// Move all media of the stickerpicker packs at `input_dir` from the homeserver of `old_config`
+/// to the homeserver of `new_config`, and rewrite the pack files with the new urls.
+///
+/// Every file is uploaded only once, even if it is used by multiple stickers or packs, or has multiple urls.
+/// The progress is saved at the [`MigrationOptions::state_file`] after every file, so running the migration again
+/// after an interruption continues, where it has stopped; completed packs are skipped.
+/// Each pack file is replaced atomically after all of its media were handled.
+/// Media, which are missing at the old homeserver or can not be moved, are listed at the report
+/// and do not stop the migration; the packs keep their old urls.
+///
+/// Downloads and uploads are limited by the [`Config::rate_limiter`] of their config;
+/// use the same limiter for both configs, to limit them together.
+pub async fn rehost_packs(
+	input_dir: &Path,
+	old_config: &Config,
+	new_config: &Config,
+	options: &MigrationOptions
+) -> Result<MigrationReport, Error> {
+	let state_path = options.state_file.clone().unwrap_or_else(|| input_dir.join(STATE_FILE));
+	let state: MigrationState = match fs::try_exists(&state_path).await? {
+		true => serde_json::from_slice(&fs::read(&state_path).await?)?,
+		false => MigrationState::default()
+	};
+	let mut migration = Migration {
+		old_config,
+		new_config,
+		new_urls: state.urls.values().cloned().collect(),
+		state_path,
+		state,
+		report: MigrationReport::default()
+	};
+	for path in pack_files(input_dir, &migration.state_path).await? {
+		migration.migrate_pack(path).await?;
+	}
+	Ok(migration.report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{rehost_packs, MigrationOptions, MigrationReport};
+	use crate::{
+		matrix::Config,
+		mock::{matrix_config, serve_errors, serve_media, serve_numbered_uploads}
+	};
+	use std::{
+		collections::BTreeMap,
+		net::TcpListener,
+		path::{Path, PathBuf}
+	};
+
+	const A: &[u8] = b"sticker a";
+	const B: &[u8] = b"sticker b";
+
+	fn config(listener: &TcpListener) -> Config {
+		matrix_config(format!("http://{}", listener.local_addr().unwrap()))
+	}
+
+	/// Pack in the format of the maunium stickerpicker, whose stickers use the same url for image and thumbnail.
+	fn pack(id: &str, urls: &[&str]) -> String {
+		let stickers: Vec<_> = urls
+			.iter()
+			.enumerate()
+			.map(|(i, url)| {
+				let info = serde_json::json!({"w": 256, "h": 256, "size": 9, "mimetype": "image/webp"});
+				let mut full_info = info.clone();
+				full_info["thumbnail_url"] = (*url).into();
+				full_info["thumbnail_info"] = info;
+				serde_json::json!({"body": "🐱", "url": url, "info": full_info, "msgtype": "m.sticker", "id": format!("{id}-{i}")})
+			})
+			.collect();
+		serde_json::json!({"title": id, "id": id, "stickers": stickers}).to_string()
+	}
+
+	fn setup(name: &str, packs: &[(&str, &[&str])]) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-migrate-{name}-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		for (id, urls) in packs {
+			std::fs::write(dir.join(format!("{id}.json")), pack(id, urls)).unwrap();
+		}
+		std::fs::write(dir.join("index.json"), r#"{"packs": ["cats.json", "dogs.json"]}"#).unwrap();
+		dir
+	}
+
+	/// Image and thumbnail urls of the pack file.
+	fn urls(path: &Path) -> Vec<String> {
+		let pack: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+		pack["stickers"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.flat_map(|sticker| [&sticker["url"], &sticker["info"]["thumbnail_url"]])
+			.map(|url| url.as_str().unwrap().to_owned())
+			.collect()
+	}
+
+	/// Run a migration, where the old homeserver does answer `downloads` requests.
+	/// `new_homeserver` gets the listener of the new one and must answer all uploads.
+	async fn migrate<F>(
+		dir: &Path,
+		files: &'static [(&str, &[u8])],
+		downloads: usize,
+		new_homeserver: F
+	) -> (MigrationReport, Vec<String>)
+	where
+		F: FnOnce(TcpListener) + Send + 'static
+	{
+		let old_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let new_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let (old_config, new_config) = (config(&old_listener), config(&new_listener));
+		let old = std::thread::spawn(move || serve_media(old_listener, files, downloads));
+		std::thread::spawn(move || new_homeserver(new_listener));
+		let report = rehost_packs(dir, &old_config, &new_config, &MigrationOptions::default())
+			.await
+			.unwrap();
+		(report, old.join().unwrap())
+	}
+
+	fn rewritten(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+		entries
+			.iter()
+			.map(|(old, new)| ((*old).to_owned(), (*new).to_owned()))
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn complete() {
+		// `c` has the same content as `a`, so it is uploaded only once
+		const FILES: &[(&str, &[u8])] = &[("mxc://old.org/a", A), ("mxc://old.org/b", B), ("mxc://old.org/c", A)];
+		let dir = setup("complete", &[
+			("cats", &["mxc://old.org/a", "mxc://old.org/b"]),
+			("dogs", &["mxc://old.org/a", "mxc://old.org/c"])
+		]);
+		let (report, downloads) = migrate(&dir, FILES, 3, |listener| {
+			serve_numbered_uploads(listener, 2, "new");
+		})
+		.await;
+		assert_eq!(downloads, ["mxc://old.org/a", "mxc://old.org/b", "mxc://old.org/c"]);
+		assert_eq!(report, MigrationReport {
+			files: vec![dir.join("cats.json"), dir.join("dogs.json")],
+			skipped_files: Vec::new(),
+			rewritten: rewritten(&[
+				("mxc://old.org/a", "mxc://example.org/new0"),
+				("mxc://old.org/b", "mxc://example.org/new1"),
+				("mxc://old.org/c", "mxc://example.org/new0")
+			]),
+			uploaded: 2,
+			missing: Vec::new(),
+			failed: Vec::new()
+		});
+		assert_eq!(urls(&dir.join("cats.json")), [
+			"mxc://example.org/new0",
+			"mxc://example.org/new0",
+			"mxc://example.org/new1",
+			"mxc://example.org/new1"
+		]);
+		assert_eq!(urls(&dir.join("dogs.json")), ["mxc://example.org/new0"; 4]);
+		// other json files are not changed and no temporary files are left over
+		let mut files: Vec<_> = std::fs::read_dir(&dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+			.collect();
+		files.sort();
+		assert_eq!(files, ["cats.json", "dogs.json", "index.json", "migration-state.json"]);
+		assert_eq!(
+			std::fs::read_to_string(dir.join("index.json")).unwrap(),
+			r#"{"packs": ["cats.json", "dogs.json"]}"#
+		);
+
+		// nothing is left to do
+		let (report, downloads) = migrate(&dir, FILES, 0, |_| ()).await;
+		assert!(downloads.is_empty());
+		assert_eq!(report.skipped_files, [dir.join("cats.json"), dir.join("dogs.json")]);
+		assert_eq!(report.uploaded, 0);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn resumed() {
+		const FILES: &[(&str, &[u8])] = &[("mxc://old.org/a", A), ("mxc://old.org/b", B)];
+		let dir = setup("resumed", &[
+			("cats", &["mxc://old.org/a", "mxc://old.org/b"]),
+			("dogs", &["mxc://old.org/a"])
+		]);
+		// the new homeserver breaks down after the first upload
+		let (report, downloads) = migrate(&dir, FILES, 2, |listener| {
+			serve_numbered_uploads(listener.try_clone().unwrap(), 1, "new");
+			serve_errors(listener, Default::default());
+		})
+		.await;
+		assert_eq!(downloads, ["mxc://old.org/a", "mxc://old.org/b"]);
+		assert_eq!(report.failed.len(), 1);
+		assert_eq!(report.failed[0].0, "mxc://old.org/b");
+		assert_eq!(report.files, [dir.join("cats.json"), dir.join("dogs.json")]);
+		assert_eq!(urls(&dir.join("cats.json")), [
+			"mxc://example.org/new0",
+			"mxc://example.org/new0",
+			"mxc://old.org/b",
+			"mxc://old.org/b"
+		]);
+
+		// only the failed file is moved, the completed pack is skipped
+		let (report, downloads) = migrate(&dir, FILES, 1, |listener| {
+			serve_numbered_uploads(listener, 1, "again");
+		})
+		.await;
+		assert_eq!(downloads, ["mxc://old.org/b"]);
+		assert_eq!(report, MigrationReport {
+			files: vec![dir.join("cats.json")],
+			skipped_files: vec![dir.join("dogs.json")],
+			rewritten: rewritten(&[("mxc://old.org/b", "mxc://example.org/again0")]),
+			uploaded: 1,
+			missing: Vec::new(),
+			failed: Vec::new()
+		});
+		assert_eq!(urls(&dir.join("cats.json")), [
+			"mxc://example.org/new0",
+			"mxc://example.org/new0",
+			"mxc://example.org/again0",
+			"mxc://example.org/again0"
+		]);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn missing_media() {
+		const FILES: &[(&str, &[u8])] = &[("mxc://old.org/a", A), ("mxc://old.org/b", B)];
+		let dir = setup("missing", &[
+			("cats", &["mxc://old.org/gone", "mxc://old.org/a"]),
+			("dogs", &["mxc://old.org/b"])
+		]);
+		let (report, downloads) = migrate(&dir, FILES, 3, |listener| {
+			serve_numbered_uploads(listener, 2, "new");
+		})
+		.await;
+		// the missing file is requested only once and does not stop the migration
+		assert_eq!(downloads, ["mxc://old.org/gone", "mxc://old.org/a", "mxc://old.org/b"]);
+		assert_eq!(report.missing, ["mxc://old.org/gone"]);
+		assert!(report.failed.is_empty());
+		assert_eq!(report.uploaded, 2);
+		assert_eq!(urls(&dir.join("cats.json")), [
+			"mxc://old.org/gone",
+			"mxc://old.org/gone",
+			"mxc://example.org/new0",
+			"mxc://example.org/new0"
+		]);
+		assert_eq!(urls(&dir.join("dogs.json")), ["mxc://example.org/new1"; 2]);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,332 @@
+use super::{sticker_formats::maunium, stickerpack::StickerPack, Config, Mxc};
+use crate::{
+	database::{self, Database},
+	error::Error,
+	image::Image
+};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::Arc
+};
+use tokio::{fs, io::AsyncWriteExt as _, sync::Mutex};
+
+#[cfg(feature = "log")]
+use log::warn;
+
+const MANIFEST: &str = "manifest.jsonl";
+
+/// Line of the manifest of a [`MediaMirror`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MirrorEntry {
+	/// hex encoded hash of the file, see [`database::hash`]
+	pub hash: String,
+	/// path of the file, relative to the mirror
+	pub file: String,
+	pub mxc: String,
+	/// id of the pack, to which the file was uploaded
+	pub pack: String
+}
+
+/// Local content addressed copy of all uploaded files, to rebuild packs if the homeserver does lose media.
+///
+/// Every file is stored as `<first two chars of the hash>/<hash>.<extension>`.
+/// The manifest `manifest.jsonl` links the hash of each upload to its url and pack, see [`MirrorEntry`].
+#[derive(Debug)]
+pub struct MediaMirror {
+	dir: PathBuf,
+	manifest: Mutex<()>
+}
+
+impl MediaMirror {
+	/// Use `dir` as mirror; it is created if it does not exist yet.
+	pub async fn new<P>(dir: P) -> Result<Self, Error>
+	where
+		P: AsRef<Path>
+	{
+		let dir = dir.as_ref().to_owned();
+		fs::create_dir_all(&dir).await?;
+		Ok(Self {
+			dir,
+			manifest: Mutex::new(())
+		})
+	}
+
+	/// Store `image` at the mirror, if it is not there yet, and return its hash and relative path.
+	async fn store(&self, image: &Image) -> Result<(String, String), Error> {
+		let hash: String = database::hash(image.data())
+			.iter()
+			.map(|byte| format!("{byte:02x}"))
+			.collect();
+		let extension = image
+			.file_name
+			.extension()
+			.map(|extension| extension.to_string_lossy().into_owned())
+			.unwrap_or_else(|| "bin".to_owned());
+		let file = format!("{}/{hash}.{extension}", &hash[..2]);
+		let path = self.dir.join(&file);
+		if !fs::try_exists(&path).await? {
+			fs::create_dir_all(self.dir.join(&hash[..2])).await?;
+			fs::write(&path, image.data_bytes()).await?;
+		}
+		Ok((hash, file))
+	}
+
+	async fn record(&self, entry: &MirrorEntry) -> Result<(), Error> {
+		let _lock = self.manifest.lock().await;
+		let mut manifest = fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(self.dir.join(MANIFEST))
+			.await?;
+		let mut line = serde_json::to_vec(entry)?;
+		line.push(b'\n');
+		manifest.write_all(&line).await?;
+		Ok(())
+	}
+
+	/// All entries of the manifest, in the order they were added.
+	pub async fn entries(&self) -> Result<Vec<MirrorEntry>, Error> {
+		let path = self.dir.join(MANIFEST);
+		if !fs::try_exists(&path).await? {
+			return Ok(Vec::new());
+		}
+		fs::read_to_string(path)
+			.await?
+			.lines()
+			.filter(|line| !line.trim().is_empty())
+			.map(|line| Ok(serde_json::from_str(line)?))
+			.collect()
+	}
+
+	/// Like [`Image::upload`], but store the image at the mirror first, so that it is kept even if the upload does fail.
+	/// After a successful upload the url is added to the manifest.
+	pub async fn upload<D>(
+		&self,
+		image: &Image,
+		pack: &str,
+		matrix_config: &Config,
+		database: Option<&D>
+	) -> Result<(Mxc, bool), Error>
+	where
+		D: Database + ?Sized
+	{
+		let (hash, file) = self.store(image).await?;
+		let (mxc, uploaded) = image.upload_with(matrix_config, database).await?;
+		// deferred uploads get their url later, see `Outbox::flush`
+		if !mxc.is_deferred() {
+			self.record(&MirrorEntry {
+				hash,
+				file,
+				mxc: mxc.url().to_owned(),
+				pack: pack.to_owned()
+			})
+			.await?;
+		}
+		Ok((mxc, uploaded))
+	}
+
+	/// Upload the mirrored files of `pack` again and replace their urls.
+	///
+	/// Every file is uploaded once, even if multiple stickers use it.
+	/// Urls, which are not listed at the manifest, are not changed.
+	/// The new urls are added to the manifest for the same pack as the old ones, so a pack can be rebuilt multiple times.
+	pub async fn rebuild(&self, pack: &mut StickerPack, matrix_config: &Config) -> Result<(), Error> {
+		let images = pack.stickers.iter_mut().flat_map(|sticker| {
+			std::iter::once((&mut sticker.image.url, sticker.image.meta_data.mimetype.as_str())).chain(
+				sticker
+					.thumbnail
+					.as_mut()
+					.map(|thumbnail| (&mut thumbnail.url, thumbnail.meta_data.mimetype.as_str()))
+			)
+		});
+		self.rebuild_urls(&pack.id, images, matrix_config).await
+	}
+
+	/// Like [`MediaMirror::rebuild`], but for a pack of the [maunium stickerpicker](maunium).
+	pub async fn rebuild_maunium(&self, pack: &mut maunium::StickerPack, matrix_config: &Config) -> Result<(), Error> {
+		let images = pack.stickers.iter_mut().flat_map(|sticker| {
+			[
+				(&mut sticker.url, sticker.info.image_info.mimetype.as_str()),
+				(&mut sticker.info.thumbnail_url, sticker.info.thumbnail_info.mimetype.as_str())
+			]
+		});
+		self.rebuild_urls(&pack.id, images, matrix_config).await
+	}
+
+	/// Upload the mirrored files of `images` again and replace their urls; each image is given with its mime type.
+	async fn rebuild_urls<'a, I>(&self, _pack_id: &str, images: I, matrix_config: &Config) -> Result<(), Error>
+	where
+		I: Iterator<Item = (&'a mut Mxc, &'a str)>
+	{
+		let by_url: HashMap<String, MirrorEntry> = self
+			.entries()
+			.await?
+			.into_iter()
+			.map(|entry| (entry.mxc.clone(), entry))
+			.collect();
+		let mut uploaded: HashMap<String, Mxc> = HashMap::new();
+		for (url, mimetype) in images {
+			let Some(entry) = by_url.get(url.url()) else {
+				#[cfg(feature = "log")]
+				warn!("{} of pack {:?} is not mirrored, keep url", url.url(), _pack_id);
+				continue;
+			};
+			if let Some(mxc) = uploaded.get(&entry.hash) {
+				*url = mxc.clone();
+				continue;
+			}
+			let data = Arc::new(fs::read(self.dir.join(&entry.file)).await?);
+			let file_name = Path::new(&entry.file)
+				.file_name()
+				.unwrap_or_default()
+				.to_string_lossy()
+				.into_owned();
+			let mxc = super::upload(matrix_config, &file_name, data, mimetype).await?;
+			// keep the pack of the import, so the manifest uses the same key for all urls of a file
+			self.record(&MirrorEntry {
+				mxc: mxc.url().to_owned(),
+				..entry.clone()
+			})
+			.await?;
+			uploaded.insert(entry.hash.clone(), mxc.clone());
+			*url = mxc;
+		}
+		Ok(())
+	}
+}
+
+/// Rebuild the pack stored at `pack_json` from the mirror at `mirror_dir`, see [`MediaMirror::rebuild`].
+/// The pack must be stored in the format of the maunium stickerpicker, as written by [`write_pack`](super::write_pack).
+/// The file is overwritten with the new urls and the rebuilt pack is returned.
+pub async fn rebuild_from_mirror<P, Q>(
+	mirror_dir: P,
+	pack_json: Q,
+	matrix_config: &Config
+) -> Result<maunium::StickerPack, Error>
+where
+	P: AsRef<Path>,
+	Q: AsRef<Path>
+{
+	let mirror = MediaMirror::new(mirror_dir).await?;
+	let mut pack: maunium::StickerPack = serde_json::from_str(&fs::read_to_string(&pack_json).await?)?;
+	mirror.rebuild_maunium(&mut pack, matrix_config).await?;
+	fs::write(&pack_json, serde_json::to_string(&pack)?).await?;
+	Ok(pack)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{rebuild_from_mirror, MediaMirror};
+	use crate::{
+		database::DummyDatabase,
+		image::Image,
+		matrix::{
+			sticker::{Image as StickerImage, Sticker},
+			sticker_formats::ponies::MetaData,
+			stickerpack::{StickerPack, TgPackInfo},
+			write_pack
+		},
+		mock::{matrix_config, serve_numbered_uploads}
+	};
+	use std::net::TcpListener;
+
+	fn sticker(image: &Image, url: crate::matrix::Mxc) -> Sticker {
+		Sticker {
+			body: image.file_name_str().to_owned(),
+			display_name: None,
+			image: StickerImage {
+				url,
+				meta_data: MetaData {
+					w: image.width,
+					h: image.height,
+					size: image.data_len(),
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: None,
+			emoji: Vec::new(),
+			tg_sticker: None,
+			content_hash: None
+		}
+	}
+
+	#[tokio::test]
+	async fn rebuild() {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-mirror-test-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		let mirror = MediaMirror::new(dir.join("mirror")).await.unwrap();
+		// the first image is used twice and uploaded again, because there is no database
+		let images: Vec<_> = [0, 100, 0]
+			.into_iter()
+			.map(|value| Image::from_rgba(vec![value; 4 * 4 * 4], 4, 4, "sticker.webp").unwrap())
+			.collect();
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let old_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_numbered_uploads(listener, 3, "old"));
+		let mut stickers = Vec::new();
+		for image in &images {
+			let (url, _) = mirror
+				.upload(image, "CuteCats", &old_config, None::<&DummyDatabase>)
+				.await
+				.unwrap();
+			stickers.push(sticker(image, url));
+		}
+		server.join().unwrap();
+		let pack = StickerPack {
+			title: "Cute Cats".to_owned(),
+			id: "tg_name_CuteCats".to_owned(),
+			tg_pack: Some(TgPackInfo {
+				name: "CuteCats".to_owned(),
+				title: "Cute Cats".to_owned()
+			}),
+			stickers
+		};
+		let entries = mirror.entries().await.unwrap();
+		assert_eq!(entries.len(), 3);
+		assert_eq!(entries[0].hash, entries[2].hash);
+		assert!(entries[0].file.starts_with(&format!("{}/", &entries[0].hash[..2])));
+		assert!(entries[0].file.ends_with(".webp"));
+		assert_eq!(
+			std::fs::read(dir.join("mirror").join(&entries[1].file)).unwrap(),
+			images[1].data_bytes()
+		);
+		std::fs::create_dir_all(dir.join("packs")).unwrap();
+		let pack_json = write_pack(&dir.join("packs"), pack.into(), false).await.unwrap();
+
+		// the homeserver has lost all media, so every file is uploaded to a fresh one
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let new_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_numbered_uploads(listener, 2, "new"));
+		let rebuilt = rebuild_from_mirror(dir.join("mirror"), &pack_json, &new_config)
+			.await
+			.unwrap();
+		assert_eq!(server.join().unwrap().len(), 2);
+		let urls: Vec<_> = rebuilt.stickers.iter().map(|sticker| sticker.url.url().as_str()).collect();
+		assert_eq!(urls, [
+			"mxc://example.org/new0",
+			"mxc://example.org/new1",
+			"mxc://example.org/new0"
+		]);
+		let thumbnail_urls: Vec<_> = rebuilt
+			.stickers
+			.iter()
+			.map(|sticker| sticker.info.thumbnail_url.url().as_str())
+			.collect();
+		assert_eq!(thumbnail_urls, urls);
+		assert_eq!(rebuilt.id, "tg_name_CuteCats");
+		assert_eq!(
+			std::fs::read_to_string(&pack_json).unwrap(),
+			serde_json::to_string(&rebuilt).unwrap()
+		);
+		let entries = mirror.entries().await.unwrap();
+		assert_eq!(entries.len(), 5);
+		assert!(entries.iter().all(|entry| entry.pack == "CuteCats"));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -1,22 +1,66 @@
+#[cfg(feature = "synapse-admin")]
+mod admin;
+mod alias;
+#[cfg(feature = "e2ee")]
+mod encrypted;
+mod estimate;
+mod health;
+pub mod migrate;
+mod mirror;
+mod outbox;
+mod rate_limit;
+mod receipt;
+mod retention;
+mod retry;
+mod shortcode;
+mod space;
 pub mod sticker;
 pub mod sticker_formats;
 pub mod stickerpack;
 mod stickerpicker;
+mod user_packs;
+
+#[cfg(feature = "synapse-admin")]
+pub use admin::delete_media;
+pub use alias::{export_alias_map, write_alias_map, AliasEntry, AliasFormat};
+#[cfg(feature = "e2ee")]
+pub use encrypted::{upload_encrypted, EncryptedFileInfo, Jwk};
+pub use estimate::{estimate, EstimateReport};
+pub use health::{health_check, HealthReport};
+pub use mirror::{rebuild_from_mirror, MediaMirror, MirrorEntry};
+pub use outbox::{Outbox, OutboxEntry};
+pub use rate_limit::RateLimiter;
+pub use receipt::{UploadReceipt, UploadReceipts};
+pub use retention::RetentionHint;
+pub(crate) use retry::is_unreachable;
+pub use retry::RetryBudget;
+pub use shortcode::{is_valid_shortcode, ShortcodeCollision, ShortcodeDictionary};
+pub use space::{publish_pack_to_space, SpaceOptions, SpacePublishReport};
+pub use stickerpicker::write_pack;
+#[cfg(feature = "synapse-admin")]
+pub use user_packs::remove_user_pack_with_media;
+pub use user_packs::{list_user_packs, publish_user_pack, remove_user_pack, PackSummary, USER_PACKS};
 
 use crate::{
 	error::{Error, MatrixError},
+	image::copy_buffer,
+	recorder::{self, HttpRecorder},
 	CLIENT
 };
 use derive_getters::Getters;
-use reqwest::Url;
+use futures_util::future::BoxFuture;
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
 	fmt::{Debug, Display},
+	hash::{Hash, Hasher},
 	ops::Deref,
 	sync::Arc
 };
 use stickerpicker::StickerWidget;
 use thiserror::Error;
+#[cfg(feature = "msc2246")]
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// Matrix file url.
 ///
@@ -34,7 +78,14 @@ impl Mxc {
 		Self { url, data }
 	}
 
+	/// `true` if the file was queued at an [`Outbox`] and is not uploaded yet.
+	/// Such urls are replaced by [`Outbox::flush`] and [`StickerPack::resolve_deferred`](stickerpack::StickerPack::resolve_deferred).
+	pub fn is_deferred(&self) -> bool {
+		self.url.starts_with(outbox::DEFERRED_PREFIX)
+	}
+
 	/// fetch data, if not cached
+	#[doc(hidden)]
 	pub async fn fetch_data(&self) -> &Vec<u8> {
 		if let Some(data) = &self.data {
 			return data;
@@ -75,9 +126,11 @@ impl PartialEq for Mxc {
 		self.url.eq(&other.url)
 	}
 }
-impl Eq for Mxc {
-	fn assert_receiver_is_total_eq(&self) {
-		self.url.assert_receiver_is_total_eq()
+impl Eq for Mxc {}
+/// Like [`PartialEq`], only the url is hashed.
+impl Hash for Mxc {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.url.hash(state)
 	}
 }
 
@@ -103,7 +156,107 @@ impl Serialize for Mxc {
 pub struct Config {
 	pub homeserver_url: String,
 	pub user: String,
-	pub access_token: String
+	pub access_token: String,
+	/// Hook which is asked before a file is uploaded, if the same file does already exist at the homeserver.
+	/// This allows to use external sources (like an index shared between multiple machines) to avoid duplicate uploads.
+	/// If the hook returns a [Mxc], the file is not uploaded again.
+	#[serde(skip)]
+	pub exists_check: Option<ExistsCheck>,
+	/// Hook which may change the data of an image right before it is uploaded by [`Image::upload`](crate::image::Image::upload),
+	/// like stripping metadata. If the hook returns new data, it is uploaded instead,
+	/// and the database and the [`Config::exists_check`] see the hash and data of the changed file.
+	#[serde(skip)]
+	pub pre_upload_hook: Option<PreUploadHook>,
+	/// If set, the answers of the homeserver to all uploads are collected here, for auditing.
+	#[serde(skip)]
+	pub receipts: Option<Arc<UploadReceipts>>,
+	/// If set, uploads and account data writes wait for this limiter.
+	/// Share it between multiple configs, to limit concurrent imports together.
+	#[serde(skip)]
+	pub rate_limiter: Option<Arc<RateLimiter>>,
+	/// If set, failed uploads are retried as long as this budget is not spent.
+	/// Share it between all uploads of a batch, to limit the retries of the whole batch.
+	#[serde(skip)]
+	pub retry_budget: Option<Arc<RetryBudget>>,
+	/// If set, uploads which fail because the homeserver is not reachable are queued here, instead of failing.
+	/// See [`Outbox`].
+	#[serde(skip)]
+	pub outbox: Option<Arc<Outbox>>,
+	/// If set, all requests to the homeserver are recorded or replayed, see [`HttpRecorder`].
+	#[serde(skip)]
+	pub http_recorder: Option<Arc<HttpRecorder>>,
+	/// If set, uploads ask the media repository to keep the files, if it does support this; see [`RetentionHint`].
+	#[serde(default)]
+	pub media_retention: Option<RetentionHint>,
+	/// If set, [`Image::upload`](crate::image::Image::upload) fails with [`Error::FileTooLarge`] for larger files,
+	/// before anything is sent to the homeserver.
+	#[serde(default)]
+	pub max_upload_size: Option<usize>
+}
+
+impl Config {
+	/// Config for `user` at the homeserver at `homeserver_url`, without any of the optional hooks and limits.
+	pub fn new(homeserver_url: String, user: String, access_token: String) -> Self {
+		Self {
+			homeserver_url,
+			user,
+			access_token,
+			exists_check: None,
+			pre_upload_hook: None,
+			receipts: None,
+			rate_limiter: None,
+			retry_budget: None,
+			outbox: None,
+			http_recorder: None,
+			media_retention: None,
+			max_upload_size: None
+		}
+	}
+
+	/// Send a request through the [`Config::http_recorder`].
+	async fn send(&self, request: RequestBuilder) -> Result<Response, Error> {
+		recorder::send(self.http_recorder.as_deref(), request).await
+	}
+
+	/// Send a request, which is subject to the [`Config::rate_limiter`].
+	async fn send_limited(&self, request: RequestBuilder) -> Result<Response, Error> {
+		let Some(limiter) = &self.rate_limiter else {
+			return self.send(request).await;
+		};
+		limiter.acquire().await;
+		let answer = self.send(request).await?;
+		// the spec requires `M_LIMIT_EXCEEDED` to be returned with this status code
+		if answer.status() == StatusCode::TOO_MANY_REQUESTS {
+			limiter.limit_exceeded();
+		}
+		Ok(answer)
+	}
+}
+
+/// Async function which receives the file data and returns the [Mxc] of an existing upload of the same data, if any.
+pub type ExistsCheckFn = dyn for<'a> Fn(&'a [u8]) -> BoxFuture<'a, Option<Mxc>> + Send + Sync;
+
+/// See [`Config::exists_check`].
+#[derive(Clone)]
+pub struct ExistsCheck(pub Arc<ExistsCheckFn>);
+
+impl Debug for ExistsCheck {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("ExistsCheck")
+	}
+}
+
+/// Function which receives the file data and returns the data to upload instead, or `None` to keep it.
+pub type PreUploadHookFn = dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// See [`Config::pre_upload_hook`].
+#[derive(Clone)]
+pub struct PreUploadHook(pub Arc<PreUploadHookFn>);
+
+impl Debug for PreUploadHook {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("PreUploadHook")
+	}
 }
 
 /// see <https://spec.matrix.org/latest/client-server-api/#standard-error-response>
@@ -118,11 +271,32 @@ pub struct MatrixApiError {
 	pub retry_after_ms: Option<u32>
 }
 
-#[allow(dead_code)]
+/// Turn an unsuccessful answer of the homeserver into [`Error::MatrixUpload`], with the [`MatrixApiError`] of its body.
+pub(crate) async fn api_error(answer: Response) -> Error {
+	let status = answer.status();
+	let error: Result<MatrixApiError, _> = answer.json().await;
+	Error::MatrixUpload(MatrixError {
+		status_code: status,
+		filename: None,
+		matrix_error: error
+	})
+}
+
+/// see <https://spec.matrix.org/latest/client-server-api/#get_matrixclientv3accountwhoami>
 #[derive(Debug, Deserialize)]
-pub struct Whoami {
-	user_id: String,
-	device_id: String
+struct Whoami {
+	user_id: String
+}
+
+/// see <https://spec.matrix.org/latest/client-server-api/#get_matrixclientv1mediaconfig>
+#[derive(Debug, Deserialize)]
+pub struct MediaConfig {
+	/// maximum size of an upload in bytes, if the homeserver has a limit
+	#[serde(rename = "m.upload.size")]
+	pub upload_size: Option<u64>,
+	/// If the homeserver does support the authenticated media endpoints, introduced by matrix 1.11.
+	#[serde(skip)]
+	pub authenticated: bool
 }
 
 #[derive(Debug, Deserialize)]
@@ -132,7 +306,7 @@ struct MatrixContentUri {
 
 pub async fn set_widget(matrix: &Config, sender: String, url: String) -> Result<(), Error> {
 	let stickerwidget = StickerWidget::new(url, sender);
-	let answer = CLIENT
+	let request = CLIENT
 		.get()
 		.put(format!(
 			"{}/_matrix/client/r0/user/{}/account_data/m.widgets",
@@ -140,9 +314,8 @@ pub async fn set_widget(matrix: &Config, sender: String, url: String) -> Result<
 		))
 		.query(&[("access_token", &matrix.access_token)])
 		.header("Content-Type", "application/json")
-		.json(&stickerwidget)
-		.send()
-		.await?;
+		.json(&stickerwidget);
+	let answer = matrix.send_limited(request).await?;
 	if answer.status() != 200 {
 		let status = answer.status();
 		let error: Result<MatrixApiError, _> = answer.json().await;
@@ -155,14 +328,15 @@ pub async fn set_widget(matrix: &Config, sender: String, url: String) -> Result<
 	Ok(())
 }
 
-pub async fn whoami(matrix: &Config) -> Result<Whoami, Error> {
+/// Return the id of the user, to which [`Config::access_token`] belongs.
+/// This is a cheap check, that the homeserver is reachable and the access token is valid; see also [`health_check`].
+pub async fn whoami(matrix: &Config) -> Result<String, Error> {
 	Url::parse(&matrix.homeserver_url)?; //check if homeserver_url is a valid url
-	let answer = CLIENT
+	let request = CLIENT
 		.get()
-		.get(format!("{}/_matrix/client/r0/account/whoami", matrix.homeserver_url))
-		.query(&[("access_token", &matrix.access_token)])
-		.send()
-		.await?;
+		.get(format!("{}/_matrix/client/v3/account/whoami", matrix.homeserver_url))
+		.query(&[("access_token", &matrix.access_token)]);
+	let answer = matrix.send(request).await?;
 	if answer.status() != 200 {
 		let status = answer.status();
 		let error: Result<MatrixApiError, _> = answer.json().await;
@@ -172,8 +346,94 @@ pub async fn whoami(matrix: &Config) -> Result<Whoami, Error> {
 			matrix_error: error
 		}))
 	} else {
-		Ok(answer.json().await?)
+		let whoami: Whoami = answer.json().await?;
+		Ok(whoami.user_id)
+	}
+}
+
+/// Get the media config of the homeserver.
+/// Use the authenticated endpoint if available, otherwise fall back to the legacy one.
+pub async fn media_config(matrix: &Config) -> Result<MediaConfig, Error> {
+	Url::parse(&matrix.homeserver_url)?; //check if homeserver_url is a valid url
+	let mut authenticated = true;
+	let request = CLIENT
+		.get()
+		.get(format!("{}/_matrix/client/v1/media/config", matrix.homeserver_url))
+		.query(&[("access_token", &matrix.access_token)]);
+	let mut answer = matrix.send(request).await?;
+	// homeservers answer unknown endpoints with 404 or 405 `M_UNRECOGNIZED`
+	if matches!(answer.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED) {
+		authenticated = false;
+		let request = CLIENT
+			.get()
+			.get(format!("{}/_matrix/media/v3/config", matrix.homeserver_url))
+			.query(&[("access_token", &matrix.access_token)]);
+		answer = matrix.send(request).await?;
+	}
+	if answer.status() != 200 {
+		let status = answer.status();
+		let error: Result<MatrixApiError, _> = answer.json().await;
+		return Err(Error::MatrixUpload(MatrixError {
+			status_code: status,
+			filename: None,
+			matrix_error: error
+		}));
 	}
+	let mut config: MediaConfig = answer.json().await?;
+	config.authenticated = authenticated;
+	Ok(config)
+}
+
+/// Split a mxc url into its server name and media id.
+pub(crate) fn split_mxc(url: &str) -> Result<(&str, &str), Error> {
+	url.strip_prefix("mxc://")
+		.and_then(|path| path.split_once('/'))
+		.filter(|(server_name, media_id)| !server_name.is_empty() && !media_id.is_empty() && !media_id.contains('/'))
+		.ok_or_else(|| Error::InvalidMxc(url.to_owned()))
+}
+
+/// Download the file of the mxc `url` and return it together with its content type.
+/// Return [`Error::NotFound`], if the homeserver does not have the file.
+/// Use the authenticated endpoint if available, otherwise fall back to the legacy one.
+pub(crate) async fn download(matrix: &Config, url: &str) -> Result<(Vec<u8>, String), Error> {
+	let (server_name, media_id) = split_mxc(url)?;
+	let request = |endpoint: &str| {
+		CLIENT
+			.get()
+			.get(format!("{}/{endpoint}/{server_name}/{media_id}", matrix.homeserver_url))
+			.query(&[("access_token", &matrix.access_token)])
+	};
+	let mut answer = matrix.send_limited(request("_matrix/client/v1/media/download")).await?;
+	let error = |status, matrix_error| {
+		Error::MatrixUpload(MatrixError {
+			status_code: status,
+			filename: Some(url.to_owned()),
+			matrix_error
+		})
+	};
+	// homeservers answer unknown endpoints with 404 or 405 `M_UNRECOGNIZED`, and unknown files with 404 `M_NOT_FOUND`
+	if matches!(answer.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED) {
+		let status = answer.status();
+		match answer.json::<MatrixApiError>().await {
+			Ok(matrix_error) if matrix_error.errcode == "M_UNRECOGNIZED" => {
+				answer = matrix.send_limited(request("_matrix/media/v3/download")).await?
+			},
+			Ok(matrix_error) if matrix_error.errcode == "M_NOT_FOUND" => return Err(Error::NotFound(url.to_owned())),
+			matrix_error => return Err(error(status, matrix_error))
+		}
+	}
+	match answer.status() {
+		StatusCode::OK => (),
+		StatusCode::NOT_FOUND => return Err(Error::NotFound(url.to_owned())),
+		status => return Err(error(status, answer.json().await))
+	}
+	let content_type = answer
+		.headers()
+		.get(reqwest::header::CONTENT_TYPE)
+		.and_then(|value| value.to_str().ok())
+		.unwrap_or("application/octet-stream")
+		.to_owned();
+	Ok((answer.bytes().await?.to_vec(), content_type))
 }
 
 pub(crate) async fn upload(matrix: &Config, filename: &String, data: Arc<Vec<u8>>, mimetype: &str) -> Result<Mxc, Error> {
@@ -183,14 +443,110 @@ pub(crate) async fn upload(matrix: &Config, filename: &String, data: Arc<Vec<u8>
 }
 
 pub(crate) async fn upload_ref(matrix: &Config, filename: &String, data: &[u8], mimetype: &str) -> Result<Mxc, Error> {
-	let answer = CLIENT
+	loop {
+		let result = upload_once(matrix, filename, data, mimetype).await;
+		let Some(budget) = &matrix.retry_budget else {
+			return result;
+		};
+		match result.as_ref().err().and_then(retry::retry_delay) {
+			Some(delay) if budget.try_spend() => tokio::time::sleep(delay).await,
+			_ => return result
+		}
+	}
+}
+
+async fn upload_once(matrix: &Config, filename: &String, data: &[u8], mimetype: &str) -> Result<Mxc, Error> {
+	let request = CLIENT
 		.get()
-		.post(&format!("{}/_matrix/media/r0/upload", matrix.homeserver_url))
+		.post(format!("{}/_matrix/media/r0/upload", matrix.homeserver_url))
 		.query(&[("access_token", &matrix.access_token), ("filename", filename)])
 		.header("Content-Type", mimetype)
-		.body(data.to_owned()) //TODO check for better solution
-		.send()
-		.await?;
+		// reqwest needs to own the body; avoiding this copy would require to store the data as `Bytes`
+		.body(copy_buffer(data));
+	let request = match &matrix.media_retention {
+		Some(hint) => match hint.header(matrix).await {
+			Some((header, value)) => request.header(header, value),
+			None => request
+		},
+		None => request
+	};
+	let answer = matrix.send_limited(request).await?;
+	let answer = match &matrix.receipts {
+		Some(receipts) => {
+			let (receipt, answer) =
+				UploadReceipt::capture(answer, filename, data.len() as u64, &matrix.access_token).await?;
+			receipts.push(receipt).await;
+			answer
+		},
+		None => answer
+	};
+	if answer.status() != 200 {
+		let status = answer.status();
+		let error: Result<MatrixApiError, _> = answer.json().await;
+		return Err(Error::MatrixUpload(MatrixError {
+			status_code: status,
+			filename: Some(filename.to_owned()),
+			matrix_error: error
+		}));
+	}
+	let content_uri: MatrixContentUri = answer.json().await?;
+	Ok(content_uri.content_uri.into())
+}
+
+/// Split `data` into chunks of `chunk_size` bytes. Only the last chunk can be smaller.
+#[cfg(feature = "msc2246")]
+fn chunks<R>(data: R, chunk_size: usize) -> impl futures_util::TryStream<Ok = Vec<u8>, Error = std::io::Error>
+where
+	R: AsyncRead + Unpin
+{
+	futures_util::stream::try_unfold(data, move |mut data| async move {
+		let mut chunk = Vec::with_capacity(chunk_size);
+		(&mut data).take(chunk_size as u64).read_to_end(&mut chunk).await?;
+		Ok((!chunk.is_empty()).then_some((chunk, data)))
+	})
+}
+
+/// Upload a large file, using the asynchronous upload api of
+/// [MSC2246](https://github.com/matrix-org/matrix-spec-proposals/pull/2246).
+/// First a mxc url is created, afterwards `data` is streamed to it as the body of a single request.
+/// The body is read in pieces of `chunk_size` bytes, so the file never has to be completely loaded into memory;
+/// the Matrix api has no way to upload a file in multiple requests.
+///
+/// If the file is not larger than `chunk_size`, it is read into memory and uploaded like any other file,
+/// so all hooks of the [`Config`] apply. Streamed files are checked against [`Config::max_upload_size`]
+/// and recorded at [`Config::receipts`], but a stream can not be sent again,
+/// so they are neither retried with the [`Config::retry_budget`] nor deferred to the [`Config::outbox`].
+#[cfg(feature = "msc2246")]
+pub async fn streamed_upload<R>(
+	matrix: &Config,
+	filename: &str,
+	mut data: R,
+	size: u64,
+	mimetype: &str,
+	chunk_size: usize
+) -> Result<Mxc, Error>
+where
+	R: AsyncRead + Send + Sync + Unpin + 'static
+{
+	if let Some(limit) = matrix.max_upload_size {
+		if size > limit as u64 {
+			return Err(Error::FileTooLarge {
+				actual: size as usize,
+				limit
+			});
+		}
+	}
+	if size <= chunk_size as u64 {
+		let mut buf = Vec::with_capacity(size as usize);
+		data.read_to_end(&mut buf).await?;
+		return upload_ref(matrix, &filename.to_owned(), &buf, mimetype).await;
+	}
+
+	let request = CLIENT
+		.get()
+		.post(format!("{}/_matrix/media/v1/create", matrix.homeserver_url))
+		.query(&[("access_token", &matrix.access_token)]);
+	let answer = matrix.send_limited(request).await?;
 	if answer.status() != 200 {
 		let status = answer.status();
 		let error: Result<MatrixApiError, _> = answer.json().await;
@@ -201,5 +557,97 @@ pub(crate) async fn upload_ref(matrix: &Config, filename: &String, data: &[u8],
 		}));
 	}
 	let content_uri: MatrixContentUri = answer.json().await?;
+
+	// the upload endpoint expects `{serverName}/{mediaId}`, which is exactly the mxc url without scheme
+	let media = content_uri
+		.content_uri
+		.strip_prefix("mxc://")
+		.unwrap_or(&content_uri.content_uri);
+	let request = CLIENT
+		.get()
+		.put(format!("{}/_matrix/media/v3/upload/{media}", matrix.homeserver_url))
+		.query(&[("access_token", matrix.access_token.as_str()), ("filename", filename)])
+		.header("Content-Type", mimetype)
+		.header("Content-Length", size)
+		.body(reqwest::Body::wrap_stream(chunks(data, chunk_size)));
+	let answer = matrix.send_limited(request).await?;
+	let answer = match &matrix.receipts {
+		Some(receipts) => {
+			let (receipt, answer) = UploadReceipt::capture(answer, filename, size, &matrix.access_token).await?;
+			receipts.push(receipt).await;
+			answer
+		},
+		None => answer
+	};
+	if answer.status() != 200 {
+		let status = answer.status();
+		let error: Result<MatrixApiError, _> = answer.json().await;
+		return Err(Error::MatrixUpload(MatrixError {
+			status_code: status,
+			filename: Some(filename.to_owned()),
+			matrix_error: error
+		}));
+	}
 	Ok(content_uri.content_uri.into())
 }
+
+#[cfg(all(test, feature = "msc2246"))]
+mod tests {
+	use super::{Config, UploadReceipts};
+	use crate::{
+		error::Error,
+		mock::{matrix_config, serve_sequence}
+	};
+	use futures_util::TryStreamExt as _;
+	use std::{net::TcpListener, sync::Arc};
+
+	#[tokio::test]
+	async fn chunks() {
+		let data: &[u8] = &[1, 2, 3, 4, 5, 6, 7];
+		let chunks: Vec<Vec<u8>> = super::chunks(data, 3).try_collect().await.unwrap();
+		assert_eq!(chunks, vec![vec![1, 2, 3], vec![4, 5, 6], vec![7]]);
+	}
+
+	#[tokio::test]
+	async fn streamed_upload() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let receipts = Arc::new(UploadReceipts::new());
+		let matrix_config = Config {
+			receipts: Some(receipts.clone()),
+			..matrix_config(format!("http://{}", listener.local_addr().unwrap()))
+		};
+		let server = std::thread::spawn(move || {
+			serve_sequence(listener, &[
+				("200 OK", r#"{"content_uri":"mxc://example.org/abc"}"#),
+				("200 OK", "{}")
+			])
+		});
+		let data: &[u8] = b"0123456789";
+		let mxc = super::streamed_upload(&matrix_config, "sticker.webp", data, 10, "image/webp", 4)
+			.await
+			.unwrap();
+		assert_eq!(mxc.url(), "mxc://example.org/abc");
+		// the whole file is sent as the body of a single request
+		let requests = server.join().unwrap();
+		assert!(requests[0].starts_with("POST /_matrix/media/v1/create"));
+		assert!(requests[1].starts_with("PUT /_matrix/media/v3/upload/example.org/abc"));
+		assert!(requests[1].ends_with("\r\n\r\n0123456789"));
+		let receipts = receipts.receipts().await;
+		assert_eq!(receipts.len(), 1);
+		assert_eq!(receipts[0].content_length, 10);
+	}
+
+	#[tokio::test]
+	async fn streamed_upload_size_limit() {
+		let matrix_config = Config {
+			max_upload_size: Some(9),
+			..matrix_config("none")
+		};
+		let data: &[u8] = b"0123456789";
+		// the homeserver url is invalid, so any other error would mean that the size was not checked first
+		let err = super::streamed_upload(&matrix_config, "sticker.webp", data, 10, "image/webp", 4)
+			.await
+			.unwrap_err();
+		assert!(matches!(err, Error::FileTooLarge { actual: 10, limit: 9 }), "{err}");
+	}
+}
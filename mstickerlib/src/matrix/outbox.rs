@@ -0,0 +1,236 @@
+use super::{Config, Mxc};
+use crate::{
+	database::{self, Database},
+	error::Error
+};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::Arc
+};
+use tokio::fs;
+
+#[cfg(feature = "log")]
+use log::info;
+
+/// Url prefix of files, which are queued at an [`Outbox`].
+pub(super) const DEFERRED_PREFIX: &str = "outbox://";
+
+/// Metadata of a queued upload, stored next to its data.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct OutboxEntry {
+	/// hex encoded hash of the file, see [`database::hash`]
+	#[serde(skip)]
+	pub hash: String,
+	pub file_name: String,
+	pub mimetype: String
+}
+
+impl OutboxEntry {
+	/// Placeholder url of the entry, see [`Mxc::is_deferred`].
+	pub fn deferred_url(&self) -> String {
+		format!("{DEFERRED_PREFIX}{}", self.hash)
+	}
+}
+
+/// Local queue of uploads, which have failed because the homeserver was not reachable.
+///
+/// Every entry is stored as `<hash>.bin` with the file data and `<hash>.json` with its metadata,
+/// so the queue survives a restart of the program.
+/// Queued files get a placeholder url, which is replaced after a successful [`Outbox::flush`].
+#[derive(Debug)]
+pub struct Outbox {
+	dir: PathBuf
+}
+
+impl Outbox {
+	/// Use `dir` as outbox; it is created if it does not exist yet.
+	pub async fn new<P>(dir: P) -> Result<Self, Error>
+	where
+		P: AsRef<Path>
+	{
+		let dir = dir.as_ref().to_owned();
+		fs::create_dir_all(&dir).await?;
+		Ok(Self { dir })
+	}
+
+	fn data_path(&self, hash: &str) -> PathBuf {
+		self.dir.join(format!("{hash}.bin"))
+	}
+
+	fn meta_path(&self, hash: &str) -> PathBuf {
+		self.dir.join(format!("{hash}.json"))
+	}
+
+	/// Queue a file and return its placeholder url.
+	pub(crate) async fn push(&self, file_name: &str, data: Arc<Vec<u8>>, mimetype: &str) -> Result<Mxc, Error> {
+		let entry = OutboxEntry {
+			hash: database::hash(&data).iter().map(|byte| format!("{byte:02x}")).collect(),
+			file_name: file_name.to_owned(),
+			mimetype: mimetype.to_owned()
+		};
+		// the metadata is written last, so that an entry is only listed once its data is complete
+		fs::write(self.data_path(&entry.hash), data.as_slice()).await?;
+		fs::write(self.meta_path(&entry.hash), serde_json::to_vec(&entry)?).await?;
+		#[cfg(feature = "log")]
+		info!("homeserver is not reachable, queue {file_name:?} at the outbox");
+		Ok(Mxc::new(entry.deferred_url(), Some(data)))
+	}
+
+	/// All queued entries, sorted by their hash.
+	pub async fn entries(&self) -> Result<Vec<OutboxEntry>, Error> {
+		let mut entries = Vec::new();
+		let mut dir = fs::read_dir(&self.dir).await?;
+		while let Some(file) = dir.next_entry().await? {
+			let path = file.path();
+			if path.extension().is_none_or(|extension| extension != "json") {
+				continue;
+			}
+			let hash = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+			let entry: OutboxEntry = serde_json::from_slice(&fs::read(&path).await?)?;
+			entries.push(OutboxEntry { hash, ..entry });
+		}
+		entries.sort_by(|a, b| a.hash.cmp(&b.hash));
+		Ok(entries)
+	}
+
+	/// Upload all queued entries and remove them from the outbox.
+	///
+	/// Files, which are already known to the database, are not uploaded again.
+	/// Return the uploaded files by their placeholder url, which can be passed to
+	/// [`StickerPack::resolve_deferred`](super::stickerpack::StickerPack::resolve_deferred).
+	/// If an upload does fail, the entry and all following ones stay at the outbox.
+	pub async fn flush<D>(&self, matrix_config: &Config, database: Option<&D>) -> Result<HashMap<String, Mxc>, Error>
+	where
+		D: Database + ?Sized
+	{
+		let mut uploaded = HashMap::new();
+		for entry in self.entries().await? {
+			let data = Arc::new(fs::read(self.data_path(&entry.hash)).await?);
+			let hash = database::hash(&data);
+			let known = match database {
+				Some(db) => db.get(&hash).await.map_err(Error::database)?,
+				None => None
+			};
+			let mxc = match known {
+				Some(url) => Mxc::new(url, Some(data)),
+				None => {
+					let mxc = super::upload(matrix_config, &entry.file_name, data, &entry.mimetype).await?;
+					if let Some(db) = database {
+						db.add(hash, mxc.url().to_owned()).await.map_err(Error::database)?;
+					}
+					mxc
+				}
+			};
+			fs::remove_file(self.meta_path(&entry.hash)).await?;
+			fs::remove_file(self.data_path(&entry.hash)).await?;
+			uploaded.insert(entry.deferred_url(), mxc);
+		}
+		Ok(uploaded)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Outbox;
+	use crate::{
+		database::{self, Database, FileDatabase},
+		image::Image,
+		matrix::{
+			sticker::{Image as StickerImage, Sticker},
+			sticker_formats::ponies::MetaData,
+			stickerpack::StickerPack,
+			Config
+		},
+		mock::{matrix_config, serve_uploads}
+	};
+	use std::{net::TcpListener, sync::Arc};
+
+	fn sticker(image: &Image, url: crate::matrix::Mxc) -> Sticker {
+		Sticker {
+			body: image.file_name_str().to_owned(),
+			display_name: None,
+			image: StickerImage {
+				url,
+				meta_data: MetaData {
+					w: image.width,
+					h: image.height,
+					size: image.data_len(),
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: None,
+			emoji: Vec::new(),
+			tg_sticker: None,
+			content_hash: None
+		}
+	}
+
+	#[tokio::test]
+	async fn flush() {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-outbox-test-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		let outbox = Arc::new(Outbox::new(dir.join("outbox")).await.unwrap());
+		let db = FileDatabase::new(dir.join("db.txt")).await.unwrap();
+		let images: Vec<_> = [0, 100, 200]
+			.into_iter()
+			.map(|value| Image::from_rgba(vec![value; 4 * 4 * 4], 4, 4, format!("{value}.webp")).unwrap())
+			.collect();
+		// the last image is already known to the database
+		db.add(database::hash(images[2].data()), "mxc://example.org/known".to_owned())
+			.await
+			.unwrap();
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = std::thread::spawn(move || serve_uploads(listener, 1));
+		let matrix_config = Config {
+			outbox: Some(outbox.clone()),
+			..matrix_config(format!("http://{addr}"))
+		};
+		let mut stickers = Vec::new();
+		let (url, _) = images[0].upload_with(&matrix_config, Some(&db)).await.unwrap();
+		stickers.push(sticker(&images[0], url));
+		// the homeserver goes down mid-import
+		server.join().unwrap();
+		let (url, uploaded) = images[1].upload_with(&matrix_config, Some(&db)).await.unwrap();
+		assert!(url.is_deferred() && !uploaded);
+		stickers.push(sticker(&images[1], url));
+		stickers.push(sticker(
+			&images[2],
+			outbox.push("200.webp", images[2].data().clone(), "image/webp").await.unwrap()
+		));
+		let mut pack = StickerPack {
+			title: "Outbox".to_owned(),
+			id: "outbox".to_owned(),
+			tg_pack: None,
+			stickers
+		};
+		assert!(pack.has_deferred());
+		let entries = outbox.entries().await.unwrap();
+		let mut file_names: Vec<_> = entries.iter().map(|entry| entry.file_name.as_str()).collect();
+		file_names.sort();
+		assert_eq!(file_names, ["100.webp", "200.webp"]);
+
+		// the homeserver is back; only the unknown image is uploaded
+		let listener = TcpListener::bind(addr).unwrap();
+		let server = std::thread::spawn(move || serve_uploads(listener, 1));
+		let uploaded = outbox.flush(&matrix_config, Some(&db)).await.unwrap();
+		assert_eq!(server.join().unwrap().len(), 1);
+		assert_eq!(uploaded.len(), 2);
+		pack.resolve_deferred(&uploaded);
+		assert!(!pack.has_deferred());
+		let urls: Vec<_> = pack.stickers.iter().map(|sticker| sticker.image.url.url().as_str()).collect();
+		assert_eq!(urls, [
+			"mxc://example.org/abc",
+			"mxc://example.org/abc",
+			"mxc://example.org/known"
+		]);
+		assert!(outbox.entries().await.unwrap().is_empty());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,167 @@
+use crate::error::Error;
+use std::{
+	sync::Mutex,
+	time::{Duration, Instant}
+};
+
+/// Time after which the rate has recovered completely, after it was reduced by [`RateLimiter::limit_exceeded`].
+const RECOVERY: Duration = Duration::from_secs(60);
+/// The rate is never reduced below the configured rate divided by this factor.
+const MAX_SLOWDOWN: f64 = 64.0;
+
+#[derive(Debug)]
+struct Bucket {
+	tokens: f64,
+	/// current rate in requests per second, which can be lower than the configured one
+	rate: f64,
+	last_refill: Instant
+}
+
+/// Token bucket, which limits the requests to the homeserver.
+///
+/// A single limiter can be shared between multiple [`Config`](super::Config)s (for example of different users),
+/// so that concurrent imports together stay below the limit of the homeserver.
+/// If the homeserver does still answer with `M_LIMIT_EXCEEDED`, the rate is halved
+/// and afterwards recovers slowly to the configured value.
+#[derive(Debug)]
+pub struct RateLimiter {
+	rate: f64,
+	burst: f64,
+	bucket: Mutex<Bucket>
+}
+
+impl RateLimiter {
+	/// Allow `requests_per_second` requests on average and up to `burst` requests at once.
+	///
+	/// The rate must be finite and larger than zero; a limiter with a rate of zero would never allow a request.
+	pub fn new(requests_per_second: f64, burst: u32) -> Result<Self, Error> {
+		if !(requests_per_second.is_finite() && requests_per_second > 0.0) {
+			return Err(Error::InvalidRate(requests_per_second));
+		}
+		let burst = burst.max(1) as f64;
+		Ok(Self {
+			rate: requests_per_second,
+			burst,
+			bucket: Mutex::new(Bucket {
+				tokens: burst,
+				rate: requests_per_second,
+				last_refill: Instant::now()
+			})
+		})
+	}
+
+	/// Current rate in requests per second.
+	pub fn current_rate(&self) -> f64 {
+		let mut bucket = self.bucket.lock().unwrap();
+		self.refill(&mut bucket, Instant::now());
+		bucket.rate
+	}
+
+	fn refill(&self, bucket: &mut Bucket, now: Instant) {
+		let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(self.burst);
+		bucket.rate = (bucket.rate + self.rate * elapsed / RECOVERY.as_secs_f64()).min(self.rate);
+		bucket.last_refill = now;
+	}
+
+	/// Wait until the next request can be sent.
+	pub async fn acquire(&self) {
+		loop {
+			let wait = {
+				let mut bucket = self.bucket.lock().unwrap();
+				self.refill(&mut bucket, Instant::now());
+				if bucket.tokens >= 1.0 {
+					bucket.tokens -= 1.0;
+					return;
+				}
+				Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate)
+			};
+			tokio::time::sleep(wait).await;
+		}
+	}
+
+	/// Report that the homeserver has answered with `M_LIMIT_EXCEEDED`, which halves the rate.
+	pub fn limit_exceeded(&self) {
+		let mut bucket = self.bucket.lock().unwrap();
+		self.refill(&mut bucket, Instant::now());
+		bucket.rate = (bucket.rate / 2.0).max(self.rate / MAX_SLOWDOWN);
+		bucket.tokens = 0.0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{RateLimiter, RECOVERY};
+	use crate::{
+		error::Error,
+		matrix::Config,
+		mock::{matrix_config, serve_uploads}
+	};
+	use std::{net::TcpListener, sync::Arc, time::Instant};
+
+	#[test]
+	fn limit_exceeded() {
+		let limiter = RateLimiter::new(10.0, 1).unwrap();
+		limiter.limit_exceeded();
+		assert!(limiter.current_rate() <= 5.1);
+		limiter.limit_exceeded();
+		assert!(limiter.current_rate() <= 2.6);
+		let mut bucket = limiter.bucket.lock().unwrap();
+		let now = Instant::now() + RECOVERY;
+		limiter.refill(&mut bucket, now);
+		assert_eq!(bucket.rate, 10.0);
+		assert_eq!(bucket.tokens, 1.0);
+	}
+
+	#[test]
+	fn invalid_rate() {
+		for rate in [0.0, -0.0, -1.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+			let err = RateLimiter::new(rate, 1).unwrap_err();
+			assert!(matches!(err, Error::InvalidRate(invalid) if invalid.to_bits() == rate.to_bits()));
+		}
+		assert!(RateLimiter::new(0.5, 1).is_ok());
+	}
+
+	/// Two imports for different users, which share one limiter, must stay below the limit together.
+	#[tokio::test]
+	async fn shared_limiter() {
+		const RATE: f64 = 20.0;
+		const BURST: u32 = 2;
+		const UPLOADS: usize = 5;
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let homeserver_url = format!("http://{}", listener.local_addr().unwrap());
+		let server = std::thread::spawn(move || serve_uploads(listener, 2 * UPLOADS));
+		let limiter = Arc::new(RateLimiter::new(RATE, BURST).unwrap());
+		let import = |user: &str| {
+			let matrix_config = Config {
+				user: user.to_owned(),
+				rate_limiter: Some(limiter.clone()),
+				..matrix_config(homeserver_url.clone())
+			};
+			async move {
+				for i in 0..UPLOADS {
+					let filename = format!("{i}.webp");
+					super::super::upload_ref(&matrix_config, &filename, &[0; 16], "image/webp")
+						.await
+						.unwrap();
+				}
+			}
+		};
+		tokio::join!(import("@alice:example.org"), import("@bob:example.org"));
+		let timestamps = server.join().unwrap();
+
+		// every time window may contain at most the burst and the requests allowed by the rate;
+		// one additional request is tolerated, because the timestamps are taken at the server
+		for (i, start) in timestamps.iter().enumerate() {
+			for (j, end) in timestamps.iter().enumerate().skip(i) {
+				let allowed = BURST as f64 + RATE * end.duration_since(*start).as_secs_f64() + 1.0;
+				assert!(
+					(j - i + 1) as f64 <= allowed,
+					"{} requests in {:?}",
+					j - i + 1,
+					end.duration_since(*start)
+				);
+			}
+		}
+	}
+}
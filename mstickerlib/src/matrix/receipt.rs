@@ -0,0 +1,145 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::{
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH}
+};
+use tokio::{fs, io::AsyncWriteExt as _, sync::Mutex};
+
+const REDACTED: &str = "<redacted>";
+
+/// Answer of the homeserver to an upload, to be able to prove later what the homeserver has returned.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UploadReceipt {
+	/// unix timestamp in seconds, when the answer was received
+	pub timestamp: u64,
+	/// http status code
+	pub status: u16,
+	/// filename, which was sent with the upload request
+	pub filename: String,
+	/// size of the uploaded file in bytes
+	pub content_length: u64,
+	/// response headers; the access token is redacted, if it does appear at any value
+	pub headers: IndexMap<String, String>,
+	/// response body; the access token is redacted, if it does appear at it
+	pub body: String
+}
+
+impl UploadReceipt {
+	/// Create a receipt from the response of the homeserver.
+	/// Because the body has to be read for this, a new response with the same content is returned.
+	pub(crate) async fn capture(
+		response: reqwest::Response,
+		filename: &str,
+		content_length: u64,
+		access_token: &str
+	) -> Result<(Self, reqwest::Response), reqwest::Error> {
+		let status = response.status();
+		let headers = response.headers().clone();
+		let body = response.bytes().await?;
+		let timestamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|time| time.as_secs())
+			.unwrap_or_default();
+		let redact = |value: &str| {
+			if access_token.is_empty() {
+				value.to_owned()
+			} else {
+				value.replace(access_token, REDACTED)
+			}
+		};
+		let receipt = Self {
+			timestamp,
+			status: status.as_u16(),
+			filename: filename.to_owned(),
+			content_length,
+			headers: headers
+				.iter()
+				.map(|(key, value)| (key.to_string(), redact(&String::from_utf8_lossy(value.as_bytes()))))
+				.collect(),
+			body: redact(&String::from_utf8_lossy(&body))
+		};
+
+		let mut response = http::Response::new(body);
+		*response.status_mut() = status;
+		*response.headers_mut() = headers;
+		Ok((receipt, response.into()))
+	}
+}
+
+/// Collects the [UploadReceipt]s of all uploads, see [`Config::receipts`](super::Config::receipts).
+#[derive(Debug, Default)]
+pub struct UploadReceipts(Mutex<Vec<UploadReceipt>>);
+
+impl UploadReceipts {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub(crate) async fn push(&self, receipt: UploadReceipt) {
+		self.0.lock().await.push(receipt);
+	}
+
+	/// Return all receipts collected so far.
+	pub async fn receipts(&self) -> Vec<UploadReceipt> {
+		self.0.lock().await.clone()
+	}
+
+	/// Append all receipts collected so far to an audit log file, as one json object per line.
+	pub async fn write_json_lines<P>(&self, path: P) -> std::io::Result<()>
+	where
+		P: AsRef<Path>
+	{
+		let mut lines = Vec::new();
+		for receipt in self.0.lock().await.iter() {
+			serde_json::to_writer(&mut lines, receipt)?;
+			lines.push(b'\n');
+		}
+		let mut file = fs::OpenOptions::new().append(true).create(true).open(path).await?;
+		file.write_all(&lines).await?;
+		file.flush().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::UploadReceipts;
+	use crate::{
+		matrix::Config,
+		mock::{matrix_config, serve_uploads}
+	};
+	use std::{net::TcpListener, sync::Arc};
+
+	#[tokio::test]
+	async fn upload_receipt() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let homeserver_url = format!("http://{}", listener.local_addr().unwrap());
+		let server = std::thread::spawn(move || serve_uploads(listener, 1));
+		let receipts = Arc::new(UploadReceipts::new());
+		let matrix_config = Config {
+			receipts: Some(receipts.clone()),
+			..matrix_config(homeserver_url)
+		};
+		let mxc = super::super::upload(
+			&matrix_config,
+			&"sticker.webp".to_owned(),
+			Arc::new(vec![0; 16]),
+			"image/webp"
+		)
+		.await
+		.unwrap();
+		server.join().unwrap();
+		assert_eq!(mxc.url(), "mxc://example.org/abc");
+
+		let receipts = receipts.receipts().await;
+		assert_eq!(receipts.len(), 1);
+		let receipt = &receipts[0];
+		assert_eq!(receipt.status, 200);
+		assert_eq!(receipt.filename, "sticker.webp");
+		assert_eq!(receipt.content_length, 16);
+		assert_eq!(receipt.body, r#"{"content_uri":"mxc://example.org/abc"}"#);
+		assert!(receipt.timestamp > 0);
+		assert_eq!(receipt.headers["x-echo"], "<redacted>");
+		assert!(!serde_json::to_string(receipt).unwrap().contains("token_secret"));
+	}
+}
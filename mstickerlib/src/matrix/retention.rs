@@ -0,0 +1,156 @@
+use super::Config;
+use crate::{error::Error, CLIENT};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+
+#[cfg(feature = "log")]
+use log::debug;
+
+/// Request to keep uploaded media, even if the media repository does remove old files by a retention policy.
+///
+/// Some media repositories can exclude uploads from their retention policy. There is no standard for this,
+/// so the header, its value and the unstable feature, which announces the support, must be configured
+/// like the media repository does document them.
+/// The hint is sent as header with every upload, if the homeserver lists [`RetentionHint::feature`] as enabled
+/// at its unstable features, or if [`RetentionHint::assume_supported`] is set. Other homeservers do not get the header.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetentionHint {
+	/// name of the header, which is added to uploads
+	pub header: String,
+	/// value of the header
+	pub value: String,
+	/// unstable feature of `/_matrix/client/versions`, which shows that the hint is understood
+	pub feature: String,
+	/// Send the hint without asking the homeserver first, for media repositories which do not list the feature.
+	#[serde(default)]
+	pub assume_supported: bool,
+	/// if the homeserver does support the hint; asked once per config
+	#[serde(skip)]
+	supported: OnceCell<bool>
+}
+
+#[derive(Debug, Deserialize)]
+struct Versions {
+	#[serde(default)]
+	unstable_features: HashMap<String, bool>
+}
+
+impl RetentionHint {
+	/// Send the header `header: value` with uploads, if the homeserver lists the unstable `feature`.
+	pub fn new(header: String, value: String, feature: String) -> Self {
+		Self {
+			header,
+			value,
+			feature,
+			assume_supported: false,
+			supported: OnceCell::new()
+		}
+	}
+
+	/// Header, which should be added to uploads to `matrix`, if the homeserver does support the hint.
+	pub(super) async fn header(&self, matrix: &Config) -> Option<(&str, &str)> {
+		let supported = *self
+			.supported
+			.get_or_init(|| async {
+				if self.assume_supported {
+					return true;
+				}
+				let supported = supports_feature(matrix, &self.feature).await;
+				#[cfg(feature = "log")]
+				match &supported {
+					Ok(true) => {},
+					Ok(false) => debug!("homeserver does not support media retention hints, ignore them"),
+					Err(err) => {
+						debug!("failed to check if the homeserver supports media retention hints, ignore them: {err}")
+					}
+				}
+				supported.unwrap_or_default()
+			})
+			.await;
+		supported.then_some((self.header.as_str(), self.value.as_str()))
+	}
+}
+
+async fn supports_feature(matrix: &Config, feature: &str) -> Result<bool, Error> {
+	let request = CLIENT.get().get(format!("{}/_matrix/client/versions", matrix.homeserver_url));
+	let versions: Versions = matrix.send(request).await?.error_for_status()?.json().await?;
+	Ok(versions.unstable_features.get(feature).copied().unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::RetentionHint;
+	use crate::{
+		matrix::Config,
+		mock::{matrix_config, serve_routes}
+	};
+	use std::net::TcpListener;
+
+	const UPLOAD: (&str, &str) = ("/_matrix/media/r0/upload", r#"{"content_uri":"mxc://example.org/abc"}"#);
+	const HEADER: &str = "x-keep-media: true";
+
+	fn hint() -> RetentionHint {
+		RetentionHint::new(
+			"X-Keep-Media".to_owned(),
+			"true".to_owned(),
+			"org.example.media_retention".to_owned()
+		)
+	}
+
+	/// Upload two files with the hint and return the requests received by the homeserver.
+	async fn upload(routes: &'static [(&'static str, &'static str)], hint: RetentionHint, count: usize) -> Vec<String> {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = Config {
+			media_retention: Some(hint),
+			..matrix_config(format!("http://{}", listener.local_addr().unwrap()))
+		};
+		let server = std::thread::spawn(move || serve_routes(listener, routes, count));
+		for i in 0..2 {
+			let filename = format!("{i}.webp");
+			super::super::upload_ref(&matrix_config, &filename, &[0; 16], "image/webp")
+				.await
+				.unwrap();
+		}
+		server.join().unwrap()
+	}
+
+	fn paths(requests: &[String]) -> Vec<&str> {
+		requests
+			.iter()
+			.map(|request| request.split_whitespace().nth(1).unwrap().split('?').next().unwrap())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn supported() {
+		const VERSIONS: (&str, &str) = (
+			"/_matrix/client/versions",
+			r#"{"versions":["v1.11"],"unstable_features":{"org.example.media_retention":true}}"#
+		);
+		let requests = upload(&[VERSIONS, UPLOAD], hint(), 3).await;
+		// the homeserver is only asked once
+		assert_eq!(paths(&requests), ["/_matrix/client/versions", UPLOAD.0, UPLOAD.0]);
+		assert!(requests[1..]
+			.iter()
+			.all(|request| request.to_ascii_lowercase().contains(HEADER)));
+
+		let hint = RetentionHint {
+			assume_supported: true,
+			..hint()
+		};
+		let requests = upload(&[UPLOAD], hint, 2).await;
+		assert_eq!(paths(&requests), [UPLOAD.0, UPLOAD.0]);
+		assert!(requests.iter().all(|request| request.to_ascii_lowercase().contains(HEADER)));
+	}
+
+	#[tokio::test]
+	async fn unsupported() {
+		const VERSIONS: (&str, &str) = ("/_matrix/client/versions", r#"{"versions":["v1.11"]}"#);
+		let requests = upload(&[VERSIONS, UPLOAD], hint(), 3).await;
+		assert_eq!(paths(&requests), ["/_matrix/client/versions", UPLOAD.0, UPLOAD.0]);
+		assert!(!requests
+			.iter()
+			.any(|request| request.to_ascii_lowercase().contains("x-keep-media")));
+	}
+}
@@ -0,0 +1,102 @@
+use crate::error::Error;
+use std::{
+	sync::atomic::{AtomicU32, Ordering},
+	time::Duration
+};
+
+/// Delay before retrying, if the homeserver does not ask for a specific delay.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Number of retries, which can be spent by all uploads together.
+///
+/// Share a single budget between the uploads of a whole batch (via [`Config::retry_budget`](super::Config::retry_budget)),
+/// so that a broadly unhealthy homeserver does not cause a retry storm.
+/// Once the budget is spent, failing uploads return their error immediately.
+#[derive(Debug)]
+pub struct RetryBudget(AtomicU32);
+
+impl RetryBudget {
+	pub fn new(retries: u32) -> Self {
+		Self(AtomicU32::new(retries))
+	}
+
+	/// Number of retries, which are left.
+	pub fn remaining(&self) -> u32 {
+		self.0.load(Ordering::SeqCst)
+	}
+
+	/// Take one retry from the budget; return `false` if it is already spent.
+	pub fn try_spend(&self) -> bool {
+		self.0
+			.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| remaining.checked_sub(1))
+			.is_ok()
+	}
+}
+
+/// `true` if the request has failed, because the homeserver was not reachable.
+pub(crate) fn is_unreachable(error: &Error) -> bool {
+	matches!(error, Error::Reqwest(error) if error.is_connect() || error.is_timeout())
+}
+
+/// Return the delay before the next attempt, if `error` is transient and the request should be retried.
+pub(super) fn retry_delay(error: &Error) -> Option<Duration> {
+	match error {
+		error if is_unreachable(error) => Some(RETRY_DELAY),
+		Error::MatrixUpload(error) if error.status_code.is_server_error() => Some(RETRY_DELAY),
+		Error::MatrixUpload(error) if error.status_code == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+			let retry_after = error.matrix_error.as_ref().ok().and_then(|error| error.retry_after_ms);
+			Some(retry_after.map(|ms| Duration::from_millis(ms as u64)).unwrap_or(RETRY_DELAY))
+		},
+		_ => None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::RetryBudget;
+	use crate::{
+		matrix::Config,
+		mock::{matrix_config, serve_errors}
+	};
+	use std::{
+		net::TcpListener,
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc
+		}
+	};
+
+	#[test]
+	fn try_spend() {
+		let budget = RetryBudget::new(2);
+		assert!(budget.try_spend());
+		assert!(budget.try_spend());
+		assert!(!budget.try_spend());
+		assert_eq!(budget.remaining(), 0);
+	}
+
+	/// A batch against a homeserver, which does always fail, must stop retrying once the budget is spent.
+	#[tokio::test]
+	async fn batch_stops_retrying() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let homeserver_url = format!("http://{}", listener.local_addr().unwrap());
+		let requests = Arc::new(AtomicUsize::new(0));
+		{
+			let requests = requests.clone();
+			std::thread::spawn(move || serve_errors(listener, requests));
+		}
+		let budget = Arc::new(RetryBudget::new(3));
+		let matrix_config = Config {
+			retry_budget: Some(budget.clone()),
+			..matrix_config(homeserver_url)
+		};
+		for i in 0..4 {
+			let filename = format!("{i}.webp");
+			let result = super::super::upload_ref(&matrix_config, &filename, &[0; 16], "image/webp").await;
+			assert!(result.is_err());
+		}
+		assert_eq!(budget.remaining(), 0);
+		// 4 uploads and 3 retries
+		assert_eq!(requests.load(Ordering::SeqCst), 7);
+	}
+}
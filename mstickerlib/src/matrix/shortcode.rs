@@ -0,0 +1,260 @@
+use super::stickerpack::StickerPack;
+use crate::error::Error;
+use serde::Deserialize;
+use std::{
+	collections::{HashMap, HashSet},
+	path::Path
+};
+use tokio::fs;
+
+#[cfg(feature = "log")]
+use log::warn;
+
+/// Check if `shortcode` is not empty and does only contain ascii letters, digits, `_` and `-`.
+pub fn is_valid_shortcode(shortcode: &str) -> bool {
+	!shortcode.is_empty() && shortcode.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// What to do, if multiple stickers would get the same shortcode.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortcodeCollision {
+	/// Fail with [`Error::ShortcodeCollision`].
+	Error,
+	/// Append a number to the shortcodes of the later stickers, like `kot_2`.
+	#[default]
+	Suffix,
+	/// Remove the later stickers from the pack.
+	Skip
+}
+
+/// Custom mapping from emoji to shortcodes, like `"🐱" → "kot"`.
+///
+/// Stickers without an entry keep the default shortcode, which is their position at the pack.
+#[derive(Clone, Debug, Default)]
+pub struct ShortcodeDictionary(HashMap<String, String>);
+
+impl ShortcodeDictionary {
+	/// Create a dictionary from an emoji to shortcode map. All shortcodes must be valid, see [`is_valid_shortcode`].
+	pub fn new(map: HashMap<String, String>) -> Result<Self, Error> {
+		if let Some(shortcode) = map.values().find(|shortcode| !is_valid_shortcode(shortcode)) {
+			return Err(Error::InvalidShortcode(shortcode.to_owned()));
+		}
+		Ok(Self(map))
+	}
+
+	/// Parse a dictionary from a json object, like `{"🐱": "kot"}`.
+	pub fn from_json(json: &str) -> Result<Self, Error> {
+		Self::new(serde_json::from_str(json)?)
+	}
+
+	/// Load a dictionary from a json file, see [`ShortcodeDictionary::from_json`].
+	pub async fn load(path: &Path) -> Result<Self, Error> {
+		Self::from_json(&fs::read_to_string(path).await?)
+	}
+
+	/// Shortcode for `emoji`, if the dictionary has an entry for it.
+	pub fn get(&self, emoji: &str) -> Option<&str> {
+		self.0.get(emoji).map(String::as_str)
+	}
+
+	/// Set the emoticon of all stickers without one, whose emoji has an entry at the dictionary.
+	/// If multiple stickers would get the same shortcode, a number is appended to the later ones, like `kot_2`.
+	pub fn apply(&self, pack: &mut StickerPack) {
+		// suffixing does always find a free shortcode
+		let _ = self.apply_with(pack, ShortcodeCollision::Suffix);
+	}
+
+	/// Like [`ShortcodeDictionary::apply`], but choose what happens, if multiple stickers would get the same shortcode.
+	/// The policy does also apply to stickers, which do already have an emoticon.
+	/// They are handled before the stickers, which get their shortcode from the dictionary,
+	/// so an existing emoticon is only changed, if another sticker has already the same emoticon.
+	/// The pack is not changed, if this fails.
+	pub fn apply_with(&self, pack: &mut StickerPack, collision: ShortcodeCollision) -> Result<(), Error> {
+		let mut used = HashSet::new();
+		let mut emoticons = vec![None; pack.stickers.len()];
+		let mut skipped = HashSet::new();
+		let (existing, new): (Vec<_>, Vec<_>) = pack
+			.stickers
+			.iter()
+			.enumerate()
+			.partition(|(_, sticker)| sticker.emoticon.is_some());
+		for (index, sticker) in existing.into_iter().chain(new) {
+			let shortcode = match &sticker.emoticon {
+				Some(emoticon) => emoticon.as_str(),
+				None => match sticker.emoji.iter().find_map(|emoji| self.get(emoji)) {
+					Some(shortcode) => shortcode,
+					None => continue
+				}
+			};
+			let mut unique = shortcode.to_owned();
+			if used.contains(&unique) {
+				match collision {
+					ShortcodeCollision::Error => {
+						return Err(Error::ShortcodeCollision {
+							shortcode: unique,
+							index
+						})
+					},
+					ShortcodeCollision::Suffix => {
+						let mut i = 1;
+						while used.contains(&unique) {
+							i += 1;
+							unique = format!("{shortcode}_{i}");
+						}
+					},
+					ShortcodeCollision::Skip => {
+						#[cfg(feature = "log")]
+						warn!(
+							"skip sticker {index} of pack {:?}, because shortcode {unique:?} is already used",
+							pack.id
+						);
+						skipped.insert(index);
+						continue;
+					}
+				}
+			}
+			used.insert(unique.clone());
+			emoticons[index] = Some(unique);
+		}
+		for (sticker, emoticon) in pack.stickers.iter_mut().zip(emoticons) {
+			if emoticon.is_some() {
+				sticker.emoticon = emoticon;
+			}
+		}
+		let mut index = 0;
+		pack.stickers.retain(|_| {
+			index += 1;
+			!skipped.contains(&(index - 1))
+		});
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ShortcodeCollision, ShortcodeDictionary};
+	use crate::{
+		error::Error,
+		matrix::{
+			sticker::{Image, Sticker},
+			sticker_formats::ponies::{self, MetaData},
+			stickerpack::StickerPack
+		}
+	};
+
+	fn sticker(emoji: &str) -> Sticker {
+		Sticker {
+			body: emoji.to_owned(),
+			display_name: None,
+			image: Image {
+				url: format!("mxc://example.org/{}", emoji.len()).into(),
+				meta_data: MetaData {
+					w: 256,
+					h: 256,
+					size: 1024,
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: None,
+			emoji: vec![emoji.to_owned()],
+			tg_sticker: None,
+			content_hash: None
+		}
+	}
+
+	#[test]
+	fn apply() {
+		let dictionary = ShortcodeDictionary::from_json(r#"{"🐱": "kot", "🐶": "pies"}"#).unwrap();
+		let mut pack = StickerPack {
+			title: "Animals".to_owned(),
+			id: "animals".to_owned(),
+			tg_pack: None,
+			stickers: vec![sticker("🐱"), sticker("🐶"), sticker("🐭"), sticker("🐱")]
+		};
+		dictionary.apply(&mut pack);
+		let emoticons: Vec<_> = pack.stickers.iter().map(|sticker| sticker.emoticon.as_deref()).collect();
+		assert_eq!(emoticons, vec![Some("kot"), Some("pies"), None, Some("kot_2")]);
+
+		// the msc2545 pack uses the same shortcodes and the position for the others
+		let pack: ponies::StickerPack = pack.into();
+		let keys: Vec<_> = pack.images.keys().map(String::as_str).collect();
+		assert_eq!(keys, vec!["kot", "pies", "0002", "kot_2"]);
+	}
+
+	#[test]
+	fn collision() {
+		let dictionary = ShortcodeDictionary::from_json(r#"{"🐱": "kot", "😺": "kot"}"#).unwrap();
+		let pack = StickerPack {
+			title: "Cats".to_owned(),
+			id: "cats".to_owned(),
+			tg_pack: None,
+			stickers: vec![sticker("🐱"), sticker("😺")]
+		};
+		let emoticons = |pack: &StickerPack| -> Vec<_> {
+			pack.stickers
+				.iter()
+				.map(|sticker| (sticker.body.clone(), sticker.emoticon.clone()))
+				.collect()
+		};
+
+		let mut suffix = pack.clone();
+		dictionary.apply_with(&mut suffix, ShortcodeCollision::Suffix).unwrap();
+		assert_eq!(emoticons(&suffix), [
+			("🐱".to_owned(), Some("kot".to_owned())),
+			("😺".to_owned(), Some("kot_2".to_owned()))
+		]);
+
+		let mut skip = pack.clone();
+		dictionary.apply_with(&mut skip, ShortcodeCollision::Skip).unwrap();
+		assert_eq!(emoticons(&skip), [("🐱".to_owned(), Some("kot".to_owned()))]);
+
+		let mut error = pack.clone();
+		let err = dictionary.apply_with(&mut error, ShortcodeCollision::Error).unwrap_err();
+		assert!(matches!(err, Error::ShortcodeCollision { shortcode, index: 1 } if shortcode == "kot"));
+		assert_eq!(emoticons(&error), emoticons(&pack));
+	}
+
+	#[test]
+	fn collision_with_existing_emoticons() {
+		let dictionary = ShortcodeDictionary::from_json(r#"{"🐱": "kot"}"#).unwrap();
+		let with_emoticon = |emoji: &str, emoticon: &str| Sticker {
+			emoticon: Some(emoticon.to_owned()),
+			..sticker(emoji)
+		};
+		let pack = StickerPack {
+			title: "Cats".to_owned(),
+			id: "cats".to_owned(),
+			tg_pack: None,
+			stickers: vec![sticker("🐱"), with_emoticon("😺", "kot"), with_emoticon("😸", "kot")]
+		};
+		let emoticons =
+			|pack: &StickerPack| -> Vec<_> { pack.stickers.iter().map(|sticker| sticker.emoticon.clone()).collect() };
+
+		let mut suffix = pack.clone();
+		dictionary.apply_with(&mut suffix, ShortcodeCollision::Suffix).unwrap();
+		assert_eq!(emoticons(&suffix), [
+			Some("kot_3".to_owned()),
+			Some("kot".to_owned()),
+			Some("kot_2".to_owned())
+		]);
+
+		let mut skip = pack.clone();
+		dictionary.apply_with(&mut skip, ShortcodeCollision::Skip).unwrap();
+		assert_eq!(emoticons(&skip), [Some("kot".to_owned())]);
+		assert_eq!(skip.stickers[0].body, "😺");
+
+		let mut error = pack.clone();
+		let err = dictionary.apply_with(&mut error, ShortcodeCollision::Error).unwrap_err();
+		assert!(matches!(err, Error::ShortcodeCollision { shortcode, index: 2 } if shortcode == "kot"));
+	}
+
+	#[test]
+	fn invalid_shortcode() {
+		let err = ShortcodeDictionary::from_json(r#"{"🐱": "kot", "🐶": "pies mały"}"#).unwrap_err();
+		assert!(matches!(err, Error::InvalidShortcode(shortcode) if shortcode == "pies mały"));
+	}
+}
@@ -0,0 +1,288 @@
+//! Publish a sticker pack to all rooms of a [space](https://spec.matrix.org/latest/client-server-api/#spaces).
+
+use super::{api_error, sticker_formats::ponies, stickerpack::StickerPack, Config};
+use crate::{error::Error, CLIENT};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+#[cfg(feature = "log")]
+use log::{info, warn};
+
+/// Type of the [MSC2545](https://github.com/matrix-org/matrix-spec-proposals/pull/2545) state event of room packs.
+const ROOM_EMOTES: &str = "im.ponies.room_emotes";
+
+/// Options of [`publish_pack_to_space`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct SpaceOptions {
+	/// How deep the space is searched for rooms, where the space itself has depth 0.
+	/// Rooms of subspaces are included from a depth of 2.
+	pub max_depth: u32,
+	/// The search stops after this many rooms were found, to protect against huge spaces.
+	pub max_rooms: usize,
+	/// Only publish to rooms with one of these join rules, like `public` or `restricted`; to all rooms if `None`.
+	pub join_rules: Option<Vec<String>>,
+	/// State key of the pack event; the id of the pack if `None`.
+	pub state_key: Option<String>
+}
+
+impl Default for SpaceOptions {
+	fn default() -> Self {
+		Self {
+			max_depth: 3,
+			max_rooms: 100,
+			join_rules: None,
+			state_key: None
+		}
+	}
+}
+
+/// Result of [`publish_pack_to_space`].
+#[derive(Debug, Default)]
+pub struct SpacePublishReport {
+	/// rooms, to which the pack was published
+	pub published: Vec<String>,
+	/// rooms, which the user has not joined or whose join rule is not selected by [`SpaceOptions::join_rules`]
+	pub skipped: Vec<String>,
+	/// rooms, to which the pack could not be published, for example because of missing permissions
+	pub failed: Vec<(String, Error)>,
+	/// `true` if the space has more rooms than [`SpaceOptions::max_rooms`]
+	pub truncated: bool
+}
+
+/// see <https://spec.matrix.org/latest/client-server-api/#get_matrixclientv3joined_rooms>
+#[derive(Deserialize)]
+struct JoinedRooms {
+	joined_rooms: HashSet<String>
+}
+
+/// see <https://spec.matrix.org/latest/client-server-api/#get_matrixclientv1roomsroomidhierarchy>
+#[derive(Deserialize)]
+struct Hierarchy {
+	rooms: Vec<HierarchyRoom>,
+	next_batch: Option<String>
+}
+
+#[derive(Deserialize)]
+struct HierarchyRoom {
+	room_id: String,
+	room_type: Option<String>,
+	join_rule: Option<String>
+}
+
+async fn joined_rooms(matrix: &Config) -> Result<HashSet<String>, Error> {
+	let request = CLIENT
+		.get()
+		.get(format!("{}/_matrix/client/v3/joined_rooms", matrix.homeserver_url))
+		.query(&[("access_token", &matrix.access_token)]);
+	let answer = matrix.send(request).await?;
+	if answer.status() != StatusCode::OK {
+		return Err(api_error(answer).await);
+	}
+	let joined: JoinedRooms = answer.json().await?;
+	Ok(joined.joined_rooms)
+}
+
+/// Collect the rooms of the space, without the space itself and its subspaces.
+/// Return `true` together with the rooms, if more than [`SpaceOptions::max_rooms`] were found.
+async fn space_rooms(matrix: &Config, space_id: &str, options: &SpaceOptions) -> Result<(Vec<HierarchyRoom>, bool), Error> {
+	let mut rooms = Vec::new();
+	let mut from: Option<String> = None;
+	loop {
+		let mut query = vec![("max_depth", options.max_depth.to_string())];
+		query.extend(from.take().map(|from| ("from", from)));
+		let request = CLIENT
+			.get()
+			.get(format!(
+				"{}/_matrix/client/v1/rooms/{space_id}/hierarchy",
+				matrix.homeserver_url
+			))
+			.query(&query)
+			.query(&[("access_token", &matrix.access_token)]);
+		let answer = matrix.send(request).await?;
+		if answer.status() != StatusCode::OK {
+			return Err(api_error(answer).await);
+		}
+		let page: Hierarchy = answer.json().await?;
+		for room in page.rooms {
+			if room.room_id == space_id || room.room_type.as_deref() == Some("m.space") {
+				continue;
+			}
+			if rooms.len() == options.max_rooms {
+				return Ok((rooms, true));
+			}
+			rooms.push(room);
+		}
+		match page.next_batch {
+			Some(next_batch) if rooms.len() < options.max_rooms => from = Some(next_batch),
+			Some(_) => return Ok((rooms, true)),
+			None => return Ok((rooms, false))
+		}
+	}
+}
+
+async fn publish_to_room(matrix: &Config, room_id: &str, state_key: &str, pack: &ponies::StickerPack) -> Result<(), Error> {
+	let request = CLIENT
+		.get()
+		.put(format!(
+			"{}/_matrix/client/v3/rooms/{room_id}/state/{ROOM_EMOTES}/{state_key}",
+			matrix.homeserver_url
+		))
+		.query(&[("access_token", &matrix.access_token)])
+		.json(pack);
+	let answer = matrix.send_limited(request).await?;
+	match answer.status() {
+		StatusCode::OK => Ok(()),
+		StatusCode::FORBIDDEN => Err(Error::PermissionDenied(room_id.to_owned())),
+		_ => Err(api_error(answer).await)
+	}
+}
+
+/// Publish `pack` as [MSC2545](https://github.com/matrix-org/matrix-spec-proposals/pull/2545) room pack
+/// to every room of the space `space_id`, which the user has joined.
+///
+/// The rooms are found by the hierarchy api of the space, including the rooms of subspaces up to
+/// [`SpaceOptions::max_depth`]. A failure at a single room, like a missing permission to send state events,
+/// is collected at the report and does not stop publishing to the other rooms.
+pub async fn publish_pack_to_space(
+	matrix: &Config,
+	space_id: &str,
+	pack: &StickerPack,
+	options: &SpaceOptions
+) -> Result<SpacePublishReport, Error> {
+	Url::parse(&matrix.homeserver_url)?; //check if homeserver_url is a valid url
+	let joined = joined_rooms(matrix).await?;
+	let (rooms, truncated) = space_rooms(matrix, space_id, options).await?;
+	let state_key = options.state_key.as_deref().unwrap_or(&pack.id);
+	let content = ponies::StickerPack::from(pack.clone());
+	let mut report = SpacePublishReport {
+		truncated,
+		..Default::default()
+	};
+	for room in rooms {
+		let join_rule_selected = match (&options.join_rules, &room.join_rule) {
+			(None, _) => true,
+			(Some(join_rules), Some(join_rule)) => join_rules.contains(join_rule),
+			(Some(_), None) => false
+		};
+		if !joined.contains(&room.room_id) || !join_rule_selected {
+			report.skipped.push(room.room_id);
+			continue;
+		}
+		match publish_to_room(matrix, &room.room_id, state_key, &content).await {
+			Ok(()) => {
+				#[cfg(feature = "log")]
+				info!("published pack {state_key:?} to {}", room.room_id);
+				report.published.push(room.room_id);
+			},
+			Err(err) => {
+				#[cfg(feature = "log")]
+				warn!("failed to publish pack {state_key:?} to {}: {err}", room.room_id);
+				report.failed.push((room.room_id, err));
+			}
+		}
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{publish_pack_to_space, SpaceOptions};
+	use crate::{
+		error::Error,
+		matrix::stickerpack::StickerPack,
+		mock::{matrix_config, serve_request_lines}
+	};
+	use std::net::TcpListener;
+
+	const ROUTES: &[(&str, &str, &str)] = &[
+		(
+			"GET /_matrix/client/v3/joined_rooms?",
+			"200 OK",
+			r#"{"joined_rooms":["!space:example.org","!general:example.org","!announcements:example.org"]}"#
+		),
+		(
+			"GET /_matrix/client/v1/rooms/!space:example.org/hierarchy?max_depth=3&from=page2&",
+			"200 OK",
+			r#"{"rooms":[
+				{"room_id":"!announcements:example.org","join_rule":"restricted","children_state":[]},
+				{"room_id":"!others:example.org","join_rule":"public","children_state":[]}
+			]}"#
+		),
+		(
+			"GET /_matrix/client/v1/rooms/!space:example.org/hierarchy?max_depth=3&",
+			"200 OK",
+			r#"{"next_batch":"page2","rooms":[
+				{"room_id":"!space:example.org","room_type":"m.space","children_state":[]},
+				{"room_id":"!general:example.org","join_rule":"public","children_state":[]},
+				{"room_id":"!subspace:example.org","room_type":"m.space","join_rule":"public","children_state":[]}
+			]}"#
+		),
+		(
+			"PUT /_matrix/client/v3/rooms/!general:example.org/state/im.ponies.room_emotes/cats?",
+			"200 OK",
+			r#"{"event_id":"$event"}"#
+		),
+		(
+			"PUT /_matrix/client/v3/rooms/!announcements:example.org/state/im.ponies.room_emotes/cats?",
+			"403 Forbidden",
+			r#"{"errcode":"M_FORBIDDEN","error":"You don't have permission to post that to the room"}"#
+		)
+	];
+
+	fn pack() -> StickerPack {
+		StickerPack {
+			title: "Cats".to_owned(),
+			id: "cats".to_owned(),
+			tg_pack: None,
+			stickers: Vec::new()
+		}
+	}
+
+	async fn publish(options: SpaceOptions, count: usize) -> (super::SpacePublishReport, Vec<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_request_lines(listener, ROUTES, count));
+		let report = publish_pack_to_space(&matrix_config, "!space:example.org", &pack(), &options)
+			.await
+			.unwrap();
+		(report, server.join().unwrap())
+	}
+
+	#[tokio::test]
+	async fn nested_space() {
+		let (report, requests) = publish(SpaceOptions::default(), 5).await;
+		assert_eq!(report.published, ["!general:example.org"]);
+		assert_eq!(report.skipped, ["!others:example.org"]);
+		assert_eq!(report.failed.len(), 1);
+		let (room, err) = &report.failed[0];
+		assert_eq!(room, "!announcements:example.org");
+		assert!(matches!(err, Error::PermissionDenied(room) if room == "!announcements:example.org"));
+		assert!(!report.truncated);
+		let put = requests.iter().find(|request| request.starts_with("PUT")).unwrap();
+		let body: serde_json::Value = serde_json::from_str(put.split_once("\r\n\r\n").unwrap().1).unwrap();
+		assert_eq!(body["pack"]["display_name"], "Cats");
+	}
+
+	#[tokio::test]
+	async fn limits() {
+		let options = SpaceOptions {
+			max_rooms: 1,
+			..Default::default()
+		};
+		// the second page is not requested
+		let (report, _) = publish(options, 3).await;
+		assert_eq!(report.published, ["!general:example.org"]);
+		assert!(report.truncated);
+
+		let options = SpaceOptions {
+			join_rules: Some(vec!["restricted".to_owned()]),
+			..Default::default()
+		};
+		let (report, _) = publish(options, 4).await;
+		assert!(report.published.is_empty());
+		assert_eq!(report.skipped, ["!general:example.org", "!others:example.org"]);
+		assert_eq!(report.failed.len(), 1);
+	}
+}
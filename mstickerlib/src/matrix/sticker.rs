@@ -5,19 +5,25 @@ use super::{
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Sticker {
 	pub body: String,
+	/// original file name of the sticker, before it was converted
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub display_name: Option<String>,
 	pub image: Image,
 	pub thumbnail: Option<Image>,
 	///abbreviation for the sticker, to be used inline
 	pub emoticon: Option<String>,
 	///unicode emoji with are assioted with the sticker
 	pub emoji: Vec<String>,
-	pub tg_sticker: Option<TgStickerInfo>
+	pub tg_sticker: Option<TgStickerInfo>,
+	/// hex encoded hash of the data of `image`, see [`database::hash`](crate::database::hash)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub content_hash: Option<String>
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 /// Matrix Image struct, containing url and meta_data
 pub struct Image {
 	pub url: Mxc,
@@ -26,7 +32,7 @@ pub struct Image {
 
 ///info about the original telegram sticker
 ///this field should not be change manual
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TgStickerInfo {
 	///pack where the sticker is from
 	pub pack_name: String,
@@ -62,11 +68,13 @@ impl From<maunium::Sticker> for Sticker {
 		let tg_sticker: Option<TgStickerInfo> = value.tg_sticker.map(|f| f.into());
 		Self {
 			body: value.body,
+			display_name: value.display_name,
 			image,
 			thumbnail,
 			emoticon: None,
 			emoji: tg_sticker.as_ref().map(|f| f.emoji.to_owned()).unwrap_or_default(),
-			tg_sticker
+			tg_sticker,
+			content_hash: None
 		}
 	}
 }
@@ -7,7 +7,7 @@ use super::ponies::MetaData;
 use monostate::MustBe;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct StickerPack {
 	pub title: String,
 	pub id: String,
@@ -17,15 +17,20 @@ pub struct StickerPack {
 }
 
 ///information about the telegram pack, which was imported
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TgPackRootInfo {
 	pub short_name: String,
+	/// access hash of the pack; empty for packs imported with the bot api
+	#[serde(default, skip_serializing_if = "String::is_empty")]
 	pub hash: String
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Sticker {
 	pub body: String,
+	/// original file name of the sticker, before it was converted; not used by the stickerpicker
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub display_name: Option<String>,
 	pub url: Mxc,
 	pub info: StickerInfo,
 	/// must always be "m.sticker", use `Default::default` to initialize it.
@@ -36,7 +41,7 @@ pub struct Sticker {
 	pub tg_sticker: Option<TgStickerInfo>
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct StickerInfo {
 	#[serde(flatten)]
 	pub image_info: MetaData,
@@ -46,7 +51,7 @@ pub struct StickerInfo {
 
 ///additonal informations about the original telegram sticker
 ///stored at stickers->net.maunium.telegram.sticker
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TgStickerInfo {
 	pub pack: TgPackInfo,
 	pub id: String,
@@ -55,7 +60,7 @@ pub struct TgStickerInfo {
 
 ///additonal informations about the original telegram stickerpack in witch the sticker was inculded
 ///stored at stickers->net.maunium.telegram.sticker->pack
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TgPackInfo {
 	pub id: String,
 	pub short_name: String
@@ -77,6 +82,7 @@ impl From<crate::matrix::sticker::Sticker> for Sticker {
 		};
 		Self {
 			body: value.body,
+			display_name: value.display_name,
 			url: value.image.url.clone(),
 			info,
 			msgtype: Default::default(),
@@ -91,8 +97,143 @@ impl From<crate::matrix::stickerpack::StickerPack> for StickerPack {
 		Self {
 			title: value.title,
 			id: value.id,
-			tg_pack: None,
+			tg_pack: value.tg_pack.map(|f| TgPackRootInfo {
+				short_name: f.name,
+				hash: String::new()
+			}),
 			stickers: value.stickers.into_iter().map(|f| f.into()).collect()
 		}
 	}
 }
+
+impl Sticker {
+	/// `true` if both stickers are the same telegram sticker.
+	/// If one of them has no telegram info, they are compared by content instead:
+	/// by their mxc url or by the size, dimensions and mime type of the image.
+	fn is_same(&self, other: &Sticker) -> bool {
+		match (&self.tg_sticker, &other.tg_sticker) {
+			(Some(tg_sticker), Some(other_tg_sticker)) => tg_sticker.id == other_tg_sticker.id,
+			_ => {
+				let (info, other_info) = (&self.info.image_info, &other.info.image_info);
+				self.url == other.url
+					|| (info.size == other_info.size
+						&& (info.w, info.h) == (other_info.w, other_info.h)
+						&& info.mimetype == other_info.mimetype)
+			}
+		}
+	}
+}
+
+impl StickerPack {
+	/// Merge a new import of a pack into an older version of it, which was maybe created by another tool.
+	///
+	/// The id and the telegram info of the old pack are kept, so that the stickerpicker treats it as the same pack.
+	/// A sticker is assumed to be unchanged, if the old pack has a sticker with the same telegram id,
+	/// or with the same content if one of them has no telegram id, independent of its position.
+	/// In this case the old sticker is kept together with its mxc url.
+	/// Each old sticker is kept at most once. Stickers, which were removed from the pack, are dropped.
+	pub fn merge_into(self, old: StickerPack) -> StickerPack {
+		let mut old_stickers: Vec<_> = old.stickers.into_iter().map(Some).collect();
+		let stickers = self
+			.stickers
+			.into_iter()
+			.map(|sticker| {
+				old_stickers
+					.iter_mut()
+					.find(|old_sticker| old_sticker.as_ref().is_some_and(|old_sticker| old_sticker.is_same(&sticker)))
+					.and_then(Option::take)
+					.unwrap_or(sticker)
+			})
+			.collect();
+		StickerPack {
+			title: self.title,
+			id: old.id,
+			tg_pack: old.tg_pack.or(self.tg_pack),
+			stickers
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{MetaData, Sticker, StickerInfo, StickerPack, TgPackInfo, TgStickerInfo};
+
+	fn sticker(url: &str, size: usize, tg_id: Option<&str>) -> Sticker {
+		let info = MetaData {
+			w: 256,
+			h: 256,
+			size,
+			mimetype: "image/webp".to_owned(),
+			blurhash: None,
+			duration: None
+		};
+		Sticker {
+			body: "🐱".to_owned(),
+			display_name: None,
+			url: url.to_owned().into(),
+			info: StickerInfo {
+				image_info: info.clone(),
+				thumbnail_url: url.to_owned().into(),
+				thumbnail_info: info
+			},
+			msgtype: Default::default(),
+			id: url.to_owned(),
+			tg_sticker: tg_id.map(|id| TgStickerInfo {
+				pack: TgPackInfo {
+					id: "1234567890".to_owned(),
+					short_name: "CuteCats".to_owned()
+				},
+				id: id.to_owned(),
+				emoticons: vec!["🐱".to_owned()]
+			})
+		}
+	}
+
+	fn pack(stickers: Vec<Sticker>) -> StickerPack {
+		StickerPack {
+			title: "Cute Cats".to_owned(),
+			id: "tg-1234567890".to_owned(),
+			tg_pack: None,
+			stickers
+		}
+	}
+
+	fn urls(pack: &StickerPack) -> Vec<&str> {
+		pack.stickers.iter().map(|sticker| sticker.url.url().as_str()).collect()
+	}
+
+	#[test]
+	fn merge_replaced() {
+		let old = pack(vec![
+			sticker("mxc://example.org/old0", 1000, Some("111")),
+			sticker("mxc://example.org/old1", 2000, Some("222")),
+		]);
+		// the second sticker was replaced by another one with the same body
+		let new = pack(vec![
+			sticker("mxc://example.org/new0", 1100, Some("111")),
+			sticker("mxc://example.org/new1", 2000, Some("333")),
+		]);
+		let merged = new.merge_into(old);
+		assert_eq!(urls(&merged), ["mxc://example.org/old0", "mxc://example.org/new1"]);
+	}
+
+	#[test]
+	fn merge_inserted() {
+		let old = pack(vec![
+			sticker("mxc://example.org/old0", 1000, None),
+			sticker("mxc://example.org/old1", 2000, None),
+		]);
+		// a sticker was inserted at the front, so all stickers have moved
+		let new = pack(vec![
+			sticker("mxc://example.org/new0", 3000, None),
+			sticker("mxc://example.org/new1", 1000, None),
+			sticker("mxc://example.org/old1", 4000, None),
+		]);
+		let merged = new.merge_into(old);
+		assert_eq!(urls(&merged), [
+			"mxc://example.org/new0",
+			"mxc://example.org/old0",
+			"mxc://example.org/old1"
+		]);
+	}
+}
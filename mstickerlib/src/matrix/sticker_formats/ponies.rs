@@ -11,13 +11,13 @@ use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct PackInfo {
 	pub display_name: String,
 	pub avatar_url: Option<String>
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct StickerPack {
 	pub images: IndexMap<String, Sticker>,
 	pub pack: PackInfo
@@ -30,12 +30,18 @@ pub enum Usage {
 	Emoticon
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct MetaData {
 	pub w: u32,
 	pub h: u32,
 	pub size: usize,
-	pub mimetype: String
+	pub mimetype: String,
+	/// [blurhash](https://blurha.sh/) of the image, which is shown by some clients while loading the image
+	#[serde(rename = "xyz.amorgan.blurhash", default, skip_serializing_if = "Option::is_none")]
+	pub blurhash: Option<String>,
+	/// duration of animated stickers in milliseconds
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub duration: Option<u64>
 }
 impl TryFrom<crate::image::Image> for MetaData {
 	type Error = NoMimeType;
@@ -43,13 +49,15 @@ impl TryFrom<crate::image::Image> for MetaData {
 		Ok(Self {
 			w: value.width,
 			h: value.height,
-			size: value.data.len(),
-			mimetype: value.mime_type()?
+			size: value.data_len(),
+			mimetype: value.mime_type()?,
+			blurhash: None,
+			duration: None
 		})
 	}
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Sticker {
 	pub body: String,
 	pub info: MetaData,
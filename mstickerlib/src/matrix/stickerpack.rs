@@ -1,9 +1,20 @@
-use super::{sticker::Sticker, sticker_formats::maunium};
+use super::{sticker::Sticker, sticker_formats::maunium, Mxc};
+#[cfg(feature = "rendition")]
+use crate::image::Rendition;
+use crate::{
+	database::{Database, Hash},
+	error::Error
+};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	fmt::{self, Display}
+};
 
 ///additonal informations about the original telegram sticker pack
 ///stored at `net.maunium.telegram.pack`
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TgPackInfo {
 	pub name: String,
 	pub title: String
@@ -17,7 +28,7 @@ impl From<&crate::tg::StickerPack> for TgPackInfo {
 	}
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct StickerPack {
 	pub title: String,
 	///unique id
@@ -53,3 +64,428 @@ impl From<maunium::StickerPack> for StickerPack {
 		}
 	}
 }
+
+impl StickerPack {
+	/// `true` if any image of the pack is still queued at an [`Outbox`](super::Outbox), see [`Mxc::is_deferred`].
+	pub fn has_deferred(&self) -> bool {
+		self.stickers.iter().any(|sticker| {
+			sticker.image.url.is_deferred()
+				|| sticker
+					.thumbnail
+					.as_ref()
+					.is_some_and(|thumbnail| thumbnail.url.is_deferred())
+		})
+	}
+
+	/// Split the pack into packs of at most `max_per_pack` stickers, for clients which limit the size of a pack.
+	/// The packs are titled `"<title> (1 of N)"`, … and get the ids `"<id>_1"`, ….
+	/// They have no telegram info, so that [`write_pack`](super::write_pack) stores them separately.
+	/// A pack, which does already fit, is returned unchanged. A `max_per_pack` of zero is treated as one.
+	pub fn split(self, max_per_pack: usize) -> Vec<StickerPack> {
+		let max_per_pack = max_per_pack.max(1);
+		if self.stickers.len() <= max_per_pack {
+			return vec![self];
+		}
+		let count = self.stickers.len().div_ceil(max_per_pack);
+		let mut stickers = self.stickers.into_iter().peekable();
+		let mut packs = Vec::with_capacity(count);
+		while stickers.peek().is_some() {
+			let number = packs.len() + 1;
+			packs.push(StickerPack {
+				title: format!("{} ({number} of {count})", self.title),
+				id: format!("{}_{number}", self.id),
+				tg_pack: None,
+				stickers: stickers.by_ref().take(max_per_pack).collect()
+			});
+		}
+		packs
+	}
+
+	/// Replace the placeholder urls of deferred images by the urls returned from [`Outbox::flush`](super::Outbox::flush).
+	pub fn resolve_deferred(&mut self, uploaded: &HashMap<String, Mxc>) {
+		for sticker in &mut self.stickers {
+			for image in std::iter::once(&mut sticker.image).chain(sticker.thumbnail.as_mut()) {
+				if let Some(mxc) = uploaded.get(image.url.url()) {
+					image.url = mxc.clone();
+				}
+			}
+		}
+	}
+
+	/// Add the url of every sticker with a [`Sticker::content_hash`] to `database`, if the hash is not known yet.
+	/// So importing the same files again does not upload them again, for example after the database was lost.
+	/// Invalid hashes and deferred urls are skipped. Return the number of added entries.
+	pub async fn seed_database<D>(&self, database: &D) -> Result<usize, Error>
+	where
+		D: Database + ?Sized
+	{
+		let mut added = 0;
+		for sticker in &self.stickers {
+			let Some(hash) = sticker.content_hash.as_deref().and_then(parse_hash) else {
+				continue;
+			};
+			if sticker.image.url.is_deferred() || database.get(&hash).await.map_err(Error::database)?.is_some() {
+				continue;
+			}
+			database
+				.add(hash, sticker.image.url.url().to_owned())
+				.await
+				.map_err(Error::database)?;
+			added += 1;
+		}
+		Ok(added)
+	}
+}
+
+/// Parse a hex encoded [`database::hash`](crate::database::hash).
+fn parse_hash(hex: &str) -> Option<Hash> {
+	let mut hash = [0; 64];
+	if hex.len() != 2 * hash.len() {
+		return None;
+	}
+	for (i, byte) in hash.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+	}
+	Some(hash)
+}
+
+#[cfg(feature = "rendition")]
+impl StickerPack {
+	/// Create an additional variant of this pack, like [`Rendition::lite`], and upload its stickers.
+	///
+	/// The data of the stickers must be cached at their [`Mxc`](super::Mxc), which is the case directly after an import.
+	/// The new pack has no telegram info, so that [`write_pack`](super::write_pack) stores it next to this pack.
+	/// Like [`tg::StickerPack::import`](crate::tg::StickerPack::import) this can partially fail.
+	pub async fn render<D>(
+		&self,
+		rendition: &Rendition,
+		matrix_config: &super::Config,
+		database: Option<&D>
+	) -> Result<StickerPack, (StickerPack, Vec<(usize, Error)>)>
+	where
+		D: Database + ?Sized
+	{
+		let stickers = futures_util::future::join_all(
+			self.stickers
+				.iter()
+				.map(|sticker| render_sticker(sticker, rendition, matrix_config, database))
+		)
+		.await;
+		let mut ok_stickers = Vec::new();
+		let mut err_stickers = Vec::new();
+		for (i, sticker) in stickers.into_iter().enumerate() {
+			match sticker {
+				Ok(value) => ok_stickers.push(value),
+				Err(err) => err_stickers.push((i, err))
+			}
+		}
+		let pack = StickerPack {
+			title: format!("{}{}", self.title, rendition.title_suffix),
+			id: format!("{}{}", self.id, rendition.id_suffix),
+			tg_pack: None,
+			stickers: ok_stickers
+		};
+		if err_stickers.is_empty() {
+			Ok(pack)
+		} else {
+			Err((pack, err_stickers))
+		}
+	}
+}
+
+#[cfg(feature = "rendition")]
+async fn render_sticker<D>(
+	sticker: &Sticker,
+	rendition: &Rendition,
+	matrix_config: &super::Config,
+	database: Option<&D>
+) -> Result<Sticker, Error>
+where
+	D: Database + ?Sized
+{
+	let meta_data = &sticker.image.meta_data;
+	let data = sticker.image.url.data().clone().ok_or_else(|| Error::UnsupportedOperation {
+		operation: "rendering a sticker without cached data",
+		file_name: sticker.body.clone()
+	})?;
+	let extension = meta_data.mimetype.split('/').next_back().unwrap_or_default();
+	let mut image = crate::image::ImageBuilder::new()
+		.data(data)
+		.file_name(format!("sticker.{extension}"))
+		.width(meta_data.w)
+		.height(meta_data.h)
+		.build()?;
+	image.duration_ms = meta_data.duration;
+	let image = image.render(rendition)?;
+	let (url, _) = image.upload_with(matrix_config, database).await?;
+	let content_hash = image.content_hash().iter().map(|byte| format!("{byte:02x}")).collect();
+	let meta_data = super::sticker_formats::ponies::MetaData {
+		duration: meta_data.duration,
+		..image.try_into()?
+	};
+	Ok(Sticker {
+		content_hash: Some(content_hash),
+		image: super::sticker::Image { url, meta_data },
+		thumbnail: None,
+		..sticker.clone()
+	})
+}
+
+/// Changes between two versions of a stickerpack, see [`diff`].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct PackDiff {
+	/// old and new title, if the title was changed
+	pub title: Option<(String, String)>,
+	/// shortcodes of stickers, which only exist at the new pack
+	pub added: Vec<String>,
+	/// shortcodes of stickers, which only exist at the old pack
+	pub removed: Vec<String>,
+	/// shortcodes of stickers, whose image was changed
+	pub changed: Vec<String>
+}
+
+impl PackDiff {
+	/// Return `true` if both packs are the same.
+	pub fn is_empty(&self) -> bool {
+		self.title.is_none() && self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+	}
+}
+
+impl Display for PackDiff {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.is_empty() {
+			return writeln!(f, "no changes");
+		}
+		if let Some((old, new)) = &self.title {
+			writeln!(f, "title: {old:?} -> {new:?}")?;
+		}
+		for shortcode in &self.added {
+			writeln!(f, "+ {shortcode}")?;
+		}
+		for shortcode in &self.removed {
+			writeln!(f, "- {shortcode}")?;
+		}
+		for shortcode in &self.changed {
+			writeln!(f, "~ {shortcode}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Shortcode of each sticker, like used for ponies packs:
+/// the emoticon of the sticker, or its position at the telegram pack (or this pack) otherwise.
+pub(super) fn shortcodes(pack: &StickerPack) -> IndexMap<String, &Sticker> {
+	pack.stickers
+		.iter()
+		.enumerate()
+		.map(|(i, sticker)| {
+			let shortcode = sticker.emoticon.clone().unwrap_or_else(|| {
+				let index = sticker.tg_sticker.as_ref().and_then(|tg| tg.index).unwrap_or(i);
+				format!("{index:04}")
+			});
+			(shortcode, sticker)
+		})
+		.collect()
+}
+
+/// Compare two versions of a stickerpack.
+/// Since files with the same hash are only uploaded once, stickers are compared by the url of their image.
+pub fn diff(old: &StickerPack, new: &StickerPack) -> PackDiff {
+	let old_stickers = shortcodes(old);
+	let new_stickers = shortcodes(new);
+	let mut diff = PackDiff {
+		title: (old.title != new.title).then(|| (old.title.clone(), new.title.clone())),
+		..Default::default()
+	};
+	for (shortcode, sticker) in &new_stickers {
+		match old_stickers.get(shortcode) {
+			None => diff.added.push(shortcode.clone()),
+			Some(old_sticker) if old_sticker.image.url != sticker.image.url => diff.changed.push(shortcode.clone()),
+			Some(_) => {}
+		}
+	}
+	diff.removed = old_stickers
+		.keys()
+		.filter(|shortcode| !new_stickers.contains_key(*shortcode))
+		.cloned()
+		.collect();
+	diff
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{diff, PackDiff, StickerPack};
+	use crate::{
+		database::{self, Database, FileDatabase},
+		matrix::{
+			sticker::{Image, Sticker},
+			sticker_formats::ponies::MetaData
+		}
+	};
+
+	fn sticker(shortcode: &str, url: &str) -> Sticker {
+		Sticker {
+			body: shortcode.to_owned(),
+			display_name: None,
+			image: Image {
+				url: url.to_owned().into(),
+				meta_data: MetaData {
+					w: 256,
+					h: 256,
+					size: 1024,
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: Some(shortcode.to_owned()),
+			emoji: Vec::new(),
+			tg_sticker: None,
+			content_hash: None
+		}
+	}
+
+	fn pack(title: &str, stickers: Vec<Sticker>) -> StickerPack {
+		StickerPack {
+			title: title.to_owned(),
+			id: "pack".to_owned(),
+			tg_pack: None,
+			stickers
+		}
+	}
+
+	#[cfg(feature = "rendition")]
+	#[tokio::test]
+	async fn render_lite() {
+		use crate::{
+			database::DummyDatabase,
+			image::Rendition,
+			mock::{matrix_config, serve_uploads}
+		};
+		use std::{net::TcpListener, sync::Arc};
+
+		let data = crate::image::Image::from_rgba(vec![200; 512 * 512 * 4], 512, 512, "sticker.webp")
+			.unwrap()
+			.into_data();
+		let mut original = pack("Cats", vec![
+			sticker("kot", "mxc://example.org/a"),
+			sticker("kot2", "mxc://example.org/b"),
+		]);
+		for sticker in &mut original.stickers {
+			sticker.image.url.data = Some(Arc::clone(&data));
+			sticker.image.meta_data.w = 512;
+			sticker.image.meta_data.h = 512;
+		}
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_uploads(listener, 2));
+		let lite = original
+			.render(&Rendition::lite(), &matrix_config, None::<&DummyDatabase>)
+			.await
+			.unwrap();
+		server.join().unwrap();
+		assert_eq!(lite.title, "Cats (lite)");
+		assert_eq!(lite.id, "pack_lite");
+		assert_eq!(lite.stickers.len(), 2);
+		for (sticker, lite_sticker) in original.stickers.iter().zip(&lite.stickers) {
+			assert_eq!(lite_sticker.body, sticker.body);
+			assert_ne!(lite_sticker.image.url.url(), sticker.image.url.url());
+			assert_eq!((lite_sticker.image.meta_data.w, lite_sticker.image.meta_data.h), (256, 256));
+		}
+	}
+
+	#[test]
+	fn split() {
+		let stickers: Vec<_> = (0..4)
+			.map(|i| sticker(&format!("kot{i}"), &format!("mxc://example.org/{i}")))
+			.collect();
+		let shortcodes = |packs: &[StickerPack]| -> Vec<Vec<String>> {
+			packs
+				.iter()
+				.map(|pack| pack.stickers.iter().map(|sticker| sticker.body.clone()).collect())
+				.collect()
+		};
+
+		let packs = pack("Cats", stickers.clone()).split(3);
+		let titles: Vec<_> = packs.iter().map(|pack| (pack.title.as_str(), pack.id.as_str())).collect();
+		assert_eq!(titles, [("Cats (1 of 2)", "pack_1"), ("Cats (2 of 2)", "pack_2")]);
+		assert_eq!(shortcodes(&packs), [vec!["kot0", "kot1", "kot2"], vec!["kot3"]]);
+
+		let packs = pack("Cats", stickers.clone()).split(1);
+		assert_eq!(packs.len(), 4);
+		assert_eq!(packs[3].title, "Cats (4 of 4)");
+		assert_eq!(shortcodes(&packs)[3], ["kot3"]);
+
+		// packs, which do already fit, are not renamed
+		for max_per_pack in [4, 50] {
+			let packs = pack("Cats", stickers.clone()).split(max_per_pack);
+			assert_eq!(packs.len(), 1);
+			assert_eq!((packs[0].title.as_str(), packs[0].id.as_str()), ("Cats", "pack"));
+			assert_eq!(packs[0].stickers.len(), 4);
+		}
+	}
+
+	#[test]
+	fn diff_packs() {
+		let old = pack("Old", vec![
+			sticker("same", "mxc://example.org/a"),
+			sticker("changed", "mxc://example.org/b"),
+			sticker("removed", "mxc://example.org/c"),
+		]);
+		let new = pack("New", vec![
+			sticker("same", "mxc://example.org/a"),
+			sticker("changed", "mxc://example.org/d"),
+			sticker("added", "mxc://example.org/e"),
+		]);
+		let diff = diff(&old, &new);
+		assert_eq!(diff, PackDiff {
+			title: Some(("Old".to_owned(), "New".to_owned())),
+			added: vec!["added".to_owned()],
+			removed: vec!["removed".to_owned()],
+			changed: vec!["changed".to_owned()]
+		});
+		assert_eq!(diff.to_string(), "title: \"Old\" -> \"New\"\n+ added\n- removed\n~ changed\n");
+		assert_eq!(
+			serde_json::to_string(&diff).unwrap(),
+			r#"{"title":["Old","New"],"added":["added"],"removed":["removed"],"changed":["changed"]}"#
+		);
+	}
+
+	#[test]
+	fn diff_unchanged() {
+		let old = pack("Pack", vec![sticker("same", "mxc://example.org/a")]);
+		assert!(diff(&old, &old.clone()).is_empty());
+	}
+
+	#[tokio::test]
+	async fn seed_database() {
+		let path = std::env::temp_dir().join(format!("mstickerlib-seed-test-{}.txt", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let db = FileDatabase::new(&path).await.unwrap();
+		let hex = |hash: database::Hash| hash.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+		let mut stickers = vec![
+			sticker("kot", "mxc://example.org/kot"),
+			sticker("pies", "mxc://example.org/pies"),
+			sticker("invalid", "mxc://example.org/invalid"),
+			sticker("none", "mxc://example.org/none"),
+		];
+		stickers[0].content_hash = Some(hex(database::hash(b"kot")));
+		// hashes are compared ignoring case
+		stickers[1].content_hash = Some(hex(database::hash(b"pies")).to_uppercase());
+		stickers[2].content_hash = Some("not a hash".to_owned());
+		let pack = pack("Pack", stickers);
+		assert_eq!(pack.seed_database(&db).await.unwrap(), 2);
+		assert_eq!(
+			db.get(&database::hash(b"kot")).await.unwrap().as_deref(),
+			Some("mxc://example.org/kot")
+		);
+		assert_eq!(
+			db.get(&database::hash(b"pies")).await.unwrap().as_deref(),
+			Some("mxc://example.org/pies")
+		);
+		// known hashes are not added again
+		assert_eq!(pack.seed_database(&db).await.unwrap(), 0);
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -1,4 +1,8 @@
+use super::sticker_formats::maunium::StickerPack;
+use crate::error::Error;
 use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::fs;
 
 #[derive(Serialize)]
 pub(crate) struct StickerWidget {
@@ -36,3 +40,181 @@ impl StickerWidget {
 		}
 	}
 }
+
+/// Check if `old` is an older version of `pack`.
+/// Packs are matched by the `net.maunium.telegram.pack` short name, or by their id if they have no telegram info.
+fn is_same_pack(old: &StickerPack, pack: &StickerPack) -> bool {
+	match (&old.tg_pack, &pack.tg_pack) {
+		(Some(old_tg), Some(tg)) => old_tg.short_name == tg.short_name,
+		_ => old.id == pack.id
+	}
+}
+
+/// Search the stickerpicker directory for an older version of `pack`.
+async fn find_pack(dir: &Path, pack: &StickerPack) -> Result<Option<(PathBuf, StickerPack)>, Error> {
+	let mut entries = fs::read_dir(dir).await?;
+	while let Some(entry) = entries.next_entry().await? {
+		let path = entry.path();
+		let is_pack = path.extension().is_some_and(|extension| extension == "json")
+			&& path.file_name().is_some_and(|name| name != "index.json");
+		if !is_pack {
+			continue;
+		}
+		// ignore other json files at the directory
+		let Ok(old) = serde_json::from_str::<StickerPack>(&fs::read_to_string(&path).await?) else {
+			continue;
+		};
+		if is_same_pack(&old, pack) {
+			return Ok(Some((path, old)));
+		}
+	}
+	Ok(None)
+}
+
+/// Save `pack` to the `packs` directory of the [maunium stickerpicker](https://github.com/maunium/stickerpicker).
+///
+/// If the directory does already contain a version of the pack, for example created by the importer of the stickerpicker,
+/// the new pack is merged into the existing file, see [`StickerPack::merge_into`].
+/// Otherwise, or if `force_new` is set, the pack is written to a new file, named after the telegram pack.
+/// A new file is added to the `index.json` of the directory, if it exists.
+/// Return the path of the written file.
+pub async fn write_pack(dir: &Path, pack: StickerPack, force_new: bool) -> Result<PathBuf, Error> {
+	let existing = match force_new {
+		true => None,
+		false => find_pack(dir, &pack).await?
+	};
+	let (path, pack) = match existing {
+		Some((path, old)) => (path, pack.merge_into(old)),
+		None => {
+			let name = pack.tg_pack.as_ref().map(|tg| tg.short_name.as_str()).unwrap_or(&pack.id);
+			(dir.join(format!("{name}.json")), pack)
+		}
+	};
+	fs::write(&path, serde_json::to_string(&pack)?).await?;
+
+	let index_path = dir.join("index.json");
+	if fs::try_exists(&index_path).await? {
+		let mut index: serde_json::Value = serde_json::from_str(&fs::read_to_string(&index_path).await?)?;
+		let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+		if let Some(packs) = index.get_mut("packs").and_then(|packs| packs.as_array_mut()) {
+			if !packs.iter().any(|entry| entry.as_str() == Some(&file_name)) {
+				packs.push(file_name.into());
+				fs::write(&index_path, serde_json::to_string(&index)?).await?;
+			}
+		}
+	}
+	Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::write_pack;
+	use crate::matrix::{
+		sticker::{Image, Sticker},
+		sticker_formats::{maunium, ponies::MetaData},
+		stickerpack::{StickerPack, TgPackInfo}
+	};
+	use std::path::Path;
+
+	/// pack like it is created by the telegram importer of the maunium stickerpicker
+	const OLD_PACK: &str = r#"{"title": "Cute Cats", "id": "tg-1234567890", "net.maunium.telegram.pack": {"short_name": "CuteCats", "hash": "-987654321"}, "stickers": [
+		{"body": "🐱", "url": "mxc://example.org/old0", "info": {"w": 256, "h": 256, "size": 1024, "mimetype": "image/webp", "thumbnail_url": "mxc://example.org/old0", "thumbnail_info": {"w": 256, "h": 256, "size": 1024, "mimetype": "image/webp"}}, "msgtype": "m.sticker", "id": "tg-111", "net.maunium.telegram.sticker": {"pack": {"id": "1234567890", "short_name": "CuteCats"}, "id": "111", "emoticons": ["🐱"]}},
+		{"body": "😿", "url": "mxc://example.org/old1", "info": {"w": 256, "h": 256, "size": 1024, "mimetype": "image/webp", "thumbnail_url": "mxc://example.org/old1", "thumbnail_info": {"w": 256, "h": 256, "size": 1024, "mimetype": "image/webp"}}, "msgtype": "m.sticker", "id": "tg-222", "net.maunium.telegram.sticker": {"pack": {"id": "1234567890", "short_name": "CuteCats"}, "id": "222", "emoticons": ["😿"]}}
+	]}"#;
+
+	fn sticker(emoji: &str, url: &str, size: usize) -> Sticker {
+		Sticker {
+			body: emoji.to_owned(),
+			display_name: None,
+			image: Image {
+				url: url.to_owned().into(),
+				meta_data: MetaData {
+					w: 256,
+					h: 256,
+					size,
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: None,
+			emoji: vec![emoji.to_owned()],
+			tg_sticker: None,
+			content_hash: None
+		}
+	}
+
+	fn new_pack() -> maunium::StickerPack {
+		StickerPack {
+			title: "Cute Cats".to_owned(),
+			id: "tg_name_CuteCats".to_owned(),
+			tg_pack: Some(TgPackInfo {
+				name: "CuteCats".to_owned(),
+				title: "Cute Cats".to_owned()
+			}),
+			stickers: vec![
+				sticker("🐱", "mxc://example.org/new0", 1024),
+				sticker("😸", "mxc://example.org/new1", 2048),
+				sticker("😺", "mxc://example.org/new2", 3072),
+			]
+		}
+		.into()
+	}
+
+	fn read(path: &Path) -> serde_json::Value {
+		serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+	}
+
+	fn setup(name: &str) -> std::path::PathBuf {
+		let dir = std::env::temp_dir().join(format!("mstickerlib-stickerpicker-{name}-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		std::fs::write(dir.join("cats.json"), OLD_PACK).unwrap();
+		std::fs::write(
+			dir.join("index.json"),
+			r#"{"packs": ["cats.json"], "homeserver_url": "https://example.org"}"#
+		)
+		.unwrap();
+		dir
+	}
+
+	#[tokio::test]
+	async fn merge_existing() {
+		let dir = setup("merge");
+		let path = write_pack(&dir, new_pack(), false).await.unwrap();
+		assert_eq!(path, dir.join("cats.json"));
+		assert!(!dir.join("CuteCats.json").exists());
+
+		let pack = read(&path);
+		assert_eq!(pack["id"], "tg-1234567890");
+		assert_eq!(pack["net.maunium.telegram.pack"]["hash"], "-987654321");
+		let urls: Vec<_> = pack["stickers"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|sticker| sticker["url"].clone())
+			.collect();
+		assert_eq!(urls, [
+			"mxc://example.org/old0",
+			"mxc://example.org/new1",
+			"mxc://example.org/new2"
+		]);
+		assert_eq!(read(&dir.join("index.json"))["packs"], serde_json::json!(["cats.json"]));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn force_new() {
+		let dir = setup("force-new");
+		let path = write_pack(&dir, new_pack(), true).await.unwrap();
+		assert_eq!(path, dir.join("CuteCats.json"));
+		assert_eq!(read(&dir.join("cats.json"))["stickers"][0]["url"], "mxc://example.org/old0");
+		assert_eq!(read(&path)["id"], "tg_name_CuteCats");
+		assert_eq!(
+			read(&dir.join("index.json"))["packs"],
+			serde_json::json!(["cats.json", "CuteCats.json"])
+		);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
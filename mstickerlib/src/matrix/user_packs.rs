@@ -0,0 +1,515 @@
+//! Personal sticker packs of a user, which are stored at the `im.ponies.user_emotes` account data of the user.
+
+#[cfg(feature = "synapse-admin")]
+use super::Mxc;
+use super::{api_error, sticker_formats::ponies, stickerpack::StickerPack, Config};
+use crate::{error::Error, CLIENT};
+use indexmap::IndexMap;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[cfg(feature = "log")]
+use log::{info, warn};
+
+/// Type of the account data, which stores the personal emotes of the user,
+/// see [MSC2545](https://github.com/matrix-org/matrix-spec-proposals/pull/2545).
+///
+/// MSC2545 stores only a single pack there, so all published packs share its `images`.
+/// Which image belongs to which pack is stored at the additional field `dev.luckyturtle.mstickereditor.packs`.
+/// Emotes, which were not published by [`publish_user_pack`], for example by another client,
+/// are listed as one more pack with the id `USER_PACKS`.
+pub const USER_PACKS: &str = "im.ponies.user_emotes";
+
+/// How often an update of the account data is tried again, if it was modified concurrently.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Content of the [`USER_PACKS`] account data.
+/// Emotes and fields of other clients are kept as they are, even if they are no valid stickers.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+struct UserEmotes {
+	#[serde(default)]
+	images: IndexMap<String, Value>,
+	#[serde(
+		rename = "dev.luckyturtle.mstickereditor.packs",
+		default,
+		skip_serializing_if = "IndexMap::is_empty"
+	)]
+	packs: IndexMap<String, PackEntry>,
+	#[serde(flatten)]
+	other: Map<String, Value>
+}
+
+/// Pack published by [`publish_user_pack`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct PackEntry {
+	#[serde(flatten)]
+	info: ponies::PackInfo,
+	/// keys of the stickers of the pack at [`UserEmotes::images`]
+	images: Vec<String>
+}
+
+impl UserEmotes {
+	/// Keys of the images, which do not belong to a published pack.
+	fn unpublished_images(&self) -> Vec<String> {
+		self.images
+			.keys()
+			.filter(|key| !self.packs.values().any(|pack| pack.images.contains(key)))
+			.cloned()
+			.collect()
+	}
+
+	/// Remove the images `keys` and return the ones, which are valid stickers.
+	fn remove_images(&mut self, keys: &[String]) -> IndexMap<String, ponies::Sticker> {
+		keys.iter()
+			.filter_map(|key| {
+				let sticker = self.images.shift_remove(key)?;
+				Some((key.clone(), serde_json::from_value(sticker).ok()?))
+			})
+			.collect()
+	}
+}
+
+/// Pack of the user, as listed by [`list_user_packs`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PackSummary {
+	/// id of the pack, which is used to remove it
+	pub id: String,
+	pub display_name: String,
+	pub avatar_url: Option<String>,
+	pub sticker_count: usize
+}
+
+fn account_data_url(matrix: &Config) -> String {
+	format!(
+		"{}/_matrix/client/v3/user/{}/account_data/{USER_PACKS}",
+		matrix.homeserver_url, matrix.user
+	)
+}
+
+/// Read the emotes of the user; a user without emotes has no account data at all.
+async fn get_emotes(matrix: &Config) -> Result<UserEmotes, Error> {
+	let request = CLIENT
+		.get()
+		.get(account_data_url(matrix))
+		.query(&[("access_token", &matrix.access_token)]);
+	let answer = matrix.send(request).await?;
+	match answer.status() {
+		StatusCode::OK => Ok(answer.json().await?),
+		StatusCode::NOT_FOUND => Ok(UserEmotes::default()),
+		_ => Err(api_error(answer).await)
+	}
+}
+
+async fn put_emotes(matrix: &Config, emotes: &UserEmotes) -> Result<(), Error> {
+	let request = CLIENT
+		.get()
+		.put(account_data_url(matrix))
+		.query(&[("access_token", &matrix.access_token)])
+		.json(emotes);
+	let answer = matrix.send_limited(request).await?;
+	if answer.status() != StatusCode::OK {
+		return Err(api_error(answer).await);
+	}
+	Ok(())
+}
+
+/// Read the emotes, apply `modify` and write them back.
+///
+/// Account data can not be written conditionally, so a change of another client between the last read and the write
+/// is lost. To make this less likely, the emotes are read again right before writing; if they have changed,
+/// `modify` is applied to the new emotes. This is no protection against lost updates, it only narrows the window.
+/// If `modify` returns `None`, nothing is written.
+async fn update<F, T>(matrix: &Config, mut modify: F) -> Result<Option<T>, Error>
+where
+	F: FnMut(&mut UserEmotes) -> Option<T>
+{
+	Url::parse(&matrix.homeserver_url)?; //check if homeserver_url is a valid url
+	for _ in 0..MAX_ATTEMPTS {
+		let current = get_emotes(matrix).await?;
+		let mut emotes = current.clone();
+		let Some(result) = modify(&mut emotes) else {
+			return Ok(None);
+		};
+		if get_emotes(matrix).await? != current {
+			#[cfg(feature = "log")]
+			warn!("{USER_PACKS} was modified concurrently, retrying");
+			continue;
+		}
+		put_emotes(matrix, &emotes).await?;
+		return Ok(Some(result));
+	}
+	Err(Error::ConcurrentModification(USER_PACKS.to_owned()))
+}
+
+/// List the personal packs of the user in the order, in which they were added.
+/// Emotes, which do not belong to a published pack, are listed last as the pack [`USER_PACKS`].
+pub async fn list_user_packs(matrix: &Config) -> Result<Vec<PackSummary>, Error> {
+	Url::parse(&matrix.homeserver_url)?; //check if homeserver_url is a valid url
+	let emotes = get_emotes(matrix).await?;
+	let unpublished = emotes.unpublished_images().len();
+	let mut summaries: Vec<_> = emotes
+		.packs
+		.into_iter()
+		.map(|(id, pack)| PackSummary {
+			id,
+			display_name: pack.info.display_name,
+			avatar_url: pack.info.avatar_url,
+			sticker_count: pack.images.len()
+		})
+		.collect();
+	if unpublished > 0 {
+		let info = emotes.other.get("pack");
+		let field = |name| info.and_then(|info| info[name].as_str()).map(str::to_owned);
+		summaries.push(PackSummary {
+			id: USER_PACKS.to_owned(),
+			display_name: field("display_name").unwrap_or_default(),
+			avatar_url: field("avatar_url"),
+			sticker_count: unpublished
+		});
+	}
+	Ok(summaries)
+}
+
+/// Add `pack` to the personal packs of the user, or replace the pack with the same id.
+/// Other packs and emotes are left untouched.
+///
+/// The keys of the stickers are prefixed with the id of the pack, so they do not collide with other emotes.
+pub async fn publish_user_pack(matrix: &Config, pack: &StickerPack) -> Result<(), Error> {
+	let content = ponies::StickerPack::from(pack.clone());
+	let mut images = IndexMap::with_capacity(content.images.len());
+	for (key, sticker) in content.images {
+		images.insert(format!("{}/{key}", pack.id), serde_json::to_value(sticker)?);
+	}
+	update(matrix, |emotes| {
+		if let Some(old) = emotes.packs.get(&pack.id).cloned() {
+			emotes.remove_images(&old.images);
+		}
+		emotes.images.extend(images.clone());
+		emotes.packs.insert(pack.id.clone(), PackEntry {
+			info: content.pack.clone(),
+			images: images.keys().cloned().collect()
+		});
+		Some(())
+	})
+	.await?;
+	#[cfg(feature = "log")]
+	info!("published pack {:?} to {USER_PACKS}", pack.id);
+	Ok(())
+}
+
+/// Remove the pack `pack_id` from the personal packs of the user and return it. Other packs are left untouched.
+/// Return [`Error::NotFound`], if the user has no such pack.
+///
+/// Emotes, which are no valid stickers, are removed too, but are not part of the returned pack.
+/// The media of the pack stays at the homeserver, see [`remove_user_pack_with_media`] to delete it too.
+pub async fn remove_user_pack(matrix: &Config, pack_id: &str) -> Result<ponies::StickerPack, Error> {
+	let removed = update(matrix, |emotes| {
+		if pack_id == USER_PACKS {
+			let keys = emotes.unpublished_images();
+			if keys.is_empty() {
+				return None;
+			}
+			let info = emotes.other.remove("pack").and_then(|info| serde_json::from_value(info).ok());
+			let info = info.unwrap_or_else(|| ponies::PackInfo {
+				display_name: String::new(),
+				avatar_url: None
+			});
+			return Some(ponies::StickerPack {
+				images: emotes.remove_images(&keys),
+				pack: info
+			});
+		}
+		let pack = emotes.packs.shift_remove(pack_id)?;
+		Some(ponies::StickerPack {
+			images: emotes.remove_images(&pack.images),
+			pack: pack.info
+		})
+	})
+	.await?;
+	let removed = removed.ok_or_else(|| Error::NotFound(format!("pack {pack_id:?}")))?;
+	#[cfg(feature = "log")]
+	info!("removed pack {pack_id:?} from {USER_PACKS}");
+	Ok(removed)
+}
+
+/// Like [`remove_user_pack`], but also delete the stickers and the avatar of the pack from the homeserver,
+/// using [`delete_media`](super::delete_media), so [`Config::access_token`] must belong to a server admin.
+///
+/// The pack is removed first; a media, which can not be deleted, does not stop deleting the other ones
+/// and its error is returned together with the media.
+#[cfg(feature = "synapse-admin")]
+pub async fn remove_user_pack_with_media(matrix: &Config, pack_id: &str) -> Result<Vec<(Mxc, Result<(), Error>)>, Error> {
+	let pack = remove_user_pack(matrix, pack_id).await?;
+	let mut media: Vec<Mxc> = pack.images.into_values().map(|sticker| sticker.url).collect();
+	media.extend(pack.pack.avatar_url.map(Mxc::from));
+	let mut seen = std::collections::HashSet::new();
+	media.retain(|mxc| seen.insert(mxc.url().to_owned()));
+	let mut outcomes = Vec::with_capacity(media.len());
+	for mxc in media {
+		let result = super::delete_media(matrix, &mxc).await;
+		#[cfg(feature = "log")]
+		if let Err(err) = &result {
+			warn!("failed to delete {mxc} of pack {pack_id:?}: {err}");
+		}
+		outcomes.push((mxc, result));
+	}
+	Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{list_user_packs, publish_user_pack, remove_user_pack, PackSummary, USER_PACKS};
+	use crate::{
+		error::Error,
+		matrix::{
+			sticker::{self, Sticker},
+			sticker_formats::ponies::MetaData,
+			stickerpack::StickerPack,
+			Config, Mxc
+		},
+		mock::{matrix_config, serve_sequence}
+	};
+	use serde_json::{json, Value};
+	use std::net::TcpListener;
+
+	const ACCOUNT_DATA: &str = "/_matrix/client/v3/user/@alice:example.org/account_data/im.ponies.user_emotes";
+
+	fn sticker(url: &str) -> Value {
+		json!({
+			"body": "cat",
+			"url": url,
+			"info": {"w": 256, "h": 256, "size": 1000, "mimetype": "image/webp"},
+			"usage": ["sticker"]
+		})
+	}
+
+	/// Account data with the published packs `cats` and `dogs`, and an emote added by another client.
+	fn emotes() -> Value {
+		json!({
+			"images": {
+				"cats/0000": sticker("mxc://example.org/cat1"),
+				"cats/0001": sticker("mxc://example.org/cat2"),
+				"dogs/0000": sticker("mxc://example.org/dog1"),
+				"smile": {"url": "mxc://example.org/smile"}
+			},
+			"pack": {"display_name": "Mine"},
+			"dev.luckyturtle.mstickereditor.packs": {
+				"cats": {"display_name": "Cats", "avatar_url": null, "images": ["cats/0000", "cats/0001"]},
+				"dogs": {"display_name": "Dogs", "avatar_url": null, "images": ["dogs/0000"]}
+			}
+		})
+	}
+
+	fn keys(value: &Value) -> Vec<&str> {
+		value.as_object().unwrap().keys().map(String::as_str).collect()
+	}
+
+	/// Run `f` against a homeserver, which gives the `answers` in order, and return the received requests.
+	async fn with_homeserver<F, Fut, T>(answers: Vec<(&'static str, String)>, f: F) -> (T, Vec<String>)
+	where
+		F: FnOnce(Config) -> Fut,
+		Fut: std::future::Future<Output = T>
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || {
+			let answers: Vec<_> = answers.iter().map(|(status, body)| (*status, body.as_str())).collect();
+			serve_sequence(listener, &answers)
+		});
+		let result = f(matrix_config).await;
+		(result, server.join().unwrap())
+	}
+
+	fn put_body(requests: &[String]) -> Value {
+		let put = requests.iter().find(|request| request.starts_with("PUT")).unwrap();
+		assert!(put.starts_with(&format!("PUT {ACCOUNT_DATA}?")), "{put}");
+		serde_json::from_str(put.split_once("\r\n\r\n").unwrap().1).unwrap()
+	}
+
+	#[tokio::test]
+	async fn list() {
+		let answers = vec![("200 OK", emotes().to_string())];
+		let (summaries, requests) = with_homeserver(answers, |matrix| async move { list_user_packs(&matrix).await }).await;
+		assert!(requests[0].starts_with(&format!("GET {ACCOUNT_DATA}?")));
+		assert_eq!(summaries.unwrap(), [
+			PackSummary {
+				id: "cats".to_owned(),
+				display_name: "Cats".to_owned(),
+				avatar_url: None,
+				sticker_count: 2
+			},
+			PackSummary {
+				id: "dogs".to_owned(),
+				display_name: "Dogs".to_owned(),
+				avatar_url: None,
+				sticker_count: 1
+			},
+			PackSummary {
+				id: USER_PACKS.to_owned(),
+				display_name: "Mine".to_owned(),
+				avatar_url: None,
+				sticker_count: 1
+			}
+		]);
+
+		let answers = vec![("404 Not Found", r#"{"errcode":"M_NOT_FOUND","error":"not found"}"#.to_owned())];
+		let (summaries, _) = with_homeserver(answers, |matrix| async move { list_user_packs(&matrix).await }).await;
+		assert_eq!(summaries.unwrap(), []);
+	}
+
+	#[tokio::test]
+	async fn publish() {
+		let cat = Sticker {
+			body: "cat".to_owned(),
+			display_name: None,
+			image: sticker::Image {
+				url: Mxc::from("mxc://example.org/cat3".to_owned()),
+				meta_data: MetaData {
+					w: 256,
+					h: 256,
+					size: 1000,
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: None,
+			emoji: Vec::new(),
+			tg_sticker: None,
+			content_hash: None
+		};
+		let pack = StickerPack {
+			title: "New Cats".to_owned(),
+			id: "cats".to_owned(),
+			tg_pack: None,
+			stickers: vec![cat]
+		};
+		let answers = vec![
+			("200 OK", emotes().to_string()),
+			("200 OK", emotes().to_string()),
+			("200 OK", "{}".to_owned()),
+		];
+		let (published, requests) =
+			with_homeserver(answers, |matrix| async move { publish_user_pack(&matrix, &pack).await }).await;
+		published.unwrap();
+		let body = put_body(&requests);
+		// the old stickers of the pack are replaced, everything else is kept
+		assert_eq!(keys(&body["images"]), ["cats/0000", "dogs/0000", "smile"]);
+		assert_eq!(body["images"]["cats/0000"]["url"], "mxc://example.org/cat3");
+		assert_eq!(body["images"]["smile"], emotes()["images"]["smile"]);
+		assert_eq!(body["pack"], emotes()["pack"]);
+		let packs = &body["dev.luckyturtle.mstickereditor.packs"];
+		assert_eq!(
+			packs["cats"],
+			json!({"display_name": "New Cats", "avatar_url": null, "images": ["cats/0000"]})
+		);
+		assert_eq!(packs["dogs"], emotes()["dev.luckyturtle.mstickereditor.packs"]["dogs"]);
+	}
+
+	#[tokio::test]
+	async fn remove_existing() {
+		let answers = vec![
+			("200 OK", emotes().to_string()),
+			("200 OK", emotes().to_string()),
+			("200 OK", "{}".to_owned()),
+		];
+		let (removed, requests) =
+			with_homeserver(answers, |matrix| async move { remove_user_pack(&matrix, "cats").await }).await;
+		let removed = removed.unwrap();
+		assert_eq!(removed.pack.display_name, "Cats");
+		assert_eq!(removed.images.len(), 2);
+		assert_eq!(requests.len(), 3);
+		let body = put_body(&requests);
+		assert_eq!(keys(&body["images"]), ["dogs/0000", "smile"]);
+		assert_eq!(keys(&body["dev.luckyturtle.mstickereditor.packs"]), ["dogs"]);
+		assert_eq!(body["pack"], emotes()["pack"]);
+	}
+
+	#[tokio::test]
+	async fn remove_unpublished() {
+		let answers = vec![
+			("200 OK", emotes().to_string()),
+			("200 OK", emotes().to_string()),
+			("200 OK", "{}".to_owned()),
+		];
+		let (removed, requests) =
+			with_homeserver(answers, |matrix| async move { remove_user_pack(&matrix, USER_PACKS).await }).await;
+		let removed = removed.unwrap();
+		assert_eq!(removed.pack.display_name, "Mine");
+		// the emote is no valid sticker
+		assert!(removed.images.is_empty());
+		let body = put_body(&requests);
+		assert_eq!(keys(&body["images"]), ["cats/0000", "cats/0001", "dogs/0000"]);
+		assert_eq!(keys(&body["dev.luckyturtle.mstickereditor.packs"]), ["cats", "dogs"]);
+		assert!(body.get("pack").is_none());
+	}
+
+	#[tokio::test]
+	async fn remove_missing() {
+		let answers = vec![("200 OK", emotes().to_string())];
+		let (removed, requests) =
+			with_homeserver(answers, |matrix| async move { remove_user_pack(&matrix, "birds").await }).await;
+		assert!(matches!(removed.unwrap_err(), Error::NotFound(pack) if pack.contains("birds")));
+		// nothing is written
+		assert_eq!(requests.len(), 1);
+	}
+
+	#[tokio::test]
+	async fn concurrent_modification() {
+		let mut modified = emotes();
+		modified["images"]["birds/0000"] = sticker("mxc://example.org/bird1");
+		modified["dev.luckyturtle.mstickereditor.packs"]["birds"] =
+			json!({"display_name": "Birds", "avatar_url": null, "images": ["birds/0000"]});
+		let answers = vec![
+			("200 OK", emotes().to_string()),
+			// another client has added a pack in the meantime
+			("200 OK", modified.to_string()),
+			("200 OK", modified.to_string()),
+			("200 OK", modified.to_string()),
+			("200 OK", "{}".to_owned()),
+		];
+		let (removed, requests) =
+			with_homeserver(answers, |matrix| async move { remove_user_pack(&matrix, "cats").await }).await;
+		removed.unwrap();
+		assert_eq!(requests.len(), 5);
+		let body = put_body(&requests);
+		assert_eq!(keys(&body["images"]), ["birds/0000", "dogs/0000", "smile"]);
+		assert_eq!(keys(&body["dev.luckyturtle.mstickereditor.packs"]), ["birds", "dogs"]);
+
+		// give up, if the emotes change at every attempt
+		let answers = (0..6)
+			.map(|i| ("200 OK", if i % 2 == 0 { emotes() } else { modified.clone() }.to_string()))
+			.collect();
+		let (removed, requests) =
+			with_homeserver(answers, |matrix| async move { remove_user_pack(&matrix, "cats").await }).await;
+		assert!(matches!(removed.unwrap_err(), Error::ConcurrentModification(_)));
+		assert!(requests.iter().all(|request| request.starts_with("GET")));
+	}
+
+	#[cfg(feature = "synapse-admin")]
+	#[tokio::test]
+	async fn remove_with_media() {
+		let answers = vec![
+			("200 OK", emotes().to_string()),
+			("200 OK", emotes().to_string()),
+			("200 OK", "{}".to_owned()),
+			("200 OK", r#"{"deleted_media":["cat1"],"total":1}"#.to_owned()),
+			(
+				"404 Not Found",
+				r#"{"errcode":"M_NOT_FOUND","error":"Unknown media"}"#.to_owned()
+			),
+		];
+		let (outcomes, requests) = with_homeserver(answers, |matrix| async move {
+			super::remove_user_pack_with_media(&matrix, "cats").await
+		})
+		.await;
+		let outcomes = outcomes.unwrap();
+		assert_eq!(outcomes.len(), 2);
+		assert_eq!(outcomes[0].0.url(), "mxc://example.org/cat1");
+		assert!(outcomes[0].1.is_ok());
+		assert!(matches!(&outcomes[1].1, Err(Error::NotFound(mxc)) if mxc == "mxc://example.org/cat2"));
+		assert!(requests[3].starts_with("DELETE /_synapse/admin/v1/media/example.org/cat1 "));
+	}
+}
@@ -0,0 +1,254 @@
+//! Minimal http server for tests, which can act as homeserver.
+
+use crate::matrix::Config;
+use std::{
+	io::{Read as _, Write as _},
+	net::{TcpListener, TcpStream},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc
+	},
+	thread,
+	time::{Duration, Instant}
+};
+
+/// Config for the homeserver at `homeserver_url`, with the user `@alice:example.org` and the access token `token_secret`.
+pub(crate) fn matrix_config(homeserver_url: impl Into<String>) -> Config {
+	Config::new(
+		homeserver_url.into(),
+		"@alice:example.org".to_owned(),
+		"token_secret".to_owned()
+	)
+}
+
+/// Read a single http request and answer it with `status` and `body`.
+fn answer(mut stream: TcpStream, status: &str, content_type: &str, body: &[u8]) {
+	read_request(&mut stream);
+	respond(stream, status, content_type, body);
+}
+
+/// Read a single http request and return it.
+fn read_request(stream: &mut TcpStream) -> String {
+	let mut request = Vec::new();
+	let mut buf = [0; 1024];
+	loop {
+		let len = stream.read(&mut buf).unwrap();
+		request.extend_from_slice(&buf[..len]);
+		let request = String::from_utf8_lossy(&request);
+		if let Some((header, body)) = request.split_once("\r\n\r\n") {
+			let content_length = header
+				.lines()
+				.find_map(|line| {
+					line.to_ascii_lowercase()
+						.strip_prefix("content-length: ")
+						.map(|len| len.parse().unwrap())
+				})
+				.unwrap_or(0);
+			if body.len() >= content_length {
+				break;
+			}
+		}
+		if len == 0 {
+			break;
+		}
+	}
+	String::from_utf8_lossy(&request).into_owned()
+}
+
+/// Write a http response with `status` and `body`.
+fn respond(mut stream: TcpStream, status: &str, content_type: &str, body: &[u8]) {
+	write!(
+		stream,
+		"HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nX-Echo: token_secret\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+		body.len()
+	)
+	.unwrap();
+	stream.write_all(body).unwrap();
+}
+
+/// Answer `count` http requests with a successful upload response, which does also contain the access token `token_secret`.
+/// Return the time, when each request was received.
+pub(crate) fn serve_uploads(listener: TcpListener, count: usize) -> Vec<Instant> {
+	let mut timestamps = Vec::with_capacity(count);
+	for _ in 0..count {
+		let (stream, _) = listener.accept().unwrap();
+		timestamps.push(Instant::now());
+		answer(
+			stream,
+			"200 OK",
+			"application/json",
+			br#"{"content_uri":"mxc://example.org/abc"}"#
+		);
+	}
+	timestamps
+}
+
+/// Like [`serve_uploads`], but answer the requests concurrently, each of them after `delay`.
+/// Return the highest number of requests, which were in flight at the same time.
+pub(crate) fn serve_concurrent_uploads(listener: TcpListener, count: usize, delay: Duration) -> usize {
+	let in_flight = AtomicUsize::new(0);
+	let peak = AtomicUsize::new(0);
+	thread::scope(|scope| {
+		for _ in 0..count {
+			let (mut stream, _) = listener.accept().unwrap();
+			let (in_flight, peak) = (&in_flight, &peak);
+			scope.spawn(move || {
+				read_request(&mut stream);
+				let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+				peak.fetch_max(current, Ordering::SeqCst);
+				thread::sleep(delay);
+				// the request is finished, before the client can see the answer
+				in_flight.fetch_sub(1, Ordering::SeqCst);
+				respond(
+					stream,
+					"200 OK",
+					"application/json",
+					br#"{"content_uri":"mxc://example.org/abc"}"#
+				);
+			});
+		}
+	});
+	peak.into_inner()
+}
+
+/// Like [`serve_uploads`], but answer the `i`-th request with the url `mxc://example.org/<prefix><i>`.
+pub(crate) fn serve_numbered_uploads(listener: TcpListener, count: usize, prefix: &str) -> Vec<Instant> {
+	let mut timestamps = Vec::with_capacity(count);
+	for i in 0..count {
+		let (stream, _) = listener.accept().unwrap();
+		timestamps.push(Instant::now());
+		let body = format!(r#"{{"content_uri":"mxc://example.org/{prefix}{i}"}}"#);
+		answer(stream, "200 OK", "application/json", body.as_bytes());
+	}
+	timestamps
+}
+
+/// Answer all requests with an internal server error, like a broadly unhealthy homeserver, and count them.
+/// This does never return.
+pub(crate) fn serve_errors(listener: TcpListener, requests: Arc<AtomicUsize>) {
+	loop {
+		let (stream, _) = listener.accept().unwrap();
+		requests.fetch_add(1, Ordering::SeqCst);
+		answer(
+			stream,
+			"500 Internal Server Error",
+			"application/json",
+			br#"{"errcode":"M_UNKNOWN","error":"unhealthy"}"#
+		);
+	}
+}
+
+/// Answer a single http request with `body`.
+pub(crate) fn serve_file(listener: TcpListener, content_type: &str, body: &[u8]) {
+	let (stream, _) = listener.accept().unwrap();
+	answer(stream, "200 OK", content_type, body);
+}
+
+/// Answer a single http request with a redirect to `location`. Return the request line.
+pub(crate) fn serve_redirect(listener: &TcpListener, location: &str) -> String {
+	let (mut stream, _) = listener.accept().unwrap();
+	let request = read_request(&mut stream);
+	write!(
+		stream,
+		"HTTP/1.1 302 Found\r\nLocation: {location}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+	)
+	.unwrap();
+	request.lines().next().unwrap_or_default().to_owned()
+}
+
+/// Answer a single http request with `status` and a json `body`.
+#[cfg(feature = "synapse-admin")]
+pub(crate) fn serve_status(listener: TcpListener, status: &str, body: &str) {
+	let (stream, _) = listener.accept().unwrap();
+	answer(stream, status, "application/json", body.as_bytes());
+}
+
+/// Answer `count` download requests with the file of the requested mxc url from `files`,
+/// or with `M_NOT_FOUND` if it is not listed. Return the requested mxc urls.
+pub(crate) fn serve_media(listener: TcpListener, files: &[(&str, &[u8])], count: usize) -> Vec<String> {
+	let mut requests = Vec::with_capacity(count);
+	for _ in 0..count {
+		let (mut stream, _) = listener.accept().unwrap();
+		let request = read_request(&mut stream);
+		let path = request
+			.split_whitespace()
+			.nth(1)
+			.and_then(|target| target.split('?').next())
+			.unwrap_or_default();
+		let url = format!(
+			"mxc://{}",
+			path.strip_prefix("/_matrix/client/v1/media/download/").unwrap_or(path)
+		);
+		match files.iter().find(|(file, _)| *file == url) {
+			Some((_, data)) => respond(stream, "200 OK", "image/webp", data),
+			None => respond(
+				stream,
+				"404 Not Found",
+				"application/json",
+				br#"{"errcode":"M_NOT_FOUND","error":"Not found"}"#
+			)
+		}
+		requests.push(url);
+	}
+	requests
+}
+
+/// Answer `count` http requests with the json body of the matching path from `routes`.
+/// Other paths are answered with `M_UNRECOGNIZED`, like homeservers do for unsupported endpoints.
+/// Return the received requests.
+pub(crate) fn serve_routes(listener: TcpListener, routes: &[(&str, &str)], count: usize) -> Vec<String> {
+	let mut requests = Vec::with_capacity(count);
+	for _ in 0..count {
+		let (mut stream, _) = listener.accept().unwrap();
+		let request = read_request(&mut stream);
+		let path = request
+			.split_whitespace()
+			.nth(1)
+			.and_then(|target| target.split('?').next())
+			.unwrap_or_default();
+		match routes.iter().find(|(route, _)| *route == path) {
+			Some((_, body)) => respond(stream, "200 OK", "application/json", body.as_bytes()),
+			None => respond(
+				stream,
+				"404 Not Found",
+				"application/json",
+				br#"{"errcode":"M_UNRECOGNIZED","error":"Unrecognized request"}"#
+			)
+		}
+		requests.push(request);
+	}
+	requests
+}
+
+/// Like [`serve_routes`], but match the start of the request line, like `PUT /path?query`, and answer with the
+/// status and json body of the first matching route. This allows different answers per method or query.
+pub(crate) fn serve_request_lines(listener: TcpListener, routes: &[(&str, &str, &str)], count: usize) -> Vec<String> {
+	let mut requests = Vec::with_capacity(count);
+	for _ in 0..count {
+		let (mut stream, _) = listener.accept().unwrap();
+		let request = read_request(&mut stream);
+		match routes.iter().find(|(route, ..)| request.starts_with(route)) {
+			Some((_, status, body)) => respond(stream, status, "application/json", body.as_bytes()),
+			None => respond(
+				stream,
+				"404 Not Found",
+				"application/json",
+				br#"{"errcode":"M_UNRECOGNIZED","error":"Unrecognized request"}"#
+			)
+		}
+		requests.push(request);
+	}
+	requests
+}
+
+/// Answer the `i`-th http request with the `i`-th status and json body of `answers`, regardless of its path.
+/// This allows to change the answers to the same request over time. Return the received requests.
+pub(crate) fn serve_sequence(listener: TcpListener, answers: &[(&str, &str)]) -> Vec<String> {
+	let mut requests = Vec::with_capacity(answers.len());
+	for (status, body) in answers {
+		let (mut stream, _) = listener.accept().unwrap();
+		requests.push(read_request(&mut stream));
+		respond(stream, status, "application/json", body.as_bytes());
+	}
+	requests
+}
@@ -0,0 +1,294 @@
+//! Recording and replaying of the http requests made by this crate.
+//!
+//! A recording contains every request together with the response of the server, so a failed import can be
+//! reproduced later without network access, for example to attach it to a bug report.
+//! Access tokens and bot keys are redacted before anything is written.
+
+use crate::error::Error;
+use reqwest::{
+	header::{HeaderName, HeaderValue, AUTHORIZATION},
+	RequestBuilder, Response, StatusCode, Url
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::{fs, io::AsyncWriteExt as _, sync::Mutex};
+
+const REDACTED: &str = "<redacted>";
+
+/// A request together with the response of the server, as stored by an [`HttpRecorder`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Exchange {
+	pub method: String,
+	/// url of the request, with redacted secrets
+	pub url: String,
+	pub status: u16,
+	pub headers: Vec<(String, String)>,
+	/// body of the response; hex encoded if `binary` is set
+	pub body: String,
+	/// the body is not valid utf-8
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub binary: bool,
+	/// the body was cut off, because it was larger than the limit of the recording
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub truncated: bool
+}
+
+impl Exchange {
+	fn response(&self) -> Result<Response, Error> {
+		let invalid = || Error::InvalidRecording(self.url.clone());
+		let body = if self.binary {
+			decode_hex(&self.body).ok_or_else(invalid)?
+		} else {
+			self.body.clone().into_bytes()
+		};
+		let mut response = http::Response::new(body);
+		*response.status_mut() = StatusCode::from_u16(self.status).map_err(|_| invalid())?;
+		for (key, value) in &self.headers {
+			let key = HeaderName::from_bytes(key.as_bytes()).map_err(|_| invalid())?;
+			let value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+			response.headers_mut().append(key, value);
+		}
+		Ok(response.into())
+	}
+}
+
+#[derive(Debug)]
+enum Mode {
+	Record {
+		file: Mutex<fs::File>,
+		max_body_len: usize
+	},
+	/// exchanges, which were not replayed yet
+	Replay(Mutex<Vec<Option<Exchange>>>)
+}
+
+/// Layer between this crate and the network, which either records all requests to a file,
+/// or answers them from such a recording without making any request.
+///
+/// The recording is a json lines file with one [`Exchange`] per line.
+/// Set it at [`matrix::Config::http_recorder`](crate::matrix::Config::http_recorder) and
+/// [`tg::Config::http_recorder`](crate::tg::Config::http_recorder).
+#[derive(Debug)]
+pub struct HttpRecorder(Mode);
+
+impl HttpRecorder {
+	/// Record all requests to `path`, which is overwritten if it does already exist.
+	/// Response bodies are truncated to `max_body_len` bytes, to keep large files like stickers out of the recording.
+	pub async fn record<P>(path: P, max_body_len: usize) -> Result<Self, Error>
+	where
+		P: AsRef<Path>
+	{
+		let file = fs::File::create(path).await?;
+		Ok(Self(Mode::Record {
+			file: Mutex::new(file),
+			max_body_len
+		}))
+	}
+
+	/// Answer all requests from the recording at `path`.
+	/// Requests with the same method and url are answered in the order they were recorded.
+	pub async fn replay<P>(path: P) -> Result<Self, Error>
+	where
+		P: AsRef<Path>
+	{
+		let exchanges = fs::read_to_string(path)
+			.await?
+			.lines()
+			.filter(|line| !line.trim().is_empty())
+			.map(|line| serde_json::from_str(line).map(Some))
+			.collect::<Result<_, _>>()?;
+		Ok(Self(Mode::Replay(Mutex::new(exchanges))))
+	}
+
+	async fn send(&self, request: RequestBuilder) -> Result<Response, Error> {
+		// execute the request with the client, which has built it, to keep its redirect policy
+		let (client, request) = request.build_split();
+		let request = request?;
+		let mut secrets = Vec::new();
+		let url = redact_url(request.url(), &mut secrets);
+		if let Some(token) = request
+			.headers()
+			.get(AUTHORIZATION)
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.strip_prefix("Bearer "))
+		{
+			secrets.push(token.to_owned());
+		}
+		let method = request.method().to_string();
+		match &self.0 {
+			Mode::Record { file, max_body_len } => {
+				let response = client.execute(request).await?;
+				let status = response.status().as_u16();
+				let headers = response
+					.headers()
+					.iter()
+					.map(|(key, value)| (key.to_string(), redact(&String::from_utf8_lossy(value.as_bytes()), &secrets)))
+					.collect();
+				let body = response.bytes().await?;
+				let truncated = body.len() > *max_body_len;
+				let kept = &body[..body.len().min(*max_body_len)];
+				let (body, binary) = match std::str::from_utf8(kept) {
+					Ok(text) => (redact(text, &secrets), false),
+					Err(_) => (kept.iter().map(|byte| format!("{byte:02x}")).collect(), true)
+				};
+				let exchange = Exchange {
+					method,
+					url,
+					status,
+					headers,
+					body,
+					binary,
+					truncated
+				};
+				let mut file = file.lock().await;
+				file.write_all(&serde_json::to_vec(&exchange)?).await?;
+				file.write_all(b"\n").await?;
+				drop(file);
+				exchange.response()
+			},
+			Mode::Replay(exchanges) => {
+				let mut exchanges = exchanges.lock().await;
+				let exchange = exchanges
+					.iter_mut()
+					.find(|exchange| {
+						exchange
+							.as_ref()
+							.is_some_and(|exchange| exchange.method == method && exchange.url == url)
+					})
+					.and_then(Option::take)
+					.ok_or(Error::NotRecorded { method, url })?;
+				exchange.response()
+			}
+		}
+	}
+}
+
+/// Send `request` through the recorder, or directly if there is none.
+pub(crate) async fn send(recorder: Option<&HttpRecorder>, request: RequestBuilder) -> Result<Response, Error> {
+	match recorder {
+		Some(recorder) => recorder.send(request).await,
+		None => Ok(request.send().await?)
+	}
+}
+
+/// Redact the access token of matrix and the bot key of telegram from `url` and collect them at `secrets`.
+fn redact_url(url: &Url, secrets: &mut Vec<String>) -> String {
+	let mut url = url.clone();
+	if let Some(segments) = url.path_segments() {
+		let segments: Vec<_> = segments
+			.map(|segment| match segment.strip_prefix("bot") {
+				// telegram bot keys look like `123456:ABC-DEF`
+				Some(key) if key.contains(':') => {
+					secrets.push(key.to_owned());
+					format!("bot{REDACTED}")
+				},
+				_ => segment.to_owned()
+			})
+			.collect();
+		url.set_path(&segments.join("/"));
+	}
+	if url.query().is_some() {
+		let pairs: Vec<(String, String)> = url
+			.query_pairs()
+			.map(|(key, value)| {
+				if key == "access_token" {
+					secrets.push(value.clone().into_owned());
+					(key.into_owned(), REDACTED.to_owned())
+				} else {
+					(key.into_owned(), value.into_owned())
+				}
+			})
+			.collect();
+		url.query_pairs_mut().clear().extend_pairs(pairs);
+	}
+	url.to_string()
+}
+
+fn redact(text: &str, secrets: &[String]) -> String {
+	secrets
+		.iter()
+		.filter(|secret| !secret.is_empty())
+		.fold(text.to_owned(), |text, secret| text.replace(secret.as_str(), REDACTED))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+	if !hex.len().is_multiple_of(2) {
+		return None;
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Exchange, HttpRecorder};
+	use crate::{
+		error::Error,
+		matrix::{health_check, Config},
+		mock::{matrix_config, serve_routes}
+	};
+	use std::{net::TcpListener, sync::Arc};
+
+	const WHOAMI: (&str, &str) = (
+		"/_matrix/client/v3/account/whoami",
+		r#"{"user_id":"@alice:example.org","device_id":"ABCDEF"}"#
+	);
+	const MEDIA_CONFIG: (&str, &str) = ("/_matrix/media/v3/config", r#"{"m.upload.size":50000000}"#);
+
+	fn config(homeserver_url: String, recorder: HttpRecorder) -> Config {
+		Config {
+			http_recorder: Some(Arc::new(recorder)),
+			..matrix_config(homeserver_url)
+		}
+	}
+
+	fn recording_path(name: &str) -> std::path::PathBuf {
+		std::env::temp_dir().join(format!("mstickerlib-recorder-{name}-{}.jsonl", std::process::id()))
+	}
+
+	#[tokio::test]
+	async fn record_and_replay() {
+		let path = recording_path("replay");
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let homeserver_url = format!("http://{}", listener.local_addr().unwrap());
+		// the authenticated media config is not supported, so the legacy endpoint is requested as well
+		let server = std::thread::spawn(move || serve_routes(listener, &[WHOAMI, MEDIA_CONFIG], 3));
+		let record = config(homeserver_url.clone(), HttpRecorder::record(&path, 1024).await.unwrap());
+		let recorded = health_check(&record).await.unwrap();
+		server.join().unwrap();
+
+		let recording = std::fs::read_to_string(&path).unwrap();
+		assert!(!recording.contains("token_secret"));
+		let exchanges: Vec<Exchange> = recording.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+		assert_eq!(exchanges.len(), 3);
+		assert!(exchanges[0].url.ends_with("/whoami?access_token=%3Credacted%3E"));
+		assert_eq!(exchanges[1].status, 404);
+		assert!(exchanges[2].headers.contains(&("x-echo".to_owned(), "<redacted>".to_owned())));
+
+		// the server is gone, so every answer comes from the recording
+		let replay = config(homeserver_url, HttpRecorder::replay(&path).await.unwrap());
+		assert_eq!(health_check(&replay).await.unwrap(), recorded);
+		// every exchange is only replayed once
+		let err = health_check(&replay).await.unwrap_err();
+		assert!(matches!(err, Error::NotRecorded { method, url } if method == "GET" && url.contains("/whoami")));
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn truncate() {
+		let path = recording_path("truncate");
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let homeserver_url = format!("http://{}", listener.local_addr().unwrap());
+		let server = std::thread::spawn(move || serve_routes(listener, &[WHOAMI], 1));
+		let record = config(homeserver_url, HttpRecorder::record(&path, 8).await.unwrap());
+		// the truncated body is also what the caller does get
+		assert!(matches!(crate::matrix::whoami(&record).await, Err(Error::Reqwest(_))));
+		server.join().unwrap();
+		let exchange: Exchange = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+		assert!(exchange.truncated);
+		assert_eq!(exchange.body, &WHOAMI.1[..8]);
+		std::fs::remove_file(&path).unwrap();
+	}
+}
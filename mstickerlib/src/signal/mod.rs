@@ -0,0 +1,98 @@
+//! Import of [Signal](https://signal.org) sticker packs, which were downloaded and decrypted before.
+
+use crate::{
+	error::Error,
+	image::{Image, ImageBuilder}
+};
+use photon_rs::native::open_image_from_bytes;
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
+pub struct Sticker {
+	/// Id of the sticker. The image is stored as `{id}.webp`.
+	pub id: u32,
+	/// Emoji associated with the sticker.
+	#[serde(default)]
+	pub emoji: Option<String>,
+	/// image of the sticker, loaded by [`StickerPack::resolve_signal_images`]
+	#[serde(skip)]
+	pub image: Option<Image>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
+pub struct StickerPack {
+	pub title: String,
+	#[serde(default)]
+	pub author: Option<String>,
+	pub stickers: Vec<Sticker>
+}
+
+impl StickerPack {
+	/// Parse the `manifest.json` of a signal stickerpack.
+	pub fn from_signal_manifest(json: &str) -> Result<Self, Error> {
+		Ok(serde_json::from_str(json)?)
+	}
+
+	/// Load the images of all stickers from `dir`, where they are stored as `{id}.webp`.
+	pub async fn resolve_signal_images(mut self, dir: &Path) -> Result<Self, Error> {
+		for sticker in &mut self.stickers {
+			let file_name = format!("{}.webp", sticker.id);
+			let data = fs::read(dir.join(&file_name)).await?;
+			let img = open_image_from_bytes(&data)?;
+			let image = ImageBuilder::new()
+				.data(data)
+				.file_name(file_name)
+				.width(img.get_width())
+				.height(img.get_height())
+				.build()?;
+			sticker.image = Some(image);
+		}
+		Ok(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::StickerPack;
+	use crate::image::Image;
+	use std::{env, fs};
+
+	const MANIFEST: &str = r#"{
+		"title": "Cats",
+		"author": "someone",
+		"stickers": [{ "id": 0, "emoji": "🐱" }, { "id": 1, "emoji": "😺" }]
+	}"#;
+
+	#[test]
+	fn from_signal_manifest() {
+		let pack = StickerPack::from_signal_manifest(MANIFEST).unwrap();
+		assert_eq!(pack.title, "Cats");
+		assert_eq!(pack.author.as_deref(), Some("someone"));
+		assert_eq!(pack.stickers.len(), 2);
+		assert_eq!(pack.stickers[1].id, 1);
+		assert_eq!(pack.stickers[1].emoji.as_deref(), Some("😺"));
+	}
+
+	#[tokio::test]
+	async fn resolve_signal_images() {
+		let dir = env::temp_dir().join(format!("mstickerlib-signal-test-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		for id in 0..2 {
+			let image = Image::from_rgba(vec![255; 4 * 3 * 4], 4, 3, "sticker.webp").unwrap();
+			fs::write(dir.join(format!("{id}.webp")), image.data_bytes()).unwrap();
+		}
+		let pack = StickerPack::from_signal_manifest(MANIFEST)
+			.unwrap()
+			.resolve_signal_images(&dir)
+			.await;
+		fs::remove_dir_all(&dir).unwrap();
+		let pack = pack.unwrap();
+		let image = pack.stickers[1].image.as_ref().unwrap();
+		assert_eq!(image.file_name_str(), "1.webp");
+		assert_eq!((image.width, image.height), (4, 3));
+	}
+}
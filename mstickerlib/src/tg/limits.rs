@@ -0,0 +1,125 @@
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits, how many stickers are converted and uploaded at the same time, see [`ImportConfig::limits`](super::ImportConfig::limits).
+///
+/// Conversions are bound by the cpu and uploads by the network, so both stages have their own limit.
+/// A sticker leaves the conversion stage before it waits for the upload stage,
+/// so further stickers can already be converted while the previous ones are uploaded.
+/// A single instance can be shared between multiple imports, which then stay below the limits together.
+#[derive(Debug)]
+pub struct ImportLimits {
+	conversions: Semaphore,
+	uploads: Semaphore
+}
+
+impl ImportLimits {
+	/// Allow up to `max_concurrent_conversions` conversions and `max_concurrent_uploads` uploads at once.
+	/// A limit of zero is treated as one.
+	pub fn new(max_concurrent_conversions: usize, max_concurrent_uploads: usize) -> Self {
+		Self {
+			conversions: Semaphore::new(max_concurrent_conversions.max(1)),
+			uploads: Semaphore::new(max_concurrent_uploads.max(1))
+		}
+	}
+
+	/// Wait until a sticker can be converted; the slot is released, when the permit is dropped.
+	pub(crate) async fn conversion(&self) -> SemaphorePermit<'_> {
+		self.conversions.acquire().await.expect("semaphore is never closed")
+	}
+
+	/// Wait until a sticker can be uploaded; the slot is released, when the permit is dropped.
+	pub(crate) async fn upload(&self) -> SemaphorePermit<'_> {
+		self.uploads.acquire().await.expect("semaphore is never closed")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::ImportLimits;
+	use crate::{
+		database::DummyDatabase,
+		image::Image,
+		mock::{matrix_config, serve_concurrent_uploads},
+		recorder::{Exchange, HttpRecorder},
+		tg::{Config, ImportConfig, StickerPack, DEFAULT_MAX_PACK_SIZE}
+	};
+	use std::{env, net::TcpListener, process, sync::Arc, time::Duration};
+
+	const STICKERS: usize = 6;
+
+	/// Recording of telegram for a pack with [`STICKERS`] stickers, which can all be downloaded.
+	fn recording() -> Vec<Exchange> {
+		let data = Image::from_rgba(vec![255; 64 * 64 * 4], 64, 64, "sticker.webp")
+			.unwrap()
+			.into_data();
+		let stickers: Vec<_> = (0 .. STICKERS)
+			.map(|i| {
+				format!(
+					r#"{{"file_id":"id_{i}","file_unique_id":"{i}","width":64,"height":64,"emoji":"🐱","is_animated":false,"is_video":false}}"#
+				)
+			})
+			.collect();
+		let json = |url: String, body: String| Exchange {
+			method: "GET".to_owned(),
+			url,
+			status: 200,
+			headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+			body,
+			binary: false,
+			truncated: false
+		};
+		let mut recording = vec![json(
+			"https://api.telegram.org/bot%3Credacted%3E/getStickerSet?name=cats".to_owned(),
+			format!(
+				r#"{{"ok":true,"result":{{"name":"cats","title":"Cats","stickers":[{}]}}}}"#,
+				stickers.join(",")
+			)
+		)];
+		for i in 0..STICKERS {
+			recording.push(json(
+				format!("https://api.telegram.org/bot%3Credacted%3E/getFile?file_id=id_{i}"),
+				format!(r#"{{"ok":true,"result":{{"file_path":"stickers/file_{i}.webp"}}}}"#)
+			));
+			recording.push(Exchange {
+				method: "GET".to_owned(),
+				url: format!("https://api.telegram.org/file/bot%3Credacted%3E/stickers/file_{i}.webp"),
+				status: 200,
+				headers: Vec::new(),
+				body: data.iter().map(|byte| format!("{byte:02x}")).collect(),
+				binary: true,
+				truncated: false
+			});
+		}
+		recording
+	}
+
+	#[tokio::test]
+	async fn concurrent_uploads() {
+		let path = env::temp_dir().join(format!("mstickerlib-limits-{}.jsonl", process::id()));
+		let recording: Vec<_> = recording()
+			.iter()
+			.map(|exchange| serde_json::to_string(exchange).unwrap())
+			.collect();
+		std::fs::write(&path, recording.join("\n")).unwrap();
+		let tg_config = Config {
+			bot_key: "123:secret".to_owned(),
+			max_pack_size: DEFAULT_MAX_PACK_SIZE,
+			http_recorder: Some(Arc::new(HttpRecorder::replay(&path).await.unwrap()))
+		};
+		let pack = StickerPack::get("cats", &tg_config).await.unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		// the uploads take much longer than the conversions, so without the limit all of them would be in flight at once
+		let server = std::thread::spawn(move || serve_concurrent_uploads(listener, STICKERS, Duration::from_millis(200)));
+		let limits = ImportLimits::new(STICKERS, 2);
+		let import_config = ImportConfig::<DummyDatabase> {
+			limits: Some(&limits),
+			..Default::default()
+		};
+		let pack = pack.import(&tg_config, &matrix_config, &import_config).await.unwrap();
+		assert_eq!(pack.stickers.len(), STICKERS);
+		assert_eq!(server.join().unwrap(), 2);
+	}
+}
@@ -1,32 +1,72 @@
 use crate::{
 	database::Database,
 	error::{Error, TelgramApiError},
-	image::AnimationFormat,
+	image::{AnimationFormat, FormatSelector},
+	matrix::{MediaMirror, ShortcodeCollision, ShortcodeDictionary},
+	recorder::{self, HttpRecorder},
 	CLIENT
 };
 use monostate::MustBe;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::sync::Arc;
+
+mod limits;
+pub use limits::ImportLimits;
 
 mod sticker;
 pub use sticker::{PhotoSize, Sticker};
 
 mod stickerpack;
-pub use stickerpack::{pack_url_to_name, StickerPack};
+pub use stickerpack::{pack_url_to_name, StickerOutcome, StickerPack};
+
+mod validate;
+pub use validate::{ConfigIssue, Severity};
+
+/// Default of [`Config::max_pack_size`].
+pub const DEFAULT_MAX_PACK_SIZE: usize = 500;
+/// Answers of the telegram api, which are larger than this, are rejected before they are parsed.
+const MAX_RESPONSE_SIZE: u64 = 8 * 1024 * 1024;
+
+fn default_max_pack_size() -> usize {
+	DEFAULT_MAX_PACK_SIZE
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-	pub bot_key: String
+	pub bot_key: String,
+	/// Packs with more stickers are rejected; telegram itself allows up to 120 stickers (200 for emoji).
+	#[serde(default = "default_max_pack_size")]
+	pub max_pack_size: usize,
+	/// If set, all requests to telegram are recorded or replayed, see [`HttpRecorder`].
+	#[serde(skip)]
+	pub http_recorder: Option<Arc<HttpRecorder>>
+}
+
+/// Value, which is used as `body` of imported stickers.
+/// The body is shown by clients as description of the sticker and can be used to search for it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BodySource {
+	/// emoji associated with the sticker
+	#[default]
+	Emoji,
+	/// original file name of the sticker, before it was converted; falls back to the emoji
+	DisplayName,
+	/// name of the pack and position of the sticker inside the pack, like `pack_name_0007`
+	Shortcode
 }
 
 /// additonal, optional configuration for importing stickers
 #[non_exhaustive]
 pub struct ImportConfig<'a, D = crate::database::DummyDatabase>
 where
-	D: Database
+	D: Database + ?Sized
 {
 	/// animaton format, to which animated sticker will be converted.
 	/// If `None` original format will be used, this is propably not supported by matrix cilents.
 	pub animation_format: AnimationFormat,
+	/// If set, the format of each animated sticker is picked by this selector instead of [`ImportConfig::animation_format`].
+	pub format_selector: Option<FormatSelector>,
 	/// database to track, which files was already uploaded,
 	/// to aviod duplicaded uploads of the same file
 	pub database: Option<&'a D>,
@@ -40,20 +80,53 @@ where
 	/// Do not convert animated sticker and keep lootie files.
 	/// Animated sticker will be still unpack (they are zstd compressed lottie files).
 	/// Import of animated stickers fail, if set to `false` and `lottie` features is dissable.
-	pub keep_lottie: bool
+	pub keep_lottie: bool,
+	/// Value, which is used as `body` of the imported stickers.
+	pub body_source: BodySource,
+	/// Store converted animations, whose frames are all the same, as static webp.
+	/// Has no effect without the `animation` feature.
+	pub collapse_static: bool,
+	/// Add the duration of animated stickers to their `info`.
+	/// Disabled by default, because not all clients tolerate unknown fields there.
+	pub emit_duration: bool,
+	/// Custom shortcodes for the emoji of the stickers.
+	pub shortcode_dictionary: Option<&'a ShortcodeDictionary>,
+	/// What to do, if multiple stickers would get the same shortcode, see [`ShortcodeDictionary::apply_with`].
+	pub shortcode_collision: ShortcodeCollision,
+	/// Scale every sticker, so that its longest side has this size, keeping the aspect ratio; 256 pixels if `None`.
+	/// The stickers are never padded to a square.
+	pub normalize_longest_side: Option<u32>,
+	/// Limit the number of concurrent conversions and uploads; all stickers of a pack are processed at once if `None`.
+	pub limits: Option<&'a ImportLimits>,
+	/// Store every uploaded sticker at this mirror before it is uploaded, to rebuild the packs later if needed.
+	pub mirror: Option<&'a MediaMirror>,
+	/// Reuse stickers, which the database does know from an earlier import of the same file with the same settings,
+	/// instead of converting and uploading them again.
+	/// Reused stickers have no [data](crate::matrix::Mxc::data), since their files are not converted.
+	pub reuse_imports: bool
 }
 
 impl<D> Default for ImportConfig<'_, D>
 where
-	D: Database
+	D: Database + ?Sized
 {
 	fn default() -> Self {
 		Self {
 			animation_format: AnimationFormat::Webp,
+			format_selector: None,
 			database: None,
 			dry_run: false,
 			keep_webm: false,
-			keep_lottie: false
+			keep_lottie: false,
+			body_source: BodySource::default(),
+			collapse_static: true,
+			emit_duration: false,
+			shortcode_dictionary: None,
+			shortcode_collision: ShortcodeCollision::default(),
+			normalize_longest_side: None,
+			limits: None,
+			mirror: None,
+			reuse_imports: true
 		}
 	}
 }
@@ -88,14 +161,26 @@ where
 	P: Serialize,
 	P: Sized
 {
-	let resp: TgResponse<T> = CLIENT
+	let request = CLIENT
 		.get()
 		.get(format!("https://api.telegram.org/bot{}/{}", tg_config.bot_key, operation))
-		.query(&params)
-		.send()
-		.await?
-		.json()
-		.await?;
+		.query(&params);
+	let mut response = recorder::send(tg_config.http_recorder.as_deref(), request).await?;
+	let too_large = || Error::TelegramResponseTooLarge {
+		operation: operation.to_owned(),
+		max_size: MAX_RESPONSE_SIZE
+	};
+	if response.content_length().is_some_and(|len| len > MAX_RESPONSE_SIZE) {
+		return Err(too_large());
+	}
+	let mut body = Vec::new();
+	while let Some(chunk) = response.chunk().await? {
+		if (body.len() + chunk.len()) as u64 > MAX_RESPONSE_SIZE {
+			return Err(too_large());
+		}
+		body.extend_from_slice(&chunk);
+	}
+	let resp: TgResponse<T> = serde_json::from_slice(&body)?;
 	let result = match resp {
 		TgResponse::Ok { result, .. } => result,
 		TgResponse::Err {
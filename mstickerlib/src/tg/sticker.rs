@@ -1,22 +1,25 @@
-use std::sync::Arc;
+use std::path::Path;
 
-use super::ImportConfig;
+use super::{BodySource, ImportConfig};
 use crate::{
+	database::{self, Hash},
 	error::Error,
-	image::Image,
+	image::{Image, ImageBuilder},
 	matrix::{self, sticker_formats::ponies, Mxc},
+	recorder,
+	trace::{Stage, StageSpan},
 	CLIENT
 };
-use anyhow::Context;
 use derive_getters::Getters;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha512};
 use tokio::fs;
 
 #[cfg(feature = "log")]
 use log::{info, warn};
 
 ///see <https://core.telegram.org/bots/api#photosize>
-#[derive(Clone, Debug, Deserialize, Hash)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
 #[non_exhaustive]
 pub struct PhotoSize {
 	/// Identifier for this file, which can be used to download or reuse the file.
@@ -33,26 +36,39 @@ impl PhotoSize {
 	/// download the image of the PhotoSize
 	pub async fn download(&self, tg_config: &super::Config) -> Result<Image, Error> {
 		let file: super::File = super::tg_get(tg_config, "getFile", [("file_id", &self.file_id)]).await?;
-		let data = CLIENT
-			.get()
-			.get(format!(
-				"https://api.telegram.org/file/bot{}/{}",
-				tg_config.bot_key, file.file_path
-			))
-			.send()
-			.await?
-			.bytes()
-			.await?
-			.to_vec();
-		Ok(Image {
-			data: Arc::new(data),
-			file_name: file.file_path,
-			width: self.width,
-			height: self.height
-		})
+		// telegram does sometimes list placeholders without pixels, which can not be converted
+		if self.width == 0 || self.height == 0 {
+			return Err(Error::DegenerateImage {
+				file_name: file.file_path,
+				width: self.width,
+				height: self.height
+			});
+		}
+		let request = CLIENT.get().get(format!(
+			"https://api.telegram.org/file/bot{}/{}",
+			tg_config.bot_key, file.file_path
+		));
+		let mut response = recorder::send(tg_config.http_recorder.as_deref(), request).await?;
+		// hash the chunks as they arrive, so the import can look up the sticker as soon as the download is done
+		let mut hasher = Sha512::new();
+		let mut data = Vec::new();
+		while let Some(chunk) = response.chunk().await? {
+			hasher.update(&chunk);
+			data.extend_from_slice(&chunk);
+		}
+		let mut image = ImageBuilder::new()
+			.data(data)
+			.file_name(file.file_path)
+			.width(self.width)
+			.height(self.height)
+			.build()?
+			.with_content_hash(hasher.finalize().into());
+		image.fix_extension_from_magic_bytes();
+		Ok(image)
 	}
 
-	pub async fn import<'a, D>(
+	#[allow(clippy::too_many_arguments)]
+	pub(crate) async fn import<'a, D>(
 		&self,
 		tg_config: &super::Config,
 		matrix_config: &crate::matrix::Config,
@@ -63,7 +79,28 @@ impl PhotoSize {
 		thumb: bool
 	) -> Result<matrix::sticker::Image, Error>
 	where
-		D: crate::database::Database
+		D: crate::database::Database + ?Sized
+	{
+		let imported = self
+			.import_with_file_name(tg_config, matrix_config, advance_config, pack_name, positon, emoji, thumb)
+			.await?;
+		Ok(imported.image)
+	}
+
+	/// Like [`PhotoSize::import`], but also return the original file name and the content hash of the sticker.
+	#[allow(clippy::too_many_arguments)]
+	pub(crate) async fn import_with_file_name<'a, D>(
+		&self,
+		tg_config: &super::Config,
+		matrix_config: &crate::matrix::Config,
+		advance_config: &ImportConfig<'a, D>,
+		pack_name: &str,
+		positon: usize,
+		emoji: Option<&str>,
+		thumb: bool
+	) -> Result<ImportedImage, Error>
+	where
+		D: crate::database::Database + ?Sized
 	{
 		#[cfg(not(feature = "log"))]
 		{
@@ -80,36 +117,44 @@ impl PhotoSize {
 		#[cfg(feature = "log")]
 		info!("download sticker {pack_name}:{positon:03} {emoji:<2} {thumbstr}");
 		// download and convert sticker from telegram
-		let mut image = self.download(tg_config).await?;
-		image = image.unpack_tgs().await?;
-		let sticker_size = 256;
-		let thumbnail_size = 64;
-		let mut animated_thumbnail: Image = image.clone();
-		if image.file_name.ends_with(".webp") {
-			image = image.resize(sticker_size as u32, sticker_size as u32)?;
-			animated_thumbnail = animated_thumbnail.resize(thumbnail_size as u32, thumbnail_size as u32)?;
-		}
-		if image.file_name.ends_with(".lottie") && !advance_config.keep_lottie {
-			// file extension is now checked double.
-			// Here and inside `convert_...`
-			// But `convert_...` function does not exist, if feature is dissable.
-			#[cfg(feature = "lottie")]
-			{
-				image = image.convert_lottie(advance_config.animation_format, Some(sticker_size as u32), Some(sticker_size as u32)).await?;
-				animated_thumbnail = animated_thumbnail.convert_lottie(advance_config.animation_format, Some(thumbnail_size as u32), Some(thumbnail_size as u32)).await?;
-			}
-			#[cfg(not(feature = "lottie"))]
-			return Err(Error::UnsupportedFormat(crate::error::UnsupportedFormat::Lottie));
-		}
-		if image.file_name.ends_with(".webm") && !advance_config.keep_webm {
-			#[cfg(feature = "ffmpeg")]
-			{
-				image = image.convert_webm2webp(Some(sticker_size as u32), Some(sticker_size as u32)).await?;
-				animated_thumbnail = animated_thumbnail.convert_webm2webp(Some(thumbnail_size as u32), Some(thumbnail_size as u32)).await?;
+		let image = self.download(tg_config).await?;
+		let file_name = image
+			.file_name
+			.file_name()
+			.unwrap_or(image.file_name.as_os_str())
+			.to_string_lossy()
+			.into_owned();
+		let import_key = import_key(&image, advance_config);
+		if let (Some(key), Some(database)) = (import_key, advance_config.database) {
+			let lookup = StageSpan::new(Stage::DbLookup, Path::new(&file_name), image.data_len());
+			let known = lookup.run(database::retry_unavailable(|| database.get_import(&key))).await?;
+			lookup.cache_hit(known.is_some());
+			match known.map(|known| serde_json::from_str::<ImportedImage>(&known)) {
+				Some(Ok(imported)) => {
+					#[cfg(feature = "log")]
+					info!("  conversion skipped; sticker was already imported");
+					return Ok(ImportedImage { file_name, ..imported });
+				},
+				Some(Err(_err)) => {
+					#[cfg(feature = "log")]
+					warn!("  failed to read the earlier import of sticker {pack_name}:{positon:03}: {_err}");
+				},
+				None => {}
 			}
-			#[cfg(not(feature = "ffmpeg"))]
-			return Err(Error::UnsupportedFormat(crate::error::UnsupportedFormat::Webm));
 		}
+		let conversion = match advance_config.limits {
+			Some(limits) => Some(limits.conversion().await),
+			None => None
+		};
+		let span = StageSpan::new(Stage::Prepare, Path::new(&file_name), image.data_len());
+		let (image, animated_thumbnail) = span.run(prepare(image, advance_config)).await?;
+		span.bytes_out(image.data_len());
+		// the next sticker can be converted, while this one waits for the upload
+		drop(conversion);
+		let _upload = match advance_config.limits {
+			Some(limits) if !advance_config.dry_run => Some(limits.upload().await),
+			_ => None
+		};
 		#[cfg(feature = "log")]
 		info!("  upload sticker {pack_name}:{positon:03} {emoji:<2} {thumbstr}");
 		let mxc = if advance_config.dry_run {
@@ -117,28 +162,176 @@ impl PhotoSize {
 			{
 				warn!("  upload skipped; dryrun");
 			}
-			Mxc::new("!!! DRY_RUN !!!".to_owned(), Some(image.data.clone())) //cloning Arc is cheap
+			Mxc::new("!!! DRY_RUN !!!".to_owned(), Some(image.data().clone())) //cloning Arc is cheap
 		} else {
-			let (mxc, has_uploded) = image.upload(matrix_config, advance_config.database).await?;
+			let (mxc, has_uploded) = match advance_config.mirror {
+				Some(mirror) => {
+					mirror
+						.upload(&image, pack_name, matrix_config, advance_config.database)
+						.await?
+				},
+				None => image.upload_with(matrix_config, advance_config.database).await?
+			};
 			#[cfg(feature = "log")]
 			if !has_uploded {
 				info!("  upload skipped; file with this hash was already uploaded");
 			}
-			let media_id = mxc.strip_prefix("mxc://").unwrap_or_default().split('/').nth(1).unwrap_or_default();
-			let path = format!("./thumbnails/{}", media_id);
-			fs::write(&path, animated_thumbnail.data.as_ref())
-				.await;
-       			info!("  thumbnail saved: {}", path);
+			let media_id = mxc
+				.strip_prefix("mxc://")
+				.unwrap_or_default()
+				.split('/')
+				.nth(1)
+				.unwrap_or_default();
+			let path = format!("./thumbnails/{media_id}");
+			let saved = fs::write(&path, animated_thumbnail.data_bytes()).await;
+			#[cfg(feature = "log")]
+			match saved {
+				Ok(()) => info!("  thumbnail saved: {path}"),
+				Err(err) => warn!("  failed to save thumbnail {path}: {err}")
+			}
+			#[cfg(not(feature = "log"))]
+			let _ = saved;
 			#[cfg(not(feature = "log"))]
 			let _ = has_uploded; //fix unused warning
 			mxc
 		};
-		let meta_data = ponies::MetaData::try_from(image)?;
-		Ok(matrix::sticker::Image { url: mxc, meta_data })
+		#[cfg(feature = "blurhash")]
+		let blurhash = if image.is_lottie() || image.is_webm() {
+			None
+		} else {
+			match image.blurhash() {
+				Ok(blurhash) => Some(blurhash),
+				Err(_err) => {
+					#[cfg(feature = "log")]
+					warn!("  failed to compute blurhash of sticker {pack_name}:{positon:03}: {_err}");
+					None
+				}
+			}
+		};
+		let duration = if advance_config.emit_duration {
+			// the duration of webp and webm is read by decoding them, which must not block the runtime
+			let image = image.clone();
+			tokio::task::spawn_blocking(move || image.duration()).await??
+		} else {
+			None
+		};
+		let content_hash = image.content_hash().iter().map(|byte| format!("{byte:02x}")).collect();
+		let meta_data = ponies::MetaData {
+			duration,
+			..ponies::MetaData::try_from(image)?
+		};
+		#[cfg(feature = "blurhash")]
+		let meta_data = ponies::MetaData { blurhash, ..meta_data };
+		let imported = ImportedImage {
+			image: matrix::sticker::Image { url: mxc, meta_data },
+			file_name,
+			content_hash
+		};
+		if let (Some(key), Some(database)) = (import_key, advance_config.database) {
+			let sticker = serde_json::to_string(&imported)?;
+			database::retry_unavailable(|| database.add_import(key, sticker.clone())).await?;
+		}
+		Ok(imported)
+	}
+}
+
+/// Sticker image imported by [`PhotoSize::import_with_file_name`],
+/// which is also stored at the database to reuse it at the next import.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct ImportedImage {
+	pub(crate) image: matrix::sticker::Image,
+	/// original file name of the sticker, before it was converted
+	#[serde(skip)]
+	pub(crate) file_name: String,
+	/// hex encoded [hash](database::hash) of the converted file
+	pub(crate) content_hash: String
+}
+
+/// Key of the import of `image` with the given settings at the database,
+/// or `None` if the result of the import can not be reused.
+fn import_key<D>(image: &Image, advance_config: &ImportConfig<'_, D>) -> Option<Hash>
+where
+	D: crate::database::Database + ?Sized
+{
+	// the selector and the mirror do look at the converted files, so they are needed every time
+	if !advance_config.reuse_imports
+		|| advance_config.dry_run
+		|| advance_config.format_selector.is_some()
+		|| advance_config.mirror.is_some()
+	{
+		return None;
+	}
+	let settings = format!(
+		"{} {:?} {:?} {} {} {} {} {}",
+		env!("CARGO_PKG_VERSION"),
+		advance_config.animation_format,
+		advance_config.normalize_longest_side,
+		advance_config.keep_webm,
+		advance_config.keep_lottie,
+		advance_config.collapse_static,
+		advance_config.emit_duration,
+		cfg!(feature = "blurhash")
+	);
+	let mut hasher = Sha512::new();
+	hasher.update(image.content_hash());
+	hasher.update(settings);
+	Some(hasher.finalize().into())
+}
+
+#[cfg(test)]
+thread_local! {
+	/// Number of stickers prepared by this thread, to check which stickers are converted.
+	static PREPARED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Unpack, scale and convert a downloaded sticker; return the sticker and its animated thumbnail.
+async fn prepare<'a, D>(image: Image, advance_config: &ImportConfig<'a, D>) -> Result<(Image, Image), Error>
+where
+	D: crate::database::Database + ?Sized
+{
+	#[cfg(test)]
+	PREPARED.with(|prepared| prepared.set(prepared.get() + 1));
+	let mut image = image.unpack_tgs().await?;
+	// the longest side is scaled to this size; the aspect ratio is kept
+	let sticker_size: u32 = advance_config.normalize_longest_side.unwrap_or(256);
+	let thumbnail_size: u32 = 64;
+	let mut animated_thumbnail: Image = image.clone();
+	if image.is_webp() {
+		image = image.normalize_longest_side(sticker_size)?.into_image();
+		animated_thumbnail = animated_thumbnail.normalize_longest_side(thumbnail_size)?.into_image();
+	}
+	if !advance_config.keep_lottie {
+		let sticker_size = Some(sticker_size);
+		let thumbnail_size = Some(thumbnail_size);
+		let animation_format = match &advance_config.format_selector {
+			Some(selector) => selector.select(&image),
+			None => advance_config.animation_format
+		};
+		image = image.convert_lottie(animation_format, sticker_size, sticker_size).await?;
+		animated_thumbnail = animated_thumbnail
+			.convert_lottie(animation_format, thumbnail_size, thumbnail_size)
+			.await?;
 	}
+	if image.is_webm() && !advance_config.keep_webm {
+		#[cfg(feature = "ffmpeg")]
+		{
+			image = image.convert_webm2webp(Some(sticker_size), Some(sticker_size)).await?;
+			animated_thumbnail = animated_thumbnail
+				.convert_webm2webp(Some(thumbnail_size), Some(thumbnail_size))
+				.await?;
+		}
+		#[cfg(not(feature = "ffmpeg"))]
+		return Err(Error::FeatureNotEnabled("ffmpeg"));
+	}
+	#[cfg(feature = "animation")]
+	if advance_config.collapse_static {
+		image = image.collapse_static_animation()?;
+		animated_thumbnail = animated_thumbnail.collapse_static_animation()?;
+	}
+	Ok((image, animated_thumbnail))
 }
 
-#[derive(Clone, Debug, Deserialize, Getters, Hash)]
+#[derive(Clone, Debug, Deserialize, Eq, Getters, Hash, PartialEq)]
 #[non_exhaustive]
 pub struct Sticker {
 	/// Emoji associated with the sticker.
@@ -159,6 +352,16 @@ pub struct Sticker {
 }
 
 impl Sticker {
+	/// Value of the `body` of the imported sticker.
+	fn body(&self, body_source: BodySource, display_name: &str) -> String {
+		match body_source {
+			BodySource::Emoji => self.emoji.clone().unwrap_or_default(),
+			BodySource::DisplayName if !display_name.is_empty() => display_name.to_owned(),
+			BodySource::DisplayName => self.emoji.clone().unwrap_or_default(),
+			BodySource::Shortcode => format!("{}_{:04}", self.pack_name, self.positon)
+		}
+	}
+
 	/// Import sticker to matrix
 	pub async fn import<'a, D>(
 		&self,
@@ -167,12 +370,12 @@ impl Sticker {
 		advance_config: &ImportConfig<'a, D>
 	) -> Result<crate::matrix::sticker::Sticker, Error>
 	where
-		D: crate::database::Database
+		D: crate::database::Database + ?Sized
 	{
 		// download sticker from telegram
-		let image = self
+		let imported = self
 			.image
-			.import(
+			.import_with_file_name(
 				tg_config,
 				matrix_config,
 				advance_config,
@@ -208,11 +411,13 @@ impl Sticker {
 			index: Some(self.positon)
 		};
 		let sticker = matrix::sticker::Sticker {
-			body: self.emoji.clone().unwrap_or_default(),
-			image,
+			body: self.body(advance_config.body_source, &imported.file_name),
+			display_name: Some(imported.file_name),
 			thumbnail: thumb,
 			emoji: self.emoji.clone().into_iter().collect(),
 			emoticon: None,
+			content_hash: Some(imported.content_hash),
+			image: imported.image,
 			tg_sticker: Some(tg_info)
 		};
 
@@ -226,3 +431,126 @@ impl Sticker {
 		Ok(sticker)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{BodySource, Sticker, PREPARED};
+	use crate::{
+		database::{Database as _, FileDatabase},
+		image::Image,
+		mock::{matrix_config, serve_uploads},
+		recorder::{Exchange, HttpRecorder},
+		tg::{ImportConfig, DEFAULT_MAX_PACK_SIZE}
+	};
+	use std::{cell::Cell, env, net::TcpListener, process, sync::Arc};
+
+	fn sticker() -> Sticker {
+		let mut sticker: Sticker = serde_json::from_str(
+			r#"{
+				"emoji": "😀",
+				"file_id": "id",
+				"file_unique_id": "unique_id",
+				"width": 512,
+				"height": 512,
+				"is_animated": false,
+				"is_video": false
+			}"#
+		)
+		.unwrap();
+		sticker.pack_name = "pack".to_owned();
+		sticker.positon = 7;
+		sticker
+	}
+
+	#[test]
+	fn body_emoji() {
+		assert_eq!(sticker().body(BodySource::Emoji, "file_7.webp"), "😀");
+	}
+
+	#[test]
+	fn body_display_name() {
+		assert_eq!(sticker().body(BodySource::DisplayName, "file_7.webp"), "file_7.webp");
+		assert_eq!(sticker().body(BodySource::DisplayName, ""), "😀");
+	}
+
+	#[test]
+	fn body_shortcode() {
+		assert_eq!(sticker().body(BodySource::Shortcode, "file_7.webp"), "pack_0007");
+	}
+
+	#[tokio::test]
+	async fn reuse_imports() {
+		let dir = env::temp_dir().join(format!("mstickerlib-reuse-imports-test-{}", process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		// telegram is asked for the sticker at each of the three imports
+		let data = Image::from_rgba(vec![255; 512 * 512 * 4], 512, 512, "sticker.webp")
+			.unwrap()
+			.into_data();
+		let get_file = Exchange {
+			method: "GET".to_owned(),
+			url: "https://api.telegram.org/bot%3Credacted%3E/getFile?file_id=id".to_owned(),
+			status: 200,
+			headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+			body: r#"{"ok":true,"result":{"file_path":"stickers/file_7.webp"}}"#.to_owned(),
+			binary: false,
+			truncated: false
+		};
+		let download = Exchange {
+			method: "GET".to_owned(),
+			url: "https://api.telegram.org/file/bot%3Credacted%3E/stickers/file_7.webp".to_owned(),
+			status: 200,
+			headers: Vec::new(),
+			body: data.iter().map(|byte| format!("{byte:02x}")).collect(),
+			binary: true,
+			truncated: false
+		};
+		let recording: Vec<_> = (0..3)
+			.flat_map(|_| [&get_file, &download])
+			.map(|exchange| serde_json::to_string(exchange).unwrap())
+			.collect();
+		std::fs::write(dir.join("recording.jsonl"), recording.join("\n")).unwrap();
+		let tg_config = crate::tg::Config {
+			bot_key: "123:secret".to_owned(),
+			max_pack_size: DEFAULT_MAX_PACK_SIZE,
+			http_recorder: Some(Arc::new(HttpRecorder::replay(dir.join("recording.jsonl")).await.unwrap()))
+		};
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		// the homeserver does only accept one upload, so later uploads would fail
+		let server = std::thread::spawn(move || serve_uploads(listener, 1));
+		let prepared = PREPARED.with(Cell::get);
+
+		let database = FileDatabase::new(dir.join("db.txt")).await.unwrap();
+		let import_config = ImportConfig {
+			database: Some(&database),
+			..Default::default()
+		};
+		let first = sticker().import(&tg_config, &matrix_config, &import_config).await.unwrap();
+		assert_eq!(PREPARED.with(Cell::get) - prepared, 1);
+		server.join().unwrap();
+		database.flush().await.unwrap();
+		drop(database);
+
+		// the import is remembered by the database file
+		let database = FileDatabase::new(dir.join("db.txt")).await.unwrap();
+		let import_config = ImportConfig {
+			database: Some(&database),
+			..Default::default()
+		};
+		let second = sticker().import(&tg_config, &matrix_config, &import_config).await.unwrap();
+		assert_eq!(PREPARED.with(Cell::get) - prepared, 1);
+		assert_eq!(second, first);
+
+		// other settings do convert the sticker again, but the result is still not uploaded twice
+		let import_config = ImportConfig {
+			database: Some(&database),
+			emit_duration: true,
+			..Default::default()
+		};
+		let third = sticker().import(&tg_config, &matrix_config, &import_config).await.unwrap();
+		assert_eq!(PREPARED.with(Cell::get) - prepared, 2);
+		assert_eq!(third.image.url, first.image.url);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -1,17 +1,21 @@
 use super::{sticker::Sticker, tg_get, Config, ImportConfig};
 use crate::{
-	database::Database,
+	database::{self, Database},
 	error::{Error, InvalidPackUrl},
 	matrix
 };
 use derive_getters::Getters;
-use futures_util::future::join_all;
+use futures_util::{
+	stream::{self, FuturesUnordered},
+	Future, Stream, StreamExt
+};
 use serde::Deserialize;
+use std::collections::HashSet;
 
 #[cfg(feature = "log")]
 use log::{info, warn};
 
-#[derive(Clone, Debug, Deserialize, Getters, Hash)]
+#[derive(Clone, Debug, Deserialize, Eq, Getters, Hash, PartialEq)]
 #[non_exhaustive]
 pub struct StickerPack {
 	pub(crate) name: String,
@@ -19,17 +23,105 @@ pub struct StickerPack {
 	pub(crate) stickers: Vec<Sticker>
 }
 
+/// Progress of an import, see [`StickerPack::import_stream`].
+#[derive(Debug)]
+pub enum StickerOutcome {
+	/// The sticker at position `index` of the telegram pack was imported.
+	Imported {
+		index: usize,
+		sticker: Box<matrix::sticker::Sticker>
+	},
+	/// The import of the sticker at position `index` of the telegram pack has failed.
+	Failed { index: usize, error: Error },
+	/// All stickers were processed. This is always the last item.
+	Finished { imported: usize, failed: usize }
+}
+
+/// Run the sticker imports concurrently and yield their outcomes in completion order, followed by a summary.
+fn outcome_stream<F>(imports: impl Iterator<Item = F>) -> impl Stream<Item = StickerOutcome>
+where
+	F: Future<Output = Result<matrix::sticker::Sticker, Error>>
+{
+	let imports: FuturesUnordered<_> = imports
+		.enumerate()
+		.map(|(index, import)| async move { (index, import.await) })
+		.collect();
+	stream::unfold(Some((imports, 0, 0)), |state| async move {
+		let (mut imports, imported, failed) = state?;
+		Some(match imports.next().await {
+			Some((index, Ok(sticker))) => (
+				StickerOutcome::Imported {
+					index,
+					sticker: Box::new(sticker)
+				},
+				Some((imports, imported + 1, failed))
+			),
+			Some((index, Err(error))) => (StickerOutcome::Failed { index, error }, Some((imports, imported, failed + 1))),
+			None => (StickerOutcome::Finished { imported, failed }, None)
+		})
+	})
+}
+
 impl StickerPack {
 	/// Request a stickerpack by its name.
+	///
+	/// The answer of telegram is validated, before anything is downloaded:
+	/// it must not have more than [`Config::max_pack_size`] stickers and every file must be unique.
 	pub async fn get(name: &str, tg_config: &Config) -> Result<Self, Error> {
-		let mut pack: Result<Self, _> = tg_get(tg_config, "getStickerSet", [("name", name)]).await;
-		if let Ok(ref mut pack) = pack {
-			for (i, sticker) in pack.stickers.iter_mut().enumerate() {
-				sticker.pack_name = pack.name.clone();
-				sticker.positon = i;
-			}
+		let mut pack: Self = tg_get(tg_config, "getStickerSet", [("name", name)]).await?;
+		if pack.stickers.len() > tg_config.max_pack_size {
+			return Err(Error::PackTooLarge {
+				name: pack.name,
+				count: pack.stickers.len(),
+				max_pack_size: tg_config.max_pack_size
+			});
 		}
-		pack
+		let mut file_ids = HashSet::new();
+		if let Some(sticker) = pack
+			.stickers
+			.iter()
+			.find(|sticker| !file_ids.insert(&sticker.image().file_unique_id))
+		{
+			return Err(Error::DuplicateSticker {
+				file_unique_id: sticker.image().file_unique_id.clone(),
+				name: pack.name
+			});
+		}
+		for (i, sticker) in pack.stickers.iter_mut().enumerate() {
+			sticker.pack_name = pack.name.clone();
+			sticker.positon = i;
+		}
+		Ok(pack)
+	}
+
+	/// Import the stickers of this pack to matrix, like [`StickerPack::import`],
+	/// but yield every sticker as soon as it was processed, so it can already be shown.
+	/// The stickers are yielded in completion order, together with their position at the pack.
+	/// The last item is always a [`StickerOutcome::Finished`] summary.
+	/// Unlike [`StickerPack::import`], this does not [flush](Database::flush) the database at the end.
+	pub fn import_stream<'a, D>(
+		&'a self,
+		tg_config: &'a Config,
+		matrix_config: &'a matrix::Config,
+		advance_config: &'a ImportConfig<'a, D>
+	) -> impl Stream<Item = StickerOutcome> + 'a
+	where
+		D: Database + ?Sized
+	{
+		#[cfg(feature = "log")]
+		if log::log_enabled!(log::Level::Info) {
+			info!(
+				"import Telegram stickerpack {:?}({})    {{{} Stickers}}",
+				self.title,
+				self.name,
+				self.stickers.len()
+			);
+		}
+		outcome_stream(
+			self.stickers
+				.iter()
+				.map(|f| f.import(tg_config, matrix_config, advance_config))
+		)
 	}
 
 	/// Import this pack to matrix.
@@ -37,6 +129,7 @@ impl StickerPack {
 	/// This function can partially fail, when the import of some stickers has failed (for example sticker use webm format, or reqwest has failed).
 	/// Because of this, the result error type inculde the successful part of the Stickerpack
 	/// and a tupple with the postion of failed stickers and the associated error.
+	/// Errors, which do not belong to a single sticker, have the position after the last sticker.
 	pub async fn import<'a, D>(
 		&self,
 		tg_config: &Config,
@@ -44,39 +137,48 @@ impl StickerPack {
 		advance_config: &ImportConfig<'a, D>
 	) -> Result<matrix::stickerpack::StickerPack, (matrix::stickerpack::StickerPack, Vec<(usize, Error)>)>
 	where
-		D: Database
+		D: Database + ?Sized
 	{
-		#[cfg(feature = "log")]
-		if log::log_enabled!(log::Level::Info) {
-			info!(
-				"import Telegram stickerpack {:?}({})    {{{} Stickers}}",
-				self.title,
-				self.name,
-				self.stickers.len()
-			);
-		}
-
-		let stickers_import_futures = self
-			.stickers
-			.iter()
-			.map(|f| f.import(tg_config, matrix_config, advance_config));
-		let stickers = join_all(stickers_import_futures).await;
-
 		let mut ok_stickers = Vec::new();
 		let mut err_stickers = Vec::new();
-		for (i, sticker) in stickers.into_iter().enumerate() {
-			match sticker {
-				Ok(value) => ok_stickers.push(value),
-				Err(err) => err_stickers.push((i, err))
+		let mut outcomes = std::pin::pin!(self.import_stream(tg_config, matrix_config, advance_config));
+		while let Some(outcome) = outcomes.next().await {
+			match outcome {
+				StickerOutcome::Imported { index, sticker } => ok_stickers.push((index, sticker)),
+				StickerOutcome::Failed { index, error } => err_stickers.push((index, error)),
+				StickerOutcome::Finished { .. } => {}
 			}
 		}
+		// the stickers are already imported, so a failure does only cost uploading them again at the next import
+		if let Some(db) = advance_config.database {
+			if let Err(err) = database::retry_unavailable(|| db.flush()).await {
+				#[cfg(feature = "log")]
+				warn!("failed to flush the database after importing pack {}: {err}", self.name);
+				#[cfg(not(feature = "log"))]
+				let _ = err;
+			}
+		}
+		// restore the order of the pack
+		ok_stickers.sort_by_key(|(index, _)| *index);
+		let (positions, ok_stickers): (Vec<_>, _) =
+			ok_stickers.into_iter().map(|(index, sticker)| (index, *sticker)).unzip();
 
-		let stickerpack = matrix::stickerpack::StickerPack {
+		let mut stickerpack = matrix::stickerpack::StickerPack {
 			title: self.title.clone(),
 			id: format!("tg_name_{}", self.name),
 			tg_pack: Some((&self).to_owned().into()),
 			stickers: ok_stickers
 		};
+		if let Some(dictionary) = advance_config.shortcode_dictionary {
+			match dictionary.apply_with(&mut stickerpack, advance_config.shortcode_collision) {
+				Ok(()) => {},
+				// report the error for the telegram position of the colliding sticker
+				Err(err @ Error::ShortcodeCollision { index, .. }) => err_stickers.push((positions[index], err)),
+				// other errors do not belong to a single sticker
+				Err(err) => err_stickers.push((self.stickers.len(), err))
+			}
+		}
+		err_stickers.sort_by_key(|(index, _)| *index);
 		#[cfg(feature = "log")]
 		if stickerpack.stickers.is_empty() {
 			warn!("imported pack {} is empty", self.name);
@@ -103,20 +205,210 @@ pub fn pack_url_to_name(url: &str) -> Result<&str, InvalidPackUrl> {
 #[cfg(test)]
 mod tests {
 
-	use super::{ImportConfig, StickerPack};
-	use crate::{database::DummyDatabase, image::AnimationFormat};
+	use super::{outcome_stream, ImportConfig, StickerOutcome, StickerPack};
+	use crate::{
+		database::DummyDatabase,
+		error::Error,
+		image::AnimationFormat,
+		matrix::{
+			sticker::{Image, Sticker},
+			sticker_formats::ponies::MetaData,
+			ShortcodeCollision, ShortcodeDictionary
+		},
+		mock::matrix_config,
+		recorder::{Exchange, HttpRecorder},
+		tg::{Config, DEFAULT_MAX_PACK_SIZE}
+	};
+	use futures_util::StreamExt as _;
 	#[cfg(feature = "lottie")]
 	use lottieconv::Rgba;
-	use std::env;
+	use std::{env, sync::Arc, time::Duration};
 
-	async fn import(pack: &str, animation_format: Option<AnimationFormat>) {
-		let matrix_config = crate::matrix::Config {
-			homeserver_url: "none".to_owned(),
-			user: "none".to_owned(),
-			access_token: "none".to_owned()
+	fn sticker(index: usize) -> Sticker {
+		Sticker {
+			body: index.to_string(),
+			display_name: None,
+			image: Image {
+				url: format!("mxc://example.org/{index}").into(),
+				meta_data: MetaData {
+					w: 256,
+					h: 256,
+					size: 1024,
+					mimetype: "image/webp".to_owned(),
+					blurhash: None,
+					duration: None
+				}
+			},
+			thumbnail: None,
+			emoticon: None,
+			emoji: Vec::new(),
+			tg_sticker: None,
+			content_hash: None
+		}
+	}
+
+	/// Request the pack `name` from a recording, which answers `getStickerSet` with `stickers`.
+	async fn get_recorded(name: &str, stickers: &[String]) -> Result<StickerPack, Error> {
+		let path = env::temp_dir().join(format!("mstickerlib-tg-{name}-{}.jsonl", std::process::id()));
+		let exchange = Exchange {
+			method: "GET".to_owned(),
+			url: format!("https://api.telegram.org/bot%3Credacted%3E/getStickerSet?name={name}"),
+			status: 200,
+			headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+			body: format!(
+				r#"{{"ok":true,"result":{{"name":"{name}","title":"Test","stickers":[{}]}}}}"#,
+				stickers.join(",")
+			),
+			binary: false,
+			truncated: false
+		};
+		std::fs::write(&path, serde_json::to_string(&exchange).unwrap()).unwrap();
+		// every other request, like a download, would fail, because it was not recorded
+		let tg_config = Config {
+			bot_key: "123:secret".to_owned(),
+			max_pack_size: DEFAULT_MAX_PACK_SIZE,
+			http_recorder: Some(Arc::new(HttpRecorder::replay(&path).await.unwrap()))
 		};
+		let pack = StickerPack::get(name, &tg_config).await;
+		std::fs::remove_file(&path).unwrap();
+		pack
+	}
+
+	fn tg_sticker(file_unique_id: &str) -> String {
+		format!(
+			r#"{{"file_id":"id_{file_unique_id}","file_unique_id":"{file_unique_id}","width":512,"height":512,"emoji":"🐱","is_animated":false,"is_video":false}}"#
+		)
+	}
+
+	#[tokio::test]
+	async fn get_validates_pack() {
+		let stickers: Vec<_> = (0..2).map(|i| tg_sticker(&i.to_string())).collect();
+		let pack = get_recorded("valid", &stickers).await.unwrap();
+		assert_eq!(pack.stickers.len(), 2);
+		assert_eq!(pack.stickers[1].positon, 1);
+
+		let stickers: Vec<_> = (0..=DEFAULT_MAX_PACK_SIZE).map(|i| tg_sticker(&i.to_string())).collect();
+		let err = get_recorded("oversized", &stickers).await.unwrap_err();
+		assert!(
+			matches!(err, Error::PackTooLarge { count, max_pack_size: DEFAULT_MAX_PACK_SIZE, .. } if count == stickers.len())
+		);
+
+		let stickers = [tg_sticker("a"), tg_sticker("b"), tg_sticker("a")];
+		let err = get_recorded("duplicate", &stickers).await.unwrap_err();
+		assert!(matches!(err, Error::DuplicateSticker { file_unique_id, .. } if file_unique_id == "a"));
+
+		let stickers = [r#"{"file_id":"a","width":512,"height":512,"is_animated":false,"is_video":false}"#.to_owned()];
+		let err = get_recorded("missing", &stickers).await.unwrap_err();
+		assert!(matches!(err, Error::Json(_)));
+	}
+
+	#[tokio::test]
+	async fn shortcode_collision_position() {
+		let path = env::temp_dir().join(format!("mstickerlib-tg-collision-{}.jsonl", std::process::id()));
+		let data = crate::image::Image::from_rgba(vec![255; 512 * 512 * 4], 512, 512, "sticker.webp")
+			.unwrap()
+			.into_data();
+		let stickers: Vec<_> = (0..3).map(|i| tg_sticker(&i.to_string())).collect();
+		let get_sticker_set = Exchange {
+			method: "GET".to_owned(),
+			url: "https://api.telegram.org/bot%3Credacted%3E/getStickerSet?name=cats".to_owned(),
+			status: 200,
+			headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+			body: format!(
+				r#"{{"ok":true,"result":{{"name":"cats","title":"Cats","stickers":[{}]}}}}"#,
+				stickers.join(",")
+			),
+			binary: false,
+			truncated: false
+		};
+		// the first sticker can not be downloaded, so the colliding sticker has another index at the imported pack
+		let mut recording = vec![get_sticker_set];
+		for i in 1..3 {
+			recording.push(Exchange {
+				method: "GET".to_owned(),
+				url: format!("https://api.telegram.org/bot%3Credacted%3E/getFile?file_id=id_{i}"),
+				status: 200,
+				headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+				body: format!(r#"{{"ok":true,"result":{{"file_path":"stickers/file_{i}.webp"}}}}"#),
+				binary: false,
+				truncated: false
+			});
+			recording.push(Exchange {
+				method: "GET".to_owned(),
+				url: format!("https://api.telegram.org/file/bot%3Credacted%3E/stickers/file_{i}.webp"),
+				status: 200,
+				headers: Vec::new(),
+				body: data.iter().map(|byte| format!("{byte:02x}")).collect(),
+				binary: true,
+				truncated: false
+			});
+		}
+		let recording: Vec<_> = recording
+			.iter()
+			.map(|exchange| serde_json::to_string(exchange).unwrap())
+			.collect();
+		std::fs::write(&path, recording.join("\n")).unwrap();
+		let tg_config = Config {
+			bot_key: "123:secret".to_owned(),
+			max_pack_size: DEFAULT_MAX_PACK_SIZE,
+			http_recorder: Some(Arc::new(HttpRecorder::replay(&path).await.unwrap()))
+		};
+		let pack = StickerPack::get("cats", &tg_config).await.unwrap();
+		let dictionary = ShortcodeDictionary::from_json(r#"{"🐱": "kot"}"#).unwrap();
+		let import_config = ImportConfig::<DummyDatabase> {
+			dry_run: true,
+			shortcode_dictionary: Some(&dictionary),
+			shortcode_collision: ShortcodeCollision::Error,
+			..Default::default()
+		};
+		let (pack, errors) = pack
+			.import(&tg_config, &matrix_config("none"), &import_config)
+			.await
+			.unwrap_err();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(pack.stickers.len(), 2);
+		let positions: Vec<_> = errors.iter().map(|(position, _)| *position).collect();
+		assert_eq!(positions, [0, 2]);
+		assert!(matches!(&errors[0].1, Error::NotRecorded { .. }));
+		assert!(matches!(&errors[1].1, Error::ShortcodeCollision { index: 1, .. }));
+	}
+
+	#[tokio::test]
+	async fn outcome_stream_order() {
+		// later stickers finish first; sticker 1 fails
+		let imports = (0..4).map(|index| async move {
+			tokio::time::sleep(Duration::from_millis(40 - index as u64 * 10)).await;
+			match index {
+				1 => Err(Error::MalformedImage("webp")),
+				_ => Ok(sticker(index))
+			}
+		});
+		let outcomes: Vec<_> = outcome_stream(imports).collect().await;
+		assert_eq!(outcomes.len(), 5);
+		let indices: Vec<_> = outcomes
+			.iter()
+			.filter_map(|outcome| match outcome {
+				StickerOutcome::Imported { index, sticker } => {
+					assert_eq!(sticker.body, index.to_string());
+					Some(*index)
+				},
+				StickerOutcome::Failed { index, .. } => Some(*index),
+				StickerOutcome::Finished { .. } => None
+			})
+			.collect();
+		assert_eq!(indices, [3, 2, 1, 0]);
+		assert!(matches!(
+			outcomes.last(),
+			Some(StickerOutcome::Finished { imported: 3, failed: 1 })
+		));
+	}
+
+	async fn import(pack: &str, animation_format: Option<AnimationFormat>) {
+		let matrix_config = matrix_config("none");
 		let tg_config = crate::tg::Config {
-			bot_key: env::var("TG_BOT_KEY").expect("environment variables TG_BOT_KEY is not set")
+			bot_key: env::var("TG_BOT_KEY").expect("environment variables TG_BOT_KEY is not set"),
+			max_pack_size: crate::tg::DEFAULT_MAX_PACK_SIZE,
+			http_recorder: None
 		};
 		let pack = StickerPack::get(pack, &tg_config).await.unwrap();
 		let mut import_config = ImportConfig::<DummyDatabase> {
@@ -0,0 +1,196 @@
+use super::{Config, ImportConfig};
+use crate::{
+	database::{Database, Hash},
+	matrix
+};
+use std::fmt::{self, Display};
+use url::Url;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+	/// import can probably start, but some stickers can fail or be imported differently than expected
+	Warning,
+	/// import can not work with this configuration
+	Error
+}
+
+/// Problem found by [`ImportConfig::validate_all`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigIssue {
+	pub severity: Severity,
+	/// name of the affected config option
+	pub field: &'static str,
+	pub message: String
+}
+
+impl ConfigIssue {
+	fn error(field: &'static str, message: impl Into<String>) -> Self {
+		Self {
+			severity: Severity::Error,
+			field,
+			message: message.into()
+		}
+	}
+
+	#[allow(dead_code)] // only used if some features are disabled
+	fn warning(field: &'static str, message: impl Into<String>) -> Self {
+		Self {
+			severity: Severity::Warning,
+			field,
+			message: message.into()
+		}
+	}
+}
+
+impl Display for ConfigIssue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let severity = match self.severity {
+			Severity::Warning => "warning",
+			Severity::Error => "error"
+		};
+		write!(f, "{severity}: {}: {}", self.field, self.message)
+	}
+}
+
+fn validate_matrix(matrix_config: &matrix::Config, issues: &mut Vec<ConfigIssue>) {
+	match Url::parse(&matrix_config.homeserver_url) {
+		Ok(url) if url.scheme() != "https" && url.scheme() != "http" => issues.push(ConfigIssue::error(
+			"matrix.homeserver_url",
+			format!("unsupported url scheme {:?}", url.scheme())
+		)),
+		Ok(_) => {},
+		Err(err) => issues.push(ConfigIssue::error("matrix.homeserver_url", format!("invalid url: {err}")))
+	}
+	let user_valid = matrix_config
+		.user
+		.strip_prefix('@')
+		.and_then(|user| user.split_once(':'))
+		.is_some_and(|(localpart, server)| !localpart.is_empty() && !server.is_empty());
+	if !user_valid {
+		issues.push(ConfigIssue::error(
+			"matrix.user",
+			format!("{:?} is not a matrix user id like @user:example.org", matrix_config.user)
+		));
+	}
+	if matrix_config.access_token.is_empty() {
+		issues.push(ConfigIssue::error("matrix.access_token", "access token is empty"));
+	} else if matrix_config.access_token.contains(char::is_whitespace) {
+		issues.push(ConfigIssue::error("matrix.access_token", "access token contains whitespace"));
+	}
+}
+
+fn validate_telegram(tg_config: &Config, issues: &mut Vec<ConfigIssue>) {
+	// bot tokens look like `123456:ABC-DEF1234ghIkl-zyx57W2v1u123ew11`
+	let bot_key_valid = tg_config.bot_key.split_once(':').is_some_and(|(id, secret)| {
+		!id.is_empty()
+			&& id.chars().all(|char| char.is_ascii_digit())
+			&& !secret.is_empty()
+			&& !secret.contains(char::is_whitespace)
+	});
+	if !bot_key_valid {
+		issues.push(ConfigIssue::error(
+			"telegram.bot_key",
+			"bot key does not look like a telegram bot token"
+		));
+	}
+}
+
+impl<D> ImportConfig<'_, D>
+where
+	D: Database + ?Sized
+{
+	/// Check this config together with the telegram and matrix config and return all problems at once,
+	/// instead of failing on the first one at import.
+	/// The import should not be started, if any issue has the severity [`Severity::Error`].
+	pub async fn validate_all(&self, tg_config: &Config, matrix_config: &matrix::Config) -> Vec<ConfigIssue> {
+		let mut issues = Vec::new();
+		validate_matrix(matrix_config, &mut issues);
+		validate_telegram(tg_config, &mut issues);
+
+		#[cfg(not(feature = "lottie"))]
+		if !self.keep_lottie {
+			issues.push(ConfigIssue::warning(
+				"keep_lottie",
+				"mstickerlib was compiled without the \"lottie\" feature, so the import of animated stickers will fail"
+			));
+		}
+		#[cfg(not(feature = "ffmpeg"))]
+		if !self.keep_webm {
+			issues.push(ConfigIssue::warning(
+				"keep_webm",
+				"mstickerlib was compiled without the \"ffmpeg\" feature, so the import of video stickers will fail"
+			));
+		}
+
+		#[cfg(any(feature = "lottie", feature = "ffmpeg"))]
+		if let Err(err) = tempfile::tempfile() {
+			issues.push(ConfigIssue::error(
+				"temp_dir",
+				format!("failed to create temporary file, which is needed for conversion: {err}")
+			));
+		}
+
+		if let Some(database) = self.database {
+			let hash: Hash = [0; 64];
+			if let Err(err) = database.get(&hash).await {
+				issues.push(ConfigIssue::error("database", format!("database is not accessible: {err:?}")));
+			}
+		}
+		issues
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ConfigIssue, Severity};
+	use crate::{
+		database::{Database, Hash},
+		matrix,
+		tg::{Config, ImportConfig}
+	};
+	use std::io;
+
+	struct BrokenDatabase;
+
+	impl Database for BrokenDatabase {
+		type Error = io::Error;
+
+		async fn get(&self, _: &Hash) -> io::Result<Option<String>> {
+			Err(io::ErrorKind::ConnectionRefused.into())
+		}
+
+		async fn add(&self, _: Hash, _: String) -> io::Result<()> {
+			Err(io::ErrorKind::ConnectionRefused.into())
+		}
+	}
+
+	#[tokio::test]
+	async fn validate_all() {
+		let matrix_config = matrix::Config::new("example.org".to_owned(), "user".to_owned(), String::new());
+		let tg_config = Config {
+			bot_key: "not a key".to_owned(),
+			max_pack_size: crate::tg::DEFAULT_MAX_PACK_SIZE,
+			http_recorder: None
+		};
+		let import_config = ImportConfig {
+			database: Some(&BrokenDatabase),
+			keep_lottie: true,
+			keep_webm: true,
+			..Default::default()
+		};
+		let issues = import_config.validate_all(&tg_config, &matrix_config).await;
+		let fields: Vec<_> = issues.iter().map(|issue| issue.field).collect();
+		assert_eq!(fields, [
+			"matrix.homeserver_url",
+			"matrix.user",
+			"matrix.access_token",
+			"telegram.bot_key",
+			"database"
+		]);
+		assert!(issues.iter().all(|issue| issue.severity == Severity::Error));
+		assert_eq!(
+			ConfigIssue::error("matrix.access_token", "access token is empty").to_string(),
+			"error: matrix.access_token: access token is empty"
+		);
+	}
+}
@@ -0,0 +1,239 @@
+//! Spans around the stages of the import pipeline, which are emitted with the `tracing` feature.
+//!
+//! Every span has the attributes `file_name` and `bytes_in`; `bytes_out` and `cache_hit` are recorded
+//! once the stage has finished. Without the feature all of this does compile to nothing.
+
+use std::{future::Future, marker::PhantomData, path::Path};
+
+/// Stage of the import pipeline, which gets its own span.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Stage {
+	/// download and conversion of a telegram sticker, before it is uploaded
+	Prepare,
+	Convert,
+	Resize,
+	Upload,
+	/// lookup of an image at the [`Database`](crate::database::Database)
+	DbLookup
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct StageSpan {
+	#[cfg(feature = "tracing")]
+	span: tracing::Span
+}
+
+/// Guard of [`StageSpan::enter`]; the span is left, when it is dropped.
+pub(crate) struct Entered<'a> {
+	#[cfg(feature = "tracing")]
+	_entered: tracing::span::Entered<'a>,
+	_span: PhantomData<&'a StageSpan>
+}
+
+impl StageSpan {
+	pub(crate) fn new(stage: Stage, file_name: &Path, bytes_in: usize) -> Self {
+		#[cfg(feature = "tracing")]
+		{
+			macro_rules! span {
+				($name:literal) => {
+					tracing::info_span!(
+						$name,
+						file_name = %file_name.display(),
+						bytes_in,
+						bytes_out = tracing::field::Empty,
+						cache_hit = tracing::field::Empty
+					)
+				};
+			}
+			let span = match stage {
+				Stage::Prepare => span!("prepare"),
+				Stage::Convert => span!("convert"),
+				Stage::Resize => span!("resize"),
+				Stage::Upload => span!("upload"),
+				Stage::DbLookup => span!("db_lookup")
+			};
+			Self { span }
+		}
+		#[cfg(not(feature = "tracing"))]
+		{
+			let _ = (stage, file_name, bytes_in);
+			Self {}
+		}
+	}
+
+	pub(crate) fn bytes_out(&self, bytes: usize) {
+		#[cfg(feature = "tracing")]
+		self.span.record("bytes_out", bytes);
+		#[cfg(not(feature = "tracing"))]
+		let _ = bytes;
+	}
+
+	pub(crate) fn cache_hit(&self, hit: bool) {
+		#[cfg(feature = "tracing")]
+		self.span.record("cache_hit", hit);
+		#[cfg(not(feature = "tracing"))]
+		let _ = hit;
+	}
+
+	/// Enter the span for synchronous code; do not hold the guard across an `.await`, use [`StageSpan::run`] instead.
+	pub(crate) fn enter(&self) -> Entered<'_> {
+		Entered {
+			#[cfg(feature = "tracing")]
+			_entered: self.span.enter(),
+			_span: PhantomData
+		}
+	}
+
+	/// Poll `future` inside of the span, so spans of nested stages become its children.
+	pub(crate) async fn run<F>(&self, future: F) -> F::Output
+	where
+		F: Future
+	{
+		#[cfg(feature = "tracing")]
+		{
+			use tracing::Instrument as _;
+			future.instrument(self.span.clone()).await
+		}
+		#[cfg(not(feature = "tracing"))]
+		future.await
+	}
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+	use crate::{
+		database::FileDatabase,
+		image::{ConversionConfig, Image},
+		mock::{matrix_config, serve_uploads}
+	};
+	use std::{
+		collections::HashMap,
+		fmt::Debug,
+		net::TcpListener,
+		sync::{Arc, Mutex}
+	};
+	use tracing::{
+		field::{Field, Visit},
+		span::{Attributes, Id, Record},
+		Event, Metadata, Subscriber
+	};
+
+	#[derive(Debug)]
+	struct CapturedSpan {
+		name: &'static str,
+		parent: Option<u64>,
+		fields: HashMap<&'static str, String>
+	}
+
+	impl Visit for CapturedSpan {
+		fn record_str(&mut self, field: &Field, value: &str) {
+			self.fields.insert(field.name(), value.to_owned());
+		}
+
+		fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+			self.fields.insert(field.name(), format!("{value:?}"));
+		}
+	}
+
+	/// Subscriber, which keeps all spans and their attributes.
+	#[derive(Clone, Default)]
+	struct Capture {
+		spans: Arc<Mutex<Vec<CapturedSpan>>>,
+		stack: Arc<Mutex<Vec<u64>>>
+	}
+
+	impl Capture {
+		fn spans(&self, name: &str) -> Vec<HashMap<&'static str, String>> {
+			let spans = self.spans.lock().unwrap();
+			spans
+				.iter()
+				.filter(|span| span.name == name)
+				.map(|span| span.fields.clone())
+				.collect()
+		}
+
+		fn parent(&self, name: &str) -> Option<&'static str> {
+			let spans = self.spans.lock().unwrap();
+			let parent = spans.iter().find(|span| span.name == name)?.parent?;
+			Some(spans[parent as usize - 1].name)
+		}
+	}
+
+	impl Subscriber for Capture {
+		fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+			true
+		}
+
+		fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+			let mut span = CapturedSpan {
+				name: attributes.metadata().name(),
+				parent: self.stack.lock().unwrap().last().copied(),
+				fields: HashMap::new()
+			};
+			attributes.record(&mut span);
+			let mut spans = self.spans.lock().unwrap();
+			spans.push(span);
+			Id::from_u64(spans.len() as u64)
+		}
+
+		fn record(&self, span: &Id, values: &Record<'_>) {
+			values.record(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1]);
+		}
+
+		fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+		fn event(&self, _event: &Event<'_>) {}
+
+		fn enter(&self, span: &Id) {
+			self.stack.lock().unwrap().push(span.into_u64());
+		}
+
+		fn exit(&self, _span: &Id) {
+			self.stack.lock().unwrap().pop();
+		}
+	}
+
+	#[tokio::test]
+	async fn spans() {
+		let capture = Capture::default();
+		let _guard = tracing::subscriber::set_default(capture.clone());
+
+		let image = Image::from_rgba(vec![255; 8 * 4 * 4], 8, 4, "sticker.webp").unwrap();
+		let bytes_in = image.data_len().to_string();
+		let image = image
+			.convert(&ConversionConfig::default(), None, Some(4), Some(4))
+			.await
+			.unwrap();
+		let convert = capture.spans("convert");
+		assert_eq!(convert.len(), 1);
+		assert_eq!(convert[0]["file_name"], "sticker.webp");
+		assert_eq!(convert[0]["bytes_in"], bytes_in);
+		assert_eq!(convert[0]["bytes_out"], image.data_len().to_string());
+		assert_eq!(capture.spans("resize")[0]["bytes_out"], image.data_len().to_string());
+		assert_eq!(capture.parent("resize"), Some("convert"));
+
+		let dir = std::env::temp_dir().join(format!("mstickerlib-trace-test-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		let db = FileDatabase::new(dir.join("db.txt")).await.unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let matrix_config = matrix_config(format!("http://{}", listener.local_addr().unwrap()));
+		let server = std::thread::spawn(move || serve_uploads(listener, 1));
+		// the second upload is found at the database
+		image.upload_with(&matrix_config, Some(&db)).await.unwrap();
+		image.upload_with(&matrix_config, Some(&db)).await.unwrap();
+		server.join().unwrap();
+		let upload = capture.spans("upload");
+		assert_eq!(upload.len(), 2);
+		assert_eq!(upload[0]["cache_hit"], "false");
+		assert_eq!(upload[0]["bytes_out"], image.data_len().to_string());
+		assert_eq!(upload[1]["cache_hit"], "true");
+		assert_eq!(upload[1]["bytes_out"], "0");
+		let lookup = capture.spans("db_lookup");
+		assert_eq!(lookup.len(), 2);
+		assert_eq!(lookup[0]["cache_hit"], "false");
+		assert_eq!(lookup[1]["cache_hit"], "true");
+		assert_eq!(capture.parent("db_lookup"), Some("upload"));
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
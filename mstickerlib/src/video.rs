@@ -1,59 +1,213 @@
 //! This module deals with translating telegram's video stickers to webp animations.
 
-use crate::error::Error;
+use crate::{error::Error, image::Image};
+#[cfg(unix)]
+use ffmpeg::Dictionary;
 use ffmpeg::{
 	codec::Context as CodecContext,
 	decoder,
 	format::{self, Pixel},
 	media::Type,
 	software::scaling::{context::Context as ScalingContext, flag::Flags},
-	util::frame::video::Video
+	util::frame::video::Video,
+	Packet
+};
+#[cfg(unix)]
+use std::{io, os::fd::AsRawFd as _, thread};
+use std::{
+	io::Write as _,
+	path::Path,
+	sync::Arc,
+	time::{Duration, Instant}
 };
-use std::path::Path;
 use webp_animation::{Encoder, WebPData};
 
-pub(crate) fn webm2webp<P: AsRef<Path>>(file: &P, width: Option<u32>, height: Option<u32>) -> Result<(WebPData, u32, u32), Error> {
+#[cfg(feature = "log")]
+use log::warn;
+
+/// Point in time, after which a conversion is aborted.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline(Option<Instant>);
+
+impl Deadline {
+	/// Deadline `timeout` from now; a timeout too large to be represented does never expire.
+	pub(crate) fn after(timeout: Duration) -> Self {
+		Self(Instant::now().checked_add(timeout))
+	}
+
+	pub(crate) fn expired(&self) -> bool {
+		self.0.is_some_and(|deadline| Instant::now() >= deadline)
+	}
+
+	/// Fail with [`ffmpeg::Error::Exit`], like an interrupted ffmpeg call, if the deadline is expired.
+	fn check(&self) -> Result<(), Error> {
+		if self.expired() {
+			return Err(ffmpeg::Error::Exit.into());
+		}
+		Ok(())
+	}
+}
+
+/// Properties of a video, which are read from its container without decoding it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VideoInfo {
+	pub width: u32,
+	pub height: u32,
+	/// duration in milliseconds, if the container does store it
+	pub duration_ms: Option<u64>,
+	/// If the video has an audio track. Audio is always dropped by the conversion to webp.
+	pub has_audio: bool
+}
+
+/// Duration of the video in milliseconds, if the container does store it.
+pub(crate) fn duration<P: AsRef<Path>>(file: &P) -> Result<Option<u64>, Error> {
+	let ictx = format::input(file)?;
+	Ok(container_duration(&ictx))
+}
+
+fn container_duration(ictx: &format::context::Input) -> Option<u64> {
+	// the duration is stored in microseconds; negative values mean the duration is unknown
+	u64::try_from(ictx.duration()).ok().map(|duration| duration / 1000)
+}
+
+pub(crate) fn probe<P: AsRef<Path>>(file: &P) -> Result<VideoInfo, Error> {
+	let ictx = format::input(file)?;
+	let input = ictx.streams().best(Type::Video).ok_or(ffmpeg::Error::StreamNotFound)?;
+	let decoder = CodecContext::from_parameters(input.parameters())?.decoder().video()?;
+	let has_audio = ictx.streams().any(|stream| stream.parameters().medium() == Type::Audio);
+	Ok(VideoInfo {
+		width: decoder.width(),
+		height: decoder.height(),
+		duration_ms: container_duration(&ictx),
+		has_audio
+	})
+}
+
+#[cfg(test)]
+thread_local! {
+	/// Number of temporary files created by [`webm2webp`] on this thread.
+	static TEMP_FILES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Convert the webm video `data`; reading and decoding is aborted with [`ffmpeg::Error::Exit`], once the `deadline` is expired.
+///
+/// On unix the video is passed to ffmpeg through a pipe, so it is never written to disk.
+/// ffmpeg builds, which can not open the pipe, like builds before ffmpeg 6.0 without the `fd` protocol,
+/// read the video from a temporary file instead.
+pub(crate) fn webm2webp(
+	data: &Arc<Vec<u8>>,
+	width: Option<u32>,
+	height: Option<u32>,
+	deadline: &Deadline
+) -> Result<(WebPData, u32, u32), Error> {
+	#[cfg(unix)]
+	if let Some(converted) = webm2webp_piped(data, width, height, deadline)? {
+		return Ok(converted);
+	}
+
+	let mut tmp = tempfile::Builder::new().suffix(".webm").tempfile()?;
+	#[cfg(test)]
+	TEMP_FILES.with(|files| files.set(files.get() + 1));
+	tmp.write_all(data)?;
+	tmp.flush()?;
+	let deadline = *deadline;
+	let mut ictx = format::input_with_interrupt(tmp.path(), move || deadline.expired())?;
+	convert_input(&mut ictx, width, height, &deadline)
+}
+
+/// Convert the video `data`, which a thread writes into a pipe, while ffmpeg reads it by the `fd` protocol.
+/// Return `None`, if ffmpeg can not open the pipe.
+#[cfg(unix)]
+fn webm2webp_piped(
+	data: &Arc<Vec<u8>>,
+	width: Option<u32>,
+	height: Option<u32>,
+	deadline: &Deadline
+) -> Result<Option<(WebPData, u32, u32)>, Error> {
+	let (mut reader, mut writer) = io::pipe()?;
+	let data = Arc::clone(data);
+	let feeder = thread::spawn(move || writer.write_all(&data));
+	let mut options = Dictionary::new();
+	options.set("fd", &reader.as_raw_fd().to_string());
+	// the fd protocol reads from a duplicate of the descriptor, so the read end stays owned by `reader`
+	let converted = match format::input_with_dictionary("fd:", options) {
+		Ok(mut ictx) => Some(convert_input(&mut ictx, width, height, deadline)),
+		Err(_err) => {
+			#[cfg(feature = "log")]
+			warn!("ffmpeg can not read from a pipe ({_err}), using a temporary file instead");
+			None
+		}
+	};
+	// ffmpeg may stop reading before the end of the video; draining the pipe lets the thread finish writing
+	io::copy(&mut reader, &mut io::sink())?;
+	feeder.join().expect("writing the video into the pipe panicked")?;
+	converted.transpose()
+}
+
+/// Convert the video of the opened container `ictx`.
+fn convert_input(
+	ictx: &mut format::context::Input,
+	width: Option<u32>,
+	height: Option<u32>,
+	deadline: &Deadline
+) -> Result<(WebPData, u32, u32), Error> {
 	// heavily inspired by
 	// https://github.com/zmwangx/rust-ffmpeg/blob/master/examples/dump-frames.rs
 
-	let mut ictx = format::input(file)?;
 	let input = ictx.streams().best(Type::Video).ok_or(ffmpeg::Error::StreamNotFound)?;
 
 	let video_stream_index = input.index();
 	let ctx_decoder = CodecContext::from_parameters(input.parameters())?;
 	let mut decoder = ctx_decoder.decoder().video()?;
 
-	let new_width = width.unwrap_or(decoder.width());
-	let new_height = height.unwrap_or(decoder.height());
+	// keep the aspect ratio, so non square stickers are not distorted
+	let (new_width, new_height) = Image::resize_preserving_aspect_ratio(decoder.width(), decoder.height(), width, height);
+	// the scaler does only produce chroma aligned frames with even dimensions
+	let (new_width, new_height) = (even(new_width), even(new_height));
 
 	let mut scaler = ScalingContext::get(
 		decoder.format(),
 		decoder.width(),
 		decoder.height(),
 		Pixel::RGBA,
-		new_width.clone(),
-		new_height.clone(),
+		new_width,
+		new_height,
 		Flags::BILINEAR
 	)?;
 
-	let mut encoder = Encoder::new((new_width.clone(), new_height.clone()))?;
+	let mut encoder = Encoder::new((new_width, new_height))?;
 	let mut timestamp = 0;
 	let frame_rate = input.rate();
 	let time_per_frame = frame_rate.1 * 1000 / frame_rate.0;
 	let mut receive_and_process_decoded_frames = |decoder: &mut decoder::Video| -> Result<(), Error> {
 		let mut decoded = Video::empty();
 		while decoder.receive_frame(&mut decoded).is_ok() {
+			deadline.check()?;
 			let mut rgba_frame = Video::empty();
 			scaler.run(&decoded, &mut rgba_frame)?;
+			debug_assert_eq!((rgba_frame.width(), rgba_frame.height()), (new_width, new_height));
 
-			encoder.add_frame(rgba_frame.data(0), timestamp)?;
+			encoder.add_frame(&packed_rows(&rgba_frame), timestamp)?;
 			timestamp += time_per_frame;
 		}
 		Ok(())
 	};
 
-	for (stream, packet) in ictx.packets() {
-		if stream.index() == video_stream_index {
+	// The packet iterator of ffmpeg does retry unreadable packets forever, so the packets are read here,
+	// to stop at the deadline and at read errors. Reading is only tried again, if no data is available yet.
+	// Only the packets of the video stream are decoded; audio tracks are dropped.
+	loop {
+		deadline.check()?;
+		let mut packet = Packet::empty();
+		match packet.read(ictx) {
+			Ok(()) => {},
+			Err(ffmpeg::Error::Eof) => break,
+			Err(ffmpeg::Error::Other {
+				errno: ffmpeg::error::EAGAIN
+			}) => continue,
+			Err(err) => return Err(err.into())
+		}
+		if packet.stream() == video_stream_index {
 			decoder.send_packet(&packet)?;
 			receive_and_process_decoded_frames(&mut decoder)?;
 		}
@@ -64,3 +218,86 @@ pub(crate) fn webm2webp<P: AsRef<Path>>(file: &P, width: Option<u32>, height: Op
 	let webp = encoder.finalize(timestamp)?;
 	Ok((webp, new_width, new_height))
 }
+
+/// Round `size` down to an even number, but at least 2.
+fn even(size: u32) -> u32 {
+	(size & !1).max(2)
+}
+
+/// Pixels of an rgba frame without the padding, which ffmpeg may add at the end of each row.
+fn packed_rows(frame: &Video) -> std::borrow::Cow<'_, [u8]> {
+	let row_len = frame.width() as usize * 4;
+	let stride = frame.stride(0);
+	let data = frame.data(0);
+	if stride == row_len {
+		return data[..row_len * frame.height() as usize].into();
+	}
+	data.chunks(stride)
+		.take(frame.height() as usize)
+		.flat_map(|row| &row[..row_len])
+		.copied()
+		.collect::<Vec<u8>>()
+		.into()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{webm2webp, Deadline, TEMP_FILES};
+	use crate::{error::Error, image::ImageBuilder};
+	use photon_rs::PhotonImage;
+	use std::{sync::Arc, time::Duration};
+
+	/// Three 64×48 VP8 frames at 10 fps.
+	const WEBM: &[u8] = include_bytes!("../tests/fixtures/sticker.webm");
+
+	#[cfg(unix)]
+	#[test]
+	fn convert_without_temp_file() {
+		let data = Arc::new(WEBM.to_vec());
+		let (webp, width, height) = webm2webp(&data, None, None, &Deadline::after(Duration::from_secs(60))).unwrap();
+		assert_eq!(TEMP_FILES.with(|files| files.get()), 0);
+		assert_eq!((width, height), (64, 48));
+		let decoder = webp_animation::Decoder::new(&webp).unwrap();
+		assert_eq!(decoder.into_iter().count(), 3);
+	}
+
+	#[tokio::test]
+	async fn timeout() {
+		let pixels = (0..8 * 4).flat_map(|i| [i as u8 * 8, 0, 255, 255]).collect();
+		let image = ImageBuilder::new()
+			.data(PhotonImage::new(pixels, 8, 4).get_bytes())
+			.file_name("sticker.webm")
+			.width(8)
+			.height(4)
+			.build()
+			.unwrap();
+		let err = image
+			.convert_webm2webp_with_timeout(None, None, Duration::ZERO)
+			.await
+			.unwrap_err();
+		assert!(
+			matches!(err, Error::Timeout {
+				timeout: Duration::ZERO,
+				..
+			}),
+			"{err}"
+		);
+	}
+
+	#[tokio::test]
+	async fn odd_size() {
+		let pixels = (0..7 * 5).flat_map(|i| [i as u8 * 7, 0, 255, 255]).collect();
+		let image = ImageBuilder::new()
+			.data(PhotonImage::new(pixels, 7, 5).get_bytes())
+			.file_name("sticker.webm")
+			.width(7)
+			.height(5)
+			.build()
+			.unwrap();
+		let webp = image.convert_webm2webp(None, None).await.unwrap();
+		assert_eq!((webp.width, webp.height), (6, 4));
+		// the stored size is the one of the encoded animation
+		let decoder = webp_animation::Decoder::new(webp.data_bytes()).unwrap();
+		assert_eq!(decoder.dimensions(), (webp.width, webp.height));
+	}
+}
@@ -0,0 +1,7 @@
+//! Internals of the crate must not be reachable from outside; see the files at `tests/compile_fail`.
+
+#[test]
+fn internals_are_not_public() {
+	let tests = trybuild::TestCases::new();
+	tests.compile_fail("tests/compile_fail/*.rs");
+}
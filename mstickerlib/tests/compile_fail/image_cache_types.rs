@@ -0,0 +1,4 @@
+// the caches of an image are an implementation detail
+use mstickerlib::image::{ContentHash, PixelCache};
+
+fn main() {}
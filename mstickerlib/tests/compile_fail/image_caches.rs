@@ -0,0 +1,11 @@
+// the decoded pixels and the hash of an image belong to its data and can not be set from outside
+use mstickerlib::image::Image;
+
+fn main() {
+	let image = Image::from_rgba(vec![0; 4], 1, 1, "sticker.webp").unwrap();
+	let _ = Image {
+		pixel_cache: image.pixel_cache.clone(),
+		content_hash: Default::default(),
+		..image
+	};
+}
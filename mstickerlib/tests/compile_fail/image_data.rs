@@ -0,0 +1,8 @@
+// the data of an image is only replaced as a whole by `Image::set_data`, which drops the caches of the old data
+use mstickerlib::image::Image;
+use std::sync::Arc;
+
+fn main() {
+	let mut image = Image::from_rgba(vec![0; 4], 1, 1, "sticker.webp").unwrap();
+	Arc::make_mut(&mut image.data).push(0);
+}
@@ -0,0 +1,11 @@
+// single stickers are imported by `tg::Sticker::import`, the thumbnails and images are an implementation detail
+use mstickerlib::{database::DummyDatabase, matrix, tg};
+
+async fn import(photo: tg::PhotoSize, tg_config: tg::Config, matrix_config: matrix::Config) {
+	let import_config: tg::ImportConfig<'_, DummyDatabase> = Default::default();
+	let _ = photo
+		.import(&tg_config, &matrix_config, &import_config, "pack", 0, None, false)
+		.await;
+}
+
+fn main() {}